@@ -0,0 +1,77 @@
+use criterion::{
+    criterion_group,
+    criterion_main,
+    BatchSize,
+    Criterion,
+};
+use std::sync::Arc;
+use timsseek::digest::digestion::{
+    DigestionEnd,
+    DigestionParameters,
+    DigestionPattern,
+    InitiatorMethionine,
+    TerminalClipping,
+};
+use timsseek::protein::fasta::{
+    ProteinSequenceCollection,
+    ProteinSequenceNmerIndex,
+};
+
+const AMINO_ACIDS: &[u8] = b"ACDEFGHIKLMNPQRSTVWY";
+
+/// Pseudo-random protein sequences, so the benchmark needs no database.
+fn random_proteins(num_proteins: usize, length: usize) -> Vec<String> {
+    let mut state: u64 = 42;
+    (0..num_proteins)
+        .map(|_| {
+            (0..length)
+                .map(|_| {
+                    state = state
+                        .wrapping_mul(6364136223846793005)
+                        .wrapping_add(1442695040888963407);
+                    AMINO_ACIDS[(state >> 33) as usize % AMINO_ACIDS.len()] as char
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn fasta_text(proteins: &[String]) -> String {
+    proteins
+        .iter()
+        .enumerate()
+        .map(|(i, x)| format!(">sp|P{:05}|PROT{}_HUMAN\n{}\n", i, i, x))
+        .collect()
+}
+
+fn bench_digestion(c: &mut Criterion) {
+    let proteins = random_proteins(2000, 500);
+    let sequences: Vec<Arc<str>> = proteins.iter().map(|x| x.as_str().into()).collect();
+    let params = DigestionParameters {
+        min_length: 6,
+        max_length: 30,
+        pattern: DigestionPattern::trypsin(),
+        digestion_end: DigestionEnd::CTerm,
+        max_missed_cleavages: 2,
+        semi_enzymatic: false,
+        additional_enzymes: Vec::new(),
+        initiator_methionine: InitiatorMethionine::Retain,
+        terminal_clipping: TerminalClipping::default(),
+        mass_range: None,
+    };
+    c.bench_function("digest_multiple", |b| {
+        b.iter(|| params.digest_multiple(&sequences))
+    });
+
+    let fasta = fasta_text(&proteins);
+    c.bench_function("nmer_index", |b| {
+        b.iter_batched(
+            || ProteinSequenceCollection::from_fasta(&fasta),
+            |collection| ProteinSequenceNmerIndex::from_collection(collection, 5),
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, bench_digestion);
+criterion_main!(benches);
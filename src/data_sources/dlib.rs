@@ -0,0 +1,297 @@
+use crate::data_sources::speclib::{
+    library_digest,
+    SpeclibRow,
+};
+use crate::errors::TimsSeekError;
+use crate::fragment_mass::elution_group_converter::{
+    queries_for_sequence,
+    SequenceToElutionGroupConverter,
+};
+use crate::fragment_mass::fragment_mass_builder::SafePosition;
+use crate::fragment_mass::modifications::split_peptidoform;
+use crate::models::LabelChannel;
+use flate2::read::ZlibDecoder;
+use rusqlite::{
+    Connection,
+    OpenFlags,
+};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+
+/// Library peaks further than this from every theoretical fragment of their
+/// precursor are not queried.
+pub const DLIB_FRAGMENT_TOLERANCE_PPM: f64 = 20.0;
+
+/// Converts an EncyclopeDIA modified sequence (eg. `[+42.010565]PEPC[+57.021464]K`)
+/// to ProForma, moving a leading modification to the N-terminus.
+pub fn dlib_to_proforma(modified: &str) -> Result<String, TimsSeekError> {
+    let proforma = match (modified.starts_with('['), modified.find(']')) {
+        (true, Some(end)) => format!("{}-{}", &modified[..=end], &modified[end + 1..]),
+        _ => modified.to_string(),
+    };
+    split_peptidoform(&proforma)?;
+    Ok(proforma)
+}
+
+/// Decompresses a zlib compressed array of big endian numbers of `N` bytes.
+fn decode_array<const N: usize>(blob: &[u8]) -> Result<Vec<[u8; N]>, std::io::Error> {
+    let mut bytes = Vec::new();
+    ZlibDecoder::new(blob).read_to_end(&mut bytes)?;
+    Ok(bytes
+        .chunks_exact(N)
+        .map(|x| x.try_into().unwrap())
+        .collect())
+}
+
+/// Row of the `entries` table.
+struct DlibEntry {
+    sequence: String,
+    charge: u8,
+    rt_seconds: f64,
+    mzs: Vec<f64>,
+    intensities: Vec<f32>,
+}
+
+/// Labels the library peaks with the closest theoretical fragment (within
+/// [`DLIB_FRAGMENT_TOLERANCE_PPM`]), keeping the most intense peak of every
+/// fragment.
+fn annotate_peaks(
+    theoretical: &HashMap<SafePosition, f64>,
+    mzs: &[f64],
+    intensities: &[f32],
+) -> (HashMap<SafePosition, f64>, HashMap<SafePosition, f32>) {
+    let mut fragment_mzs = HashMap::new();
+    let mut fragment_intensities: HashMap<SafePosition, f32> = HashMap::new();
+    for (mz, intensity) in mzs.iter().zip(intensities) {
+        let closest = theoretical
+            .iter()
+            .map(|(position, x)| (position, (x - mz).abs() / x * 1e6))
+            .filter(|x| x.1 <= DLIB_FRAGMENT_TOLERANCE_PPM)
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        let Some((position, _)) = closest else {
+            continue;
+        };
+        if fragment_intensities
+            .get(position)
+            .is_some_and(|x| x >= intensity)
+        {
+            continue;
+        }
+        fragment_mzs.insert(*position, theoretical[position]);
+        fragment_intensities.insert(*position, *intensity);
+    }
+    (fragment_mzs, fragment_intensities)
+}
+
+/// Query of a library entry: precursor m/z and mobility come from the
+/// theoretical query of its sequence, RT and fragments from the library.
+/// None if the sequence has no query or no peak matches a fragment, an error
+/// if the sequence cannot be converted.
+fn dlib_row(
+    entry: DlibEntry,
+    id: u64,
+    converter: &SequenceToElutionGroupConverter,
+) -> Result<Option<SpeclibRow>, TimsSeekError> {
+    let sequence = dlib_to_proforma(&entry.sequence)?;
+    let charges = entry.charge..=entry.charge;
+    let queries = queries_for_sequence(&sequence, charges, converter).map_err(|e| {
+        TimsSeekError::ParseError {
+            msg: format!("Error converting {}: {:?}", sequence, e),
+        }
+    })?;
+    let Some((mut eg, _)) = queries.into_iter().next() else {
+        return Ok(None);
+    };
+    let (fragment_mzs, fragment_intensities) =
+        annotate_peaks(&eg.fragment_mzs, &entry.mzs, &entry.intensities);
+    if fragment_mzs.is_empty() {
+        return Ok(None);
+    }
+    eg.id = id;
+    eg.rt_seconds = entry.rt_seconds as f32;
+    eg.fragment_mzs = fragment_mzs;
+    eg.expected_fragment_intensity = Some(fragment_intensities);
+    let digest = library_digest(&sequence, false)?;
+    Ok(Some((eg, entry.charge, digest, None, LabelChannel::Light)))
+}
+
+fn sqlite_error(path: &Path) -> impl Fn(rusqlite::Error) -> TimsSeekError + '_ {
+    move |e| TimsSeekError::ParseError {
+        msg: format!("Error reading {}: {}", path.display(), e),
+    }
+}
+
+/// Reads the `entries` table of an EncyclopeDIA (or Prosit) `.dlib`/`.elib`
+/// library, one row per precursor with its peaks as zlib compressed big
+/// endian arrays (f64 m/z and f32 intensities).
+///
+/// The peaks are not annotated in the library, they are matched to the
+/// theoretical fragments `converter` gives the sequence (so its fragment m/z
+/// range, isotopes, adduct and mobility predictor apply). RTs are the seconds
+/// of the library runs. Every entry is a target, decoys come from the
+/// `decoys` setting of the speclib input.
+///
+/// Entries whose sequence cannot be converted are skipped with a warning.
+pub(crate) fn dlib_rows(
+    path: &Path,
+    converter: &SequenceToElutionGroupConverter,
+) -> Result<Vec<SpeclibRow>, TimsSeekError> {
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(sqlite_error(path))?;
+    let mut statement = connection
+        .prepare(
+            "SELECT PeptideModSeq, PrecursorCharge, RTInSeconds, MassArray, IntensityArray \
+             FROM entries",
+        )
+        .map_err(sqlite_error(path))?;
+    let entries = statement
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, u8>(1)?,
+                row.get::<_, f64>(2)?,
+                row.get::<_, Vec<u8>>(3)?,
+                row.get::<_, Vec<u8>>(4)?,
+            ))
+        })
+        .map_err(sqlite_error(path))?;
+
+    let mut rows = Vec::new();
+    let mut num_skipped = 0;
+    let mut conversion_errors = Vec::new();
+    for entry in entries {
+        let (sequence, charge, rt_seconds, mass_array, intensity_array) =
+            entry.map_err(sqlite_error(path))?;
+        let array_error = |e: std::io::Error| TimsSeekError::ParseError {
+            msg: format!("Error decoding the peaks of {}: {}", sequence, e),
+        };
+        let mzs = decode_array::<8>(&mass_array)
+            .map_err(array_error)?
+            .into_iter()
+            .map(f64::from_be_bytes)
+            .collect();
+        let intensities = decode_array::<4>(&intensity_array)
+            .map_err(array_error)?
+            .into_iter()
+            .map(f32::from_be_bytes)
+            .collect();
+        let entry = DlibEntry {
+            sequence,
+            charge,
+            rt_seconds,
+            mzs,
+            intensities,
+        };
+        match dlib_row(entry, rows.len() as u64, converter) {
+            Ok(Some(x)) => rows.push(x),
+            Ok(None) => num_skipped += 1,
+            Err(e) => conversion_errors.push(e),
+        }
+    }
+    if let Some(first) = conversion_errors.first() {
+        log::warn!(
+            "Skipped {} entries of {} that could not be converted ({})",
+            conversion_errors.len(),
+            path.display(),
+            first
+        );
+    }
+    if num_skipped > 0 {
+        log::warn!(
+            "Skipped {} entries of {} without matching fragments",
+            num_skipped,
+            path.display()
+        );
+    }
+    if rows.is_empty() {
+        return Err(TimsSeekError::ParseError {
+            msg: format!("No precursors found in {}", path.display()),
+        });
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn encode(bytes: Vec<u8>) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_dlib_to_proforma() {
+        assert_eq!(dlib_to_proforma("PEPTIDEK").unwrap(), "PEPTIDEK");
+        assert_eq!(
+            dlib_to_proforma("[+42.010565]PEPC[+57.021464]K").unwrap(),
+            "[+42.010565]-PEPC[+57.021464]K"
+        );
+        assert!(dlib_to_proforma("PEPC[+57.021464K").is_err());
+    }
+
+    #[test]
+    fn test_dlib_rows() {
+        let converter = SequenceToElutionGroupConverter {
+            min_precursor_mz: 0.0,
+            max_precursor_mz: f64::INFINITY,
+            ..Default::default()
+        };
+        let (theoretical, _) = queries_for_sequence("PEPTIDEK", 2..=2, &converter)
+            .unwrap()
+            .remove(0);
+        let mut fragments: Vec<(SafePosition, f64)> =
+            theoretical.fragment_mzs.into_iter().collect();
+        fragments.sort_by(|a, b| a.1.total_cmp(&b.1));
+        let (position, mz) = fragments[fragments.len() / 2];
+        // One peak 5 ppm off a fragment and one matching nothing.
+        let mzs = [mz * (1.0 + 5e-6), 12345.6];
+        let intensities = [1000.0f32, 50.0];
+
+        let path = std::env::temp_dir().join("timsseek_test_dlib.dlib");
+        let _ = std::fs::remove_file(&path);
+        let connection = Connection::open(&path).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE entries (PrecursorMz double, PrecursorCharge int, \
+                 PeptideModSeq string, PeptideSeq string, RTInSeconds double, \
+                 MassArray blob, IntensityArray blob)",
+            )
+            .unwrap();
+        connection
+            .execute(
+                "INSERT INTO entries VALUES (?1, 2, 'PEPTIDEK', 'PEPTIDEK', 1234.5, ?2, ?3)",
+                (
+                    theoretical.precursor_mzs[1],
+                    encode(mzs.iter().flat_map(|x| x.to_be_bytes()).collect()),
+                    encode(intensities.iter().flat_map(|x| x.to_be_bytes()).collect()),
+                ),
+            )
+            .unwrap();
+        drop(connection);
+
+        let rows = dlib_rows(&path, &converter).unwrap();
+        // The only matching fragment is below the fragment m/z range.
+        let above_fragment = SequenceToElutionGroupConverter {
+            min_precursor_mz: 0.0,
+            max_precursor_mz: f64::INFINITY,
+            min_fragment_mz: mz + 1.0,
+            ..Default::default()
+        };
+        assert!(dlib_rows(&path, &above_fragment).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(rows.len(), 1);
+        let (eg, charge, digest, _, _) = &rows[0];
+        assert_eq!(*charge, 2);
+        assert_eq!(digest.decoy, crate::models::DecoyMarking::Target);
+        assert_eq!(eg.rt_seconds, 1234.5);
+        assert_eq!(eg.fragment_mzs.len(), 1);
+        assert_eq!(eg.fragment_mzs[&position], mz);
+        assert_eq!(eg.expected_fragment_intensity.as_ref().unwrap()[&position], 1000.0);
+    }
+}
@@ -1 +1,5 @@
+pub mod dlib;
+pub mod peptide_list;
+pub mod raw_data;
 pub mod speclib;
+pub mod spectronaut;
@@ -0,0 +1,39 @@
+use crate::errors::TimsSeekError;
+use crate::fragment_mass::modifications::split_peptidoform;
+use serde::Deserialize;
+use std::path::Path;
+
+/// Single entry of a targeted peptide list.
+///
+/// The sequence is ProForma, so it can carry modifications. If no charge is
+/// given every charge of the converter is used.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PeptideListEntry {
+    pub sequence: String,
+    #[serde(default)]
+    pub charge: Option<u8>,
+}
+
+/// Reads a tab separated peptide list with a `sequence` column and an
+/// optional `charge` column.
+///
+/// Sequences that are not valid ProForma are reported with their line.
+pub fn read_peptide_list(path: &Path) -> Result<Vec<PeptideListEntry>, TimsSeekError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_path(path)
+        .map_err(|e| TimsSeekError::ParseError { msg: e.to_string() })?;
+    let mut out = Vec::new();
+    for (i, record) in reader.deserialize().enumerate() {
+        let entry: PeptideListEntry = record.map_err(|e| TimsSeekError::ParseError {
+            msg: format!("Error reading line {} of {}: {}", i + 2, path.display(), e),
+        })?;
+        if let Err(e) = split_peptidoform(&entry.sequence) {
+            return Err(TimsSeekError::ParseError {
+                msg: format!("Error reading line {} of {}: {}", i + 2, path.display(), e),
+            });
+        }
+        out.push(entry);
+    }
+    Ok(out)
+}
@@ -0,0 +1,43 @@
+use crate::errors::TimsSeekError;
+use std::path::Path;
+
+/// Only Bruker .d directories can be indexed for now, so other raw formats
+/// (eg. mzML from Orbitrap DIA runs) are rejected up front with a readable
+/// error instead of failing somewhere inside timsrust.
+pub fn check_raw_data_path(path: &Path) -> Result<(), TimsSeekError> {
+    let extension = path
+        .extension()
+        .and_then(|x| x.to_str())
+        .map(|x| x.to_lowercase());
+    match extension.as_deref() {
+        Some("d") => Ok(()),
+        Some("mzml") => Err(TimsSeekError::UnsupportedInput {
+            msg: format!(
+                "mzML input is not supported yet, only Bruker .d directories can be searched: {}",
+                path.display()
+            ),
+        }),
+        _ => Err(TimsSeekError::UnsupportedInput {
+            msg: format!("Unrecognized raw data format: {}", path.display()),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_raw_data_path() {
+        assert!(check_raw_data_path(Path::new("run.d")).is_ok());
+        assert!(check_raw_data_path(Path::new("/data/run.D/")).is_ok());
+        assert!(matches!(
+            check_raw_data_path(Path::new("run.mzML")),
+            Err(TimsSeekError::UnsupportedInput { .. })
+        ));
+        assert!(matches!(
+            check_raw_data_path(Path::new("run.raw")),
+            Err(TimsSeekError::UnsupportedInput { .. })
+        ));
+    }
+}
@@ -1,29 +1,246 @@
+use crate::data_sources::dlib::dlib_rows;
+use crate::data_sources::spectronaut::{
+    spectronaut_file_rows,
+    spectronaut_text_rows,
+};
 use crate::digest;
+use crate::digest::decoys::{
+    as_shuffled_decoy_string,
+    non_colliding_seed,
+};
 use crate::errors::TimsSeekError;
+use crate::fragment_mass::elution_group_converter::SequenceToElutionGroupConverter;
 use crate::fragment_mass::fragment_mass_builder::SafePosition;
+use crate::fragment_mass::modifications::{
+    apply_fixed_modifications,
+    modification_names,
+    split_peptidoform,
+    FixedModification,
+};
+use crate::isotopes::precursor_isotope_mzs;
 use crate::models::{
+    ChannelLabel,
     DecoyMarking,
     DigestSlice,
+    LabelChannel,
     NamedQueryChunk,
 };
-use log::debug;
+use log::{
+    debug,
+    info,
+    warn,
+};
 use rayon::prelude::*;
 use serde::{
     Deserialize,
     Serialize,
 };
+use std::collections::{
+    HashMap,
+    HashSet,
+};
 use std::path;
 use std::sync::Arc;
 use timsquery::models::elution_group::ElutionGroup;
-use timsrust::TimsRustError;
 
 #[derive(Debug, Clone)]
 pub struct Speclib {
     digests: Vec<DigestSlice>,
     charges: Vec<u8>,
     queries: Vec<ElutionGroup<SafePosition>>,
+    /// Library pair id (endogenous/heavy standard) and channel of every
+    /// precursor, paired precursors are kept next to each other.
+    pair_ids: Vec<Option<u64>>,
+    channels: Vec<LabelChannel>,
+}
+
+/// How decoys are built for a target-only library.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpeclibDecoys {
+    /// Use the library as-is.
+    #[default]
+    None,
+    /// Reversed sequence (same termini) with its fragment m/z recomputed,
+    /// every fragment keeps the intensity of the same target fragment.
+    /// Entries that cannot be reversed (eg. modified sequences) get a
+    /// mass-shifted decoy instead, how many is logged.
+    Reverse,
+    /// Same sequence with all fragment m/z shifted.
+    MassShift,
+}
+
+/// What to do with the library decoys that have the sequence of a target.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecoyCollisionHandling {
+    /// Remove them from the library.
+    #[default]
+    Drop,
+    /// Replace them with a shuffle of their sequence (same termini) that is
+    /// not a target, with the fragment m/z recomputed. Modified decoys, and
+    /// the ones with no such shuffle, are dropped.
+    Reshuffle,
+}
+
+/// A library decoy with the sequence of a target, and how it was resolved.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DecoyCollision {
+    pub sequence: String,
+    pub charge: u8,
+    /// The reshuffled sequence, None if the decoy was dropped.
+    pub replacement: Option<String>,
+}
+
+/// Maximum number of shuffles tried for a colliding decoy.
+const MAX_RESHUFFLES: usize = 20;
+
+/// Sequences are compared with I and L as the same residue, they have the
+/// same mass.
+fn collision_key(sequence: &str) -> String {
+    sequence.replace('I', "L")
+}
+
+/// Fragment m/z shift of the mass-shifted decoys, far enough from the target
+/// fragments (and their isotopes) for any sensible tolerance.
+pub const DECOY_FRAGMENT_MZ_SHIFT: f64 = 10.0;
+
+/// Decoy of a library entry with the reversed sequence, None if the
+/// sequence is modified or the decoy cannot be converted.
+fn reversed_decoy(
+    query: &ElutionGroup<SafePosition>,
+    digest: &DigestSlice,
+    charge: u8,
+    converter: &SequenceToElutionGroupConverter,
+) -> Option<(ElutionGroup<SafePosition>, DigestSlice)> {
+    // Reversing the sequence would scramble the modifications.
+    if digest.peptidoform.is_some() {
+        return None;
+    }
+    let decoy = digest.with_decoy(DecoyMarking::Decoy);
+    let decoy_sequence: String = decoy.clone().into();
+    let (egs, _charges) = converter
+        .convert_sequence_with_charges(&decoy_sequence, query.id, charge..=charge)
+        .ok()?;
+    let decoy_eg = egs.into_iter().next()?;
+    let fragment_mzs: HashMap<SafePosition, f64> = query
+        .fragment_mzs
+        .keys()
+        .filter_map(|k| decoy_eg.fragment_mzs.get(k).map(|mz| (*k, *mz)))
+        .collect();
+    if fragment_mzs.is_empty() {
+        return None;
+    }
+    let expected_fragment_intensity = query.expected_fragment_intensity.as_ref().map(|x| {
+        x.iter()
+            .filter(|(k, _)| fragment_mzs.contains_key(k))
+            .map(|(k, v)| (*k, *v))
+            .collect()
+    });
+    // Reversing keeps the composition, so the precursor is the same.
+    let eg = ElutionGroup {
+        id: query.id,
+        precursor_mzs: query.precursor_mzs.clone(),
+        mobility: query.mobility,
+        rt_seconds: query.rt_seconds,
+        fragment_mzs,
+        expected_fragment_intensity,
+        expected_precursor_intensity: query.expected_precursor_intensity.clone(),
+    };
+    Some((eg, decoy))
 }
 
+/// Colliding decoy with its sequence replaced by `sequence`, None if it is
+/// modified or the new sequence cannot be converted.
+fn reshuffled_decoy(
+    query: &ElutionGroup<SafePosition>,
+    digest: &DigestSlice,
+    charge: u8,
+    sequence: &str,
+    converter: &SequenceToElutionGroupConverter,
+) -> Option<(ElutionGroup<SafePosition>, DigestSlice)> {
+    if digest.peptidoform.is_some() {
+        return None;
+    }
+    let (egs, _charges) = converter
+        .convert_sequence_with_charges(sequence, query.id, charge..=charge)
+        .ok()?;
+    let shuffled_eg = egs.into_iter().next()?;
+    let fragment_mzs: HashMap<SafePosition, f64> = query
+        .fragment_mzs
+        .keys()
+        .filter_map(|k| shuffled_eg.fragment_mzs.get(k).map(|mz| (*k, *mz)))
+        .collect();
+    if fragment_mzs.is_empty() {
+        return None;
+    }
+    let mut eg = query.clone();
+    if let Some(intensities) = eg.expected_fragment_intensity.as_mut() {
+        intensities.retain(|k, _| fragment_mzs.contains_key(k));
+    }
+    // Same composition, the precursor is unchanged.
+    eg.fragment_mzs = fragment_mzs;
+    let mut decoy = DigestSlice::new(
+        sequence.into(),
+        0..sequence.len(),
+        DecoyMarking::ReversedDecoy,
+    );
+    decoy.library_source = digest.library_source.clone();
+    decoy.target_decoy_pair_id = digest.target_decoy_pair_id;
+    Some((eg, decoy))
+}
+
+/// Decoy of a library entry with the same sequence (reported as-is) and
+/// shifted fragment m/z.
+fn mass_shifted_decoy(
+    query: &ElutionGroup<SafePosition>,
+    digest: &DigestSlice,
+) -> (ElutionGroup<SafePosition>, DigestSlice) {
+    let mut eg = query.clone();
+    eg.fragment_mzs
+        .values_mut()
+        .for_each(|mz| *mz += DECOY_FRAGMENT_MZ_SHIFT);
+    let mut decoy = digest.with_decoy(DecoyMarking::MassShiftedDecoy);
+    decoy.peptidoform = digest.peptidoform.clone();
+    (eg, decoy)
+}
+
+/// Sequence, modifications and charge of a precursor, see [`precursor_key`].
+pub type PrecursorKey = (String, Vec<String>, u8);
+
+/// Key matching the precursors of a library and the in-silico ones.
+///
+/// The fixed modifications are added first, since library entries usually
+/// carry them and in-silico digests do not. Decoys get the key of their
+/// target: the sequence is the one they were built from, and the
+/// modifications are compared by name (sorted), since reversing a sequence
+/// moves them.
+pub fn precursor_key(
+    digest: &DigestSlice,
+    charge: u8,
+    fixed_modifications: &[FixedModification],
+) -> PrecursorKey {
+    let peptidoform = digest.peptidoform();
+    let mut modifications =
+        modification_names(&apply_fixed_modifications(&peptidoform, fixed_modifications));
+    modifications.sort();
+    (digest.unmarked_sequence().to_string(), modifications, charge)
+}
+
+fn retain_by_mask<T>(values: &mut Vec<T>, keep: &[bool]) {
+    let mut keep = keep.iter();
+    values.retain(|_| *keep.next().unwrap_or(&true));
+}
+
+/// Parsed library entry: query, charge, digest, pair id and channel.
+pub(crate) type SpeclibRow = (
+    ElutionGroup<SafePosition>,
+    u8,
+    DigestSlice,
+    Option<u64>,
+    LabelChannel,
+);
+
 pub struct SpeclibIterator {
     speclib: Speclib,
     chunk_size: usize,
@@ -33,7 +250,7 @@ pub struct SpeclibIterator {
 
 impl SpeclibIterator {
     pub fn new(speclib: Speclib, chunk_size: usize) -> Self {
-        let max_iters = speclib.digests.len() / chunk_size;
+        let max_iters = speclib.digests.len().div_ceil(chunk_size);
         Self {
             speclib,
             chunk_size,
@@ -47,7 +264,7 @@ impl Iterator for SpeclibIterator {
     type Item = NamedQueryChunk;
 
     fn next(&mut self) -> Option<Self::Item> {
-        // No need to make decoys when we have a speclib!!
+        // Decoys are part of the library (or added by `Speclib::with_decoys`).
         let out = self
             .speclib
             .get_chunk(self.iteration_index, self.chunk_size);
@@ -63,94 +280,388 @@ impl ExactSizeIterator for SpeclibIterator {
 }
 
 impl Speclib {
-    pub fn from_json(json: &str) -> Self {
-        let speclib: Vec<SpeclibElement> = serde_json::from_str(json).unwrap();
+    pub fn from_json(json: &str) -> Result<Self, TimsSeekError> {
+        let speclib: Vec<SpeclibElement> =
+            serde_json::from_str(json).map_err(|e| TimsSeekError::LibraryReadingError {
+                line: Some(e.line()),
+                msg: e.to_string(),
+            })?;
 
-        let (queries, (charges, digests)): (
-            Vec<ElutionGroup<SafePosition>>,
-            (Vec<u8>, Vec<DigestSlice>),
-        ) = speclib
+        let rows: Vec<SpeclibRow> = speclib
             .into_par_iter()
-            .map(|x| {
-                let charge = x.precursor.charge;
-                let elution_group = x.elution_group;
-                let digest = x.precursor.into();
-                (elution_group, (charge, digest))
+            .map(|x| x.into_row(None))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self::from_rows(rows))
+    }
+
+    /// Builds the library, moving the members of each pair next to the
+    /// first one so a chunk never splits a pair.
+    pub(crate) fn from_rows(rows: Vec<SpeclibRow>) -> Self {
+        let mut first_of_pair: HashMap<u64, usize> = HashMap::new();
+        let mut rows: Vec<(usize, SpeclibRow)> = rows
+            .into_iter()
+            .enumerate()
+            .map(|(i, row)| {
+                let group = match row.3 {
+                    Some(pair_id) => *first_of_pair.entry(pair_id).or_insert(i),
+                    None => i,
+                };
+                (group, row)
             })
-            .unzip();
+            .collect();
+        rows.sort_by_key(|x| x.0);
 
-        Self {
-            digests,
-            charges,
-            queries,
+        let mut out = Self {
+            digests: Vec::with_capacity(rows.len()),
+            charges: Vec::with_capacity(rows.len()),
+            queries: Vec::with_capacity(rows.len()),
+            pair_ids: Vec::with_capacity(rows.len()),
+            channels: Vec::with_capacity(rows.len()),
+        };
+        for (_, (query, charge, digest, pair_id, channel)) in rows {
+            out.queries.push(query);
+            out.charges.push(charge);
+            out.digests.push(digest);
+            out.pair_ids.push(pair_id);
+            out.channels.push(channel);
         }
+        out
     }
 
-    pub fn from_ndjson(json: &str) -> Self {
-        // Split on newlines and parse each ...
-        let lines: Vec<&str> = json.split('\n').collect();
-        let mut digests = Vec::new();
-        let mut charges = Vec::new();
-        let mut queries = Vec::new();
+    /// One entry per line, a line that cannot be read fails with its line
+    /// number.
+    pub fn from_ndjson(json: &str) -> Result<Self, TimsSeekError> {
+        let mut rows: Vec<SpeclibRow> = Vec::new();
 
         let mut num_show = 10;
-        for line in lines {
+        for (i, line) in json.split('\n').enumerate() {
             // Continue if the line is empty.
             if line.is_empty() {
                 continue;
             }
-            let elem: SpeclibElement = match serde_json::from_str(line) {
-                Ok(x) => x,
-                Err(e) => {
-                    panic!("Error parsing line: {:?}", line);
-                    // return Err(TimsSeekError::TimsRust(TimsRustError::Serde(e)));
-                }
-            };
+            let elem: SpeclibElement =
+                serde_json::from_str(line).map_err(|e| TimsSeekError::LibraryReadingError {
+                    line: Some(i + 1),
+                    msg: e.to_string(),
+                })?;
 
             if num_show > 0 {
                 num_show -= 1;
                 debug!("{:?}", elem);
             }
-            charges.push(elem.precursor.charge);
-            digests.push(elem.precursor.into());
-            queries.push(elem.elution_group);
+            rows.push(elem.into_row(Some(i + 1))?);
         }
 
-        if digests.is_empty() {
-            panic!("No digests found in speclib file");
+        if rows.is_empty() {
+            return Err(TimsSeekError::LibraryReadingError {
+                line: None,
+                msg: "No digests found in speclib file".to_string(),
+            });
         }
 
-        Self {
-            digests,
-            charges,
-            queries,
-        }
+        Ok(Self::from_rows(rows))
     }
 
+    /// Entries without a `library_source` get the name of the file.
     pub fn from_ndjson_file(path: &path::Path) -> Result<Self, TimsSeekError> {
         let json = std::fs::read_to_string(path)?;
-        Ok(Self::from_ndjson(&json))
+        let source = path
+            .file_stem()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_default();
+        Ok(Self::from_ndjson(&json)?.with_default_source(&source))
+    }
+
+    /// Library exported by Spectronaut, with the precursor isotope peaks
+    /// `converter` queries, see
+    /// [`crate::data_sources::spectronaut::spectronaut_text_rows`].
+    pub fn from_spectronaut(
+        text: &str,
+        converter: &SequenceToElutionGroupConverter,
+    ) -> Result<Self, TimsSeekError> {
+        Ok(Self::from_rows(spectronaut_text_rows(text, converter)?))
+    }
+
+    /// Entries get the name of the file as their `library_source`.
+    pub fn from_spectronaut_file(
+        path: &path::Path,
+        converter: &SequenceToElutionGroupConverter,
+    ) -> Result<Self, TimsSeekError> {
+        let source = path
+            .file_stem()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let rows = spectronaut_file_rows(path, converter)?;
+        Ok(Self::from_rows(rows).with_default_source(&source))
+    }
+
+    /// EncyclopeDIA library, see [`crate::data_sources::dlib::dlib_rows`].
+    /// Entries get the name of the file as their `library_source`.
+    pub fn from_dlib_file(
+        path: &path::Path,
+        converter: &SequenceToElutionGroupConverter,
+    ) -> Result<Self, TimsSeekError> {
+        let source = path
+            .file_stem()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_default();
+        Ok(Self::from_rows(dlib_rows(path, converter)?).with_default_source(&source))
+    }
+
+    /// Reads a library by its extension, Spectronaut exports (`.tsv`, `.xls`,
+    /// `.csv` or `.txt`), EncyclopeDIA libraries (`.dlib` or `.elib`) or the
+    /// ndjson speclib format otherwise.
+    ///
+    /// `converter` builds the precursor isotope peaks of the Spectronaut
+    /// exports, which have none, and the theoretical fragments the peaks of
+    /// the EncyclopeDIA libraries are matched to.
+    pub fn from_library_file(
+        path: &path::Path,
+        converter: &SequenceToElutionGroupConverter,
+    ) -> Result<Self, TimsSeekError> {
+        let extension = path
+            .extension()
+            .map(|x| x.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("tsv" | "xls" | "csv" | "txt") => Self::from_spectronaut_file(path, converter),
+            Some("dlib" | "elib") => Self::from_dlib_file(path, converter),
+            _ => Self::from_ndjson_file(path),
+        }
+    }
+
+    pub(crate) fn with_default_source(mut self, source: &str) -> Self {
+        let source: Arc<str> = source.into();
+        for digest in self.digests.iter_mut() {
+            if digest.library_source.is_none() {
+                digest.library_source = Some(source.clone());
+            }
+        }
+        self
+    }
+
+    /// Appends a decoy for every (unlabeled) target of a target-only
+    /// library.
+    ///
+    /// Libraries that already have decoys are left as they are.
+    pub fn with_decoys(
+        mut self,
+        mode: SpeclibDecoys,
+        converter: &SequenceToElutionGroupConverter,
+    ) -> Self {
+        if mode == SpeclibDecoys::None {
+            return self;
+        }
+        if self.digests.iter().any(|x| x.decoy != DecoyMarking::Target) {
+            info!("The library already has decoys, not building more");
+            return self;
+        }
+        // Every light target is paired with its decoy by its own index.
+        for i in 0..self.digests.len() {
+            if self.channels[i] == LabelChannel::Light {
+                self.digests[i].target_decoy_pair_id = Some(i as u64);
+            }
+        }
+        let decoys: Vec<(ElutionGroup<SafePosition>, u8, DigestSlice)> = (0..self.digests.len())
+            .into_par_iter()
+            .filter(|&i| self.channels[i] == LabelChannel::Light)
+            .map(|i| {
+                let (query, digest, charge) = (&self.queries[i], &self.digests[i], self.charges[i]);
+                let reversed = match mode {
+                    SpeclibDecoys::Reverse => reversed_decoy(query, digest, charge, converter),
+                    _ => None,
+                };
+                let (eg, decoy) = reversed.unwrap_or_else(|| mass_shifted_decoy(query, digest));
+                (eg, charge, decoy)
+            })
+            .collect();
+        info!("Built {} library decoys", decoys.len());
+        if mode == SpeclibDecoys::Reverse {
+            let num_shifted = decoys
+                .iter()
+                .filter(|(_, _, x)| x.decoy == DecoyMarking::MassShiftedDecoy)
+                .count();
+            if num_shifted > 0 {
+                warn!(
+                    "{} of {} library entries could not be reversed (eg. modified \
+                     sequences), their decoys are mass-shifted instead",
+                    num_shifted,
+                    decoys.len()
+                );
+            }
+        }
+        for (query, charge, digest) in decoys {
+            self.queries.push(query);
+            self.charges.push(charge);
+            self.digests.push(digest);
+            self.pair_ids.push(None);
+            self.channels.push(LabelChannel::Light);
+        }
+        self
+    }
+
+    /// Checks the decoys of the library against its target sequences (with
+    /// I and L as the same residue) and drops or reshuffles the ones that
+    /// match a target.
+    pub fn resolve_decoy_collisions(
+        &mut self,
+        handling: DecoyCollisionHandling,
+        converter: &SequenceToElutionGroupConverter,
+    ) -> Vec<DecoyCollision> {
+        let targets: HashSet<String> = self
+            .digests
+            .iter()
+            .filter(|x| x.decoy == DecoyMarking::Target)
+            .map(|x| collision_key(x.unmarked_sequence()))
+            .collect();
+        let mut collisions = Vec::new();
+        let mut keep = vec![true; self.digests.len()];
+        for i in 0..self.digests.len() {
+            if self.digests[i].decoy == DecoyMarking::Target {
+                continue;
+            }
+            let sequence: String = self.digests[i].clone().into();
+            if !targets.contains(&collision_key(&sequence)) {
+                continue;
+            }
+            let replacement = match handling {
+                DecoyCollisionHandling::Drop => None,
+                DecoyCollisionHandling::Reshuffle => {
+                    let is_valid = |seed: u64| {
+                        !targets.contains(&collision_key(&as_shuffled_decoy_string(
+                            &sequence, seed,
+                        )))
+                    };
+                    let seed = non_colliding_seed(i as u64, MAX_RESHUFFLES, &is_valid);
+                    let shuffled = as_shuffled_decoy_string(&sequence, seed);
+                    if is_valid(seed) {
+                        reshuffled_decoy(
+                            &self.queries[i],
+                            &self.digests[i],
+                            self.charges[i],
+                            &shuffled,
+                            converter,
+                        )
+                    } else {
+                        None
+                    }
+                }
+            };
+            let replacement = match replacement {
+                Some((eg, digest)) => {
+                    self.queries[i] = eg;
+                    self.digests[i] = digest;
+                    Some(self.digests[i].clone().into())
+                }
+                None => {
+                    keep[i] = false;
+                    None
+                }
+            };
+            collisions.push(DecoyCollision {
+                sequence,
+                charge: self.charges[i],
+                replacement,
+            });
+        }
+        if !collisions.is_empty() {
+            warn!(
+                "{} library decoys match a target sequence, {} reshuffled, the rest dropped",
+                collisions.len(),
+                collisions.iter().filter(|x| x.replacement.is_some()).count()
+            );
+        }
+        self.retain_indices(&keep);
+        collisions
+    }
+
+    /// Keeps the entries whose index is true in `keep`.
+    fn retain_indices(&mut self, keep: &[bool]) {
+        retain_by_mask(&mut self.digests, keep);
+        retain_by_mask(&mut self.charges, keep);
+        retain_by_mask(&mut self.queries, keep);
+        retain_by_mask(&mut self.pair_ids, keep);
+        retain_by_mask(&mut self.channels, keep);
+    }
+
+    /// [`precursor_key`] of every target precursor.
+    pub fn precursor_keys(
+        &self,
+        fixed_modifications: &[FixedModification],
+    ) -> HashSet<PrecursorKey> {
+        self.digests
+            .iter()
+            .zip(self.charges.iter())
+            .filter(|(digest, _)| digest.decoy == DecoyMarking::Target)
+            .map(|(digest, charge)| precursor_key(digest, *charge, fixed_modifications))
+            .collect()
+    }
+
+    /// Appends the precursors of `other`.
+    ///
+    /// Pair ids are only matched within each library, the ones of `other` are
+    /// shifted past the ones of `self`.
+    pub fn merge(mut self, other: Speclib) -> Self {
+        let offset = self
+            .pair_ids
+            .iter()
+            .flatten()
+            .max()
+            .map(|x| x + 1)
+            .unwrap_or(0);
+        self.digests.extend(other.digests);
+        self.charges.extend(other.charges);
+        self.queries.extend(other.queries);
+        self.pair_ids
+            .extend(other.pair_ids.into_iter().map(|x| x.map(|id| id + offset)));
+        self.channels.extend(other.channels);
+        self
+    }
+
+    /// First index at or after `index` that does not split a pair.
+    fn pair_boundary(&self, index: usize) -> usize {
+        let mut index = index.min(self.digests.len());
+        while index > 0
+            && index < self.digests.len()
+            && self.pair_ids[index].is_some()
+            && self.pair_ids[index] == self.pair_ids[index - 1]
+        {
+            index += 1;
+        }
+        index
     }
 
     fn get_chunk(&self, chunk_index: usize, chunk_size: usize) -> Option<NamedQueryChunk> {
-        let start = chunk_index * chunk_size;
+        let start = self.pair_boundary(chunk_index * chunk_size);
         if start >= self.digests.len() {
             return None;
         }
-        let end = start + chunk_size;
-        let end = if end > self.digests.len() {
-            self.digests.len()
-        } else {
-            end
-        };
+        let end = self.pair_boundary(start.max((chunk_index + 1) * chunk_size));
         let digests = &self.digests[start..end];
         let charges = &self.charges[start..end];
         let queries = &self.queries[start..end];
-        Some(NamedQueryChunk::new(
-            digests.to_vec(),
-            charges.to_vec(),
-            queries.to_vec(),
-        ))
+
+        // Pair ids come from the first member of the library pair, as from
+        // the light query for the heavy channels built on the fly. Pairs are
+        // never split across chunks.
+        let mut first_of_pairs: HashMap<u64, u64> = HashMap::new();
+        let channels = (start..end)
+            .map(|i| {
+                let own_id = || ChannelLabel::pair_id_of(&self.digests[i], self.charges[i]);
+                let pair_id = match self.pair_ids[i] {
+                    Some(pair_id) => *first_of_pairs.entry(pair_id).or_insert_with(own_id),
+                    None => own_id(),
+                };
+                ChannelLabel {
+                    channel: self.channels[i],
+                    pair_id,
+                }
+            })
+            .collect();
+        Some(
+            NamedQueryChunk::new(digests.to_vec(), charges.to_vec(), queries.to_vec())
+                .with_channels(channels),
+        )
     }
 
     pub fn as_iterator(self, chunk_size: usize) -> SpeclibIterator {
@@ -161,7 +672,24 @@ impl Speclib {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct SpeclibElement {
     precursor: PrecursorEntry,
-    elution_group: ElutionGroup<SafePosition>,
+    elution_group: SpeclibElutionGroup,
+}
+
+impl SpeclibElement {
+    /// Errors are reported as library errors at `line` (of an ndjson file).
+    fn into_row(self, line: Option<usize>) -> Result<SpeclibRow, TimsSeekError> {
+        let as_library_error = |e: TimsSeekError| TimsSeekError::LibraryReadingError {
+            line,
+            msg: format!("{:?}", e),
+        };
+        let elution_group = self
+            .elution_group
+            .into_elution_group(&self.precursor)
+            .map_err(as_library_error)?;
+        self.precursor
+            .into_row(elution_group)
+            .map_err(as_library_error)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -169,21 +697,119 @@ struct PrecursorEntry {
     sequence: String,
     charge: u8,
     decoy: bool,
+    /// Neutral monoisotopic mass, used when the elution group does not
+    /// define its precursor m/z values.
+    neutral_mass: Option<f64>,
+    /// Links an endogenous precursor with its isotope-labeled internal
+    /// standard, both are extracted together and their ratio reported.
+    #[serde(default)]
+    pair_id: Option<u64>,
+    /// Whether this is the heavy (spiked-in standard) member of a pair.
+    #[serde(default)]
+    heavy_standard: bool,
+    /// Library the entry comes from (eg. "empirical" or "predicted"), when
+    /// several libraries were merged into one file.
+    #[serde(default)]
+    library_source: Option<String>,
 }
 
-impl From<PrecursorEntry> for DigestSlice {
-    fn from(x: PrecursorEntry) -> Self {
-        let decoy = if x.decoy {
-            DecoyMarking::ReversedDecoy
+impl PrecursorEntry {
+    fn into_row(
+        self,
+        elution_group: ElutionGroup<SafePosition>,
+    ) -> Result<SpeclibRow, TimsSeekError> {
+        let channel = if self.heavy_standard {
+            LabelChannel::Heavy
         } else {
-            DecoyMarking::Target
+            LabelChannel::Light
+        };
+        let charge = self.charge;
+        let pair_id = self.pair_id;
+        Ok((elution_group, charge, self.try_into()?, pair_id, channel))
+    }
+}
+
+/// Elution group as written in the speclib.
+///
+/// Same as `ElutionGroup` but the precursor m/z values are optional, since
+/// they can be computed from the neutral mass of the precursor.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SpeclibElutionGroup {
+    id: u64,
+    precursor_mzs: Option<Vec<f64>>,
+    mobility: f32,
+    rt_seconds: f32,
+    fragment_mzs: HashMap<SafePosition, f64>,
+    expected_fragment_intensity: Option<HashMap<SafePosition, f32>>,
+    expected_precursor_intensity: Option<Vec<f32>>,
+}
+
+impl SpeclibElutionGroup {
+    fn into_elution_group(
+        self,
+        precursor: &PrecursorEntry,
+    ) -> Result<ElutionGroup<SafePosition>, TimsSeekError> {
+        let precursor_mzs = match (self.precursor_mzs, precursor.neutral_mass) {
+            (Some(mzs), _) => mzs,
+            (None, Some(neutral_mass)) => {
+                // Same layout as the converter, -1, M, M+1, M+2 by default.
+                let num_peaks = self
+                    .expected_precursor_intensity
+                    .as_ref()
+                    .map(|x| x.len())
+                    .unwrap_or(4);
+                precursor_isotope_mzs(neutral_mass, precursor.charge, num_peaks)
+            }
+            (None, None) => {
+                return Err(TimsSeekError::ParseError {
+                    msg: format!(
+                        "Precursor {} has neither precursor_mzs nor neutral_mass",
+                        precursor.sequence
+                    ),
+                });
+            }
         };
-        let seq: Arc<str> = x.sequence.clone().into();
-        let range = 0..seq.as_ref().len();
-        DigestSlice::new(seq, range, decoy)
+
+        Ok(ElutionGroup {
+            id: self.id,
+            precursor_mzs,
+            mobility: self.mobility,
+            rt_seconds: self.rt_seconds,
+            fragment_mzs: self.fragment_mzs,
+            expected_fragment_intensity: self.expected_fragment_intensity,
+            expected_precursor_intensity: self.expected_precursor_intensity,
+        })
+    }
+}
+
+/// The sequence is ProForma, the digest gets the bare sequence and keeps the
+/// modified one as its peptidoform.
+impl TryFrom<PrecursorEntry> for DigestSlice {
+    type Error = TimsSeekError;
+
+    fn try_from(x: PrecursorEntry) -> Result<Self, Self::Error> {
+        let mut digest = library_digest(&x.sequence, x.decoy)?;
+        digest.library_source = x.library_source.map(Arc::from);
+        Ok(digest)
     }
 }
 
+/// Digest of a library entry with a ProForma sequence, decoys are reported
+/// with their sequence as-is.
+pub(crate) fn library_digest(sequence: &str, decoy: bool) -> Result<DigestSlice, TimsSeekError> {
+    let decoy = if decoy {
+        DecoyMarking::ReversedDecoy
+    } else {
+        DecoyMarking::Target
+    };
+    let (bare, peptidoform) = split_peptidoform(sequence)?;
+    let seq: Arc<str> = bare.into();
+    let range = 0..seq.as_ref().len();
+    let mut digest = DigestSlice::new(seq, range, decoy);
+    digest.peptidoform = peptidoform.map(Arc::from);
+    Ok(digest)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -224,7 +850,7 @@ mod tests {
                 }
             }
         ]"#;
-        let speclib = Speclib::from_json(json);
+        let speclib = Speclib::from_json(json).unwrap();
         assert_eq!(speclib.digests.len(), 1);
         assert_eq!(speclib.charges.len(), 1);
         assert_eq!(speclib.queries.len(), 1);
@@ -234,4 +860,201 @@ mod tests {
         assert_eq!(speclib.digests[0].len(), 11);
         assert_eq!(speclib.queries[0].fragment_mzs.len(), 3);
     }
+
+    #[test]
+    fn test_speclib_neutral_mass() {
+        let json = r#"{"precursor": {"sequence": "PEPTIDEPINK", "charge": 2, "decoy": false, "neutral_mass": 1808.902788}, "elution_group": {"id": 0, "fragment_mzs": {"b1": 123.0}, "mobility": 0.8, "rt_seconds": 0.0, "expected_precursor_intensity": [0.001, 1.0, 0.5, 0.2], "expected_fragment_intensity": {"b1": 1.0}}}"#;
+        let speclib = Speclib::from_ndjson(json).unwrap();
+        assert_eq!(speclib.queries.len(), 1);
+        assert_eq!(speclib.queries[0].precursor_mzs.len(), 4);
+        assert!((speclib.queries[0].precursor_mzs[1] - 905.458670).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_speclib_errors() {
+        let valid = r#"{"precursor": {"sequence": "PEPTIDEK", "charge": 2, "decoy": false}, "elution_group": {"id": 0, "precursor_mzs": [500.0], "fragment_mzs": {"b1": 123.0}, "mobility": 0.8, "rt_seconds": 0.0}}"#;
+        let line = |e: TimsSeekError| match e {
+            TimsSeekError::LibraryReadingError { line, .. } => line,
+            e => panic!("Unexpected error {:?}", e),
+        };
+
+        // Not json, on the third line.
+        let ndjson = format!("{}\n\n{{\"precursor\": \n", valid);
+        assert_eq!(line(Speclib::from_ndjson(&ndjson).unwrap_err()), Some(3));
+
+        // Not ProForma, on the second line.
+        let invalid = valid.replace("PEPTIDEK", "PEP[NotAModification]TIDEK");
+        let ndjson = format!("{}\n{}\n", valid, invalid);
+        assert_eq!(line(Speclib::from_ndjson(&ndjson).unwrap_err()), Some(2));
+
+        // No precursor m/z nor neutral mass.
+        let invalid = valid.replace("\"precursor_mzs\": [500.0], ", "");
+        assert_eq!(line(Speclib::from_ndjson(&invalid).unwrap_err()), Some(1));
+        assert_eq!(line(Speclib::from_ndjson("").unwrap_err()), None);
+    }
+
+    #[test]
+    fn test_speclib_pairs() {
+        let entry = |seq: &str, pair_id: Option<u64>, heavy: bool| {
+            serde_json::json!({
+                "precursor": {"sequence": seq, "charge": 2, "decoy": false, "pair_id": pair_id, "heavy_standard": heavy},
+                "elution_group": {"id": 0, "precursor_mzs": [500.0, 500.5], "fragment_mzs": {"b1": 123.0}, "mobility": 0.8, "rt_seconds": 0.0},
+            })
+            .to_string()
+        };
+        let ndjson = [
+            entry("PEPTIDEK", Some(7), false),
+            entry("AAAAAK", None, false),
+            entry("PEPTIDEK", Some(7), true),
+            entry("CCCCCK", None, false),
+        ]
+        .join("\n");
+        let speclib = Speclib::from_ndjson(&ndjson).unwrap();
+        // The heavy standard is moved next to its endogenous precursor.
+        assert_eq!(speclib.pair_ids, vec![Some(7), Some(7), None, None]);
+
+        // A chunk boundary in the middle of the pair is moved past it, which
+        // leaves the next chunk empty.
+        let chunk = speclib.get_chunk(0, 1).unwrap();
+        assert_eq!(chunk.len(), 2);
+        assert!(speclib.get_chunk(1, 1).unwrap().is_empty());
+        assert_eq!(speclib.get_chunk(2, 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_speclib_decoys() {
+        let entry = |seq: &str, heavy: bool| {
+            serde_json::json!({
+                "precursor": {"sequence": seq, "charge": 2, "decoy": false, "pair_id": 0, "heavy_standard": heavy},
+                "elution_group": {"id": 0, "precursor_mzs": [500.0, 500.5], "fragment_mzs": {"y3": 400.0, "y4": 500.0}, "mobility": 0.8, "rt_seconds": 10.0, "expected_fragment_intensity": {"y3": 1.0, "y4": 0.5}},
+            })
+            .to_string()
+        };
+        let ndjson = [entry("PEPTIDEK", false), entry("PEPTIDEK", true)].join("\n");
+        let converter = SequenceToElutionGroupConverter::default();
+
+        let speclib = Speclib::from_ndjson(&ndjson)
+            .unwrap()
+            .with_decoys(SpeclibDecoys::MassShift, &converter);
+        // Only the light member of the pair gets a decoy.
+        assert_eq!(speclib.digests.len(), 3);
+        assert_eq!(String::from(speclib.digests[2].clone()), "PEPTIDEK");
+        assert_eq!(speclib.digests[2].decoy, DecoyMarking::MassShiftedDecoy);
+        assert_eq!(speclib.queries[2].fragment_mzs.values().sum::<f64>(), 920.0);
+        let pairs: Vec<Option<u64>> = speclib
+            .digests
+            .iter()
+            .map(|x| x.target_decoy_pair_id)
+            .collect();
+        assert_eq!(pairs, vec![Some(0), None, Some(0)]);
+
+        // Decoys are not added twice.
+        let speclib = speclib.with_decoys(SpeclibDecoys::MassShift, &converter);
+        assert_eq!(speclib.digests.len(), 3);
+
+        let speclib = Speclib::from_ndjson(&ndjson)
+            .unwrap()
+            .with_decoys(SpeclibDecoys::Reverse, &converter);
+        assert_eq!(speclib.digests.len(), 3);
+        assert_eq!(String::from(speclib.digests[2].clone()), "PEDITPEK");
+        assert_eq!(speclib.digests[2].decoy, DecoyMarking::Decoy);
+        assert_eq!(speclib.queries[2].precursor_mzs, vec![500.0, 500.5]);
+        assert_eq!(speclib.queries[2].rt_seconds, 10.0);
+        let intensities = speclib.queries[2].expected_fragment_intensity.as_ref().unwrap();
+        assert_eq!(intensities.len(), speclib.queries[2].fragment_mzs.len());
+    }
+
+    #[test]
+    fn test_speclib_decoy_collisions() {
+        let entry = |seq: &str, decoy: bool| {
+            serde_json::json!({
+                "precursor": {"sequence": seq, "charge": 2, "decoy": decoy},
+                "elution_group": {"id": 0, "precursor_mzs": [500.0, 500.5], "fragment_mzs": {"y3": 400.0, "y4": 500.0}, "mobility": 0.8, "rt_seconds": 10.0, "expected_fragment_intensity": {"y3": 1.0, "y4": 0.5}},
+            })
+            .to_string()
+        };
+        // The first decoy is the target with I -> L.
+        let ndjson = [
+            entry("PEPTIDEK", false),
+            entry("PEPTLDEK", true),
+            entry("KEDITPEP", true),
+        ]
+        .join("\n");
+        let converter = SequenceToElutionGroupConverter::default();
+
+        let mut speclib = Speclib::from_ndjson(&ndjson).unwrap();
+        let collisions =
+            speclib.resolve_decoy_collisions(DecoyCollisionHandling::Drop, &converter);
+        assert_eq!(
+            collisions,
+            vec![DecoyCollision {
+                sequence: "PEPTLDEK".to_string(),
+                charge: 2,
+                replacement: None,
+            }]
+        );
+        assert_eq!(speclib.digests.len(), 2);
+        assert_eq!(speclib.queries.len(), 2);
+        assert_eq!(String::from(speclib.digests[1].clone()), "KEDITPEP");
+
+        let mut speclib = Speclib::from_ndjson(&ndjson).unwrap();
+        let collisions =
+            speclib.resolve_decoy_collisions(DecoyCollisionHandling::Reshuffle, &converter);
+        let replacement = collisions[0].replacement.clone().unwrap();
+        assert_eq!(speclib.digests.len(), 3);
+        assert_eq!(String::from(speclib.digests[1].clone()), replacement);
+        assert!(replacement.starts_with('P') && replacement.ends_with('K'));
+        assert_ne!(replacement.replace('I', "L"), "PEPTLDEK");
+        assert_eq!(speclib.digests[1].decoy, DecoyMarking::ReversedDecoy);
+        assert_eq!(speclib.queries[1].precursor_mzs, vec![500.0, 500.5]);
+        assert!(speclib.queries[1].fragment_mzs.values().all(|x| *x != 400.0));
+    }
+
+    #[test]
+    fn test_speclib_peptidoforms() {
+        let json = r#"{"precursor": {"sequence": "PEM[Oxidation]TIDEK", "charge": 2, "decoy": false}, "elution_group": {"id": 0, "precursor_mzs": [500.0, 500.5], "fragment_mzs": {"y3": 400.0}, "mobility": 0.8, "rt_seconds": 0.0}}"#;
+        let converter = SequenceToElutionGroupConverter::default();
+        let speclib = Speclib::from_ndjson(json)
+            .unwrap()
+            .with_decoys(SpeclibDecoys::Reverse, &converter);
+        assert_eq!(String::from(speclib.digests[0].clone()), "PEMTIDEK");
+        assert_eq!(speclib.digests[0].peptidoform(), "PEM[Oxidation]TIDEK");
+        let key = ("PEMTIDEK".to_string(), vec!["Oxidation".to_string()], 2);
+        assert!(speclib.precursor_keys(&[]).contains(&key));
+        // The decoy has the key of its target.
+        assert_eq!(precursor_key(&speclib.digests[1], 2, &[]), key);
+        // Modified entries get a mass-shifted decoy, which keeps the
+        // modifications.
+        assert_eq!(speclib.digests[1].decoy, DecoyMarking::MassShiftedDecoy);
+        assert_eq!(speclib.digests[1].peptidoform(), "PEM[Oxidation]TIDEK");
+    }
+
+    #[test]
+    fn test_speclib_merge_sources() {
+        let entry = |seq: &str, pair_id: Option<u64>, source: Option<&str>| {
+            serde_json::json!({
+                "precursor": {"sequence": seq, "charge": 2, "decoy": false, "pair_id": pair_id, "library_source": source},
+                "elution_group": {"id": 0, "precursor_mzs": [500.0, 500.5], "fragment_mzs": {"b1": 123.0}, "mobility": 0.8, "rt_seconds": 0.0},
+            })
+            .to_string()
+        };
+        let empirical = Speclib::from_ndjson(&entry("PEPTIDEK", Some(0), Some("empirical")))
+            .unwrap()
+            .with_default_source("lib_a");
+        let predicted = Speclib::from_ndjson(&entry("AAAAAK", Some(0), None))
+            .unwrap()
+            .with_default_source("lib_b");
+        let merged = empirical.merge(predicted);
+
+        let sources: Vec<Option<&str>> = merged
+            .digests
+            .iter()
+            .map(|x| x.library_source.as_deref())
+            .collect();
+        assert_eq!(sources, vec![Some("empirical"), Some("lib_b")]);
+        assert_eq!(merged.pair_ids, vec![Some(0), Some(1)]);
+        assert!(merged
+            .precursor_keys(&[])
+            .contains(&("AAAAAK".to_string(), Vec::new(), 2)));
+    }
 }
@@ -0,0 +1,397 @@
+use crate::data_sources::speclib::{
+    library_digest,
+    SpeclibRow,
+};
+use crate::errors::TimsSeekError;
+use crate::fragment_mass::elution_group_converter::{
+    supersimpleprediction,
+    SequenceToElutionGroupConverter,
+};
+use crate::fragment_mass::fragment_mass_builder::{
+    NeutralLoss,
+    SafePosition,
+};
+use crate::fragment_mass::modifications::split_peptidoform;
+use crate::isotopes::{
+    precursor_isotope_mzs,
+    PROTON_MASS,
+};
+use crate::models::LabelChannel;
+use crate::numeric::parse_decimal;
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::Path;
+use timsquery::models::elution_group::ElutionGroup;
+
+/// Spectronaut names of modifications that differ from their Unimod name.
+const MODIFICATION_ALIASES: [(&str, &str); 3] = [
+    ("Deamidation", "Deamidated"),
+    ("Carbamidomethylation", "Carbamidomethyl"),
+    ("Phosphorylation", "Phospho"),
+];
+
+/// Converts a Spectronaut modified sequence (eg.
+/// `_[Acetyl (Protein N-term)]M[Oxidation (M)]PEPC[Carbamidomethyl (C)]K_`)
+/// to ProForma (`[Acetyl]-M[Oxidation]PEPC[Carbamidomethyl]K`).
+///
+/// Modifications are placed on the termini following their site (`N-term`
+/// or `C-term`), mass shifts (`C[+57]`) are kept as they are.
+pub fn spectronaut_to_proforma(modified: &str) -> Result<String, TimsSeekError> {
+    let sequence = modified.trim().trim_matches('_');
+    let mut out = String::with_capacity(sequence.len());
+    let mut rest = sequence;
+    let mut seen_residue = false;
+    while let Some(c) = rest.chars().next() {
+        if c != '[' {
+            if c.is_ascii_uppercase() {
+                seen_residue = true;
+            }
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+        let Some(end) = rest.find(']') else {
+            return Err(TimsSeekError::ParseError {
+                msg: format!("Unclosed modification in {:?}", modified),
+            });
+        };
+        let (name, site) = rest[1..end]
+            .rsplit_once(" (")
+            .unwrap_or((&rest[1..end], ""));
+        let name = name.trim();
+        let name = MODIFICATION_ALIASES
+            .iter()
+            .find(|x| x.0 == name)
+            .map(|x| x.1)
+            .unwrap_or(name);
+        rest = &rest[end + 1..];
+        if !seen_residue {
+            out.push_str(&format!("[{}]-", name));
+        } else if site.contains("C-term") && rest.is_empty() {
+            out.push_str(&format!("-[{}]", name));
+        } else {
+            out.push_str(&format!("[{}]", name));
+        }
+    }
+    split_peptidoform(&out)?;
+    Ok(out)
+}
+
+/// Columns of a Spectronaut library, found by their header.
+struct Columns {
+    sequence: usize,
+    charge: usize,
+    precursor_mz: usize,
+    rt: usize,
+    mobility: Option<usize>,
+    decoy: Option<usize>,
+    fragment_mz: usize,
+    fragment_intensity: usize,
+    fragment_type: usize,
+    fragment_number: usize,
+    fragment_charge: usize,
+    fragment_loss: Option<usize>,
+}
+
+impl Columns {
+    fn new(headers: &csv::StringRecord) -> Result<Self, TimsSeekError> {
+        let find = |names: &[&str]| {
+            names.iter().find_map(|name| {
+                headers
+                    .iter()
+                    .position(|x| x.trim_start_matches('\u{feff}') == *name)
+            })
+        };
+        let require = |names: &[&str]| {
+            find(names).ok_or_else(|| TimsSeekError::ParseError {
+                msg: format!("Spectronaut library has none of the {:?} columns", names),
+            })
+        };
+        Ok(Self {
+            sequence: require(&["ModifiedPeptide", "ModifiedSequence", "LabeledPeptide"])?,
+            charge: require(&["PrecursorCharge"])?,
+            precursor_mz: require(&["PrecursorMz"])?,
+            rt: require(&["iRT", "RT", "NormalizedRetentionTime"])?,
+            mobility: find(&["IonMobility", "PrecursorIonMobility"]),
+            decoy: find(&["IsDecoy", "Decoy"]),
+            fragment_mz: require(&["FragmentMz"])?,
+            fragment_intensity: require(&["RelativeIntensity", "LibraryIntensity"])?,
+            fragment_type: require(&["FragmentType"])?,
+            fragment_number: require(&["FragmentNumber"])?,
+            fragment_charge: require(&["FragmentCharge"])?,
+            fragment_loss: find(&["FragmentLossType"]),
+        })
+    }
+}
+
+/// Fragment label of a Spectronaut row, None for losses we do not model.
+fn fragment_position(
+    fragment_type: &str,
+    number: u16,
+    charge: u8,
+    loss: &str,
+) -> Option<SafePosition> {
+    let series_id = *fragment_type.trim().as_bytes().first()?;
+    let neutral_loss = match loss.trim() {
+        "" | "noloss" => None,
+        x => Some(NeutralLoss::from_token(x)?),
+    };
+    Some(SafePosition {
+        series_id: series_id.to_ascii_lowercase(),
+        series_number: number,
+        series_end: 0,
+        neutral_loss,
+        charge,
+    })
+}
+
+/// One row per fragment is folded into one entry per precursor.
+struct PrecursorBuilder {
+    sequence: String,
+    charge: u8,
+    decoy: bool,
+    precursor_mz: f64,
+    irt: f32,
+    mobility: Option<f32>,
+    fragment_mzs: HashMap<SafePosition, f64>,
+    fragment_intensities: HashMap<SafePosition, f32>,
+}
+
+impl PrecursorBuilder {
+    /// The precursor m/z are the -1 peak (if the converter queries it) and
+    /// `num_precursor_isotopes` peaks from the monoisotopic one up, as the
+    /// converter queries them.
+    fn into_row(
+        self,
+        id: u64,
+        converter: &SequenceToElutionGroupConverter,
+    ) -> Result<SpeclibRow, TimsSeekError> {
+        let neutral_mass = (self.precursor_mz - PROTON_MASS) * self.charge as f64;
+        let mobility = self
+            .mobility
+            .unwrap_or_else(|| supersimpleprediction(self.precursor_mz, self.charge as i32) as f32);
+        let precursor_mzs = precursor_isotope_mzs(
+            neutral_mass,
+            self.charge,
+            converter.num_precursor_isotopes + 1,
+        )
+        .into_iter()
+        .skip(usize::from(!converter.minus_one_isotope))
+        .collect();
+        let elution_group = ElutionGroup {
+            id,
+            precursor_mzs,
+            mobility,
+            rt_seconds: self.irt,
+            fragment_mzs: self.fragment_mzs,
+            expected_fragment_intensity: Some(self.fragment_intensities),
+            expected_precursor_intensity: None,
+        };
+        let digest = library_digest(&self.sequence, self.decoy)?;
+        Ok((elution_group, self.charge, digest, None, LabelChannel::Light))
+    }
+}
+
+fn csv_error(e: csv::Error) -> TimsSeekError {
+    TimsSeekError::ParseError {
+        msg: format!("Error reading Spectronaut library: {}", e),
+    }
+}
+
+/// Tab separated exports (`.tsv`/`.xls`) have a tab in their header line,
+/// the others are comma separated.
+fn library_delimiter(header: &str) -> u8 {
+    if header.contains('\t') {
+        b'\t'
+    } else {
+        b','
+    }
+}
+
+/// Same as [`spectronaut_rows`] for a library file, which is read as it is
+/// parsed instead of in full.
+pub(crate) fn spectronaut_file_rows(
+    path: &Path,
+    converter: &SequenceToElutionGroupConverter,
+) -> Result<Vec<SpeclibRow>, TimsSeekError> {
+    let mut header = String::new();
+    std::io::BufReader::new(std::fs::File::open(path)?).read_line(&mut header)?;
+    let reader = csv::ReaderBuilder::new()
+        .delimiter(library_delimiter(&header))
+        .from_path(path)
+        .map_err(csv_error)?;
+    spectronaut_rows(reader, converter)
+}
+
+/// Same as [`spectronaut_rows`] for the text of a library.
+pub(crate) fn spectronaut_text_rows(
+    text: &str,
+    converter: &SequenceToElutionGroupConverter,
+) -> Result<Vec<SpeclibRow>, TimsSeekError> {
+    let header = text.lines().next().unwrap_or_default();
+    let reader = csv::ReaderBuilder::new()
+        .delimiter(library_delimiter(header))
+        .from_reader(text.as_bytes());
+    spectronaut_rows(reader, converter)
+}
+
+/// Reads a Spectronaut library export (`.tsv`/`.xls`, tab separated, or
+/// `.csv`), one row per fragment. The precursors get the isotope peaks the
+/// converter queries for in-silico peptides (the -1 peak if enabled, and
+/// `num_precursor_isotopes` from the monoisotopic one up).
+///
+/// The retention times of Spectronaut libraries are iRT, so they should be
+/// searched with `irt_anchors` or the calibration pass.
+fn spectronaut_rows<R: std::io::Read>(
+    mut reader: csv::Reader<R>,
+    converter: &SequenceToElutionGroupConverter,
+) -> Result<Vec<SpeclibRow>, TimsSeekError> {
+    let columns = Columns::new(reader.headers().map_err(csv_error)?)?;
+
+    let mut precursors: Vec<PrecursorBuilder> = Vec::new();
+    let mut index: HashMap<(String, u8), usize> = HashMap::new();
+    let mut num_skipped = 0;
+    for (i, record) in reader.records().enumerate() {
+        let record = record.map_err(csv_error)?;
+        let field = |column: usize| record.get(column).unwrap_or_default();
+        let number = |column: usize| {
+            parse_decimal(field(column)).map_err(|e| TimsSeekError::ParseError {
+                msg: format!("Error reading line {} of the Spectronaut library: {}", i + 2, e),
+            })
+        };
+        let charge = number(columns.charge)? as u8;
+        let key = (field(columns.sequence).to_string(), charge);
+        let entry = match index.get(&key).copied() {
+            Some(x) => &mut precursors[x],
+            None => {
+                let mobility = match columns.mobility {
+                    Some(x) if !field(x).trim().is_empty() => Some(number(x)? as f32),
+                    _ => None,
+                };
+                let decoy = columns.decoy.map(field).is_some_and(|x| {
+                    x.trim().eq_ignore_ascii_case("true") || x.trim() == "1"
+                });
+                index.insert(key.clone(), precursors.len());
+                precursors.push(PrecursorBuilder {
+                    sequence: spectronaut_to_proforma(&key.0)?,
+                    charge,
+                    decoy,
+                    precursor_mz: number(columns.precursor_mz)?,
+                    irt: number(columns.rt)? as f32,
+                    mobility,
+                    fragment_mzs: HashMap::new(),
+                    fragment_intensities: HashMap::new(),
+                });
+                precursors.last_mut().unwrap()
+            }
+        };
+
+        let loss = columns.fragment_loss.map(field).unwrap_or_default();
+        let position = fragment_position(
+            field(columns.fragment_type),
+            number(columns.fragment_number)? as u16,
+            number(columns.fragment_charge)? as u8,
+            loss,
+        );
+        let Some(position) = position else {
+            num_skipped += 1;
+            continue;
+        };
+        if entry.fragment_mzs.contains_key(&position) {
+            continue;
+        }
+        entry
+            .fragment_mzs
+            .insert(position, number(columns.fragment_mz)?);
+        entry
+            .fragment_intensities
+            .insert(position, number(columns.fragment_intensity)? as f32);
+    }
+    if num_skipped > 0 {
+        log::warn!(
+            "Skipped {} fragments of the Spectronaut library with unsupported losses",
+            num_skipped
+        );
+    }
+    if precursors.is_empty() {
+        return Err(TimsSeekError::ParseError {
+            msg: "No precursors found in the Spectronaut library".to_string(),
+        });
+    }
+
+    precursors
+        .into_iter()
+        .enumerate()
+        .map(|(i, x)| x.into_row(i as u64, converter))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spectronaut_to_proforma() {
+        assert_eq!(spectronaut_to_proforma("_PEPTIDEK_").unwrap(), "PEPTIDEK");
+        assert_eq!(
+            spectronaut_to_proforma("_[Acetyl (Protein N-term)]M[Oxidation (M)]PEPCK_").unwrap(),
+            "[Acetyl]-M[Oxidation]PEPCK"
+        );
+        assert_eq!(
+            spectronaut_to_proforma("_PEPC[Carbamidomethyl (C)]K[Amidated (Peptide C-term)]_")
+                .unwrap(),
+            "PEPC[Carbamidomethyl]K-[Amidated]"
+        );
+        assert_eq!(
+            spectronaut_to_proforma("_PEPN[Deamidation (NQ)]IDEK_").unwrap(),
+            "PEPN[Deamidated]IDEK"
+        );
+        assert_eq!(spectronaut_to_proforma("_PEPC[+57]IDEK_").unwrap(), "PEPC[+57]IDEK");
+        assert!(spectronaut_to_proforma("_PEPC[Carbamidomethyl (C)IDEK_").is_err());
+    }
+
+    #[test]
+    fn test_spectronaut_rows() {
+        let tsv = "ModifiedPeptide\tPrecursorCharge\tPrecursorMz\tiRT\tIonMobility\t\
+                   FragmentMz\tRelativeIntensity\tFragmentType\tFragmentNumber\t\
+                   FragmentCharge\tFragmentLossType\n\
+                   _PEPTIDEK_\t2\t464,7347\t35,2\t0,85\t703.3668\t100\ty\t6\t1\tnoloss\n\
+                   _PEPTIDEK_\t2\t464,7347\t35,2\t0,85\t685.3562\t20\ty\t6\t1\tH2O\n\
+                   _PEPTIDEK_\t2\t464,7347\t35,2\t0,85\t227.1026\t45\tb\t2\t1\tCO\n\
+                   _M[Oxidation (M)]EPTIDEK_\t3\t327.8237\t50\t\t588.3399\t100\ty\t5\t1\tnoloss\n";
+        let converter = SequenceToElutionGroupConverter::default();
+        let rows = spectronaut_text_rows(tsv, &converter).unwrap();
+        assert_eq!(rows.len(), 2);
+
+        let (eg, charge, digest, _, _) = &rows[0];
+        assert_eq!(*charge, 2);
+        assert_eq!(digest.peptidoform, None);
+        assert_eq!(eg.rt_seconds, 35.2);
+        assert_eq!(eg.mobility, 0.85);
+        assert_eq!(eg.precursor_mzs.len(), 4);
+        assert!((eg.precursor_mzs[1] - 464.7347).abs() < 1e-4);
+        // The b2-CO fragment is skipped.
+        assert_eq!(eg.fragment_mzs.len(), 2);
+        let y6 = SafePosition::from_str("y6").unwrap();
+        assert_eq!(eg.expected_fragment_intensity.as_ref().unwrap()[&y6], 100.0);
+
+        let (eg, charge, digest, _, _) = &rows[1];
+        assert_eq!(*charge, 3);
+        assert_eq!(eg.id, 1);
+        assert_eq!(digest.peptidoform.as_deref(), Some("M[Oxidation]EPTIDEK"));
+        assert!(eg.mobility > 0.0);
+
+        let path = std::env::temp_dir().join("timsseek_test_spectronaut.tsv");
+        std::fs::write(&path, tsv).unwrap();
+        let mut converter = SequenceToElutionGroupConverter::default();
+        converter.num_precursor_isotopes = 5;
+        converter.minus_one_isotope = false;
+        let rows = spectronaut_file_rows(&path, &converter).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(rows.len(), 2);
+        // Without the -1 peak, as the converter queries them.
+        let (eg, _, _, _, _) = &rows[0];
+        assert_eq!(eg.precursor_mzs.len(), 5);
+        assert!((eg.precursor_mzs[0] - 464.7347).abs() < 1e-4);
+    }
+}
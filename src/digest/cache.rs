@@ -0,0 +1,183 @@
+use crate::errors::TimsSeekError;
+use crate::models::{
+    DecoyMarking,
+    DigestSlice,
+};
+use sha2::{
+    Digest,
+    Sha256,
+};
+use std::io::{
+    BufRead,
+    BufReader,
+    BufWriter,
+    Write,
+};
+use std::path::Path;
+use std::sync::Arc;
+
+const CACHE_HEADER: &str = "# timsseek digest cache v3";
+
+/// Key identifying a digestion, the SHA-256 (hex) of the FASTA contents and
+/// the (serialized) digestion parameters.
+///
+/// Unlike the std hashers it is stable across Rust releases, so caches stay
+/// valid after upgrading.
+pub fn digest_cache_key(fasta: &str, parameters: &str) -> String {
+    let mut hasher = Sha256::new();
+    // The length keeps the boundary between the two inputs unambiguous.
+    hasher.update((fasta.len() as u64).to_le_bytes());
+    hasher.update(fasta.as_bytes());
+    hasher.update(parameters.as_bytes());
+    hasher
+        .finalize()
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+
+/// Writes deduplicated digests as a tab separated file, one peptide per line
+/// (sequence, decoy, missed cleavages, `;` separated protein ids, protein
+/// N-terminal flag).
+///
+/// Decoys are stored with their final sequence, so they are read back as
+/// `ReversedDecoy`. The cache is written to a temporary file that replaces
+/// `path` once complete, so an interrupted write never leaves a truncated
+/// cache behind.
+pub fn write_digest_cache(
+    path: &Path,
+    key: &str,
+    digests: &[DigestSlice],
+) -> Result<(), TimsSeekError> {
+    let tmp_path = path.with_extension("tsv.tmp");
+    let mut writer = BufWriter::new(std::fs::File::create(&tmp_path)?);
+    writeln!(writer, "{} {}", CACHE_HEADER, key)?;
+    for digest in digests {
+        let sequence: String = digest.clone().into();
+        let decoy = match digest.decoy {
+            DecoyMarking::Target => "target",
+            _ => "decoy",
+        };
+        let missed = digest
+            .missed_cleavages
+            .map(|x| x.to_string())
+            .unwrap_or_default();
+        let proteins = digest
+            .protein_ids()
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>()
+            .join(";");
+        let nterm = if digest.protein_nterm { "nterm" } else { "" };
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}",
+            sequence, decoy, missed, proteins, nterm
+        )?;
+    }
+    writer.flush()?;
+    drop(writer);
+    std::fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+/// Reads digests written by [`write_digest_cache`].
+///
+/// Returns None if there is no cache or it was built for another key.
+pub fn read_digest_cache(path: &Path, key: &str) -> Result<Option<Vec<DigestSlice>>, TimsSeekError> {
+    let file = match std::fs::File::open(path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut lines = BufReader::new(file).lines();
+    let expected_header = format!("{} {}", CACHE_HEADER, key);
+    let header = match lines.next() {
+        Some(x) => x?,
+        None => return Ok(None),
+    };
+    if header != expected_header {
+        return Ok(None);
+    }
+
+    let parse_error = |line: &str| TimsSeekError::ParseError {
+        msg: format!("Invalid digest cache line in {}: {:?}", path.display(), line),
+    };
+    let mut out = Vec::new();
+    for line in lines {
+        let line = line?;
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 5 {
+            return Err(parse_error(&line));
+        }
+        let decoy = match fields[1] {
+            "target" => DecoyMarking::Target,
+            "decoy" => DecoyMarking::ReversedDecoy,
+            _ => return Err(parse_error(&line)),
+        };
+        let protein_ids = fields[3]
+            .split(';')
+            .filter(|x| !x.is_empty())
+            .map(|x| x.parse::<u32>())
+            .collect::<Result<Vec<u32>, _>>()?;
+        let sequence: Arc<str> = fields[0].into();
+        let range = 0..sequence.len();
+        let mut digest = DigestSlice::new(sequence, range, decoy)
+            .with_protein_ids(&protein_ids)
+            .with_protein_nterm(fields[4] == "nterm");
+        if !fields[2].is_empty() {
+            digest = digest.with_missed_cleavages(fields[2].parse()?);
+        }
+        out.push(digest);
+    }
+    Ok(Some(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digest_cache_round_trip() {
+        let seq: Arc<str> = "PEPTIDEKPINK".into();
+        let digests = vec![
+            DigestSlice::new(seq.clone(), 0..8, DecoyMarking::Target)
+                .with_protein_ids(&[0, 3])
+                .with_missed_cleavages(0)
+                .with_protein_nterm(true),
+            DigestSlice::new(seq.clone(), 0..12, DecoyMarking::Decoy),
+        ];
+        let path = std::env::temp_dir().join("timsseek_test_digest_cache.tsv");
+        let key = digest_cache_key(">P1\nPEPTIDEK\n", "{}");
+        let other_key = digest_cache_key(">P1\nPEPTIDEK\n", "{\"min_length\":6}");
+        write_digest_cache(&path, &key, &digests).unwrap();
+        assert!(!path.with_extension("tsv.tmp").exists());
+
+        assert!(read_digest_cache(&path, &other_key).unwrap().is_none());
+        let read = read_digest_cache(&path, &key).unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(read.len(), 2);
+        assert_eq!(String::from(read[0].clone()), "PEPTIDEK");
+        assert_eq!(read[0].protein_ids(), &[0, 3]);
+        assert_eq!(read[0].missed_cleavages, Some(0));
+        assert!(read[0].protein_nterm);
+        assert!(!read[1].protein_nterm);
+        assert_eq!(read[1].decoy, DecoyMarking::ReversedDecoy);
+        assert_eq!(String::from(read[1].clone()), String::from(digests[1].clone()));
+        assert_eq!(read[1].missed_cleavages, None);
+    }
+
+    #[test]
+    fn test_digest_cache_key() {
+        // Fixed value, the key must not change between builds.
+        assert_eq!(
+            digest_cache_key(">P1\nPEPTIDEK\n", "{\"min_length\":6}"),
+            "d8727f1079abcdeb7263fe023ead8d48bdda80b40c1305ade46e941af1109c07"
+        );
+        assert_ne!(
+            digest_cache_key(">P1\nPEPTIDEK", "\n{\"min_length\":6}"),
+            digest_cache_key(">P1\nPEPTIDEK\n", "{\"min_length\":6}")
+        );
+    }
+}
@@ -0,0 +1,500 @@
+use crate::digest::digestion::{
+    DigestionEnd,
+    DigestionPattern,
+};
+use crate::models::{
+    stable_hash,
+    DecoyGenerator,
+    DecoyMarking,
+    DigestSlice,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Tiny deterministic RNG (splitmix64), so decoys are reproducible from a seed
+/// without pulling in a full RNG crate.
+#[derive(Debug, Clone)]
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform-ish integer in `0..upper`.
+    pub fn next_below(&mut self, upper: usize) -> usize {
+        (self.next_u64() % upper as u64) as usize
+    }
+}
+
+/// How the first decoy of every target is built, extra decoys are shuffles
+/// (cleavage preserving ones for [`DecoyStrategy::CleavageShuffle`]).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DecoyStrategy {
+    /// Reverses all residues but the termini.
+    #[default]
+    Reverse,
+    /// Seeded shuffle of all residues but the termini.
+    Shuffle,
+    /// Seeded shuffle that keeps the cleavage sites in place (see
+    /// [`as_cleavage_shuffled_decoy_string`]) and avoids every target
+    /// sequence, more realistic than reversal for short peptides.
+    #[serde(rename = "cleavage_shuffle")]
+    CleavageShuffle,
+    /// Mutates the residues next to the termini (DIA-NN style), which also
+    /// works for palindromic and low complexity peptides.
+    Mutate,
+    /// No decoys are built.
+    None,
+}
+
+impl DecoyStrategy {
+    /// Generator for the strategy, extra decoys per target are shuffles
+    /// seeded from `seed`.
+    ///
+    /// `targets` are the target sequences cleavage preserving shuffles must
+    /// not collide with and `sites` the cleavage sites they keep in place,
+    /// the other strategies ignore both.
+    pub fn generator(
+        &self,
+        seed: u64,
+        shuffle_max_retries: usize,
+        targets: Arc<HashSet<String>>,
+        sites: CleavageSites,
+    ) -> Box<dyn DecoyGenerator> {
+        let shuffled = ShuffledDecoys {
+            seed,
+            max_retries: shuffle_max_retries,
+        };
+        match self {
+            DecoyStrategy::Reverse => Box::new(ReversedDecoys(shuffled)),
+            DecoyStrategy::Shuffle => Box::new(shuffled),
+            DecoyStrategy::CleavageShuffle => Box::new(CleavageShuffledDecoys {
+                seed,
+                max_retries: shuffle_max_retries,
+                targets,
+                sites,
+            }),
+            DecoyStrategy::Mutate => Box::new(MutatedDecoys(shuffled)),
+            DecoyStrategy::None => Box::new(NoDecoys),
+        }
+    }
+}
+
+/// Shuffle seed of a replicate of the decoys of `sequence`, a hash of the
+/// three so every peptide gets its own permutation instead of all of them
+/// sharing one per replicate.
+pub fn decoy_seed(seed: u64, sequence: &str, replicate: usize) -> u64 {
+    stable_hash(&(sequence, seed, replicate as u64))
+}
+
+/// Seeded shuffles, every peptide and replicate with its own seed (see
+/// [`decoy_seed`]).
+///
+/// Shuffles that give back the target sequence are retried with other
+/// seeds.
+#[derive(Debug, Clone, Copy)]
+pub struct ShuffledDecoys {
+    pub seed: u64,
+    pub max_retries: usize,
+}
+
+impl DecoyGenerator for ShuffledDecoys {
+    fn decoy(&self, target: &DigestSlice, replicate: usize) -> Option<DigestSlice> {
+        let sequence = target.unmarked_sequence();
+        let seed = non_colliding_shuffle_seed(
+            sequence,
+            decoy_seed(self.seed, sequence, replicate),
+            self.max_retries,
+        );
+        Some(target.with_decoy(DecoyMarking::ShuffledDecoy(seed)))
+    }
+}
+
+/// Cleavage preserving shuffles, every peptide and replicate with its own
+/// seed (see [`decoy_seed`]).
+///
+/// Shuffles that give back the target, or any other target sequence, are
+/// retried with other seeds.
+#[derive(Debug, Clone)]
+pub struct CleavageShuffledDecoys {
+    pub seed: u64,
+    pub max_retries: usize,
+    pub targets: Arc<HashSet<String>>,
+    pub sites: CleavageSites,
+}
+
+impl DecoyGenerator for CleavageShuffledDecoys {
+    fn decoy(&self, target: &DigestSlice, replicate: usize) -> Option<DigestSlice> {
+        let sequence = target.unmarked_sequence();
+        let seed = non_colliding_seed(
+            decoy_seed(self.seed, sequence, replicate),
+            self.max_retries,
+            |seed| {
+                let decoy = as_cleavage_shuffled_decoy_string(sequence, seed, &self.sites);
+                decoy != sequence && !self.targets.contains(&decoy)
+            },
+        );
+        Some(target.with_decoy(DecoyMarking::CleavageShuffledDecoy(seed, self.sites)))
+    }
+}
+
+/// Reversed first decoy, shuffles for the extra replicates.
+#[derive(Debug, Clone, Copy)]
+pub struct ReversedDecoys(pub ShuffledDecoys);
+
+impl DecoyGenerator for ReversedDecoys {
+    fn decoy(&self, target: &DigestSlice, replicate: usize) -> Option<DigestSlice> {
+        match replicate {
+            0 => Some(target.with_decoy(DecoyMarking::Decoy)),
+            _ => self.0.decoy(target, replicate),
+        }
+    }
+}
+
+/// Mutated first decoy, shuffles for the extra replicates.
+#[derive(Debug, Clone, Copy)]
+pub struct MutatedDecoys(pub ShuffledDecoys);
+
+impl DecoyGenerator for MutatedDecoys {
+    fn decoy(&self, target: &DigestSlice, replicate: usize) -> Option<DigestSlice> {
+        match replicate {
+            0 => Some(target.with_decoy(DecoyMarking::MutatedDecoy)),
+            _ => self.0.decoy(target, replicate),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct NoDecoys;
+
+impl DecoyGenerator for NoDecoys {
+    fn decoy(&self, _target: &DigestSlice, _replicate: usize) -> Option<DigestSlice> {
+        None
+    }
+}
+
+/// Replacement used for mutated decoys, same as DIA-NN.
+///
+/// Most replacements are close in mass, so the mass distribution of the
+/// decoys stays close to the one of the targets.
+fn mutate_residue(residue: char) -> char {
+    match residue {
+        'G' => 'L',
+        'A' => 'L',
+        'V' => 'L',
+        'L' => 'V',
+        'I' => 'V',
+        'F' => 'L',
+        'M' => 'L',
+        'P' => 'L',
+        'W' => 'L',
+        'S' => 'T',
+        'C' => 'S',
+        'T' => 'S',
+        'Y' => 'S',
+        'H' => 'S',
+        'K' => 'L',
+        'R' => 'L',
+        'N' => 'Q',
+        'D' => 'E',
+        'E' => 'D',
+        'Q' => 'N',
+        x => x,
+    }
+}
+
+/// Mutates the second and the second to last residues, the termini (and so
+/// the cleavage sites) are kept.
+pub fn as_mutated_decoy_string(sequence: &str) -> String {
+    let mut residues: Vec<char> = sequence.chars().collect();
+    if residues.len() < 3 {
+        return sequence.to_string();
+    }
+    let second_to_last = residues.len() - 2;
+    residues[1] = mutate_residue(residues[1]);
+    if second_to_last != 1 {
+        residues[second_to_last] = mutate_residue(residues[second_to_last]);
+    }
+    residues.into_iter().collect()
+}
+
+/// Seed whose shuffle of `sequence` differs from it, starting with `seed`
+/// and trying up to `max_retries` others derived from it.
+///
+/// Low complexity sequences may have no such shuffle, then the last seed
+/// tried is returned.
+pub fn non_colliding_shuffle_seed(sequence: &str, seed: u64, max_retries: usize) -> u64 {
+    non_colliding_seed(seed, max_retries, |seed| {
+        as_shuffled_decoy_string(sequence, seed) != sequence
+    })
+}
+
+/// First seed accepted by `is_valid`, starting with `seed` and trying up to
+/// `max_retries` others derived from it, or the last seed tried.
+pub(crate) fn non_colliding_seed(seed: u64, max_retries: usize, is_valid: impl Fn(u64) -> bool) -> u64 {
+    // Retry seeds come from the rng instead of seed + 1, so the retries of
+    // nearby seeds do not overlap.
+    let mut rng = SplitMix64::new(seed);
+    let mut current = seed;
+    for _ in 0..max_retries {
+        if is_valid(current) {
+            return current;
+        }
+        current = rng.next_u64();
+    }
+    current
+}
+
+/// Shuffles all residues but the first and last one (same termini as the
+/// reversed decoys) with a seeded Fisher-Yates shuffle.
+pub fn as_shuffled_decoy_string(sequence: &str, seed: u64) -> String {
+    let mut residues: Vec<char> = sequence.chars().collect();
+    if residues.len() < 4 {
+        return sequence.to_string();
+    }
+    let last = residues.len() - 1;
+    let mut rng = SplitMix64::new(seed);
+    let inner = &mut residues[1..last];
+    for i in (1..inner.len()).rev() {
+        let j = rng.next_below(i + 1);
+        inner.swap(i, j);
+    }
+    residues.into_iter().collect()
+}
+
+/// Residues an enzyme cleaves at and the side of them it cuts, the anchors
+/// of [`as_cleavage_shuffled_decoy_string`].
+///
+/// Taken from the main enzyme of the digestion. Residues are the single
+/// letters its pattern matches, so multi-residue motifs of custom enzymes
+/// are approximated by their individual residues.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub struct CleavageSites {
+    /// Bit `i` is set if the enzyme cleaves at residue `'A' + i`.
+    residues: u32,
+    end: DigestionEnd,
+}
+
+impl CleavageSites {
+    pub fn new(pattern: &DigestionPattern, end: DigestionEnd) -> Self {
+        let residues = ('A'..='Z')
+            .enumerate()
+            .filter(|(_, c)| pattern.regex.is_match(&c.to_string()))
+            .fold(0u32, |acc, (i, _)| acc | (1 << i));
+        Self { residues, end }
+    }
+
+    pub fn contains(&self, residue: char) -> bool {
+        residue.is_ascii_uppercase() && self.residues & (1 << (residue as u8 - b'A')) != 0
+    }
+
+    /// Whether residue `i` stays in place: the terminal residue on the
+    /// cleaved side, every cleavage residue and its neighbour across the
+    /// cut (so a proline blocking a trypsin cleavage stays, and none is
+    /// moved next to a cleavage residue).
+    fn is_anchor(&self, residues: &[char], i: usize) -> bool {
+        if self.contains(residues[i]) {
+            return true;
+        }
+        match self.end {
+            DigestionEnd::CTerm => {
+                i + 1 == residues.len() || (i > 0 && self.contains(residues[i - 1]))
+            }
+            DigestionEnd::NTerm => {
+                i == 0 || (i + 1 < residues.len() && self.contains(residues[i + 1]))
+            }
+        }
+    }
+}
+
+impl Default for CleavageSites {
+    /// Trypsin.
+    fn default() -> Self {
+        Self::new(&DigestionPattern::trypsin(), DigestionEnd::CTerm)
+    }
+}
+
+/// Seeded shuffle of the residues that are not cleavage anchors (see
+/// [`CleavageSites`]).
+///
+/// The composition, the terminal residue on the cleaved side and the
+/// (missed) cleavage sites are the same as the ones of the target.
+pub fn as_cleavage_shuffled_decoy_string(
+    sequence: &str,
+    seed: u64,
+    sites: &CleavageSites,
+) -> String {
+    let mut residues: Vec<char> = sequence.chars().collect();
+    let free: Vec<usize> = (0..residues.len())
+        .filter(|i| !sites.is_anchor(&residues, *i))
+        .collect();
+    let mut rng = SplitMix64::new(seed);
+    for i in (1..free.len()).rev() {
+        let j = rng.next_below(i + 1);
+        residues.swap(free[i], free[j]);
+    }
+    residues.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::digestion::Enzyme;
+
+    #[test]
+    fn test_shuffled_decoy() {
+        let seq = "PEPTIDEPINK";
+        let decoy = as_shuffled_decoy_string(seq, 42);
+        assert_eq!(decoy, as_shuffled_decoy_string(seq, 42));
+        assert_eq!(decoy.len(), seq.len());
+        assert!(decoy.starts_with('P'));
+        assert!(decoy.ends_with('K'));
+
+        let mut sorted_decoy: Vec<char> = decoy.chars().collect();
+        let mut sorted_seq: Vec<char> = seq.chars().collect();
+        sorted_decoy.sort();
+        sorted_seq.sort();
+        assert_eq!(sorted_decoy, sorted_seq);
+    }
+
+    #[test]
+    fn test_decoy_generators() {
+        let seq: Arc<str> = "PEPTIDEK".into();
+        let target = DigestSlice::new(seq.clone(), 0..seq.len(), DecoyMarking::Target);
+        let decoy_of = |strategy: DecoyStrategy, replicate: usize| {
+            strategy
+                .generator(42, 10, Arc::new(HashSet::new()), CleavageSites::default())
+                .decoy(&target, replicate)
+                .map(|x| x.decoy)
+        };
+        assert_eq!(decoy_of(DecoyStrategy::Reverse, 0), Some(DecoyMarking::Decoy));
+        assert_eq!(decoy_of(DecoyStrategy::Mutate, 0), Some(DecoyMarking::MutatedDecoy));
+        assert!(matches!(
+            decoy_of(DecoyStrategy::Shuffle, 0),
+            Some(DecoyMarking::ShuffledDecoy(_))
+        ));
+        assert!(matches!(
+            decoy_of(DecoyStrategy::Reverse, 2),
+            Some(DecoyMarking::ShuffledDecoy(_))
+        ));
+        assert_ne!(decoy_of(DecoyStrategy::Reverse, 1), decoy_of(DecoyStrategy::Reverse, 2));
+        assert_eq!(decoy_of(DecoyStrategy::None, 0), None);
+    }
+
+    #[test]
+    fn test_decoy_seed_per_peptide() {
+        let generator = DecoyStrategy::Shuffle.generator(
+            42,
+            10,
+            Arc::new(HashSet::new()),
+            CleavageSites::default(),
+        );
+        let seed_of = |sequence: &str| {
+            let seq: Arc<str> = sequence.into();
+            let target = DigestSlice::new(seq.clone(), 0..seq.len(), DecoyMarking::Target);
+            generator.decoy(&target, 0).unwrap().decoy
+        };
+        assert_eq!(seed_of("PEPTIDEK"), seed_of("PEPTIDEK"));
+        assert_ne!(seed_of("PEPTIDEK"), seed_of("LESLIEKK"));
+        assert_ne!(decoy_seed(42, "PEPTIDEK", 0), decoy_seed(43, "PEPTIDEK", 0));
+        assert_ne!(decoy_seed(42, "PEPTIDEK", 0), decoy_seed(42, "PEPTIDEK", 1));
+    }
+
+    #[test]
+    fn test_cleavage_shuffled_decoy() {
+        let trypsin = CleavageSites::default();
+        let seq = "PEPTKPIDERAGLSTK";
+        for seed in 0..20 {
+            let decoy = as_cleavage_shuffled_decoy_string(seq, seed, &trypsin);
+            let mut sorted_decoy: Vec<char> = decoy.chars().collect();
+            let mut sorted_seq: Vec<char> = seq.chars().collect();
+            sorted_decoy.sort();
+            sorted_seq.sort();
+            assert_eq!(sorted_decoy, sorted_seq);
+            // K/R, the residues after them and the C-terminus stay in place.
+            for i in [4, 5, 9, 10, 15] {
+                assert_eq!(decoy.as_bytes()[i], seq.as_bytes()[i]);
+            }
+        }
+        assert_ne!(
+            as_cleavage_shuffled_decoy_string(seq, 1, &trypsin),
+            as_cleavage_shuffled_decoy_string(seq, 2, &trypsin)
+        );
+
+        // Asp-N cuts before D, the N-terminus and the residue before every D
+        // stay, the K/R are shuffled.
+        let asp_n = CleavageSites::new(&Enzyme::AspN.pattern(), Enzyme::AspN.digestion_end());
+        assert!(asp_n.contains('D') && !asp_n.contains('K'));
+        let seq = "DPEPTIKDAGLSTR";
+        for seed in 0..20 {
+            let decoy = as_cleavage_shuffled_decoy_string(seq, seed, &asp_n);
+            for i in [0, 6, 7] {
+                assert_eq!(decoy.as_bytes()[i], seq.as_bytes()[i]);
+            }
+        }
+        assert!((0..20).any(|x| {
+            !as_cleavage_shuffled_decoy_string(seq, x, &asp_n).ends_with('R')
+        }));
+    }
+
+    #[test]
+    fn test_cleavage_shuffle_avoids_targets() {
+        let seq: Arc<str> = "AGLSEK".into();
+        let target = DigestSlice::new(seq.clone(), 0..seq.len(), DecoyMarking::Target);
+        // Every shuffle of the first seeds is a target.
+        let targets: HashSet<String> = (0..5)
+            .map(|x| as_cleavage_shuffled_decoy_string(&seq, x, &CleavageSites::default()))
+            .collect();
+        let generator = DecoyStrategy::CleavageShuffle.generator(
+            0,
+            10,
+            Arc::new(targets.clone()),
+            CleavageSites::default(),
+        );
+        let decoy: String = generator.decoy(&target, 0).unwrap().into();
+        assert!(!targets.contains(&decoy));
+        assert_ne!(decoy, "AGLSEK");
+        assert!(decoy.ends_with('K'));
+    }
+
+    #[test]
+    fn test_mutated_decoy() {
+        assert_eq!(as_mutated_decoy_string("PEPTIDEK"), "PDPTIDDK");
+        // Palindromes, where reversing gives back the target.
+        assert_eq!(as_mutated_decoy_string("AGGAK"), "ALGLK");
+        assert_eq!(as_mutated_decoy_string("PAK"), "PLK");
+        assert_eq!(as_mutated_decoy_string("PK"), "PK");
+    }
+
+    #[test]
+    fn test_non_colliding_shuffle_seed() {
+        // Find a seed that leaves the sequence as-is, the retries move past it.
+        let seq = "PEAK";
+        let colliding = (0..100)
+            .find(|x| as_shuffled_decoy_string(seq, *x) == seq)
+            .unwrap();
+        let seed = non_colliding_shuffle_seed(seq, colliding, 10);
+        assert_ne!(seed, colliding);
+        assert_ne!(as_shuffled_decoy_string(seq, seed), seq);
+
+        // Nothing to do for a sequence without distinct inner residues.
+        let seed = non_colliding_shuffle_seed("PAAAK", 1, 3);
+        assert_eq!(as_shuffled_decoy_string("PAAAK", seed), "PAAAK");
+    }
+}
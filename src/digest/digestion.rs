@@ -1,13 +1,23 @@
+use crate::digest::masses::peptide_monoisotopic_mass;
 use crate::models::{
     DecoyMarking,
     DigestSlice,
 };
+use rayon::prelude::*;
 use regex::Regex;
+use serde::{
+    Deserialize,
+    Serialize,
+};
 use std::ops::Range;
 use std::sync::Arc;
 
-#[derive(Debug, Clone)]
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
 pub enum DigestionEnd {
+    #[default]
     CTerm,
     NTerm,
 }
@@ -37,6 +47,165 @@ impl DigestionPattern {
     }
 }
 
+/// Proteases that can be selected by name in the config.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Enzyme {
+    #[default]
+    #[serde(rename = "trypsin")]
+    Trypsin,
+    #[serde(rename = "trypsin/p")]
+    TrypsinP,
+    #[serde(rename = "lys-c")]
+    LysC,
+    #[serde(rename = "lys-n")]
+    LysN,
+    #[serde(rename = "arg-c")]
+    ArgC,
+    #[serde(rename = "glu-c")]
+    GluC,
+    #[serde(rename = "asp-n")]
+    AspN,
+    #[serde(rename = "chymotrypsin")]
+    Chymotrypsin,
+}
+
+impl Enzyme {
+    pub fn pattern(&self) -> DigestionPattern {
+        let (regex, skip_suffix) = match self {
+            Enzyme::Trypsin => return DigestionPattern::trypsin(),
+            Enzyme::TrypsinP => return DigestionPattern::trypsin_norestriction(),
+            Enzyme::LysC => ("(K)", None),
+            Enzyme::LysN => ("(K)", None),
+            Enzyme::ArgC => ("(R)", Some('P')),
+            Enzyme::GluC => ("(E)", None),
+            Enzyme::AspN => ("(D)", None),
+            Enzyme::Chymotrypsin => ("([FWYL])", Some('P')),
+        };
+        DigestionPattern {
+            regex: Regex::new(regex).unwrap(),
+            skip_suffix,
+            skip_prefix: None,
+        }
+    }
+
+    pub fn digestion_end(&self) -> DigestionEnd {
+        match self {
+            Enzyme::LysN | Enzyme::AspN => DigestionEnd::NTerm,
+            _ => DigestionEnd::CTerm,
+        }
+    }
+}
+
+/// User defined protease, for the ones without a name in [`Enzyme`]
+/// (eg. proteinase K, thermolysin).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CustomEnzyme {
+    /// Regex matching the residue(s) the enzyme cleaves at.
+    pub regex: String,
+    /// Residue after the site that blocks cleavage (eg. 'P' for trypsin).
+    #[serde(default)]
+    pub skip_suffix: Option<char>,
+    /// Residue before the site that blocks cleavage.
+    #[serde(default)]
+    pub skip_prefix: Option<char>,
+    /// Side of the matched residue(s) that is cleaved.
+    #[serde(default)]
+    pub terminus: DigestionEnd,
+}
+
+/// Protease as written in the config, either a name or a custom rule.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EnzymeSpec {
+    Named(Enzyme),
+    Custom(CustomEnzyme),
+}
+
+impl Default for EnzymeSpec {
+    fn default() -> Self {
+        EnzymeSpec::Named(Enzyme::default())
+    }
+}
+
+impl EnzymeSpec {
+    pub fn pattern(&self) -> Result<DigestionPattern, regex::Error> {
+        match self {
+            EnzymeSpec::Named(enzyme) => Ok(enzyme.pattern()),
+            EnzymeSpec::Custom(custom) => Ok(DigestionPattern {
+                regex: Regex::new(&custom.regex)?,
+                skip_suffix: custom.skip_suffix,
+                skip_prefix: custom.skip_prefix,
+            }),
+        }
+    }
+
+    pub fn digestion_end(&self) -> DigestionEnd {
+        match self {
+            EnzymeSpec::Named(enzyme) => enzyme.digestion_end(),
+            EnzymeSpec::Custom(custom) => custom.terminus,
+        }
+    }
+
+    pub fn cleavage_rule(&self, max_missed_cleavages: usize) -> Result<CleavageRule, regex::Error> {
+        Ok(CleavageRule {
+            pattern: self.pattern()?,
+            digestion_end: self.digestion_end(),
+            max_missed_cleavages,
+        })
+    }
+}
+
+/// What to do with the initiator methionine of the proteins.
+///
+/// Most mature proteins lose Met1, so their N-terminal peptide is only seen
+/// without it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InitiatorMethionine {
+    /// Only generate the N-terminal peptides as encoded.
+    #[default]
+    Retain,
+    /// Only generate the N-terminal peptides with Met1 removed.
+    Cleave,
+    /// Generate both versions.
+    Both,
+}
+
+/// Non-enzymatic truncations of the peptides at the protein termini, like
+/// the ragged C-termini left by carboxypeptidases.
+///
+/// Only the peptides that start at the protein N-terminus (or right after an
+/// excised initiator methionine) or end at the protein C-terminus are
+/// clipped, the internal peptides are untouched.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TerminalClipping {
+    /// Maximum number of residues removed from the start of the protein
+    /// N-terminal peptides.
+    pub nterm: usize,
+    /// Maximum number of residues removed from the end of the protein
+    /// C-terminal peptides.
+    pub cterm: usize,
+}
+
+/// Cleavage rule of an enzyme added to a multi-enzyme digestion.
+#[derive(Debug, Clone)]
+pub struct CleavageRule {
+    pub pattern: DigestionPattern,
+    pub digestion_end: DigestionEnd,
+    pub max_missed_cleavages: usize,
+}
+
+impl Enzyme {
+    pub fn cleavage_rule(&self, max_missed_cleavages: usize) -> CleavageRule {
+        CleavageRule {
+            pattern: self.pattern(),
+            digestion_end: self.digestion_end(),
+            max_missed_cleavages,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DigestionParameters {
     pub min_length: usize,
@@ -44,75 +213,271 @@ pub struct DigestionParameters {
     pub pattern: DigestionPattern,
     pub digestion_end: DigestionEnd,
     pub max_missed_cleavages: usize,
+    /// Also generate peptides with only one enzymatic terminus.
+    pub semi_enzymatic: bool,
+    /// Other enzymes used in the same digestion (eg. trypsin + Lys-C), the
+    /// cleavage sites are the union of all enzymes and missed cleavages are
+    /// limited per enzyme.
+    pub additional_enzymes: Vec<CleavageRule>,
+    pub initiator_methionine: InitiatorMethionine,
+    pub terminal_clipping: TerminalClipping,
+    /// Monoisotopic mass range (Da) of the peptides kept, so out of range
+    /// peptides never reach the elution group conversion. The mass is the
+    /// one of the unmodified sequence, without the fixed modifications.
+    pub mass_range: Option<(f64, f64)>,
 }
 
-impl DigestionParameters {
+impl Default for DigestionParameters {
+    /// Fully tryptic peptides of 6 to 20 residues without missed cleavages.
+    fn default() -> Self {
+        DigestionParameters {
+            min_length: 6,
+            max_length: 20,
+            pattern: DigestionPattern::trypsin(),
+            digestion_end: DigestionEnd::CTerm,
+            max_missed_cleavages: 0,
+            semi_enzymatic: false,
+            additional_enzymes: Vec::new(),
+            initiator_methionine: InitiatorMethionine::Retain,
+            terminal_clipping: TerminalClipping::default(),
+            mass_range: None,
+        }
+    }
+}
+
+impl DigestionPattern {
     // This section is NEARLY copy-pasted from the Sage implementation.
     // Mike, you rock! sorry about that.
-    fn cleavage_sites(&self, sequence: &str) -> Vec<Range<usize>> {
-        let mut sites = Vec::new();
+    /// Positions strictly inside `sequence` where the pattern cleaves.
+    fn cut_positions(&self, sequence: &str, digestion_end: &DigestionEnd) -> Vec<usize> {
+        let mut cuts = Vec::new();
         let mut left = 0;
-        for mat in self.pattern.regex.find_iter(sequence) {
-            let right = match self.digestion_end {
+        for mat in self.regex.find_iter(sequence) {
+            let right = match digestion_end {
                 DigestionEnd::CTerm => mat.end(),
                 DigestionEnd::NTerm => mat.start(),
             };
 
             // Is this needed? Shouldnt I just use the regex?
             // Fun fact ... lookbehinds are not supported so I do need it ...
-            if let Some(skip) = self.pattern.skip_suffix {
+            if let Some(skip) = self.skip_suffix {
                 if right < sequence.len() && sequence[right..].starts_with(skip) {
                     continue;
                 }
             }
 
-            if let Some(skip) = self.pattern.skip_prefix {
+            if let Some(skip) = self.skip_prefix {
                 if left > 0 && sequence[left - 1..].ends_with(skip) {
                     continue;
                 }
             }
-            sites.push(left..right);
+            if right > 0 && right < sequence.len() {
+                cuts.push(right);
+            }
             left = right;
         }
-        if left < sequence.len() {
-            sites.push(left..sequence.len());
-        }
-        sites
+        cuts
+    }
+}
+
+impl DigestionParameters {
+    /// Cut positions and missed cleavage limit of every enzyme.
+    fn enzyme_cuts(&self, sequence: &str) -> Vec<(Vec<usize>, usize)> {
+        let mut out = vec![(
+            self.pattern.cut_positions(sequence, &self.digestion_end),
+            self.max_missed_cleavages,
+        )];
+        out.extend(self.additional_enzymes.iter().map(|rule| {
+            (
+                rule.pattern.cut_positions(sequence, &rule.digestion_end),
+                rule.max_missed_cleavages,
+            )
+        }));
+        out
+    }
+
+    /// Sorted boundaries between cleavage sites (of any enzyme), including
+    /// both ends of the sequence.
+    fn site_boundaries(seq_len: usize, cuts: &[(Vec<usize>, usize)]) -> Vec<usize> {
+        let mut bounds: Vec<usize> = cuts.iter().flat_map(|(x, _)| x.iter().copied()).collect();
+        bounds.push(0);
+        bounds.push(seq_len);
+        bounds.sort_unstable();
+        bounds.dedup();
+        bounds
+    }
+
+    pub fn cleavage_sites(&self, sequence: &str) -> Vec<Range<usize>> {
+        let cuts = self.enzyme_cuts(sequence);
+        Self::site_boundaries(sequence.len(), &cuts)
+            .windows(2)
+            .map(|w| w[0]..w[1])
+            .collect()
     }
 
     pub fn digest(&self, sequence: Arc<str>) -> Vec<DigestSlice> {
-        let sites = self.cleavage_sites(sequence.as_ref());
-        let num_sites = sites.len();
-        (0..sites.len())
-            .flat_map(|i| {
-                let start = sites[i].start;
-                let local_out: Vec<DigestSlice> = (0..(self.max_missed_cleavages + 1))
-                    .filter_map(|j| {
-                        if i + j > num_sites - 1 {
-                            return None;
-                        }
-                        let end = sites[i + j].end;
-                        let span = end - start;
+        let cuts = self.enzyme_cuts(sequence.as_ref());
+        let bounds = Self::site_boundaries(sequence.len(), &cuts);
+        let within_missed_cleavages = |start: usize, end: usize| {
+            cuts.iter().all(|(positions, max_missed)| {
+                let missed = positions.partition_point(|x| *x < end)
+                    - positions.partition_point(|x| *x <= start);
+                missed <= *max_missed
+            })
+        };
 
-                        if span < self.min_length || span > self.max_length {
-                            return None;
+        let excise_met = self.initiator_methionine != InitiatorMethionine::Retain
+            && sequence.starts_with('M');
+
+        let mut out = Vec::new();
+        for (i, start) in bounds.iter().enumerate() {
+            for end in bounds[(i + 1)..].iter() {
+                // Missed cleavages only grow with the end, so the first span
+                // over the limit ends the search from this start.
+                if !within_missed_cleavages(*start, *end) {
+                    break;
+                }
+                let mut spans = Vec::new();
+                if *start != 0
+                    || self.initiator_methionine != InitiatorMethionine::Cleave
+                    || !excise_met
+                {
+                    spans.extend(self.spans(*start, *end));
+                }
+                if *start == 0 && excise_met {
+                    // Without Met1 the protein starts at the second residue.
+                    spans.extend(self.spans(1, *end));
+                }
+                if !self.semi_enzymatic {
+                    for span in self.clipped_spans(*start, *end, sequence.len(), excise_met) {
+                        if !spans.contains(&span) {
+                            spans.push(span);
                         }
-                        Some(DigestSlice::new(
-                            sequence.clone(),
-                            start..end,
-                            DecoyMarking::Target,
-                        ))
-                    })
-                    .collect();
-                local_out
-            })
-            .collect()
+                    }
+                }
+                out.extend(
+                    spans
+                        .into_iter()
+                        // A zero minimum length would let empty spans through.
+                        .filter(|range| {
+                            !range.is_empty() && self.within_mass_range(&sequence[range.clone()])
+                        })
+                        .map(|range| {
+                            let missed_cleavages = bounds.partition_point(|x| *x < range.end)
+                                - bounds.partition_point(|x| *x <= range.start);
+                            let protein_nterm = range.start == 0 || (range.start == 1 && excise_met);
+                            DigestSlice::new(sequence.clone(), range, DecoyMarking::Target)
+                                .with_missed_cleavages(missed_cleavages)
+                                .with_protein_nterm(protein_nterm)
+                        }),
+                );
+            }
+        }
+        out
+    }
+
+    /// Spans of `start..end` with the protein termini clipped, within the
+    /// length limits.
+    ///
+    /// Semi-enzymatic digestion already generates all of them.
+    fn clipped_spans(
+        &self,
+        start: usize,
+        end: usize,
+        seq_len: usize,
+        excise_met: bool,
+    ) -> Vec<Range<usize>> {
+        let within_length =
+            |x: &Range<usize>| x.len() >= self.min_length && x.len() <= self.max_length;
+        let mut out = Vec::new();
+        if start == 0 {
+            // Clipping counts from the mature N-terminus when Met1 is removed.
+            let (first, last) = match (excise_met, self.initiator_methionine) {
+                (true, InitiatorMethionine::Cleave) => (2, self.terminal_clipping.nterm + 1),
+                (true, _) => (1, self.terminal_clipping.nterm + 1),
+                (false, _) => (1, self.terminal_clipping.nterm),
+            };
+            out.extend((first..=last).filter(|x| *x < end).map(|x| x..end));
+        }
+        if end == seq_len {
+            let starts = if start == 0 && excise_met {
+                match self.initiator_methionine {
+                    InitiatorMethionine::Cleave => vec![1],
+                    _ => vec![0, 1],
+                }
+            } else {
+                vec![start]
+            };
+            for new_start in starts {
+                out.extend(
+                    (1..=self.terminal_clipping.cterm)
+                        .filter(|x| new_start + x < end)
+                        .map(|x| new_start..(end - x)),
+                );
+            }
+        }
+        out.retain(within_length);
+        out
+    }
+
+    /// Peptides with residues of unknown mass are kept.
+    ///
+    /// Modifications (fixed ones included) are applied after digestion, so
+    /// the unmodified mass is compared, eg. carbamidomethylated peptides
+    /// are 57 Da per cysteine heavier than the mass checked here.
+    fn within_mass_range(&self, peptide: &str) -> bool {
+        match (self.mass_range, peptide_monoisotopic_mass(peptide)) {
+            (Some((min_mass, max_mass)), Some(mass)) => mass >= min_mass && mass <= max_mass,
+            _ => true,
+        }
+    }
+
+    /// All the peptide spans, within the length limits, generated from the
+    /// enzymatic span `start..end`.
+    ///
+    /// In semi-enzymatic mode this includes the spans that keep only the
+    /// enzymatic start (C-term ragged) or only the enzymatic end (N-term ragged).
+    fn spans(&self, start: usize, end: usize) -> Vec<Range<usize>> {
+        let mut out = Vec::new();
+        let span = end - start;
+        if span >= self.min_length && span <= self.max_length {
+            out.push(start..end);
+        }
+        if !self.semi_enzymatic || span <= self.min_length {
+            return out;
+        }
+
+        // Keep the enzymatic start, trim the end.
+        let last_end = (end - 1).min(start + self.max_length);
+        for new_end in (start + self.min_length)..=last_end {
+            out.push(start..new_end);
+        }
+
+        // Keep the enzymatic end, trim the start.
+        let first_start = (start + 1).max(end.saturating_sub(self.max_length));
+        for new_start in first_start..=(end - self.min_length) {
+            out.push(new_start..end);
+        }
+        out
     }
 
     pub fn digest_multiple(&self, sequences: &[Arc<str>]) -> Vec<DigestSlice> {
         sequences
-            .iter()
-            .flat_map(|seq| self.digest(seq.clone()))
+            .par_iter()
+            .flat_map_iter(|seq| self.digest(seq.clone()))
+            .collect()
+    }
+
+    /// Digests `(protein_id, sequence)` pairs, every peptide keeps the id of
+    /// the protein it comes from.
+    pub fn digest_proteins(&self, proteins: &[(u32, Arc<str>)]) -> Vec<DigestSlice> {
+        proteins
+            .par_iter()
+            .flat_map_iter(|(id, seq)| {
+                self.digest(seq.clone())
+                    .into_iter()
+                    .map(move |x| x.with_protein_ids(&[*id]))
+            })
             .collect()
     }
 }
@@ -126,9 +491,8 @@ mod tests {
         let params = DigestionParameters {
             min_length: 3,
             max_length: 7,
-            pattern: DigestionPattern::trypsin(),
-            digestion_end: DigestionEnd::CTerm,
             max_missed_cleavages: 1,
+            ..Default::default()
         };
         let seq = "PEPTIKDEPINK";
         let sites = params.cleavage_sites(seq);
@@ -147,9 +511,7 @@ mod tests {
         let params = DigestionParameters {
             min_length: 3,
             max_length: 7,
-            pattern: DigestionPattern::trypsin(),
-            digestion_end: DigestionEnd::CTerm,
-            max_missed_cleavages: 0,
+            ..Default::default()
         };
         let seq: Arc<str> = "PEPTIKDEPINK".into();
         let digests = params.digest(seq);
@@ -159,14 +521,63 @@ mod tests {
         assert_eq!(Into::<String>::into(digests[1].clone()), "DEPINK");
     }
 
+    #[test]
+    fn test_digest_missed_cleavages() {
+        let params = DigestionParameters {
+            min_length: 3,
+            max_missed_cleavages: 1,
+            ..Default::default()
+        };
+        let seq: Arc<str> = "PEPTIKDEPINK".into();
+        let missed: Vec<Option<usize>> = params
+            .digest(seq)
+            .into_iter()
+            .map(|x| x.missed_cleavages)
+            .collect();
+        assert_eq!(missed, vec![Some(0), Some(1), Some(0)]);
+    }
+
+    #[test]
+    fn test_digest_semi_enzymatic() {
+        let params = DigestionParameters {
+            min_length: 5,
+            max_length: 7,
+            semi_enzymatic: true,
+            ..Default::default()
+        };
+        let seq: Arc<str> = "PEPTIKDEPINK".into();
+        let digests: Vec<String> = params.digest(seq).into_iter().map(|x| x.into()).collect();
+        let expected = vec![
+            "PEPTIK", "PEPTI", "EPTIK", "DEPINK", "DEPIN", "EPINK",
+        ];
+        assert_eq!(digests, expected);
+    }
+
+    #[test]
+    fn test_enzyme_asp_n() {
+        let enzyme = Enzyme::AspN;
+        let params = DigestionParameters {
+            min_length: 3,
+            max_length: 10,
+            pattern: enzyme.pattern(),
+            digestion_end: enzyme.digestion_end(),
+            ..Default::default()
+        };
+        let seq: Arc<str> = "PEPTIDEPINK".into();
+        let digests = params.digest(seq);
+        assert_eq!(digests.len(), 2, "Expected 2 digests, got: {:?}", digests);
+        assert_eq!(Into::<String>::into(digests[0].clone()), "PEPTI");
+        assert_eq!(Into::<String>::into(digests[1].clone()), "DEPINK");
+    }
+
     #[test]
     fn test_digest_nterm() {
         let params = DigestionParameters {
             min_length: 3,
             max_length: 7,
-            pattern: DigestionPattern::trypsin(),
             digestion_end: DigestionEnd::NTerm,
             max_missed_cleavages: 1,
+            ..Default::default()
         };
         let seq: Arc<str> = "PEPTIKDEPINK".into();
         let digests = params.digest(seq);
@@ -176,4 +587,106 @@ mod tests {
         assert_eq!(Into::<String>::into(digests[1].clone()), "KDEPIN");
         assert_eq!(Into::<String>::into(digests[2].clone()), "KDEPINK");
     }
+
+    #[test]
+    fn test_digest_multi_enzyme() {
+        let params = DigestionParameters {
+            min_length: 3,
+            max_length: 10,
+            additional_enzymes: vec![Enzyme::AspN.cleavage_rule(1)],
+            ..Default::default()
+        };
+        let seq: Arc<str> = "PEPTIDEKAINR".into();
+        let digests: Vec<String> = params.digest(seq).into_iter().map(|x| x.into()).collect();
+        // The Asp-N site can be missed once, the tryptic one never.
+        assert_eq!(digests, vec!["PEPTI", "PEPTIDEK", "DEK", "AINR"]);
+    }
+
+    #[test]
+    fn test_digest_initiator_methionine() {
+        let mut params = DigestionParameters {
+            min_length: 3,
+            max_length: 7,
+            initiator_methionine: InitiatorMethionine::Both,
+            ..Default::default()
+        };
+        let seq: Arc<str> = "MPEPTIKDEPINK".into();
+        let digests: Vec<String> = params
+            .digest(seq.clone())
+            .into_iter()
+            .map(|x| x.into())
+            .collect();
+        assert_eq!(digests, vec!["MPEPTIK", "PEPTIK", "DEPINK"]);
+        let nterm: Vec<bool> = params
+            .digest(seq.clone())
+            .iter()
+            .map(|x| x.protein_nterm)
+            .collect();
+        assert_eq!(nterm, vec![true, true, false]);
+
+        params.initiator_methionine = InitiatorMethionine::Cleave;
+        let digests: Vec<String> = params.digest(seq).into_iter().map(|x| x.into()).collect();
+        assert_eq!(digests, vec!["PEPTIK", "DEPINK"]);
+    }
+
+    #[test]
+    fn test_custom_enzyme() {
+        let named: EnzymeSpec = serde_json::from_str(r#""lys-c""#).unwrap();
+        assert_eq!(named, EnzymeSpec::Named(Enzyme::LysC));
+
+        let spec: EnzymeSpec =
+            serde_json::from_str(r#"{"regex": "[FWY]", "skip_suffix": "P"}"#).unwrap();
+        let params = DigestionParameters {
+            min_length: 2,
+            max_length: 10,
+            pattern: spec.pattern().unwrap(),
+            digestion_end: spec.digestion_end(),
+            ..Default::default()
+        };
+        let sites = params.cleavage_sites("AAAFGGGWPGGYAA");
+        assert_eq!(sites, vec![0..4, 4..12, 12..14]);
+    }
+
+    #[test]
+    fn test_digest_mass_range() {
+        let params = DigestionParameters {
+            min_length: 3,
+            max_length: 10,
+            mass_range: Some((700.0, 800.0)),
+            ..Default::default()
+        };
+        // PEPTIK is ~683.4 Da, WEPTIDEK ~1016.5 Da and DEPINK ~714.4 Da
+        let seq: Arc<str> = "PEPTIKWEPTIDEKDEPINK".into();
+        let digests: Vec<String> = params.digest(seq).into_iter().map(|x| x.into()).collect();
+        assert_eq!(digests, vec!["DEPINK"]);
+    }
+
+    #[test]
+    fn test_digest_terminal_clipping() {
+        let mut params = DigestionParameters {
+            min_length: 4,
+            max_length: 10,
+            terminal_clipping: TerminalClipping { nterm: 2, cterm: 2 },
+            ..Default::default()
+        };
+        let seq: Arc<str> = "MPEPTIKLLDEPINAG".into();
+        let digests: Vec<String> = params
+            .digest(seq.clone())
+            .into_iter()
+            .map(|x| x.into())
+            .collect();
+        assert_eq!(
+            digests,
+            vec!["MPEPTIK", "PEPTIK", "EPTIK", "LLDEPINAG", "LLDEPINA", "LLDEPIN"]
+        );
+
+        // Clipping starts after the excised Met, whose own span is not repeated.
+        params.initiator_methionine = InitiatorMethionine::Both;
+        params.terminal_clipping.cterm = 0;
+        let digests: Vec<String> = params.digest(seq).into_iter().map(|x| x.into()).collect();
+        assert_eq!(
+            digests,
+            vec!["MPEPTIK", "PEPTIK", "EPTIK", "PTIK", "LLDEPINAG"]
+        );
+    }
 }
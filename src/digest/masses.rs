@@ -0,0 +1,54 @@
+/// Mass of water, added once to the residue masses of a peptide.
+pub const WATER_MASS: f64 = 18.0105646863;
+
+/// Monoisotopic mass of an (unmodified) amino acid residue.
+pub fn residue_mass(residue: u8) -> Option<f64> {
+    let mass = match residue {
+        b'G' => 57.021464,
+        b'A' => 71.037114,
+        b'S' => 87.032028,
+        b'P' => 97.052764,
+        b'V' => 99.068414,
+        b'T' => 101.047679,
+        b'C' => 103.009185,
+        b'L' => 113.084064,
+        b'I' => 113.084064,
+        b'N' => 114.042927,
+        b'D' => 115.026943,
+        b'Q' => 128.058578,
+        b'K' => 128.094963,
+        b'E' => 129.042593,
+        b'M' => 131.040485,
+        b'H' => 137.058912,
+        b'F' => 147.068414,
+        b'U' => 150.953633,
+        b'R' => 156.101111,
+        b'Y' => 163.063329,
+        b'W' => 186.079313,
+        b'O' => 237.147727,
+        _ => return None,
+    };
+    Some(mass)
+}
+
+/// Monoisotopic neutral mass of an unmodified peptide, None if it has
+/// ambiguous residues (eg. X, B, Z).
+pub fn peptide_monoisotopic_mass(sequence: &str) -> Option<f64> {
+    sequence
+        .bytes()
+        .map(residue_mass)
+        .sum::<Option<f64>>()
+        .map(|x| x + WATER_MASS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peptide_mass() {
+        let mass = peptide_monoisotopic_mass("PEPTIDE").unwrap();
+        assert!((mass - 799.359964).abs() < 1e-4, "Got {}", mass);
+        assert_eq!(peptide_monoisotopic_mass("PEPXIDE"), None);
+    }
+}
@@ -1 +1,5 @@
+pub mod cache;
+pub mod decoys;
 pub mod digestion;
+pub mod masses;
+pub mod prioritization;
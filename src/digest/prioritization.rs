@@ -0,0 +1,147 @@
+use crate::models::{
+    DigestSlice,
+    PeptideUniqueness,
+};
+use crate::rt_prediction::AdditiveRtModel;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// Lengths that ionize and fragment well, longer or shorter peptides are
+/// penalized by how far out they are.
+const PREFERRED_LENGTH: (f64, f64) = (8.0, 16.0);
+
+/// Mean retention coefficient per residue (see [`AdditiveRtModel`]) of
+/// peptides that are neither lost in the void volume nor stuck on the column.
+const PREFERRED_MEAN_HYDROPHOBICITY: (f64, f64) = (0.5, 4.0);
+
+fn distance_outside(x: f64, (low, high): (f64, f64)) -> f64 {
+    (low - x).max(x - high).max(0.0)
+}
+
+/// Heuristic detectability of a peptide, higher is better.
+///
+/// Prefers proteotypic peptides without missed cleavages, of a length and
+/// hydrophobicity that usually behave well, and without methionines (which
+/// split the signal between oxidation states).
+pub fn detectability_score(digest: &DigestSlice, model: &AdditiveRtModel) -> f64 {
+    let sequence = digest.unmarked_sequence();
+    let mut score = 0.0;
+    if digest.uniqueness == Some(PeptideUniqueness::Proteotypic) {
+        score += 2.0;
+    }
+    score -= digest.missed_cleavages.unwrap_or(0) as f64;
+    let length = sequence.len() as f64;
+    score -= 0.25 * distance_outside(length, PREFERRED_LENGTH);
+    let mean_hydrophobicity = model.hydrophobicity(sequence) / length.max(1.0);
+    score -= 0.5 * distance_outside(mean_hydrophobicity, PREFERRED_MEAN_HYDROPHOBICITY);
+    score -= 0.5 * sequence.matches('M').count() as f64;
+    score
+}
+
+/// [`detectability_score`] with the default retention coefficients.
+pub fn default_detectability_score(digest: &DigestSlice) -> f64 {
+    static MODEL: OnceLock<AdditiveRtModel> = OnceLock::new();
+    detectability_score(digest, MODEL.get_or_init(AdditiveRtModel::default))
+}
+
+/// Sorts the peptides from the most to the least detectable, ties keep
+/// their order.
+pub fn sort_by_detectability(digests: &mut [DigestSlice]) {
+    let mut scored: Vec<(f64, DigestSlice)> = digests
+        .iter()
+        .map(|x| (default_detectability_score(x), x.clone()))
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    for (slot, (_, digest)) in digests.iter_mut().zip(scored) {
+        *slot = digest;
+    }
+}
+
+/// Keeps the `max_per_protein` most detectable peptides of every protein.
+///
+/// A peptide is kept if it makes the cut for any of its proteins, so shared
+/// peptides can take a slot in several of them. The order of the kept
+/// peptides does not change.
+pub fn limit_peptides_per_protein(
+    digests: Vec<DigestSlice>,
+    max_per_protein: usize,
+) -> Vec<DigestSlice> {
+    let model = AdditiveRtModel::default();
+    let scores: Vec<f64> = digests
+        .iter()
+        .map(|x| detectability_score(x, &model))
+        .collect();
+    let mut by_protein: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, digest) in digests.iter().enumerate() {
+        for protein in digest.protein_ids() {
+            by_protein.entry(*protein).or_default().push(i);
+        }
+    }
+
+    // Peptides without proteins are not limited.
+    let mut keep: Vec<bool> = digests
+        .iter()
+        .map(|x| x.protein_ids().is_empty())
+        .collect();
+    for members in by_protein.values_mut() {
+        // Stable, so ties go to the first peptides of the protein.
+        members.sort_by(|a, b| scores[*b].total_cmp(&scores[*a]));
+        for &i in members.iter().take(max_per_protein) {
+            keep[i] = true;
+        }
+    }
+    digests
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(digest, keep)| keep.then_some(digest))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::DecoyMarking;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_limit_peptides_per_protein() {
+        let digest = |seq: &str, proteins: &[u32]| {
+            let seq: Arc<str> = seq.into();
+            let mut digest = DigestSlice::new(seq.clone(), 0..seq.len(), DecoyMarking::Target)
+                .with_protein_ids(proteins)
+                .with_missed_cleavages(0);
+            digest.uniqueness = PeptideUniqueness::from_num_proteins(proteins.len());
+            digest
+        };
+        let digests = vec![
+            // Too short.
+            digest("AGK", &[0]),
+            digest("LSVEAPGTK", &[0]),
+            // Shared, with two methionines.
+            digest("MEMPTIDEAK", &[0, 1]),
+            digest("VTSEGLIPAR", &[0]),
+            digest("GGGGK", &[1]),
+        ];
+        let kept: Vec<String> = limit_peptides_per_protein(digests, 2)
+            .into_iter()
+            .map(String::from)
+            .collect();
+        // Protein 0 keeps its two best peptides, the shared one makes it
+        // through protein 1.
+        assert_eq!(kept, vec!["LSVEAPGTK", "MEMPTIDEAK", "VTSEGLIPAR", "GGGGK"]);
+    }
+
+    #[test]
+    fn test_sort_by_detectability() {
+        let mut digests: Vec<DigestSlice> = ["AGK", "MEMPTIDEAK", "LSVEAPGTK"]
+            .iter()
+            .map(|seq| {
+                let seq: Arc<str> = (*seq).into();
+                DigestSlice::new(seq.clone(), 0..seq.len(), DecoyMarking::Target)
+            })
+            .collect();
+        sort_by_detectability(&mut digests);
+        let sorted: Vec<String> = digests.into_iter().map(String::from).collect();
+        assert_eq!(sorted, vec!["LSVEAPGTK", "MEMPTIDEAK", "AGK"]);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::fragment_mass::fragment_mass_builder::AnnotationParseError;
 use serde_json;
 use timsquery::TimsqueryError;
 use timsrust::TimsRustError;
@@ -8,6 +9,11 @@ pub enum TimsSeekError {
     Timsquery(TimsqueryError),
     Io(std::io::Error),
     ParseError { msg: String },
+    UnsupportedInput { msg: String },
+    /// Invalid spectral library entry, with its (1-based) line when known.
+    LibraryReadingError { line: Option<usize>, msg: String },
+    SearchError { msg: String },
+    AnnotationParse(AnnotationParseError),
 }
 
 impl std::fmt::Display for TimsSeekError {
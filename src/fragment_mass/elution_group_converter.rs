@@ -1,11 +1,25 @@
 use super::fragment_mass_builder::FragmentMassBuilder;
 use crate::fragment_mass::fragment_mass_builder::SafePosition;
-use crate::isotopes::peptide_isotopes;
-use crate::models::DigestSlice;
-use log::{
-    error,
-    warn,
+use crate::fragment_mass::modifications::{
+    apply_fixed_modifications,
+    FixedModification,
+    VariableModifications,
+};
+use crate::intensity_prediction::FragmentIntensityPredictor;
+use crate::isotopes::{
+    adduct_isotope_mzs,
+    peptide_isotope_envelope,
+    ElementCounts,
+    MINUS_ONE_ISOTOPE_INTENSITY,
+    PROTON_MASS,
+};
+use crate::mobility_prediction::{
+    MobilityPredictor,
+    SimpleMobilityModel,
 };
+use crate::models::DigestSlice;
+use crate::rt_prediction::RtPredictor;
+use log::warn;
 use rayon::prelude::*;
 use rustyms::error::{
     Context,
@@ -17,8 +31,17 @@ use rustyms::{
     MolecularFormula,
     MultiChemical,
 };
+use serde::{
+    Deserialize,
+    Serialize,
+};
 use std::collections::HashMap;
 use std::ops::RangeInclusive;
+use std::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+};
+use std::sync::Arc;
 use timsquery::models::elution_group::ElutionGroup;
 
 /// Super simple 1/k0 prediction.
@@ -49,6 +72,39 @@ pub fn supersimpleprediction(mz: f64, charge: i32) -> f64 {
         + (1.417e-01 * charge as f64)
 }
 
+/// Ion carrying the charge of the precursors.
+///
+/// Fragments are built protonated and moved by the difference between the
+/// adduct and a proton, as if every fragment kept one adduct per charge. That
+/// is only an approximation for anything but protons.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Adduct {
+    #[default]
+    Proton,
+    Sodium,
+    Potassium,
+    Ammonium,
+    /// Negative mode, `[M - zH]z-`. Charges are still reported unsigned.
+    Deprotonation,
+    /// Mass of the charge carrier, negative if it is removed.
+    Custom(f64),
+}
+
+impl Adduct {
+    /// Mass added to the neutral peptide per charge.
+    pub fn carrier_mass(&self) -> f64 {
+        match self {
+            Adduct::Proton => PROTON_MASS,
+            Adduct::Sodium => 22.989218,
+            Adduct::Potassium => 38.963158,
+            Adduct::Ammonium => 18.033823,
+            Adduct::Deprotonation => -PROTON_MASS,
+            Adduct::Custom(mass) => *mass,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct SequenceToElutionGroupConverter {
     pub precursor_charge_range: RangeInclusive<u8>,
@@ -57,6 +113,54 @@ pub struct SequenceToElutionGroupConverter {
     pub min_precursor_mz: f64,
     pub max_fragment_mz: f64,
     pub min_fragment_mz: f64,
+    /// Applied to every sequence before computing its masses.
+    pub fixed_modifications: Vec<FixedModification>,
+    /// Every digest is converted once per peptidoform.
+    pub variable_modifications: VariableModifications,
+    /// Also search the protein N-terminal peptides with an acetylated
+    /// N-terminus.
+    pub protein_nterm_acetylation: bool,
+    /// Fragments closer than this (ppm) are queried once, as the most
+    /// intense of them with their summed expected intensity, so the shared
+    /// signal is not counted twice. 0 (the default) keeps them all.
+    pub fragment_merge_ppm: f64,
+    /// Fragments in any of these m/z ranges are not queried (eg. the reporter
+    /// ions of isobaric labels).
+    pub excluded_fragment_mz_ranges: Vec<(f64, f64)>,
+    /// Only query fragments up to the precursor charge minus one (at least
+    /// 1), on top of the max charge of the fragment builder.
+    pub cap_fragment_charge: bool,
+    /// Only the most intense (by expected intensity) fragments are queried,
+    /// diagnostic ions are kept on top of them. 0 keeps them all.
+    pub max_fragments: usize,
+    /// Precursors with fewer fragments left (after the m/z filters and the
+    /// merging, not counting diagnostic ions) are not queried. 0 keeps them
+    /// all.
+    pub min_fragments: usize,
+    /// Precursors skipped for having fewer than `min_fragments` fragments.
+    pub num_skipped_few_fragments: AtomicUsize,
+    /// Precursor isotopologues queried from the monoisotopic one up.
+    pub num_precursor_isotopes: usize,
+    /// Also query the -1 isotope peak (first, with a tiny expected
+    /// intensity), to detect interferences.
+    pub minus_one_isotope: bool,
+    /// Weights of the precursor isotope traces from the monoisotopic one up
+    /// (1 for the ones without), multiplying their expected intensities in
+    /// the MS1 cosine. Traces weighted 0 are not queried, so they are not in
+    /// the summed MS1 intensity either. The monoisotopic trace is always
+    /// queried.
+    pub precursor_isotope_weights: Vec<f32>,
+    /// Isotope traces expected below this fraction of the most intense one
+    /// are not queried (eg. the negligible M+2 of short peptides).
+    pub min_isotope_relative_intensity: f32,
+    pub adduct: Adduct,
+    /// Sets the 1/K0 of the elution groups.
+    pub mobility_predictor: Arc<dyn MobilityPredictor>,
+    /// Sets the RT of the elution groups, left at 0 without one.
+    pub rt_predictor: Option<Arc<dyn RtPredictor>>,
+    /// Sets the expected fragment intensities of the precursors it has a
+    /// prediction for, the fragments it predicts nothing for get 0.
+    pub intensity_predictor: Option<Arc<dyn FragmentIntensityPredictor>>,
 }
 
 impl Default for SequenceToElutionGroupConverter {
@@ -68,32 +172,86 @@ impl Default for SequenceToElutionGroupConverter {
             min_precursor_mz: 400.,
             max_fragment_mz: 2000.,
             min_fragment_mz: 200.,
+            fixed_modifications: Vec::new(),
+            variable_modifications: VariableModifications::default(),
+            protein_nterm_acetylation: false,
+            fragment_merge_ppm: 0.0,
+            excluded_fragment_mz_ranges: Vec::new(),
+            cap_fragment_charge: false,
+            max_fragments: 0,
+            min_fragments: 0,
+            num_skipped_few_fragments: AtomicUsize::new(0),
+            num_precursor_isotopes: 3,
+            minus_one_isotope: true,
+            precursor_isotope_weights: Vec::new(),
+            min_isotope_relative_intensity: 0.0,
+            adduct: Adduct::Proton,
+            mobility_predictor: Arc::new(SimpleMobilityModel::default()),
+            rt_predictor: None,
+            intensity_predictor: None,
         }
     }
 }
 
-const PROTON_MASS: f64 = 1.007276466;
+/// Merges the fragments within `ppm` of each other (sorting them by m/z),
+/// keeping the position and m/z of the most intense one and the summed
+/// expected intensity.
+fn merge_colliding_fragments(
+    mut fragments: Vec<(SafePosition, f64, f32)>,
+    ppm: f64,
+) -> Vec<(SafePosition, f64, f32)> {
+    fragments.sort_by(|a, b| a.1.total_cmp(&b.1));
+    let mut out: Vec<(SafePosition, f64, f32)> = Vec::with_capacity(fragments.len());
+    for frag in fragments {
+        match out.last_mut() {
+            Some(last) if frag.1 - last.1 <= last.1 * ppm * 1e-6 => {
+                let intensity = last.2 + frag.2;
+                if frag.2 > last.2 {
+                    (last.0, last.1) = (frag.0, frag.1);
+                }
+                last.2 = intensity;
+            }
+            _ => out.push(frag),
+        }
+    }
+    out
+}
 
-// TODO: Find right way ...
-const NEUTRON_MASS: f64 = 1.00;
+/// Keeps the `max_fragments` fragments with the highest expected intensity
+/// (the lowest m/z first on ties) and all the diagnostic ions.
+fn keep_most_intense_fragments(
+    fragments: Vec<(SafePosition, f64, f32)>,
+    max_fragments: usize,
+) -> Vec<(SafePosition, f64, f32)> {
+    let (mut out, mut sequence_ions): (Vec<_>, Vec<_>) =
+        fragments.into_iter().partition(|x| x.0.is_diagnostic());
+    sequence_ions.sort_by(|a, b| b.2.total_cmp(&a.2).then(a.1.total_cmp(&b.1)));
+    sequence_ions.truncate(max_fragments);
+    out.extend(sequence_ions);
+    out
+}
 
-fn count_carbon_sulphur(form: &MolecularFormula) -> (u16, u16) {
-    let mut ncarbon = 0;
-    let mut nsulphur = 0;
+fn count_elements(form: &MolecularFormula) -> ElementCounts {
+    let mut counts = ElementCounts::default();
 
     for (elem, count, _extras) in form.elements() {
-        match (elem, count) {
-            (&rustyms::Element::C, Some(cnt)) => {
-                ncarbon += *cnt;
-            }
-            (&rustyms::Element::S, Some(cnt)) => {
-                nsulphur += *cnt;
-            }
+        let Some(cnt) = count else {
+            continue;
+        };
+        match elem {
+            rustyms::Element::C => counts.carbon += *cnt,
+            rustyms::Element::H => counts.hydrogen += *cnt,
+            rustyms::Element::N => counts.nitrogen += *cnt,
+            rustyms::Element::O => counts.oxygen += *cnt,
+            rustyms::Element::S => counts.sulfur += *cnt,
+            rustyms::Element::Se => counts.selenium += *cnt,
+            rustyms::Element::Cl => counts.chlorine += *cnt,
+            rustyms::Element::Br => counts.bromine += *cnt,
             _ => {}
         }
     }
 
-    (ncarbon, nsulphur)
+    counts
 }
 
 impl SequenceToElutionGroupConverter {
@@ -102,7 +260,59 @@ impl SequenceToElutionGroupConverter {
         sequence: &str,
         id: u64,
     ) -> Result<(Vec<ElutionGroup<SafePosition>>, Vec<u8>), CustomError> {
-        let mut peptide = LinearPeptide::pro_forma(sequence)?;
+        self.convert_sequence_with_charges(sequence, id, self.precursor_charge_range.clone())
+    }
+
+    /// Number of precursors skipped for having too few fragments since the
+    /// last call.
+    pub fn take_num_skipped_few_fragments(&self) -> usize {
+        self.num_skipped_few_fragments.swap(0, Ordering::Relaxed)
+    }
+
+    /// Weight of an isotope trace, by its index from the monoisotopic one.
+    fn isotope_weight(&self, isotope: usize) -> f32 {
+        self.precursor_isotope_weights
+            .get(isotope)
+            .copied()
+            .unwrap_or(1.0)
+    }
+
+    /// Indices (from the monoisotopic one) of the isotope traces to query,
+    /// given their expected intensities.
+    fn kept_isotopes(&self, envelope: &[f32]) -> Vec<usize> {
+        let max = envelope.iter().cloned().fold(0.0f32, f32::max);
+        (0..envelope.len())
+            .filter(|i| {
+                *i == 0
+                    || (self.isotope_weight(*i) > 0.0
+                        && envelope[*i] >= self.min_isotope_relative_intensity * max)
+            })
+            .collect()
+    }
+
+    /// Whether the RT predictor has a prediction for the sequence, the
+    /// queries of the other sequences are at RT 0.
+    pub fn has_predicted_rt(&self, sequence: &str) -> bool {
+        self.rt_predictor
+            .as_ref()
+            .is_some_and(|x| x.predict(sequence).is_some())
+    }
+
+    /// Same as [`Self::convert_sequence`] but for the given charges instead
+    /// of the ones of the converter.
+    pub fn convert_sequence_with_charges(
+        &self,
+        sequence: &str,
+        id: u64,
+        charges: RangeInclusive<u8>,
+    ) -> Result<(Vec<ElutionGroup<SafePosition>>, Vec<u8>), CustomError> {
+        let rt_seconds = self
+            .rt_predictor
+            .as_ref()
+            .and_then(|x| x.predict(sequence))
+            .unwrap_or(0.0);
+        let proforma = apply_fixed_modifications(sequence, &self.fixed_modifications);
+        let mut peptide = LinearPeptide::pro_forma(&proforma)?;
         let pep_formulas = peptide.formulas();
         let (pep_mono_mass, pep_formula) = if pep_formulas.len() > 1 {
             return Err(CustomError::error(
@@ -115,22 +325,36 @@ impl SequenceToElutionGroupConverter {
             let mono_mass = pep_formulas[0].mass(rustyms::MassMode::Monoisotopic);
             (mono_mass.value, form)
         };
-        let (ncarbon, nsulphur) = count_carbon_sulphur(&pep_formula);
-        let pep_isotope = peptide_isotopes(ncarbon, nsulphur);
-        let mut expected_prec_inten = vec![1e-3f32; 4];
-
-        for (ii, isot) in pep_isotope.iter().enumerate() {
-            expected_prec_inten[1 + ii] = *isot
+        let envelope =
+            peptide_isotope_envelope(&count_elements(&pep_formula), self.num_precursor_isotopes);
+        let kept_isotopes = self.kept_isotopes(&envelope);
+        let mut expected_prec_inten = Vec::new();
+        if self.minus_one_isotope {
+            expected_prec_inten.push(MINUS_ONE_ISOTOPE_INTENSITY);
         }
+        expected_prec_inten.extend(
+            kept_isotopes
+                .iter()
+                .map(|i| envelope[*i] * self.isotope_weight(*i)),
+        );
 
         let mut out = Vec::new();
         let mut out_charges = Vec::new();
 
-        for charge in self.precursor_charge_range.clone() {
-            // Q: Why am I adding the charge here manually instead of using the calculator in the
-            // Formula?
-            let precursor_mz = (pep_mono_mass + (charge as f64 * PROTON_MASS)) / charge as f64;
-            let nmf = NEUTRON_MASS / (charge as f64);
+        let carrier_mass = self.adduct.carrier_mass();
+        for charge in charges {
+            let isotope_mzs = adduct_isotope_mzs(
+                pep_mono_mass,
+                charge,
+                carrier_mass,
+                self.num_precursor_isotopes + 1,
+            );
+            let precursor_mz = (pep_mono_mass + charge as f64 * carrier_mass) / charge as f64;
+            let mut precursor_mzs = Vec::with_capacity(expected_prec_inten.len());
+            if self.minus_one_isotope {
+                precursor_mzs.push(isotope_mzs[0]);
+            }
+            precursor_mzs.extend(kept_isotopes.iter().map(|i| isotope_mzs[i + 1]));
 
             if precursor_mz < self.min_precursor_mz || precursor_mz > self.max_precursor_mz {
                 continue;
@@ -141,14 +365,48 @@ impl SequenceToElutionGroupConverter {
             let mut fragment_mzs = self
                 .fragment_buildder
                 .fragment_mzs_from_linear_peptide(&peptide)?;
-            fragment_mzs
-                .retain(|(_pos, mz, _)| *mz > self.min_fragment_mz && *mz < self.max_fragment_mz);
+            if self.adduct != Adduct::Proton {
+                let shift = carrier_mass - PROTON_MASS;
+                fragment_mzs.iter_mut().for_each(|x| x.1 += shift);
+            }
+            if let Some(predicted) = self
+                .intensity_predictor
+                .as_ref()
+                .and_then(|x| x.predict(sequence, charge))
+            {
+                fragment_mzs
+                    .iter_mut()
+                    .for_each(|x| x.2 = predicted.get(&x.0).copied().unwrap_or(0.0));
+            }
+            let max_fragment_charge = if self.cap_fragment_charge {
+                charge.saturating_sub(1).max(1)
+            } else {
+                u8::MAX
+            };
+            fragment_mzs.retain(|(pos, mz, _)| {
+                pos.charge <= max_fragment_charge
+                    && *mz > self.min_fragment_mz
+                    && *mz < self.max_fragment_mz
+                    && !self
+                        .excluded_fragment_mz_ranges
+                        .iter()
+                        .any(|(low, high)| mz >= low && mz <= high)
+            });
+            if self.fragment_merge_ppm > 0.0 {
+                fragment_mzs = merge_colliding_fragments(fragment_mzs, self.fragment_merge_ppm);
+            }
+            if self.max_fragments > 0 {
+                fragment_mzs = keep_most_intense_fragments(fragment_mzs, self.max_fragments);
+            }
+            let num_fragments = fragment_mzs.iter().filter(|x| !x.0.is_diagnostic()).count();
+            if num_fragments < self.min_fragments {
+                self.num_skipped_few_fragments.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
 
-            let mobility = supersimpleprediction(precursor_mz, charge as i32);
-            let mut precursor_mzs = vec![precursor_mz; 4];
-            precursor_mzs[0] -= nmf;
-            precursor_mzs[2] += nmf;
-            precursor_mzs[3] += 2. * nmf;
+            let mobility = self
+                .mobility_predictor
+                .predict(sequence, precursor_mz, charge);
 
             let fragment_expect_inten =
                 HashMap::from_iter(fragment_mzs.iter().map(|(k, _, v)| (*k, *v)));
@@ -158,7 +416,7 @@ impl SequenceToElutionGroupConverter {
                 id,
                 precursor_mzs,
                 mobility: mobility as f32,
-                rt_seconds: 0.0f32,
+                rt_seconds,
                 // precursor_charge: charge,
                 fragment_mzs,
                 expected_fragment_intensity: Some(fragment_expect_inten),
@@ -170,77 +428,129 @@ impl SequenceToElutionGroupConverter {
         Ok((out, out_charges))
     }
 
-    pub fn convert_sequences<'a>(
+    /// Elution groups of every peptidoform (see [`VariableModifications`],
+    /// and the acetylated protein N-termini) of a digest, with the digest of
+    /// each.
+    fn convert_digest(
+        &self,
+        digest: &DigestSlice,
+        id: u64,
+    ) -> Vec<ConvertedQuery> {
+        let sequence: String = digest.clone().into();
+        let mut out = Vec::new();
+        let mut peptidoforms = self.variable_modifications.expand(&sequence);
+        if self.protein_nterm_acetylation && digest.protein_nterm {
+            let acetylated: Vec<String> = peptidoforms
+                .iter()
+                .filter(|x| !x.starts_with('['))
+                .map(|x| format!("[Acetyl]-{}", x))
+                .collect();
+            peptidoforms.extend(acetylated);
+        }
+        for (i, peptidoform) in peptidoforms.into_iter().enumerate() {
+            let (egs, charges) = match self.convert_sequence(&peptidoform, id) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("Error converting sequence {:?}, err: {:?}", peptidoform, e);
+                    continue;
+                }
+            };
+            let mut digest = digest.clone();
+            if i > 0 {
+                digest.peptidoform = Some(peptidoform.into());
+            }
+            out.extend(
+                egs.into_iter()
+                    .zip(charges)
+                    .map(|(eg, charge)| (digest.clone(), eg, charge)),
+            );
+        }
+        out
+    }
+
+    pub fn convert_sequences(
         &self,
-        sequences: &'a [DigestSlice],
+        sequences: &[DigestSlice],
     ) -> Result<
         (
-            Vec<&'a DigestSlice>,
+            Vec<DigestSlice>,
             Vec<ElutionGroup<SafePosition>>,
             Vec<u8>,
         ),
         CustomError,
     > {
-        let (seqs, (eg, crg)) = sequences
+        let converted: Vec<ConvertedQuery> = sequences
             .par_iter()
             .enumerate()
-            .flat_map(|(id, dig_slice)| {
-                let sequence: String = dig_slice.clone().into();
-                let tmp = self.convert_sequence(sequence.as_ref(), id as u64);
-                match tmp {
-                    Ok(x) => {
-                        let expanded_sequence: Vec<&DigestSlice> =
-                            (0..(x.0.len())).map(|_x| dig_slice).collect();
-                        Some((expanded_sequence, (x.0, x.1)))
-                    }
-                    Err(e) => {
-                        warn!("Error converting sequence {:?}, err: {:?}", sequence, e);
-                        None
-                    }
-                }
-            })
-            .flatten()
+            .flat_map_iter(|(id, dig_slice)| self.convert_digest(dig_slice, id as u64))
             .collect();
-        Ok((seqs, eg, crg))
+        Ok(unzip_converted(converted))
     }
 
-    pub fn convert_enumerated_sequences<'a>(
+    pub fn convert_enumerated_sequences(
         &self,
-        enum_sequences: &'a [(usize, DigestSlice)],
+        enum_sequences: &[(usize, DigestSlice)],
     ) -> Result<
         (
-            Vec<&'a DigestSlice>,
+            Vec<DigestSlice>,
             Vec<ElutionGroup<SafePosition>>,
             Vec<u8>,
         ),
         CustomError,
     > {
-        let (seqs, (eg, crg)) = enum_sequences
+        let converted: Vec<ConvertedQuery> = enum_sequences
             .par_iter()
-            .flat_map(|(i, s)| {
-                let sequence: String = s.clone().into();
-                let tmp = self.convert_sequence(sequence.as_ref(), *i as u64);
-                match tmp {
-                    Ok(x) => {
-                        let expanded_sequence: Vec<&DigestSlice> =
-                            (0..(x.0.len())).map(|_x| s).collect();
-                        Some((expanded_sequence, (x.0, x.1)))
-                    }
-                    Err(e) => {
-                        error!("Error converting sequence {:?}, err: {:?}", s, e);
-                        None
-                    }
-                }
-            })
-            .flatten()
+            .flat_map_iter(|(i, s)| self.convert_digest(s, *i as u64))
             .collect();
-        Ok((seqs, eg, crg))
+        Ok(unzip_converted(converted))
     }
 }
 
+/// Query of a (peptidoform of a) digest at one charge.
+type ConvertedQuery = (DigestSlice, ElutionGroup<SafePosition>, u8);
+
+fn unzip_converted(
+    converted: Vec<ConvertedQuery>,
+) -> (
+    Vec<DigestSlice>,
+    Vec<ElutionGroup<SafePosition>>,
+    Vec<u8>,
+) {
+    let mut digests = Vec::with_capacity(converted.len());
+    let mut egs = Vec::with_capacity(converted.len());
+    let mut charges = Vec::with_capacity(converted.len());
+    for (digest, eg, charge) in converted {
+        digests.push(digest);
+        egs.push(eg);
+        charges.push(charge);
+    }
+    (digests, egs, charges)
+}
+
+/// Queries (with their precursor charge) of a single ProForma sequence at
+/// the given charges, with all the filters and predictors of `settings`.
+///
+/// This is the entry point for converting one-off sequences (eg. from
+/// notebooks or other tools), so they get the same queries as a search with
+/// the same settings. The queries have id 0.
+pub fn queries_for_sequence(
+    sequence: &str,
+    charges: RangeInclusive<u8>,
+    settings: &SequenceToElutionGroupConverter,
+) -> Result<Vec<(ElutionGroup<SafePosition>, u8)>, CustomError> {
+    let (egs, charges) = settings.convert_sequence_with_charges(sequence, 0, charges)?;
+    Ok(egs.into_iter().zip(charges).collect())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fragment_mass::labeling::{
+        IsobaricLabel,
+        IsobaricLabeling,
+    };
+    use crate::fragment_mass::modifications::ModificationPosition;
+    use crate::isotopes::monoisotopic_precursor_mz;
     use crate::models::DecoyMarking;
     use rustyms::model::{
         Location,
@@ -276,11 +586,16 @@ mod tests {
                     glycan_fragmentation: None,
                 },
                 max_charge: Charge::new::<e>(2.0),
+                internal_fragments: None,
+                diagnostic_ions: None,
             },
             max_precursor_mz: 1000.,
             min_precursor_mz: 400.,
             max_fragment_mz: 2000.,
             min_fragment_mz: 200.,
+            fixed_modifications: Vec::new(),
+            variable_modifications: VariableModifications::default(),
+            ..Default::default()
         };
         let seq: Arc<str> = "PEPTIDEPINK".into();
         let range_use: std::ops::Range<usize> = 0..seq.len();
@@ -289,4 +604,319 @@ mod tests {
         let out = converter.convert_sequences(&seq_slc).unwrap();
         assert_eq!(out.0.len(), 2);
     }
+
+    #[test]
+    fn test_fragment_charge_cap() {
+        let max_charge = |egs: &[ElutionGroup<SafePosition>], i: usize| {
+            egs[i].fragment_mzs.keys().map(|x| x.charge).max().unwrap()
+        };
+        let mut converter = SequenceToElutionGroupConverter {
+            max_precursor_mz: 2000.,
+            ..Default::default()
+        };
+        converter.fragment_buildder.set_max_charge(3);
+        let (egs, charges) = converter.convert_sequence("PEPTIDEPINKPEPTIDEK", 0).unwrap();
+        assert_eq!(charges, vec![2, 3]);
+        assert_eq!(max_charge(&egs, 0), 2);
+        assert_eq!(max_charge(&egs, 1), 3);
+
+        converter.cap_fragment_charge = true;
+        let (egs, _) = converter.convert_sequence("PEPTIDEPINKPEPTIDEK", 0).unwrap();
+        assert_eq!(max_charge(&egs, 0), 1);
+        assert_eq!(max_charge(&egs, 1), 2);
+    }
+
+    #[test]
+    fn test_selenocysteine_isotopes() {
+        let converter = SequenceToElutionGroupConverter::default();
+        let (cys, _) = converter.convert_sequence("PEPCTIDEK", 0).unwrap();
+        let (sec, _) = converter.convert_sequence("PEPUTIDEK", 0).unwrap();
+        assert_eq!(cys.len(), sec.len());
+        assert!(!sec.is_empty());
+
+        let cys_inten = cys[0].expected_precursor_intensity.as_ref().unwrap();
+        let sec_inten = sec[0].expected_precursor_intensity.as_ref().unwrap();
+        // 82Se raises the M+2 peak well above the one of 34S.
+        assert!(sec_inten[3] > cys_inten[3] + 0.1, "{:?} {:?}", sec_inten, cys_inten);
+        // Se is ~48 Da heavier than S.
+        let shift = (sec[0].precursor_mzs[1] - cys[0].precursor_mzs[1]) * 2.0;
+        assert!((shift - 47.944).abs() < 0.01, "{}", shift);
+    }
+
+    #[test]
+    fn test_precursor_isotopes() {
+        let mut converter = SequenceToElutionGroupConverter::default();
+        let (egs, _) = converter.convert_sequence("PEPTIDEPINK", 0).unwrap();
+        let mzs = &egs[0].precursor_mzs;
+        let intensities = egs[0].expected_precursor_intensity.as_ref().unwrap();
+        assert_eq!(mzs.len(), 4);
+        assert_eq!(intensities.len(), 4);
+        // 13C spacing at charge 2, the monoisotopic peak second.
+        assert!((mzs[2] - mzs[1] - 0.5016774).abs() < 1e-6, "{:?}", mzs);
+        assert!((mzs[1] - mzs[0] - 0.5016774).abs() < 1e-6, "{:?}", mzs);
+        assert_eq!(intensities[1], 1.0);
+
+        converter.num_precursor_isotopes = 5;
+        let (egs, _) = converter.convert_sequence("PEPTIDEPINK", 0).unwrap();
+        assert_eq!(egs[0].precursor_mzs.len(), 6);
+        assert_eq!(egs[0].expected_precursor_intensity.as_ref().unwrap().len(), 6);
+        assert_eq!(&egs[0].precursor_mzs[..4], &mzs[..]);
+
+        converter.minus_one_isotope = false;
+        let (egs, _) = converter.convert_sequence("PEPTIDEPINK", 0).unwrap();
+        let intensities = egs[0].expected_precursor_intensity.as_ref().unwrap();
+        assert_eq!(egs[0].precursor_mzs.len(), 5);
+        assert_eq!(intensities.len(), 5);
+        assert_eq!(egs[0].precursor_mzs[0], mzs[1]);
+        assert_eq!(intensities[0], 1.0);
+    }
+
+    #[test]
+    fn test_adducts() {
+        let mut converter = SequenceToElutionGroupConverter {
+            precursor_charge_range: 2..=2,
+            ..Default::default()
+        };
+        let (protonated, _) = converter.convert_sequence("PEPTIDEPINK", 0).unwrap();
+        converter.adduct = Adduct::Sodium;
+        let (sodiated, _) = converter.convert_sequence("PEPTIDEPINK", 0).unwrap();
+        let shift = sodiated[0].precursor_mzs[1] - protonated[0].precursor_mzs[1];
+        assert!((shift - 21.981942).abs() < 1e-5, "{}", shift);
+        let y4 = SafePosition::from_str("y4").unwrap();
+        let y4_shift = sodiated[0].fragment_mzs[&y4] - protonated[0].fragment_mzs[&y4];
+        assert!((y4_shift - 21.981942).abs() < 1e-5, "{}", y4_shift);
+
+        converter.adduct = Adduct::Deprotonation;
+        let (negative, _) = converter.convert_sequence("PEPTIDEPINK", 0).unwrap();
+        let shift = protonated[0].precursor_mzs[1] - negative[0].precursor_mzs[1];
+        assert!((shift - 2.0 * PROTON_MASS).abs() < 1e-6, "{}", shift);
+    }
+
+    #[test]
+    fn test_queries_for_sequence() {
+        let converter = SequenceToElutionGroupConverter::default();
+        let queries = queries_for_sequence("PEPTIDEPINK", 2..=4, &converter).unwrap();
+        // The 4+ precursor is below the precursor m/z range.
+        let charges: Vec<u8> = queries.iter().map(|x| x.1).collect();
+        assert_eq!(charges, vec![2, 3]);
+        let (egs, _) = converter.convert_sequence("PEPTIDEPINK", 0).unwrap();
+        assert_eq!(queries[1].0.precursor_mzs, egs[1].precursor_mzs);
+        assert_eq!(queries[1].0.fragment_mzs, egs[1].fragment_mzs);
+
+        assert!(queries_for_sequence("PEPTIDE[K", 2..=2, &converter).is_err());
+    }
+
+    #[test]
+    fn test_max_fragments() {
+        let mut converter = SequenceToElutionGroupConverter {
+            precursor_charge_range: 2..=2,
+            ..Default::default()
+        };
+        let (all, _) = converter.convert_sequence("PEPTIDEPINK", 0).unwrap();
+        converter.max_fragments = 4;
+        let (top, _) = converter.convert_sequence("PEPTIDEPINK", 0).unwrap();
+        let top_intensities = top[0].expected_fragment_intensity.as_ref().unwrap();
+        assert_eq!(top[0].fragment_mzs.len(), 4);
+        assert_eq!(top_intensities.len(), 4);
+        let min_kept = top_intensities.values().cloned().fold(f32::MAX, f32::min);
+        let num_above = all[0]
+            .expected_fragment_intensity
+            .as_ref()
+            .unwrap()
+            .values()
+            .filter(|x| **x > min_kept)
+            .count();
+        assert!(num_above <= 4);
+
+        let fragment = |pos: &str, intensity: f32| {
+            (SafePosition::from_str(pos).unwrap(), 100.0, intensity)
+        };
+        let kept = keep_most_intense_fragments(
+            vec![
+                fragment("y1", 0.2),
+                fragment("y2", 1.0),
+                fragment("b2", 0.5),
+                fragment("y3", 0.1),
+            ],
+            2,
+        );
+        let kept: Vec<SafePosition> = kept.into_iter().map(|x| x.0).collect();
+        assert_eq!(
+            kept,
+            vec![
+                SafePosition::from_str("y2").unwrap(),
+                SafePosition::from_str("b2").unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_min_fragments() {
+        let mut converter = SequenceToElutionGroupConverter {
+            min_fragment_mz: 600.0,
+            ..Default::default()
+        };
+        let (egs, _) = converter.convert_sequence("PEPTIDEK", 0).unwrap();
+        let num_fragments = egs.iter().map(|x| x.fragment_mzs.len()).min().unwrap();
+
+        converter.min_fragments = num_fragments + 1;
+        let (skipped, _) = converter.convert_sequence("PEPTIDEK", 0).unwrap();
+        assert!(skipped.len() < egs.len());
+        assert_eq!(
+            converter.take_num_skipped_few_fragments(),
+            egs.len() - skipped.len()
+        );
+        assert_eq!(converter.take_num_skipped_few_fragments(), 0);
+    }
+
+    #[test]
+    fn test_isotope_weights() {
+        let mut converter = SequenceToElutionGroupConverter {
+            precursor_charge_range: 2..=2,
+            ..Default::default()
+        };
+        let (default, _) = converter.convert_sequence("PEPTIDEPINK", 0).unwrap();
+        let default_intensities = default[0].expected_precursor_intensity.clone().unwrap();
+
+        // Emphasize the monoisotopic trace and drop the M+2 one.
+        converter.precursor_isotope_weights = vec![2.0, 1.0, 0.0];
+        let (egs, _) = converter.convert_sequence("PEPTIDEPINK", 0).unwrap();
+        let intensities = egs[0].expected_precursor_intensity.as_ref().unwrap();
+        assert_eq!(egs[0].precursor_mzs, default[0].precursor_mzs[..3].to_vec());
+        assert_eq!(intensities.len(), 3);
+        assert_eq!(intensities[0], MINUS_ONE_ISOTOPE_INTENSITY);
+        assert_eq!(intensities[1], 2.0 * default_intensities[1]);
+        assert_eq!(intensities[2], default_intensities[2]);
+        assert_eq!(
+            monoisotopic_precursor_mz(&egs[0].precursor_mzs, Some(intensities)),
+            default[0].precursor_mzs[1]
+        );
+
+        // Only the traces expected above half of the most intense one.
+        converter.precursor_isotope_weights = Vec::new();
+        converter.minus_one_isotope = false;
+        converter.min_isotope_relative_intensity = 0.5;
+        let (egs, _) = converter.convert_sequence("PEPTIDEPINK", 0).unwrap();
+        let expected: Vec<f32> = default_intensities[1..]
+            .iter()
+            .cloned()
+            .filter(|x| *x >= 0.5)
+            .collect();
+        assert_eq!(egs[0].expected_precursor_intensity.as_ref().unwrap(), &expected);
+        assert_eq!(egs[0].precursor_mzs.len(), expected.len());
+        assert_eq!(egs[0].precursor_mzs[0], default[0].precursor_mzs[1]);
+    }
+
+    #[test]
+    fn test_predicted_intensities() {
+        use crate::intensity_prediction::TableIntensityPredictor;
+
+        let y3 = SafePosition::from_str("y3").unwrap();
+        let y4 = SafePosition::from_str("y4").unwrap();
+        let table = TableIntensityPredictor::new(HashMap::from([(
+            ("PEPTIDEPINK".to_string(), 2),
+            HashMap::from([(y3, 0.5), (y4, 1.0)]),
+        )]));
+        let converter = SequenceToElutionGroupConverter {
+            fragment_merge_ppm: 0.0,
+            intensity_predictor: Some(Arc::new(table)),
+            ..Default::default()
+        };
+        let (egs, charges) = converter.convert_sequence("PEPTIDEPINK", 0).unwrap();
+        assert_eq!(charges, vec![2, 3]);
+        let predicted = egs[0].expected_fragment_intensity.as_ref().unwrap();
+        assert_eq!(predicted[&y3], 0.5);
+        assert_eq!(predicted[&y4], 1.0);
+        assert!(predicted
+            .iter()
+            .filter(|(pos, _)| **pos != y3 && **pos != y4)
+            .all(|(_, x)| *x == 0.0));
+        // No prediction for 3+, the builder intensities are kept.
+        let fixed = egs[1].expected_fragment_intensity.as_ref().unwrap();
+        assert_eq!(fixed[&y3], 1.0);
+    }
+
+    #[test]
+    fn test_fixed_modifications() {
+        let mut converter = SequenceToElutionGroupConverter::default();
+        let (unmodified, _) = converter.convert_sequence("PEPCTIDEK", 0).unwrap();
+        converter.fixed_modifications = vec![FixedModification {
+            residue: 'C',
+            modification: "+57.021464".to_string(),
+            position: ModificationPosition::Anywhere,
+        }];
+        let (modified, _) = converter.convert_sequence("PEPCTIDEK", 0).unwrap();
+
+        let shift = (modified[0].precursor_mzs[1] - unmodified[0].precursor_mzs[1]) * 2.0;
+        assert!((shift - 57.021464).abs() < 1e-6, "{}", shift);
+        // Only the fragments with the cysteine move.
+        let y3 = SafePosition::from_str("y3").unwrap();
+        let y6 = SafePosition::from_str("y6").unwrap();
+        assert_eq!(modified[0].fragment_mzs[&y3], unmodified[0].fragment_mzs[&y3]);
+        let y6_shift = modified[0].fragment_mzs[&y6] - unmodified[0].fragment_mzs[&y6];
+        assert!((y6_shift - 57.021464).abs() < 1e-6, "{}", y6_shift);
+    }
+
+    #[test]
+    fn test_isobaric_labeling() {
+        let labeling = IsobaricLabeling {
+            label: IsobaricLabel::Tmt,
+            exclude_reporter_region: false,
+        };
+        let mut converter = SequenceToElutionGroupConverter::default();
+        let (unlabeled, _) = converter.convert_sequence("PEPTIDEK", 0).unwrap();
+        converter.fixed_modifications = labeling.fixed_modifications();
+        let (labeled, _) = converter.convert_sequence("PEPTIDEK", 0).unwrap();
+
+        // N-terminus and lysine, at charge 2.
+        let shift = labeled[0].precursor_mzs[1] - unlabeled[0].precursor_mzs[1];
+        assert!((shift - 229.162932).abs() < 1e-6, "{}", shift);
+        let y2 = SafePosition::from_str("y2").unwrap();
+        let y2_shift = labeled[0].fragment_mzs[&y2] - unlabeled[0].fragment_mzs[&y2];
+        assert!((y2_shift - 229.162932).abs() < 1e-6, "{}", y2_shift);
+
+        let y2_mz = labeled[0].fragment_mzs[&y2];
+        converter.excluded_fragment_mz_ranges = vec![(y2_mz - 0.5, y2_mz + 0.5)];
+        let (labeled, _) = converter.convert_sequence("PEPTIDEK", 0).unwrap();
+        assert!(!labeled[0].fragment_mzs.contains_key(&y2));
+    }
+
+    #[test]
+    fn test_merge_colliding_fragments() {
+        let pos = |x: &str| SafePosition::from_str(x).unwrap();
+        let fragments = vec![
+            (pos("y4"), 500.0, 0.2),
+            (pos("b3"), 300.0, 1.0),
+            (pos("b5^2"), 500.002, 0.5),
+        ];
+        let merged = merge_colliding_fragments(fragments.clone(), 10.0);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0], (pos("b3"), 300.0, 1.0));
+        assert_eq!(merged[1].0, pos("b5^2"));
+        assert_eq!(merged[1].1, 500.002);
+        assert!((merged[1].2 - 0.7).abs() < 1e-6);
+
+        // 4 ppm apart, kept apart with a tighter window.
+        assert_eq!(merge_colliding_fragments(fragments, 1.0).len(), 3);
+    }
+
+    #[test]
+    fn test_protein_nterm_acetylation() {
+        let seq: Arc<str> = "PEPTIDEKAAAA".into();
+        let nterm = DigestSlice::new(seq.clone(), 0..8, DecoyMarking::Target).with_protein_nterm(true);
+        let converter = SequenceToElutionGroupConverter {
+            precursor_charge_range: 2..=2,
+            protein_nterm_acetylation: true,
+            ..Default::default()
+        };
+        let converted = converter.convert_digest(&nterm, 0);
+        assert_eq!(converted.len(), 2);
+        assert_eq!(converted[0].0.peptidoform, None);
+        assert_eq!(converted[1].0.peptidoform(), "[Acetyl]-PEPTIDEK");
+        let shift = (converted[1].1.precursor_mzs[1] - converted[0].1.precursor_mzs[1]) * 2.0;
+        assert!((shift - 42.010565).abs() < 1e-5, "{}", shift);
+
+        let internal = DigestSlice::new(seq, 0..8, DecoyMarking::Target);
+        assert_eq!(converter.convert_digest(&internal, 0).len(), 1);
+    }
 }
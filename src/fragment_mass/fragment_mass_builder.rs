@@ -1,4 +1,9 @@
+use crate::digest::masses::{
+    residue_mass,
+    WATER_MASS,
+};
 use crate::errors::TimsSeekError;
+use crate::isotopes::PROTON_MASS;
 use rustyms::error::{
     Context,
     CustomError,
@@ -23,10 +28,68 @@ use serde::{
 };
 use std::fmt::Display;
 
+/// Neutral losses that can be part of a fragment annotation.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum NeutralLoss {
+    H2O,
+    NH3,
+    H3PO4,
+    HPO3,
+}
+
+impl NeutralLoss {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NeutralLoss::H2O => "H2O",
+            NeutralLoss::NH3 => "NH3",
+            NeutralLoss::H3PO4 => "H3PO4",
+            NeutralLoss::HPO3 => "HPO3",
+        }
+    }
+
+    pub fn from_token(token: &str) -> Option<Self> {
+        match token {
+            "H2O" => Some(NeutralLoss::H2O),
+            "NH3" => Some(NeutralLoss::NH3),
+            "H3PO4" => Some(NeutralLoss::H3PO4),
+            "HPO3" => Some(NeutralLoss::HPO3),
+            _ => None,
+        }
+    }
+
+    /// Monoisotopic mass of the loss.
+    pub fn mass(&self) -> f64 {
+        match self {
+            NeutralLoss::H2O => 18.010565,
+            NeutralLoss::NH3 => 17.026549,
+            NeutralLoss::H3PO4 => 97.976896,
+            NeutralLoss::HPO3 => 79.966331,
+        }
+    }
+}
+
+/// Error parsing a fragment annotation.
+///
+/// `token` is the part of the annotation that could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnotationParseError {
+    pub annotation: String,
+    pub token: String,
+    pub reason: &'static str,
+}
+
+/// Fragment label (ion series, position, loss and charge).
+///
+/// Internal ions use the `m` series, with `series_number` and `series_end`
+/// being the (1-based, inclusive) first and last residues they span.
+/// Immonium and diagnostic ions use the `i` series, numbered by the first
+/// residue that produces them. The precursor uses series id 0.
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct SafePosition {
     pub series_id: u8,
     pub series_number: u16,
+    pub series_end: u16,
+    pub neutral_loss: Option<NeutralLoss>,
     pub charge: u8,
 }
 
@@ -39,10 +102,16 @@ impl Serialize for SafePosition {
     }
 }
 
-/// Deserializes simple annotations for fragments.
+/// Deserializes annotations for fragments.
 ///
+/// Grammar: `<series><ordinal>[-<loss>][^<charge>]`, eg.
 /// b12^3 -> b12 charge 3
 /// b13 -> b13 charge 1
+/// y7-H2O^2 -> y7 losing water, charge 2
+/// m3:6 -> internal ion spanning residues 3 to 6
+/// p^2 -> precursor, charge 2
+///
+/// The legacy `b.12^3` form is also accepted.
 impl<'de> Deserialize<'de> for SafePosition {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -76,34 +145,71 @@ impl SafePosition {
         Ok(Self {
             series_id,
             series_number,
+            series_end: 0,
+            neutral_loss: None,
             charge,
         })
     }
 
+    pub fn is_internal(&self) -> bool {
+        self.series_id == b'm'
+    }
+
+    pub fn is_diagnostic(&self) -> bool {
+        self.series_id == b'i'
+    }
+
     pub fn from_str(s: &str) -> Result<Self, TimsSeekError> {
-        let (rest, charge) = match s.split_once('^') {
-            Some((rest, charge)) => {
-                let charge = charge.parse::<u8>()?;
-                (rest, charge)
-            }
+        let err = |token: &str, reason: &'static str| {
+            TimsSeekError::AnnotationParse(AnnotationParseError {
+                annotation: s.to_string(),
+                token: token.to_string(),
+                reason,
+            })
+        };
+
+        let (rest, charge) = match s.rsplit_once('^') {
+            Some((rest, charge)) => match charge.parse::<u8>() {
+                Ok(x) => (rest, x),
+                Err(_) => return Err(err(charge, "Invalid charge")),
+            },
             None => (s, 1),
         };
 
-        // "b12" split into "b" and "12"
-        let (series, ordinal) = match rest.split_at(1) {
-            (series_chunk, series_ordinal) => {
-                let series_id = series_chunk.chars().next().unwrap() as u8;
-                let series_ordinal = series_ordinal.parse::<u16>()?;
-                (series_id, series_ordinal)
-            }
-            _ => {
-                return Err(TimsSeekError::ParseError { msg: s.to_string() });
-            }
+        let series_char = match rest.chars().next() {
+            Some(x) if x.is_ascii_alphabetic() => x,
+            Some(x) => return Err(err(&x.to_string(), "Invalid ion series")),
+            None => return Err(err(s, "Empty annotation")),
+        };
+        let rest = &rest[1..];
+        let rest = rest.strip_prefix('.').unwrap_or(rest);
+
+        let (ordinal, neutral_loss) = match rest.split_once('-') {
+            Some((ordinal, loss)) => match NeutralLoss::from_token(loss) {
+                Some(x) => (ordinal, Some(x)),
+                None => return Err(err(loss, "Unknown neutral loss")),
+            },
+            None => (rest, None),
+        };
+
+        let parse_ordinal = |x: &str| match x.parse::<u16>() {
+            Ok(x) => Ok(x),
+            Err(_) => Err(err(x, "Invalid ordinal")),
+        };
+        let (series_id, series_number, series_end) = match series_char {
+            'p' if ordinal.is_empty() => (0, 0, 0),
+            'm' => match ordinal.split_once(':') {
+                Some((first, last)) => (b'm', parse_ordinal(first)?, parse_ordinal(last)?),
+                None => return Err(err(ordinal, "Internal ions need a start:end range")),
+            },
+            x => (x as u8, parse_ordinal(ordinal)?, 0),
         };
 
         Ok(Self {
-            series_id: series,
-            series_number: ordinal,
+            series_id,
+            series_number,
+            series_end,
+            neutral_loss,
             charge,
         })
     }
@@ -111,11 +217,60 @@ impl SafePosition {
 
 impl Display for SafePosition {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}.{}^{}",
-            self.series_id as char, self.series_number, self.charge
-        )
+        match self.series_id {
+            0 => write!(f, "p")?,
+            b'm' => write!(f, "m{}:{}", self.series_number, self.series_end)?,
+            x => write!(f, "{}{}", x as char, self.series_number)?,
+        }
+        if let Some(loss) = self.neutral_loss {
+            write!(f, "-{}", loss.as_str())?;
+        }
+        write!(f, "^{}", self.charge)
+    }
+}
+
+/// Internal fragments (b-type, singly charged) added to the queries of long
+/// peptides, where the terminal series alone leave few fragments in the
+/// scanned m/z range.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InternalFragments {
+    /// Only peptides with at least this many residues get internal fragments.
+    pub min_peptide_length: usize,
+    /// Residues spanned by the internal fragments.
+    pub min_length: usize,
+    pub max_length: usize,
+}
+
+impl Default for InternalFragments {
+    fn default() -> Self {
+        Self {
+            min_peptide_length: 20,
+            min_length: 2,
+            max_length: 6,
+        }
+    }
+}
+
+/// Immonium and PTM-diagnostic ions (singly charged) added to the queries.
+///
+/// Most immonium ions fall below the usual scan range, they are only queried
+/// if the fragment m/z range of the converter allows it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiagnosticIons {
+    /// Immonium ions of the unmodified residues.
+    pub immonium: bool,
+    /// Immonium ions of the modified residues (eg. 216.042 for
+    /// phospho-tyrosine), which only the modified peptidoforms produce.
+    pub modified_immonium: bool,
+}
+
+impl Default for DiagnosticIons {
+    fn default() -> Self {
+        Self {
+            immonium: false,
+            modified_immonium: true,
+        }
     }
 }
 
@@ -123,6 +278,8 @@ impl Display for SafePosition {
 pub struct FragmentMassBuilder {
     pub model: Model,
     pub max_charge: Charge,
+    pub internal_fragments: Option<InternalFragments>,
+    pub diagnostic_ions: Option<DiagnosticIons>,
 }
 
 impl Default for FragmentMassBuilder {
@@ -145,11 +302,18 @@ impl Default for FragmentMassBuilder {
         Self {
             model: by_ions,
             max_charge,
+            internal_fragments: None,
+            diagnostic_ions: None,
         }
     }
 }
 
 impl FragmentMassBuilder {
+    /// Sets the highest fragment charge built.
+    pub fn set_max_charge(&mut self, max_charge: u8) {
+        self.max_charge = Charge::new::<e>(max_charge as f64);
+    }
+
     pub fn fragment_mzs_from_linear_peptide(
         &self,
         peptide: &LinearPeptide,
@@ -166,7 +330,8 @@ impl FragmentMassBuilder {
             .collect();
 
         // Does this generate ions above the charge of the precursor?
-        ions.into_iter()
+        let mut out = ions
+            .into_iter()
             .map(|x| {
                 let intensity = match x.ion {
                     FragmentType::Y(_) => 1.0,
@@ -179,10 +344,178 @@ impl FragmentMassBuilder {
                     intensity,
                 ))
             })
-            .collect()
+            .collect::<Result<Vec<_>, CustomError>>()?;
+        if let Some(internal) = &self.internal_fragments {
+            out.extend(internal_fragment_mzs(peptide, internal));
+        }
+        if let Some(diagnostic) = &self.diagnostic_ions {
+            out.extend(diagnostic_ion_mzs(peptide, diagnostic));
+        }
+        Ok(out)
     }
 }
 
+/// Model generating every singly charged b and/or y ion.
+fn all_ions_model(b: bool, y: bool) -> Model {
+    let location = |x: bool| if x { Location::All } else { Location::None };
+    Model {
+        a: (Location::None, Vec::new()),
+        b: (location(b), vec![]),
+        c: (Location::None, Vec::new()),
+        d: (Location::None, Vec::new()),
+        v: (Location::None, Vec::new()),
+        w: (Location::None, Vec::new()),
+        x: (Location::None, Vec::new()),
+        y: (location(y), vec![]),
+        z: (Location::None, Vec::new()),
+        precursor: vec![],
+        ppm: MassOverCharge::new::<mz>(20.0),
+        glycan_fragmentation: None,
+    }
+}
+
+/// Expected intensity of the internal fragments, relative to the y ions.
+const INTERNAL_FRAGMENT_INTENSITY: f32 = 0.05;
+
+/// Singly charged b-type internal fragments of a peptide, labeled with the
+/// `m` series.
+///
+/// Their masses come from the differences between the b ions, so the
+/// modifications of the spanned residues are accounted for. Internal
+/// fragments never include the first or the last residue.
+fn internal_fragment_mzs(
+    peptide: &LinearPeptide,
+    internal: &InternalFragments,
+) -> Vec<(SafePosition, f64, f32)> {
+    let b_ions = all_ions_model(true, false);
+    // b_mzs[k] is the m/z of b(k + 1), for b1 to b(n - 1).
+    let mut b_mzs: Vec<(u16, f64)> = peptide
+        .generate_theoretical_fragments(Charge::new::<e>(1.0), &b_ions)
+        .into_iter()
+        .filter_map(|x| match x.ion {
+            FragmentType::b(position) => Some((
+                position.series_number as u16,
+                x.mz(MassMode::Monoisotopic).value,
+            )),
+            _ => None,
+        })
+        .collect();
+    b_mzs.sort_by_key(|x| x.0);
+    b_mzs.dedup_by_key(|x| x.0);
+    let peptide_length = b_mzs.len() + 1;
+    if peptide_length < internal.min_peptide_length
+        || b_mzs.iter().enumerate().any(|(i, x)| x.0 as usize != i + 1)
+    {
+        return Vec::new();
+    }
+
+    let mut out = Vec::new();
+    // Spans residues first..=last (1-based), first >= 2 and last <= n - 1.
+    for first in 2..peptide_length {
+        for last in first..peptide_length {
+            let length = last - first + 1;
+            if length < internal.min_length {
+                continue;
+            }
+            if length > internal.max_length {
+                break;
+            }
+            let residues = b_mzs[last - 1].1 - b_mzs[first - 2].1;
+            let position = SafePosition {
+                series_id: b'm',
+                series_number: first as u16,
+                series_end: last as u16,
+                neutral_loss: None,
+                charge: 1,
+            };
+            out.push((position, residues + PROTON_MASS, INTERNAL_FRAGMENT_INTENSITY));
+        }
+    }
+    out
+}
+
+/// Expected intensity of the diagnostic ions, relative to the y ions.
+const DIAGNOSTIC_ION_INTENSITY: f32 = 0.1;
+
+/// Mass of CO, lost from a residue when it forms an immonium ion.
+const CO_MASS: f64 = 27.994915;
+
+/// Masses of the residues of a peptide, with their modifications, from the
+/// differences between its singly charged b and y ions. Terminal
+/// modifications count towards the terminal residues.
+fn residue_masses(peptide: &LinearPeptide) -> Option<Vec<f64>> {
+    let mut b_mzs: Vec<(u16, f64)> = Vec::new();
+    let mut y_mzs: Vec<(u16, f64)> = Vec::new();
+    let ions =
+        peptide.generate_theoretical_fragments(Charge::new::<e>(1.0), &all_ions_model(true, true));
+    for x in ions {
+        let mz = x.mz(MassMode::Monoisotopic).value;
+        match x.ion {
+            FragmentType::b(position) => b_mzs.push((position.series_number as u16, mz)),
+            FragmentType::y(position) => y_mzs.push((position.series_number as u16, mz)),
+            _ => {}
+        }
+    }
+    for ions in [&mut b_mzs, &mut y_mzs] {
+        ions.sort_by_key(|x| x.0);
+        ions.dedup_by_key(|x| x.0);
+    }
+    // b1 to b(n - 1), and at least y1.
+    if b_mzs.is_empty()
+        || y_mzs.first().map(|x| x.0) != Some(1)
+        || b_mzs.iter().enumerate().any(|(i, x)| x.0 as usize != i + 1)
+    {
+        return None;
+    }
+    let mut out = Vec::with_capacity(b_mzs.len() + 1);
+    out.push(b_mzs[0].1 - PROTON_MASS);
+    out.extend(b_mzs.windows(2).map(|x| x[1].1 - x[0].1));
+    out.push(y_mzs[0].1 - WATER_MASS - PROTON_MASS);
+    Some(out)
+}
+
+/// Whether a residue mass is the one of an unmodified amino acid.
+fn is_unmodified_residue(mass: f64) -> bool {
+    b"GASPVTCLNDQKEMHFURYWO"
+        .iter()
+        .filter_map(|x| residue_mass(*x))
+        .any(|x| (x - mass).abs() < 1e-3)
+}
+
+/// Singly charged immonium ions of a peptide, labeled with the `i` series.
+///
+/// Residues producing the same ion (same amino acid and modification) are
+/// queried once.
+fn diagnostic_ion_mzs(
+    peptide: &LinearPeptide,
+    diagnostic: &DiagnosticIons,
+) -> Vec<(SafePosition, f64, f32)> {
+    let Some(masses) = residue_masses(peptide) else {
+        return Vec::new();
+    };
+    let mut out: Vec<(SafePosition, f64, f32)> = Vec::new();
+    for (i, mass) in masses.into_iter().enumerate() {
+        let wanted = if is_unmodified_residue(mass) {
+            diagnostic.immonium
+        } else {
+            diagnostic.modified_immonium
+        };
+        let immonium_mz = mass - CO_MASS + PROTON_MASS;
+        if !wanted || out.iter().any(|x| (x.1 - immonium_mz).abs() < 1e-6) {
+            continue;
+        }
+        let position = SafePosition {
+            series_id: b'i',
+            series_number: i as u16 + 1,
+            series_end: 0,
+            neutral_loss: None,
+            charge: 1,
+        };
+        out.push((position, immonium_mz, DIAGNOSTIC_ION_INTENSITY));
+    }
+    out
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -196,4 +529,74 @@ mod tests {
         assert_eq!(deser.series_number, 12);
         assert_eq!(deser.charge, 3);
     }
+
+    #[test]
+    fn test_annotation_round_trip() {
+        for annot in ["b12^3", "y7-H2O^2", "m3:6^1", "p^2", "y1-NH3^1"] {
+            let pos = SafePosition::from_str(annot).unwrap();
+            assert_eq!(pos.to_string(), annot);
+        }
+        let legacy = SafePosition::from_str("b.12^3").unwrap();
+        assert_eq!(legacy, SafePosition::from_str("b12^3").unwrap());
+
+        let loss = SafePosition::from_str("y7-H2O^2").unwrap();
+        assert_eq!(loss.neutral_loss, Some(NeutralLoss::H2O));
+        let internal = SafePosition::from_str("m3:6").unwrap();
+        assert!(internal.is_internal());
+        assert_eq!(internal.series_end, 6);
+    }
+
+    #[test]
+    fn test_annotation_errors() {
+        match SafePosition::from_str("y7-CO2^2") {
+            Err(TimsSeekError::AnnotationParse(e)) => assert_eq!(e.token, "CO2"),
+            x => panic!("Expected an annotation error, got {:?}", x),
+        }
+        match SafePosition::from_str("y7^x") {
+            Err(TimsSeekError::AnnotationParse(e)) => assert_eq!(e.token, "x"),
+            x => panic!("Expected an annotation error, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_internal_fragments() {
+        let peptide = LinearPeptide::pro_forma("PEPTIDEK").unwrap();
+        let mut internal = InternalFragments {
+            min_peptide_length: 8,
+            min_length: 2,
+            max_length: 3,
+        };
+        let fragments = internal_fragment_mzs(&peptide, &internal);
+        assert_eq!(fragments.len(), 9);
+        assert!(fragments.iter().all(|(pos, _, _)| pos.is_internal()));
+        let pt = SafePosition::from_str("m3:4").unwrap();
+        let (_, pt_mz, _) = fragments.iter().find(|(pos, _, _)| *pos == pt).unwrap();
+        // P + T + proton
+        assert!((pt_mz - 199.107716).abs() < 1e-4, "{}", pt_mz);
+
+        internal.min_peptide_length = 9;
+        assert!(internal_fragment_mzs(&peptide, &internal).is_empty());
+    }
+
+    #[test]
+    fn test_diagnostic_ions() {
+        let peptide = LinearPeptide::pro_forma("PEPY[Phospho]TIDEK").unwrap();
+        let fragments = diagnostic_ion_mzs(&peptide, &DiagnosticIons::default());
+        // Only the phospho-tyrosine immonium ion.
+        assert_eq!(fragments.len(), 1);
+        let (pos, py_mz, _) = fragments[0];
+        assert!(pos.is_diagnostic());
+        assert_eq!(pos.to_string(), "i4^1");
+        assert!((py_mz - 216.0420).abs() < 1e-3, "{}", py_mz);
+
+        let all = DiagnosticIons {
+            immonium: true,
+            modified_immonium: true,
+        };
+        // P, E, pY, T, I, D and K, the second P and E are the same ions.
+        let fragments = diagnostic_ion_mzs(&peptide, &all);
+        assert_eq!(fragments.len(), 7);
+        let p_immonium = fragments[0].1;
+        assert!((p_immonium - 70.0651).abs() < 1e-3, "{}", p_immonium);
+    }
 }
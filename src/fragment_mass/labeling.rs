@@ -0,0 +1,209 @@
+use crate::fragment_mass::fragment_mass_builder::SafePosition;
+use crate::fragment_mass::modifications::{
+    strip_modifications,
+    FixedModification,
+    ModificationPosition,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use timsquery::models::elution_group::ElutionGroup;
+
+/// Mass offsets used to build the heavy channel of a SILAC-style search.
+///
+/// Defaults are the usual Lys8 (13C6 15N2) and Arg10 (13C6 15N4) labels.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HeavyLabel {
+    pub lys_mass_shift: f64,
+    pub arg_mass_shift: f64,
+}
+
+impl Default for HeavyLabel {
+    fn default() -> Self {
+        Self {
+            lys_mass_shift: 8.014199,
+            arg_mass_shift: 10.008269,
+        }
+    }
+}
+
+impl HeavyLabel {
+    /// Total mass shift for a stretch of residues.
+    pub fn residue_shift(&self, residues: &[char]) -> f64 {
+        residues
+            .iter()
+            .map(|c| match c {
+                'K' => self.lys_mass_shift,
+                'R' => self.arg_mass_shift,
+                _ => 0.0,
+            })
+            .sum()
+    }
+
+    /// Mass shift carried by a single fragment of a peptide with `residues`.
+    ///
+    /// N-terminal series (a/b/c) carry the first `series_number` residues,
+    /// C-terminal series (x/y/z) carry the last ones and internal ions the
+    /// residues they span.
+    fn fragment_shift(&self, residues: &[char], position: &SafePosition) -> f64 {
+        let n = (position.series_number as usize).min(residues.len());
+        match position.series_id {
+            b'a' | b'b' | b'c' => self.residue_shift(&residues[..n]),
+            b'x' | b'y' | b'z' => self.residue_shift(&residues[(residues.len() - n)..]),
+            b'm' => {
+                let end = (position.series_end as usize).min(residues.len());
+                self.residue_shift(&residues[n.saturating_sub(1).min(end)..end])
+            }
+            _ => self.residue_shift(residues),
+        }
+    }
+
+    /// Builds the heavy counterpart of an elution group built for the light
+    /// version of the ProForma `peptidoform` at `charge`.
+    ///
+    /// The shifts follow the residues of the peptidoform, so its
+    /// modifications do not move the labeled positions. Everything but the
+    /// m/z values (id, mobility, rt, expected intensities) is shared with the
+    /// light query.
+    pub fn heavy_elution_group(
+        &self,
+        peptidoform: &str,
+        charge: u8,
+        light: &ElutionGroup<SafePosition>,
+    ) -> ElutionGroup<SafePosition> {
+        let residues: Vec<char> = strip_modifications(peptidoform).chars().collect();
+        let sequence = residues.as_slice();
+        let mut heavy = light.clone();
+        let precursor_shift = self.residue_shift(sequence) / charge as f64;
+        heavy
+            .precursor_mzs
+            .iter_mut()
+            .for_each(|mz| *mz += precursor_shift);
+        heavy.fragment_mzs.iter_mut().for_each(|(pos, mz)| {
+            *mz += self.fragment_shift(sequence, pos) / pos.charge.max(1) as f64;
+        });
+        heavy
+    }
+}
+
+/// Isobaric (TMT/iTRAQ) label reagent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IsobaricLabel {
+    /// TMT 6/10/11-plex.
+    Tmt,
+    /// TMTpro 16/18-plex.
+    TmtPro,
+    Itraq4plex,
+    Itraq8plex,
+}
+
+impl IsobaricLabel {
+    /// Monoisotopic mass added to the labeled sites.
+    pub fn mass(&self) -> f64 {
+        match self {
+            Self::Tmt => 229.162932,
+            Self::TmtPro => 304.207146,
+            Self::Itraq4plex => 144.102063,
+            Self::Itraq8plex => 304.205360,
+        }
+    }
+
+    /// m/z range of the reporter ions.
+    pub fn reporter_mz_range(&self) -> (f64, f64) {
+        match self {
+            Self::Tmt | Self::TmtPro => (126.0, 135.5),
+            Self::Itraq4plex => (113.5, 117.5),
+            Self::Itraq8plex => (112.5, 121.5),
+        }
+    }
+}
+
+/// Isobaric labeling of the sample, the label is on every lysine and peptide
+/// N-terminus.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IsobaricLabeling {
+    pub label: IsobaricLabel,
+    /// Skip fragments in the reporter ion region, where the reporter ions of
+    /// every co-isolated peptide add up.
+    #[serde(default)]
+    pub exclude_reporter_region: bool,
+}
+
+impl IsobaricLabeling {
+    /// Label as fixed modifications of the lysines and N-termini.
+    pub fn fixed_modifications(&self) -> Vec<FixedModification> {
+        let modification = format!("{:+.6}", self.label.mass());
+        ['^', 'K']
+            .into_iter()
+            .map(|residue| FixedModification {
+                residue,
+                modification: modification.clone(),
+                position: ModificationPosition::Anywhere,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fragment_shift() {
+        let label = HeavyLabel::default();
+        let seq: Vec<char> = "PEPTIDEKPINR".chars().collect();
+        let seq = seq.as_slice();
+        let b3 = SafePosition::from_str("b3").unwrap();
+        let b8 = SafePosition::from_str("b8").unwrap();
+        let y2 = SafePosition::from_str("y2^2").unwrap();
+        assert_eq!(label.fragment_shift(seq, &b3), 0.0);
+        assert_eq!(label.fragment_shift(seq, &b8), label.lys_mass_shift);
+        assert_eq!(label.fragment_shift(seq, &y2), label.arg_mass_shift);
+        assert!(
+            (label.residue_shift(seq) - (label.lys_mass_shift + label.arg_mass_shift)).abs()
+                < 1e-9
+        );
+    }
+
+    #[test]
+    fn test_heavy_elution_group_modified() {
+        let label = HeavyLabel::default();
+        let light = ElutionGroup {
+            id: 0,
+            precursor_mzs: vec![500.0],
+            mobility: 0.9,
+            rt_seconds: 0.0,
+            fragment_mzs: [
+                (SafePosition::from_str("b2").unwrap(), 200.0),
+                (SafePosition::from_str("y1").unwrap(), 175.0),
+            ]
+            .into_iter()
+            .collect(),
+            expected_fragment_intensity: None,
+            expected_precursor_intensity: None,
+        };
+        // The bracketed modifications are not counted as residues.
+        let heavy = label.heavy_elution_group("[Acetyl]-KM[Oxidation]PEPTIDER/2", 2, &light);
+        let b2 = SafePosition::from_str("b2").unwrap();
+        let y1 = SafePosition::from_str("y1").unwrap();
+        assert!((heavy.fragment_mzs[&b2] - (200.0 + label.lys_mass_shift)).abs() < 1e-9);
+        assert!((heavy.fragment_mzs[&y1] - (175.0 + label.arg_mass_shift)).abs() < 1e-9);
+        let precursor_shift = (label.lys_mass_shift + label.arg_mass_shift) / 2.0;
+        assert!((heavy.precursor_mzs[0] - (500.0 + precursor_shift)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_isobaric_fixed_modifications() {
+        let labeling = IsobaricLabeling {
+            label: IsobaricLabel::Tmt,
+            exclude_reporter_region: false,
+        };
+        let mods = labeling.fixed_modifications();
+        assert_eq!(mods[0].residue, '^');
+        assert_eq!(mods[1].residue, 'K');
+        assert_eq!(mods[1].modification, "+229.162932");
+    }
+}
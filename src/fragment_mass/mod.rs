@@ -1,2 +1,4 @@
 pub mod elution_group_converter;
 pub mod fragment_mass_builder;
+pub mod labeling;
+pub mod modifications;
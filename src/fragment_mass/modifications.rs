@@ -0,0 +1,692 @@
+use crate::errors::TimsSeekError;
+use crate::fragment_mass::fragment_mass_builder::{
+    FragmentMassBuilder,
+    SafePosition,
+};
+use rustyms::{
+    LinearPeptide,
+    MassMode,
+    MolecularFormula,
+    MultiChemical,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Unimod modifications most searches use, listed when a configured
+/// modification cannot be resolved.
+const COMMON_UNIMOD_MODIFICATIONS: &[(&str, u32)] = &[
+    ("Acetyl", 1),
+    ("Amidated", 2),
+    ("Carbamidomethyl", 4),
+    ("Carbamyl", 5),
+    ("Deamidated", 7),
+    ("Phospho", 21),
+    ("Glu->pyro-Glu", 27),
+    ("Gln->pyro-Glu", 28),
+    ("Methyl", 34),
+    ("Oxidation", 35),
+    ("Dimethyl", 36),
+    ("Trimethyl", 37),
+    ("Propionyl", 58),
+    ("Succinyl", 64),
+    ("GG", 121),
+    ("iTRAQ4plex", 214),
+    ("Label:13C(6)15N(2)", 259),
+    ("Label:13C(6)15N(4)", 267),
+    ("Nitro", 354),
+    ("iTRAQ8plex", 730),
+    ("TMT6plex", 737),
+    ("TMTpro", 2016),
+];
+
+/// Resolves a modification, given as a Unimod name ("Oxidation"), accession
+/// ("UNIMOD:35") or mass shift ("+15.994915"), on a residue (`^` for the
+/// peptide N-terminus) to the formula it adds.
+///
+/// Resolution goes through the rustyms ProForma parser, so anything
+/// accepted here is accepted when building the queries.
+pub fn resolve_modification(
+    residue: char,
+    modification: &str,
+) -> Result<MolecularFormula, TimsSeekError> {
+    let (base, modified) = match residue {
+        '^' => ("G".to_string(), format!("[{}]-G", modification)),
+        c if c.is_ascii_uppercase() => (c.to_string(), format!("{}[{}]", c, modification)),
+        c => {
+            return Err(TimsSeekError::ParseError {
+                msg: format!(
+                    "Invalid residue {:?} for modification {:?}, expected a one letter code or ^",
+                    c, modification
+                ),
+            })
+        }
+    };
+    let formula = |sequence: &str| {
+        LinearPeptide::pro_forma(sequence).map(|peptide| peptide.formulas()[0].clone())
+    };
+    match (formula(&modified), formula(&base)) {
+        (Ok(modified), Ok(base)) => Ok(&modified - &base),
+        (Err(e), _) | (_, Err(e)) => {
+            let common: Vec<String> = COMMON_UNIMOD_MODIFICATIONS
+                .iter()
+                .map(|(name, accession)| format!("{} (UNIMOD:{})", name, accession))
+                .collect();
+            Err(TimsSeekError::ParseError {
+                msg: format!(
+                    "Cannot resolve modification {:?} on {}: {}\n\
+                     Use a Unimod name, a Unimod accession or a mass shift (eg. \"+15.994915\"), \
+                     common ones are: {}",
+                    modification,
+                    residue,
+                    e,
+                    common.join(", ")
+                ),
+            })
+        }
+    }
+}
+
+/// Monoisotopic mass (Da) added by a modification resolved with
+/// [`resolve_modification`].
+pub fn resolved_modification_mass(formula: &MolecularFormula) -> f64 {
+    formula.mass(MassMode::Monoisotopic).value
+}
+
+/// Where in the peptide a residue modification can go.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModificationPosition {
+    #[default]
+    Anywhere,
+    /// Only on the first residue of the peptide.
+    PeptideNTerm,
+    /// Only on the last residue of the peptide.
+    PeptideCTerm,
+}
+
+impl ModificationPosition {
+    fn allows(&self, residue_index: usize, num_residues: usize) -> bool {
+        match self {
+            ModificationPosition::Anywhere => true,
+            ModificationPosition::PeptideNTerm => residue_index == 0,
+            ModificationPosition::PeptideCTerm => residue_index + 1 == num_residues,
+        }
+    }
+}
+
+/// Modification applied to every occurrence of a residue, eg. the
+/// carbamidomethylation of the cysteines of alkylated samples.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FixedModification {
+    /// One letter code of the modified residue, or `^` for the peptide
+    /// N-terminus.
+    pub residue: char,
+    /// ProForma modification, either a name ("Carbamidomethyl"), an
+    /// accession ("UNIMOD:4") or a mass shift ("+57.021464").
+    pub modification: String,
+    #[serde(default)]
+    pub position: ModificationPosition,
+}
+
+impl FixedModification {
+    pub fn carbamidomethyl() -> Self {
+        Self {
+            residue: 'C',
+            modification: "UNIMOD:4".to_string(),
+            position: ModificationPosition::Anywhere,
+        }
+    }
+}
+
+/// Modification that may or may not be present on a residue, every
+/// combination is searched as a separate peptidoform.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VariableModification {
+    /// One letter code of the modified residue, or `^` for the peptide
+    /// N-terminus (eg. acetylation).
+    pub residue: char,
+    /// ProForma modification, as in [`FixedModification`].
+    pub modification: String,
+    #[serde(default)]
+    pub position: ModificationPosition,
+}
+
+/// Modification of a bespoke chemistry, declared by its mass instead of a
+/// Unimod entry, eg. `{"residues": "K", "mass_delta": 114.042927}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CustomModification {
+    /// One letter codes of the residues it goes on. Empty for any residue,
+    /// only allowed at a peptide terminus.
+    #[serde(default)]
+    pub residues: String,
+    /// Monoisotopic mass added (Da).
+    pub mass_delta: f64,
+    #[serde(default)]
+    pub position: ModificationPosition,
+    /// Search every peptide with and without it instead of on every site.
+    #[serde(default)]
+    pub variable: bool,
+}
+
+const AMINO_ACIDS: &str = "ACDEFGHIKLMNPQRSTVWY";
+
+impl CustomModification {
+    /// Residues the modification goes on, `^` for the peptide N-terminus.
+    fn residue_codes(&self) -> Result<Vec<char>, TimsSeekError> {
+        let invalid = |msg: String| TimsSeekError::ParseError { msg };
+        if self.residues.is_empty() {
+            return match self.position {
+                ModificationPosition::Anywhere => Err(invalid(format!(
+                    "Custom modification {:+.6} needs residues unless it is terminal",
+                    self.mass_delta
+                ))),
+                ModificationPosition::PeptideNTerm => Ok(vec!['^']),
+                ModificationPosition::PeptideCTerm => Ok(AMINO_ACIDS.chars().collect()),
+            };
+        }
+        match self.residues.chars().find(|c| !AMINO_ACIDS.contains(*c)) {
+            Some(c) => Err(invalid(format!(
+                "Invalid residue {:?} in custom modification {:+.6}",
+                c, self.mass_delta
+            ))),
+            None => Ok(self.residues.chars().collect()),
+        }
+    }
+
+    /// ProForma mass shift of the modification.
+    fn modification(&self) -> String {
+        format!("{:+.6}", self.mass_delta)
+    }
+
+    pub fn fixed_modifications(&self) -> Result<Vec<FixedModification>, TimsSeekError> {
+        Ok(self
+            .residue_codes()?
+            .into_iter()
+            .map(|residue| FixedModification {
+                residue,
+                modification: self.modification(),
+                position: self.position,
+            })
+            .collect())
+    }
+
+    pub fn variable_modifications(&self) -> Result<Vec<VariableModification>, TimsSeekError> {
+        Ok(self
+            .fixed_modifications()?
+            .into_iter()
+            .map(|x| VariableModification {
+                residue: x.residue,
+                modification: x.modification,
+                position: x.position,
+            })
+            .collect())
+    }
+
+    /// Checks that the modification shifts the precursor and exactly the
+    /// fragments that carry the modified residue by `mass_delta`, on a test
+    /// peptide for each residue.
+    pub fn validate(&self) -> Result<(), TimsSeekError> {
+        let builder = FragmentMassBuilder::default();
+        let as_error = |e: rustyms::error::CustomError| TimsSeekError::ParseError {
+            msg: format!("Custom modification {:+.6}: {}", self.mass_delta, e),
+        };
+        for modification in self.fixed_modifications()? {
+            // The modified residue sits at `site`, the peptide is long enough
+            // for the fragments on both sides of it to be built.
+            let (sequence, site) = match (modification.residue, self.position) {
+                ('^', _) => ("GGGGGGK".to_string(), 0),
+                (c, ModificationPosition::PeptideNTerm) => (format!("{}GGGGGK", c), 0),
+                (c, ModificationPosition::PeptideCTerm) => (format!("GGGGGG{}", c), 6),
+                (c, ModificationPosition::Anywhere) => (format!("GGG{}GGK", c), 3),
+            };
+            let modified = apply_fixed_modifications(&sequence, &[modification.clone()]);
+            let unmodified = LinearPeptide::pro_forma(&sequence).map_err(as_error)?;
+            let modified = LinearPeptide::pro_forma(&modified).map_err(as_error)?;
+
+            let precursor_shift = modified.formulas()[0].mass(MassMode::Monoisotopic).value
+                - unmodified.formulas()[0].mass(MassMode::Monoisotopic).value;
+            let mut shifts = vec![("precursor".to_string(), precursor_shift, self.mass_delta)];
+            let unmodified: HashMap<SafePosition, f64> = builder
+                .fragment_mzs_from_linear_peptide(&unmodified)
+                .map_err(as_error)?
+                .into_iter()
+                .map(|(pos, mz, _)| (pos, mz))
+                .collect();
+            for (pos, mz, _) in builder
+                .fragment_mzs_from_linear_peptide(&modified)
+                .map_err(as_error)?
+            {
+                let Some(unmodified_mz) = unmodified.get(&pos) else {
+                    continue;
+                };
+                let length = pos.series_number as usize;
+                let carries_site = match pos.series_id {
+                    b'b' => length > site,
+                    b'y' => length >= sequence.len() - site,
+                    _ => continue,
+                };
+                let expected = if carries_site {
+                    self.mass_delta / pos.charge as f64
+                } else {
+                    0.0
+                };
+                shifts.push((pos.to_string(), mz - unmodified_mz, expected));
+            }
+            if let Some((ion, shift, expected)) = shifts
+                .into_iter()
+                .find(|(_, shift, expected)| (shift - expected).abs() > 1e-4)
+            {
+                return Err(TimsSeekError::ParseError {
+                    msg: format!(
+                        "Custom modification {:+.6} on {} is not applied consistently: {} of {} moves by {:.6} instead of {:.6}",
+                        self.mass_delta, modification.residue, ion, sequence, shift, expected
+                    ),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VariableModifications {
+    pub modifications: Vec<VariableModification>,
+    /// Maximum number of variable modifications on a peptide.
+    pub max_per_peptide: usize,
+}
+
+impl Default for VariableModifications {
+    fn default() -> Self {
+        Self {
+            modifications: Vec::new(),
+            max_per_peptide: 2,
+        }
+    }
+}
+
+/// Place a variable modification can go: the text is inserted at `position`
+/// of the sequence.
+struct ModificationSite {
+    position: usize,
+    insertions: Vec<String>,
+}
+
+impl VariableModifications {
+    /// Unmodified residues of a ProForma sequence that can carry a variable
+    /// modification, in sequence order.
+    fn sites(&self, sequence: &str) -> Vec<ModificationSite> {
+        let mut sites = Vec::new();
+        if !sequence.starts_with('[') {
+            let insertions: Vec<String> = self
+                .modifications
+                .iter()
+                .filter(|m| m.residue == '^')
+                .map(|m| format!("[{}]-", m.modification))
+                .collect();
+            if !insertions.is_empty() {
+                sites.push(ModificationSite {
+                    position: 0,
+                    insertions,
+                });
+            }
+        }
+
+        let num_residues = strip_modifications(sequence).len();
+        let mut residue_index = 0;
+        let mut depth = 0usize;
+        let mut chars = sequence.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '[' => depth += 1,
+                ']' => depth = depth.saturating_sub(1),
+                '/' if depth == 0 => break,
+                c if depth == 0 && c.is_ascii_uppercase() => {
+                    residue_index += 1;
+                    if chars.peek().map(|x| x.1) == Some('[') {
+                        continue;
+                    }
+                    let insertions: Vec<String> = self
+                        .modifications
+                        .iter()
+                        .filter(|m| {
+                            m.residue == c && m.position.allows(residue_index - 1, num_residues)
+                        })
+                        .map(|m| format!("[{}]", m.modification))
+                        .collect();
+                    if !insertions.is_empty() {
+                        sites.push(ModificationSite {
+                            position: i + c.len_utf8(),
+                            insertions,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        sites
+    }
+
+    /// Peptidoforms of a ProForma sequence with up to `max_per_peptide`
+    /// variable modifications, the unmodified sequence first.
+    pub fn expand(&self, sequence: &str) -> Vec<String> {
+        let mut out = vec![sequence.to_string()];
+        if self.modifications.is_empty() || self.max_per_peptide == 0 {
+            return out;
+        }
+        let sites = self.sites(sequence);
+        let mut chosen = Vec::new();
+        self.push_combinations(sequence, &sites, 0, &mut chosen, &mut out);
+        out
+    }
+
+    fn push_combinations(
+        &self,
+        sequence: &str,
+        sites: &[ModificationSite],
+        first_site: usize,
+        chosen: &mut Vec<(usize, usize)>,
+        out: &mut Vec<String>,
+    ) {
+        for site in first_site..sites.len() {
+            for insertion in 0..sites[site].insertions.len() {
+                chosen.push((site, insertion));
+                let mut peptidoform = String::with_capacity(sequence.len() + 32);
+                let mut last = 0;
+                for (site, insertion) in chosen.iter() {
+                    let position = sites[*site].position;
+                    peptidoform.push_str(&sequence[last..position]);
+                    peptidoform.push_str(&sites[*site].insertions[*insertion]);
+                    last = position;
+                }
+                peptidoform.push_str(&sequence[last..]);
+                out.push(peptidoform);
+                if chosen.len() < self.max_per_peptide {
+                    self.push_combinations(sequence, sites, site + 1, chosen, out);
+                }
+                chosen.pop();
+            }
+        }
+    }
+}
+
+/// Adds the fixed modifications to a ProForma sequence.
+///
+/// Residues that already carry a modification are left as they are, so
+/// sequences coming with their own modifications (eg. from a library) are
+/// not modified twice.
+pub fn apply_fixed_modifications<'a>(
+    sequence: &'a str,
+    modifications: &[FixedModification],
+) -> Cow<'a, str> {
+    let nterm = modifications
+        .iter()
+        .find(|m| m.residue == '^')
+        .filter(|_| !sequence.starts_with('['));
+    if nterm.is_none() && !modifications.iter().any(|m| sequence.contains(m.residue)) {
+        return Cow::Borrowed(sequence);
+    }
+
+    let num_residues = strip_modifications(sequence).len();
+    let mut residue_index = 0;
+    let mut out = String::with_capacity(sequence.len() + 16);
+    if let Some(m) = nterm {
+        out.push('[');
+        out.push_str(&m.modification);
+        out.push_str("]-");
+    }
+    let mut depth = 0usize;
+    let mut chars = sequence.chars().peekable();
+    while let Some(c) = chars.next() {
+        out.push(c);
+        match c {
+            '[' => depth += 1,
+            ']' => depth = depth.saturating_sub(1),
+            // Anything after the charge is not a residue.
+            '/' if depth == 0 => {
+                out.extend(chars.by_ref());
+                break;
+            }
+            c if depth == 0 && c.is_ascii_uppercase() => {
+                residue_index += 1;
+                if chars.peek() == Some(&'[') {
+                    continue;
+                }
+                if let Some(m) = modifications.iter().find(|m| {
+                    m.residue == c && m.position.allows(residue_index - 1, num_residues)
+                }) {
+                    out.push('[');
+                    out.push_str(&m.modification);
+                    out.push(']');
+                }
+            }
+            _ => {}
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// Removes the modifications (`[...]`, `(...)`) and terminal dashes from a
+/// ProForma-like peptidoform, leaving the bare amino acid sequence.
+pub fn strip_modifications(peptidoform: &str) -> String {
+    let mut out = String::with_capacity(peptidoform.len());
+    let mut depth = 0usize;
+    for c in peptidoform.chars() {
+        match c {
+            '[' | '(' => depth += 1,
+            ']' | ')' => depth = depth.saturating_sub(1),
+            _ if depth > 0 => {}
+            c if c.is_ascii_uppercase() => out.push(c),
+            _ => {}
+        }
+    }
+    out
+}
+
+/// Modifications of a ProForma sequence (the contents of its brackets or
+/// parentheses), in sequence order.
+pub fn modification_names(peptidoform: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0usize;
+    for c in peptidoform.chars() {
+        match c {
+            '[' | '(' => {
+                if depth > 0 {
+                    current.push(c);
+                }
+                depth += 1;
+            }
+            ']' | ')' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    out.push(std::mem::take(&mut current));
+                } else {
+                    current.push(c);
+                }
+            }
+            c if depth > 0 => current.push(c),
+            _ => {}
+        }
+    }
+    out
+}
+
+/// Checks a ProForma sequence (eg. from a library or a peptide list) and
+/// splits it into its bare sequence and, if it is modified, the full
+/// peptidoform.
+pub fn split_peptidoform(sequence: &str) -> Result<(String, Option<String>), TimsSeekError> {
+    if let Err(e) = LinearPeptide::pro_forma(sequence) {
+        return Err(TimsSeekError::ParseError {
+            msg: format!("Invalid ProForma sequence {:?}: {:?}", sequence, e),
+        });
+    }
+    let bare = strip_modifications(sequence);
+    let peptidoform = if bare == sequence {
+        None
+    } else {
+        Some(sequence.to_string())
+    };
+    Ok((bare, peptidoform))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_fixed_modifications() {
+        let mods = vec![FixedModification::carbamidomethyl()];
+        assert!(matches!(
+            apply_fixed_modifications("PEPTIDEK", &mods),
+            Cow::Borrowed(_)
+        ));
+        assert_eq!(
+            apply_fixed_modifications("PEPCTIDEC/2", &mods),
+            "PEPC[UNIMOD:4]TIDEC[UNIMOD:4]/2"
+        );
+        // Already modified residues and the contents of the modifications are
+        // left alone.
+        assert_eq!(
+            apply_fixed_modifications("[Acetyl]-PC[+58.005]CM[Oxidation]", &mods),
+            "[Acetyl]-PC[+58.005]C[UNIMOD:4]M[Oxidation]"
+        );
+
+        let nterm = vec![FixedModification {
+            residue: '^',
+            modification: "+229.162932".to_string(),
+            position: ModificationPosition::Anywhere,
+        }];
+        assert_eq!(
+            apply_fixed_modifications("PEPTIDEK", &nterm),
+            "[+229.162932]-PEPTIDEK"
+        );
+        assert_eq!(
+            apply_fixed_modifications("[Acetyl]-PEPTIDEK", &nterm),
+            "[Acetyl]-PEPTIDEK"
+        );
+    }
+
+    #[test]
+    fn test_expand_variable_modifications() {
+        let mut mods = VariableModifications {
+            modifications: vec![VariableModification {
+                residue: 'M',
+                modification: "Oxidation".to_string(),
+                position: ModificationPosition::Anywhere,
+            }],
+            max_per_peptide: 1,
+        };
+        assert_eq!(
+            mods.expand("PEMTIDEMK"),
+            vec!["PEMTIDEMK", "PEM[Oxidation]TIDEMK", "PEMTIDEM[Oxidation]K"]
+        );
+
+        mods.max_per_peptide = 2;
+        mods.modifications.push(VariableModification {
+            residue: '^',
+            modification: "Acetyl".to_string(),
+            position: ModificationPosition::Anywhere,
+        });
+        let forms = mods.expand("PEMTIDEMK");
+        // Every subset of the 3 sites, up to 2 of them.
+        assert_eq!(forms.len(), 7);
+        assert!(forms.contains(&"[Acetyl]-PEM[Oxidation]TIDEMK".to_string()));
+        assert!(forms.contains(&"PEM[Oxidation]TIDEM[Oxidation]K".to_string()));
+
+        // Already modified residues are not sites.
+        assert_eq!(mods.expand("[Acetyl]-PEM[Oxidation]TIDEK").len(), 1);
+    }
+
+    #[test]
+    fn test_resolve_modification() {
+        let mass = |residue: char, modification: &str| {
+            resolved_modification_mass(&resolve_modification(residue, modification).unwrap())
+        };
+        assert!((mass('M', "Oxidation") - 15.994915).abs() < 1e-4);
+        assert!((mass('M', "UNIMOD:35") - 15.994915).abs() < 1e-4);
+        assert!((mass('C', "Carbamidomethyl") - 57.021464).abs() < 1e-4);
+        assert!((mass('^', "Acetyl") - 42.010565).abs() < 1e-4);
+        assert!((mass('K', "+229.162932") - 229.162932).abs() < 1e-4);
+
+        let err = resolve_modification('M', "Oxidatoin").unwrap_err();
+        assert!(format!("{}", err).contains("Oxidation (UNIMOD:35)"));
+        assert!(resolve_modification('m', "Oxidation").is_err());
+    }
+
+    #[test]
+    fn test_custom_modifications() {
+        let custom = CustomModification {
+            residues: "Q".to_string(),
+            mass_delta: -17.026549,
+            position: ModificationPosition::PeptideNTerm,
+            variable: false,
+        };
+        custom.validate().unwrap();
+        let fixed = custom.fixed_modifications().unwrap();
+        assert_eq!(
+            apply_fixed_modifications("QPEQK", &fixed),
+            "Q[-17.026549]PEQK"
+        );
+
+        let variable = VariableModifications {
+            modifications: CustomModification {
+                residues: "KR".to_string(),
+                mass_delta: 14.01565,
+                position: ModificationPosition::PeptideCTerm,
+                variable: true,
+            }
+            .variable_modifications()
+            .unwrap(),
+            max_per_peptide: 1,
+        };
+        assert_eq!(
+            variable.expand("PEKTIDEK"),
+            vec!["PEKTIDEK", "PEKTIDEK[+14.015650]"]
+        );
+
+        let nterm = CustomModification {
+            residues: String::new(),
+            mass_delta: 28.0313,
+            position: ModificationPosition::PeptideNTerm,
+            variable: false,
+        };
+        nterm.validate().unwrap();
+        assert_eq!(nterm.fixed_modifications().unwrap()[0].residue, '^');
+
+        let anywhere = CustomModification {
+            position: ModificationPosition::Anywhere,
+            ..nterm
+        };
+        assert!(anywhere.fixed_modifications().is_err());
+    }
+
+    #[test]
+    fn test_split_peptidoform() {
+        assert_eq!(
+            split_peptidoform("PEPTIDEK").unwrap(),
+            ("PEPTIDEK".to_string(), None)
+        );
+        assert_eq!(
+            split_peptidoform("[Acetyl]-PEM[Oxidation]TIDEK").unwrap(),
+            (
+                "PEMTIDEK".to_string(),
+                Some("[Acetyl]-PEM[Oxidation]TIDEK".to_string())
+            )
+        );
+        assert!(split_peptidoform("PEM[+15.99K").is_err());
+    }
+
+    #[test]
+    fn test_modification_names() {
+        assert!(modification_names("PEPTIDEK").is_empty());
+        assert_eq!(
+            modification_names("[Acetyl]-PEM[Oxidation]TIDES(Phospho)K/2"),
+            vec!["Acetyl", "Oxidation", "Phospho"]
+        );
+    }
+}
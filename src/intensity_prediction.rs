@@ -0,0 +1,121 @@
+use crate::errors::TimsSeekError;
+use crate::fragment_mass::fragment_mass_builder::SafePosition;
+use crate::numeric::deserialize_decimal_f32;
+use crate::rt_prediction::read_tsv;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::sync::Arc;
+
+/// Fragment intensity predictor used to set the expected intensities of the
+/// queries (instead of the fixed y/b ones of the fragment builder).
+pub trait FragmentIntensityPredictor: Debug + Send + Sync {
+    /// Predicted relative intensities of the fragments of a precursor of a
+    /// ProForma sequence, None if the predictor has nothing for it.
+    fn predict(&self, sequence: &str, charge: u8) -> Option<&HashMap<SafePosition, f32>>;
+}
+
+#[derive(Debug, Deserialize)]
+struct IntensityTableEntry {
+    sequence: String,
+    charge: u8,
+    fragment: SafePosition,
+    #[serde(deserialize_with = "deserialize_decimal_f32")]
+    intensity: f32,
+}
+
+/// Fragment intensities predicted ahead of time by an external model (eg. an
+/// ONNX model or a Koina server), looked up by sequence and charge.
+///
+/// The intensities of every precursor are scaled so the highest is 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableIntensityPredictor {
+    intensities: HashMap<(String, u8), HashMap<SafePosition, f32>>,
+}
+
+impl TableIntensityPredictor {
+    pub fn new(mut intensities: HashMap<(String, u8), HashMap<SafePosition, f32>>) -> Self {
+        for fragments in intensities.values_mut() {
+            let max = fragments.values().cloned().fold(0.0f32, f32::max);
+            if max > 0.0 {
+                fragments.values_mut().for_each(|x| *x /= max);
+            }
+        }
+        Self { intensities }
+    }
+
+    /// Reads a tab separated file with `sequence`, `charge`, `fragment` (eg.
+    /// `y7^2`) and `intensity` columns, one row per fragment.
+    pub fn from_tsv(path: &Path) -> Result<Self, TimsSeekError> {
+        let entries: Vec<IntensityTableEntry> = read_tsv(path)?;
+        let mut intensities: HashMap<(String, u8), HashMap<SafePosition, f32>> = HashMap::new();
+        for entry in entries {
+            intensities
+                .entry((entry.sequence, entry.charge))
+                .or_default()
+                .insert(entry.fragment, entry.intensity);
+        }
+        Ok(Self::new(intensities))
+    }
+}
+
+impl FragmentIntensityPredictor for TableIntensityPredictor {
+    fn predict(&self, sequence: &str, charge: u8) -> Option<&HashMap<SafePosition, f32>> {
+        self.intensities.get(&(sequence.to_string(), charge))
+    }
+}
+
+/// Which fragment intensity predictor is used for the queries.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IntensityModel {
+    /// No prediction, the fixed intensities of the fragment builder.
+    #[default]
+    None,
+    /// [`TableIntensityPredictor`] read from a tab separated file.
+    Table(PathBuf),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IntensityPredictorConfig {
+    pub model: IntensityModel,
+}
+
+impl IntensityPredictorConfig {
+    /// Builds the configured predictor, None if intensity prediction is off.
+    pub fn build(&self) -> Result<Option<Arc<dyn FragmentIntensityPredictor>>, TimsSeekError> {
+        match &self.model {
+            IntensityModel::None => Ok(None),
+            IntensityModel::Table(path) => {
+                Ok(Some(Arc::new(TableIntensityPredictor::from_tsv(path)?)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_intensity_predictor() {
+        let pos = |x: &str| SafePosition::from_str(x).unwrap();
+        let table = TableIntensityPredictor::new(HashMap::from([(
+            ("PEPTIDEK".to_string(), 2),
+            HashMap::from([(pos("y3"), 50.0), (pos("y5"), 200.0), (pos("b2"), 0.0)]),
+        )]));
+        let predicted = table.predict("PEPTIDEK", 2).unwrap();
+        assert_eq!(predicted[&pos("y5")], 1.0);
+        assert_eq!(predicted[&pos("y3")], 0.25);
+        assert_eq!(predicted[&pos("b2")], 0.0);
+        assert!(table.predict("PEPTIDEK", 3).is_none());
+    }
+}
@@ -2,60 +2,311 @@
 // Which I sincerely feel is such an elegant implementation.
 // https://github.com/lazear/sage/crates/sage/src/isotopes.rs
 
-// Manually unroll the convolution loop
-fn convolve(a: &[f32; 4], b: &[f32; 4]) -> [f32; 4] {
-    [
-        a[0] * b[0],
-        a[0] * b[1] + a[1] * b[0],
-        a[0] * b[2] + a[1] * b[1] + a[2] * b[0],
-        a[0] * b[3] + a[1] * b[2] + a[2] * b[1] + a[3] * b[0],
-        // a[0] * b[4] + a[1] * b[3] + a[2] * b[2] + a[3] * b[1] + a[4] * b[0],
-    ]
-}
-
-fn carbon_isotopes(count: u16) -> [f32; 4] {
-    let lambda = count as f32 * 0.011;
-    let mut c13 = [0.0; 4];
-
-    let fact = [1, 1, 2, 6];
-    for k in 0..4 {
-        c13[k] = lambda.powi(k as i32) * f32::exp(-lambda) / fact[k] as f32;
+pub const PROTON_MASS: f64 = 1.007276466;
+
+/// Mass difference between 13C and 12C, the spacing of peptide isotopologues.
+pub const C13_C12_MASS_DIFF: f64 = 1.0033548;
+
+/// Expected intensity of the -1 isotope peak of a query, which is only there
+/// to detect interferences.
+pub const MINUS_ONE_ISOTOPE_INTENSITY: f32 = 1e-3;
+
+/// Monoisotopic m/z of the precursor of a query, skipping the -1 peak if the
+/// expected intensities have one. Without expected intensities the -1 peak
+/// is assumed to be there, as in the default layout.
+pub fn monoisotopic_precursor_mz(precursor_mzs: &[f64], expected_intensities: Option<&[f32]>) -> f64 {
+    precursor_mzs
+        .get(monoisotopic_index(precursor_mzs, expected_intensities))
+        .or(precursor_mzs.first())
+        .copied()
+        .unwrap_or(0.0)
+}
+
+/// Index of the monoisotopic peak in the precursor m/z of a query, see
+/// [`monoisotopic_precursor_mz`].
+fn monoisotopic_index(precursor_mzs: &[f64], expected_intensities: Option<&[f32]>) -> usize {
+    let has_minus_one = match expected_intensities {
+        Some(x) => x.first().is_some_and(|first| *first <= MINUS_ONE_ISOTOPE_INTENSITY),
+        None => precursor_mzs.len() > 1,
+    };
+    if has_minus_one {
+        1
+    } else {
+        0
     }
-    c13
-}
-
-fn sulfur_isotopes(count: u16) -> [f32; 4] {
-    let lambda33 = count as f32 * 0.0076;
-    let lambda35 = count as f32 * 0.044;
-    let mut s33 = [0.0; 4];
-    let s35 = [
-        lambda35.powi(0) * f32::exp(-lambda35),
-        0.0,
-        lambda35.powi(1) * f32::exp(-lambda35),
-        0.0,
-        // lambda35.powi(2) * f32::exp(-lambda35) / 2.0,
-    ];
+}
+
+/// Number of precursor isotope peaks outside of the `(low, high)` MS1 scan
+/// range.
+pub fn num_isotopes_outside_range(precursor_mzs: &[f64], (low, high): (f64, f64)) -> usize {
+    precursor_mzs
+        .iter()
+        .filter(|mz| **mz < low || **mz > high)
+        .count()
+}
 
-    let fact = [1, 1, 2, 6];
-    for k in 0..4 {
-        s33[k] = lambda33.powi(k as i32) * f32::exp(-lambda33) / fact[k] as f32;
+/// Drops the precursor isotope peaks (and their expected intensities)
+/// outside of the `(low, high)` MS1 scan range, the monoisotopic one (and
+/// the -1 peak before it) is always kept, so the monoisotopic peak is found
+/// at the same index afterwards. Returns how many were dropped.
+pub fn trim_isotopes_to_range(
+    precursor_mzs: &mut Vec<f64>,
+    expected_intensities: &mut Option<Vec<f32>>,
+    (low, high): (f64, f64),
+) -> usize {
+    let mono = monoisotopic_index(precursor_mzs, expected_intensities.as_deref());
+    let keep: Vec<bool> = precursor_mzs
+        .iter()
+        .enumerate()
+        .map(|(i, mz)| i <= mono || (*mz >= low && *mz <= high))
+        .collect();
+    let mut keep_iter = keep.iter();
+    precursor_mzs.retain(|_| *keep_iter.next().unwrap());
+    if let Some(intensities) = expected_intensities {
+        let mut keep_iter = keep.iter();
+        intensities.retain(|_| *keep_iter.next().unwrap_or(&true));
     }
+    keep.iter().filter(|x| !**x).count()
+}
+
+/// m/z values of the isotopologues of a protonated precursor, starting at
+/// the -1 peak.
+///
+/// `num_peaks` includes the -1 peak, so 4 peaks are the -1, M, M+1 and M+2.
+pub fn precursor_isotope_mzs(neutral_mass: f64, charge: u8, num_peaks: usize) -> Vec<f64> {
+    adduct_isotope_mzs(neutral_mass, charge, PROTON_MASS, num_peaks)
+}
+
+/// Same as [`precursor_isotope_mzs`], for precursors carrying `charge` ions
+/// of `carrier_mass` each (negative for removed ones, eg. deprotonation).
+pub fn adduct_isotope_mzs(
+    neutral_mass: f64,
+    charge: u8,
+    carrier_mass: f64,
+    num_peaks: usize,
+) -> Vec<f64> {
+    let mono_mz = (neutral_mass + (charge as f64 * carrier_mass)) / charge as f64;
+    let spacing = C13_C12_MASS_DIFF / charge as f64;
+    (0..num_peaks)
+        .map(|i| mono_mz + (i as f64 - 1.0) * spacing)
+        .collect()
+}
+
+/// Convolution of two envelopes, truncated to the length of the first.
+fn convolve(a: &[f32], b: &[f32]) -> Vec<f32> {
+    (0..a.len())
+        .map(|k| {
+            (0..=k)
+                .map(|i| a[i] * b.get(k - i).copied().unwrap_or(0.0))
+                .sum()
+        })
+        .collect()
+}
 
-    convolve(&s33, &s35)
+/// Poisson envelope of `num_peaks` peaks, for a heavy isotope `spacing`
+/// neutrons above the light one with `lambda` expected heavy atoms.
+fn poisson_isotopes(lambda: f32, spacing: usize, num_peaks: usize) -> Vec<f32> {
+    let mut out = vec![0.0; num_peaks];
+    let mut term = f32::exp(-lambda);
+    let mut k = 0;
+    while k * spacing < num_peaks {
+        out[k * spacing] = term;
+        k += 1;
+        term *= lambda / k as f32;
+    }
+    out
+}
+
+fn carbon_isotopes(count: u16, num_peaks: usize) -> Vec<f32> {
+    poisson_isotopes(count as f32 * 0.011, 1, num_peaks)
+}
+
+fn sulfur_isotopes(count: u16, num_peaks: usize) -> Vec<f32> {
+    let s33 = poisson_isotopes(count as f32 * 0.0076, 1, num_peaks);
+    let s34 = poisson_isotopes(count as f32 * 0.044, 2, num_peaks);
+    convolve(&s33, &s34)
+}
+
+fn nitrogen_isotopes(count: u16, num_peaks: usize) -> Vec<f32> {
+    poisson_isotopes(count as f32 * 0.00366, 1, num_peaks)
+}
+
+fn hydrogen_isotopes(count: u16, num_peaks: usize) -> Vec<f32> {
+    poisson_isotopes(count as f32 * 0.000115, 1, num_peaks)
+}
+
+fn oxygen_isotopes(count: u16, num_peaks: usize) -> Vec<f32> {
+    let o17 = poisson_isotopes(count as f32 * 0.00038, 1, num_peaks);
+    let o18 = poisson_isotopes(count as f32 * 0.00205, 2, num_peaks);
+    convolve(&o17, &o18)
+}
+
+/// Atom counts of the elements that shape the isotope envelope of a peptide.
+///
+/// Hydrogen, nitrogen and oxygen only shift a few percent of the signal, so
+/// they can be left at 0 for a carbon/sulfur approximation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ElementCounts {
+    pub carbon: u16,
+    pub hydrogen: u16,
+    pub nitrogen: u16,
+    pub oxygen: u16,
+    pub sulfur: u16,
+    /// Selenocysteine (U) and selenomethionine.
+    pub selenium: u16,
+    pub chlorine: u16,
+    pub bromine: u16,
+}
+
+// Isotopes of a single atom as (nominal offset, abundance), relative to the
+// most abundant one, which is the one used for the monoisotopic mass. For
+// selenium that is 80Se, so 74Se to 78Se are below the monoisotopic peak.
+const SELENIUM_ISOTOPES: [(i8, f32); 6] = [
+    (-6, 0.0179),
+    (-4, 0.1889),
+    (-3, 0.1538),
+    (-2, 0.4791),
+    (0, 1.0),
+    (2, 0.1760),
+];
+const CHLORINE_ISOTOPES: [(i8, f32); 2] = [(0, 1.0), (2, 0.320)];
+const BROMINE_ISOTOPES: [(i8, f32); 2] = [(0, 1.0), (2, 0.973)];
+
+/// How many peaks the lightest isotope of an atom is below the reference one.
+fn lightest_offset(per_atom: &[(i8, f32)]) -> usize {
+    per_atom
+        .iter()
+        .map(|(offset, _)| -(*offset as i32))
+        .max()
+        .unwrap_or(0)
+        .max(0) as usize
+}
+
+/// Exact envelope of a few atoms of an element, by repeated convolution.
+///
+/// The envelope starts `count * lightest_offset(per_atom)` peaks below the
+/// monoisotopic one.
+fn atom_isotopes(per_atom: &[(i8, f32)], count: u16, num_peaks: usize) -> Vec<f32> {
+    let lightest = lightest_offset(per_atom) as i32;
+    let mut single = vec![0.0; num_peaks];
+    for (offset, abundance) in per_atom {
+        if let Some(x) = single.get_mut((*offset as i32 + lightest) as usize) {
+            *x = *abundance;
+        }
+    }
+    let mut out = vec![0.0; num_peaks];
+    if let Some(first) = out.first_mut() {
+        *first = 1.0;
+    }
+    for _ in 0..count {
+        out = convolve(&out, &single);
+    }
+    out
 }
 
 pub fn peptide_isotopes(carbons: u16, sulfurs: u16) -> [f32; 3] {
-    let c = carbon_isotopes(carbons);
-    let s = sulfur_isotopes(sulfurs);
-    let mut c = convolve(&c, &s);
-    let max = c[0].max(c[1]).max(c[2]);
-    c.iter_mut().for_each(|val| *val /= max);
+    peptide_isotopes_from_counts(&ElementCounts {
+        carbon: carbons,
+        sulfur: sulfurs,
+        ..Default::default()
+    })
+}
+
+/// Relative intensities of the M, M+1 and M+2 peaks, normalized to the
+/// highest of them.
+pub fn peptide_isotopes_from_counts(counts: &ElementCounts) -> [f32; 3] {
+    let c = peptide_isotope_envelope(counts, 3);
     [c[0], c[1], c[2]]
 }
 
+/// Relative intensities of the first `num_peaks` isotopologues (starting at
+/// the monoisotopic one), normalized to the highest of them.
+pub fn peptide_isotope_envelope(counts: &ElementCounts, num_peaks: usize) -> Vec<f32> {
+    let tabulated = [
+        (&SELENIUM_ISOTOPES[..], counts.selenium),
+        (&CHLORINE_ISOTOPES[..], counts.chlorine),
+        (&BROMINE_ISOTOPES[..], counts.bromine),
+    ];
+    // Isotopes lighter than the reference one combine with heavy ones of
+    // other elements, so the envelope is computed from the lightest peak.
+    let below: usize = tabulated
+        .iter()
+        .map(|(per_atom, count)| lightest_offset(per_atom) * *count as usize)
+        .sum();
+    let len = num_peaks + below;
+    let mut c = carbon_isotopes(counts.carbon, len);
+    c = convolve(&c, &hydrogen_isotopes(counts.hydrogen, len));
+    c = convolve(&c, &nitrogen_isotopes(counts.nitrogen, len));
+    c = convolve(&c, &oxygen_isotopes(counts.oxygen, len));
+    c = convolve(&c, &sulfur_isotopes(counts.sulfur, len));
+    for (per_atom, count) in tabulated {
+        c = convolve(&c, &atom_isotopes(per_atom, count, len));
+    }
+    let mut c = c.split_off(below);
+    let max = c.iter().cloned().fold(0.0f32, f32::max);
+    if max > 0.0 {
+        c.iter_mut().for_each(|val| *val /= max);
+    }
+    c
+}
+
 #[cfg(test)]
 mod tests {
-    use super::peptide_isotopes;
+    use super::{
+        monoisotopic_precursor_mz,
+        peptide_isotope_envelope,
+        peptide_isotopes,
+        peptide_isotopes_from_counts,
+        precursor_isotope_mzs,
+        ElementCounts,
+    };
+
+    #[test]
+    fn test_precursor_isotope_mzs() {
+        let mzs = precursor_isotope_mzs(1808.902788, 2, 4);
+        assert_eq!(mzs.len(), 4);
+        assert!((mzs[1] - 905.458670).abs() < 1e-4, "{:?}", mzs);
+        assert!((mzs[2] - mzs[1] - 0.5016774).abs() < 1e-6);
+        assert!(mzs[0] < mzs[1]);
+    }
+
+    #[test]
+    fn test_monoisotopic_precursor_mz() {
+        let mzs = [499.5, 500.0, 500.5];
+        assert_eq!(monoisotopic_precursor_mz(&mzs, None), 500.0);
+        assert_eq!(monoisotopic_precursor_mz(&mzs, Some(&[1e-3, 1.0, 0.5])), 500.0);
+        assert_eq!(monoisotopic_precursor_mz(&mzs[1..], Some(&[1.0, 0.5])), 500.0);
+        assert_eq!(monoisotopic_precursor_mz(&mzs[1..2], None), 500.0);
+    }
+
+    #[test]
+    fn test_trim_isotopes_to_range() {
+        let mzs = vec![399.5, 400.0, 400.5, 401.0];
+        assert_eq!(num_isotopes_outside_range(&mzs, (400.0, 400.8)), 2);
+
+        // The -1 peak is kept with the monoisotopic one.
+        let mut trimmed = mzs.clone();
+        let mut intensities = Some(vec![1e-3, 1.0, 0.6, 0.2]);
+        assert_eq!(trim_isotopes_to_range(&mut trimmed, &mut intensities, (400.0, 400.8)), 1);
+        assert_eq!(trimmed, vec![399.5, 400.0, 400.5]);
+        assert_eq!(intensities, Some(vec![1e-3, 1.0, 0.6]));
+        assert_eq!(monoisotopic_precursor_mz(&trimmed, intensities.as_deref()), 400.0);
+
+        // The monoisotopic peak is kept even if it is out of range.
+        let mut trimmed = mzs.clone();
+        let mut intensities = Some(vec![1e-3, 1.0, 0.6, 0.2]);
+        assert_eq!(trim_isotopes_to_range(&mut trimmed, &mut intensities, (400.2, 400.8)), 1);
+        assert_eq!(trimmed, vec![399.5, 400.0, 400.5]);
+        assert_eq!(intensities, Some(vec![1e-3, 1.0, 0.6]));
+
+        // Without expected intensities the monoisotopic peak is still found.
+        let mut trimmed = mzs.clone();
+        let mut intensities = None;
+        assert_eq!(trim_isotopes_to_range(&mut trimmed, &mut intensities, (400.0, 400.8)), 1);
+        assert_eq!(trimmed, vec![399.5, 400.0, 400.5]);
+        assert_eq!(intensities, None);
+        assert_eq!(monoisotopic_precursor_mz(&trimmed, None), 400.0);
+    }
 
     #[test]
     fn smoke_isotopes() {
@@ -67,4 +318,63 @@ mod tests {
 
         assert!(matched, "{:?} {:?}", iso, expected);
     }
+
+    #[test]
+    fn test_selenium_isotopes() {
+        let counts = ElementCounts {
+            carbon: 60,
+            sulfur: 4,
+            ..Default::default()
+        };
+        let base = peptide_isotopes_from_counts(&counts);
+        assert_eq!(base, peptide_isotopes(60, 4));
+
+        let with_se = peptide_isotopes_from_counts(&ElementCounts {
+            selenium: 1,
+            ..counts
+        });
+        assert!(with_se[2] > base[2] + 0.15, "{:?} {:?}", with_se, base);
+        // 78Se with two 13C adds more to the M peak than to the M+1 one.
+        assert!(with_se[1] < base[1], "{:?} {:?}", with_se, base);
+        let se_only = peptide_isotope_envelope(
+            &ElementCounts {
+                selenium: 1,
+                ..Default::default()
+            },
+            3,
+        );
+        assert_eq!(se_only, vec![1.0, 0.0, 0.176]);
+
+        // Two bromines make the M+2 peak the most abundant.
+        let with_br = peptide_isotopes_from_counts(&ElementCounts {
+            bromine: 2,
+            ..counts
+        });
+        assert_eq!(with_br[2], 1.0);
+        assert!(with_br[0] < 0.6, "{:?}", with_br);
+    }
+
+    #[test]
+    fn test_isotope_envelope() {
+        // PEPTIDEPINK, C52 H83 N13 O18.
+        let counts = ElementCounts {
+            carbon: 52,
+            hydrogen: 83,
+            nitrogen: 13,
+            oxygen: 18,
+            ..Default::default()
+        };
+        let envelope = peptide_isotope_envelope(&counts, 5);
+        assert_eq!(envelope.len(), 5);
+        assert_eq!(envelope[0], 1.0);
+        // ~0.63 and ~0.23 for the M+1 and M+2 peaks.
+        assert!((envelope[1] - 0.63).abs() < 0.02, "{:?}", envelope);
+        assert!((envelope[2] - 0.23).abs() < 0.02, "{:?}", envelope);
+        assert!(envelope[3] > envelope[4]);
+
+        // The first peaks do not depend on how many are computed.
+        let short = peptide_isotope_envelope(&counts, 3);
+        assert_eq!(short[..], envelope[..3]);
+        assert!(peptide_isotope_envelope(&counts, 0).is_empty());
+    }
 }
@@ -2,7 +2,15 @@ pub mod data_sources;
 pub mod digest;
 pub mod errors;
 pub mod fragment_mass;
+pub mod intensity_prediction;
 pub mod isotopes;
+pub mod metrics;
+pub mod mobility_prediction;
 pub mod models;
+pub mod numeric;
+pub mod progress;
 pub mod protein;
+pub mod query_cache;
+pub mod rt_prediction;
 pub mod scoring;
+pub mod search_space;
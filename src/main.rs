@@ -1,9 +1,10 @@
 use log::info;
 use rayon::prelude::*;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::time::Instant;
 use timsquery::models::aggregators::raw_peak_agg::multi_chromatogram_agg::multi_chromatogram_agg::{NaturalFinalizedMultiCMGStatsArrays, ApexScores};
+use timsquery::models::aggregators::raw_peak_agg::point_agg::RawPeakVectorAggregator;
 use timsquery::models::aggregators::MultiCMGStatsFactory;
 use timsquery::models::indices::transposed_quad_index::QuadSplittedTransposedIndex;
 use timsquery::queriable_tims_data::queriable_tims_data::query_multi_group;
@@ -11,18 +12,52 @@ use timsquery::traits::tolerance::{
     DefaultTolerance, MobilityTolerance, MzToleramce, QuadTolerance, RtTolerance,
 };
 use timsquery::ElutionGroup;
-use timsseek::digest::digestion::{DigestionEnd, DigestionParameters, DigestionPattern};
+use timsseek::digest::cache::{digest_cache_key, read_digest_cache, write_digest_cache};
+use timsseek::digest::decoys::{CleavageSites, DecoyStrategy, SplitMix64};
+use timsseek::digest::digestion::{DigestionParameters, EnzymeSpec, InitiatorMethionine, TerminalClipping};
+use timsseek::digest::prioritization::{limit_peptides_per_protein, sort_by_detectability};
 use timsseek::errors::TimsSeekError;
-use timsseek::fragment_mass::elution_group_converter::SequenceToElutionGroupConverter;
-use timsseek::fragment_mass::fragment_mass_builder::SafePosition;
-use timsseek::protein::fasta::ProteinSequenceCollection;
-use timsseek::scoring::search_results::{IonSearchResults, write_results_to_csv};
-use timsseek::models::{DigestSlice, deduplicate_digests, NamedQueryChunk};
+use timsseek::isotopes::{monoisotopic_precursor_mz, trim_isotopes_to_range};
+use timsseek::fragment_mass::elution_group_converter::{queries_for_sequence, Adduct, SequenceToElutionGroupConverter};
+use timsseek::fragment_mass::fragment_mass_builder::{DiagnosticIons, InternalFragments, SafePosition};
+use timsseek::fragment_mass::modifications::{resolve_modification, resolved_modification_mass, CustomModification, FixedModification, VariableModifications};
+use timsseek::protein::fasta::{ProteinAnnotations, ProteinSequenceCollection, ProteinSequenceNmerIndex};
+use timsseek::protein::inference::{assign_peptides, write_protein_map_to_csv, ProteinEvidence};
+use timsseek::fragment_mass::labeling::{HeavyLabel, IsobaricLabeling};
+use timsseek::intensity_prediction::IntensityPredictorConfig;
+use timsseek::mobility_prediction::{MobilityPredictor, MobilityPredictorConfig};
+use timsseek::progress::ChunkCostEstimator;
+use timsseek::rt_prediction::{RtModel, RtPredictorConfig};
+use timsseek::scoring::apex::{ApexScorer, ApexStrategy};
+use timsseek::scoring::fdr_preview::FdrPreview;
+use timsseek::scoring::filter::{filter_results_csv, FilterExpression};
+use timsseek::scoring::localization::assign_localization_probabilities;
+use timsseek::scoring::irt_calibration::{anchor_observation, AnchorObservation, IrtAnchors, IrtCalibration};
+use timsseek::scoring::lock_mass::{lock_mass_error, LockMassConfig, MassCorrection};
+use timsseek::scoring::mobility_drift::{MobilityDrift, MobilityDriftTracker, MobilityRecalibrationConfig};
+use timsseek::scoring::noise::NoiseModel;
+use timsseek::scoring::noise_floor::{noise_floor_by_segment, overall_noise_floor, probe_points, NoiseFloorScorer, NoiseFloorSegment, NoisePrescan};
+use timsseek::scoring::rollup::{ChargeStateRollup, PeptideRollup};
+use timsseek::scoring::run_comparison::{combine_runs, summarize_run, write_comparison};
+use timsseek::search_space::{DatabaseStats, IsolationWindowIndex, SearchSpaceStats};
+use timsseek::scoring::scorers::{Ms1ScanRangeScorer, PsmScorer, scorers_from_names};
+use timsseek::scoring::xics::{diagnostic_frames, raw_peaks, score_trace_arrays, write_diagnostics_to_ndjson, write_score_traces_to_ndjson, write_xics_to_ndjson, xic_profiles};
+use timsseek::scoring::search_calibration::{confident_points, with_shifted_decoys, CalibrationPassConfig, ErrorDistribution, MassErrorCollector, MassErrors, SearchCalibration};
+use timsseek::scoring::search_results::{IonSearchResults, assign_channel_ratios, results_csv_headers, write_results_to_csv, write_results_to_csv_with_headers, write_results_to_ndjson};
+use timsseek::metrics::{peak_memory_kb, ChunkMetrics, MetricsWriter};
+use timsseek::models::{DecoyGenerator, DecoyMarking, DigestSlice, deduplicate_digests_with_variants, write_collapsed_variants_to_csv, NamedQueryChunk};
 use core::marker::Send;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use timsrust::readers::MetadataReader;
+use timsrust::Metadata;
+use signal_hook::consts::{SIGINT, SIGTERM};
 use rayon::prelude::*;
-use timsseek::data_sources::speclib::Speclib;
-use clap::Parser;
+use timsseek::data_sources::peptide_list::read_peptide_list;
+use timsseek::data_sources::raw_data::check_raw_data_path;
+use timsseek::query_cache::{QueryCache, QueryCacheKey};
+use timsseek::data_sources::speclib::{precursor_key, DecoyCollision, DecoyCollisionHandling, Speclib, SpeclibDecoys};
+use clap::{Parser, Subcommand};
 use serde::{
     Deserialize,
     Serialize,
@@ -30,31 +65,236 @@ use serde::{
 use std::path::PathBuf;
 use indicatif::ProgressIterator;
 use indicatif::{
+    HumanDuration,
+    ProgressBar,
     ProgressStyle,
 };
 
-fn process_chunk<'a>(
-    queries: NamedQueryChunk,
+/// Tolerances of the queries of a chunk, the ones with a predicted RT get
+/// the RT window of the predictor (if there is one).
+#[derive(Debug, Clone)]
+struct ChunkTolerance {
+    base: StageTolerance,
+    predicted_rt: Option<StageTolerance>,
+}
+
+impl ChunkTolerance {
+    fn new(analysis: &AnalysisConfig, base: StageTolerance) -> Self {
+        let predicted_rt = analysis
+            .rt_predictor
+            .search_window_seconds()
+            .map(|_| base.map(|x| analysis.with_rt_window(x)));
+        Self { base, predicted_rt }
+    }
+
+    /// Same tolerances, each transformed by `f`.
+    fn map<F: Fn(&DefaultTolerance) -> DefaultTolerance>(&self, f: F) -> Self {
+        Self {
+            base: self.base.map(&f),
+            predicted_rt: self.predicted_rt.as_ref().map(|x| x.map(&f)),
+        }
+    }
+
+    fn of_query(&self, rt_predicted: bool) -> &StageTolerance {
+        match (&self.predicted_rt, rt_predicted) {
+            (Some(tolerance), true) => tolerance,
+            _ => &self.base,
+        }
+    }
+}
+
+/// Index of the run with the aggregators of its queries, and the cache of
+/// the extraction results if `query_cache_size` is set.
+struct QuerySource<'a> {
     index: &'a QuadSplittedTransposedIndex,
     factory: &'a MultiCMGStatsFactory<SafePosition>,
-    tolerance: &'a DefaultTolerance,
-) -> Vec<IonSearchResults> {
+    cache: Option<Mutex<QueryCache<NaturalFinalizedMultiCMGStatsArrays<SafePosition>>>>,
+    /// Converters of the raw peaks of the diagnosed precursors, only read if
+    /// there are any.
+    metadata: Option<Metadata>,
+}
+
+impl<'a> QuerySource<'a> {
+    fn new(
+        index: &'a QuadSplittedTransposedIndex,
+        factory: &'a MultiCMGStatsFactory<SafePosition>,
+        cache_size: Option<usize>,
+    ) -> Self {
+        Self {
+            index,
+            factory,
+            cache: cache_size.map(|x| Mutex::new(QueryCache::new(x))),
+            metadata: None,
+        }
+    }
+
+    fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+}
+
+/// Raw peaks matched in every frame by the queries of the diagnosed
+/// sequences (see [`OutputConfig::diagnostic_sequences`]), None for the other
+/// queries.
+fn diagnostic_peaks(
+    queries: &NamedQueryChunk,
+    sequences: &HashSet<&str>,
+    source: &QuerySource,
+    tolerance: &ChunkTolerance,
+) -> Vec<Option<serde_json::Value>> {
+    let Some(metadata) = &source.metadata else {
+        return vec![None; queries.len()];
+    };
+    let build = |x: &ElutionGroup<SafePosition>| RawPeakVectorAggregator::new(x.id);
+    queries
+        .digests()
+        .iter()
+        .zip(&queries.queries)
+        .zip(queries.rt_predicted())
+        .map(|((digest, query), rt_predicted)| {
+            let sequence: String = digest.clone().into();
+            if !sequences.contains(sequence.as_str()) {
+                return None;
+            }
+            let tolerance = &tolerance.of_query(*rt_predicted).fragment;
+            let query = std::slice::from_ref(query);
+            let peaks = query_multi_group(source.index, tolerance, query, &build);
+            peaks
+                .first()
+                .map(|x| diagnostic_frames(raw_peaks(x, metadata)))
+        })
+        .collect()
+}
+
+/// Queries the elution groups of a chunk, the ones with a predicted RT and
+/// the others separately if they have different tolerances. The results are
+/// in the order of the queries.
+fn query_chunk(
+    queries: &NamedQueryChunk,
+    source: &QuerySource,
+    tolerance: &ChunkTolerance,
+) -> Vec<NaturalFinalizedMultiCMGStatsArrays<SafePosition>> {
+    let rt_predicted = queries.rt_predicted();
+    if tolerance.predicted_rt.is_none() || !rt_predicted.iter().any(|x| *x) {
+        return query_stage(&queries.queries, source, &tolerance.base);
+    }
+    let (windowed, rest): (Vec<usize>, Vec<usize>) =
+        (0..queries.len()).partition(|i| rt_predicted[*i]);
+    let mut out: Vec<Option<NaturalFinalizedMultiCMGStatsArrays<SafePosition>>> =
+        (0..queries.len()).map(|_| None).collect();
+    for (ids, rt_predicted) in [(windowed, true), (rest, false)] {
+        if ids.is_empty() {
+            continue;
+        }
+        let group: Vec<ElutionGroup<SafePosition>> =
+            ids.iter().map(|i| queries.queries[*i].clone()).collect();
+        let res = query_stage(&group, source, tolerance.of_query(rt_predicted));
+        for (i, res_elem) in ids.into_iter().zip(res) {
+            out[i] = Some(res_elem);
+        }
+    }
+    out.into_iter().map(|x| x.unwrap()).collect()
+}
+
+/// Queries the elution groups with the stage tolerance, only the ones
+/// missing from the cache (if any) hit the index.
+fn query_stage(
+    queries: &[ElutionGroup<SafePosition>],
+    source: &QuerySource,
+    tolerance: &StageTolerance,
+) -> Vec<NaturalFinalizedMultiCMGStatsArrays<SafePosition>> {
+    let Some(cache) = &source.cache else {
+        return query_index(queries, source, tolerance);
+    };
+    let keys: Vec<QueryCacheKey> = queries
+        .iter()
+        .map(|x| QueryCacheKey::new(x, tolerance))
+        .collect();
+    let mut cache = cache.lock().unwrap();
+    let mut out: Vec<Option<NaturalFinalizedMultiCMGStatsArrays<SafePosition>>> =
+        keys.iter().map(|x| cache.get(x)).collect();
+    let missing: Vec<usize> = (0..queries.len()).filter(|i| out[*i].is_none()).collect();
+    if !missing.is_empty() {
+        let group: Vec<ElutionGroup<SafePosition>> =
+            missing.iter().map(|i| queries[*i].clone()).collect();
+        for (i, res_elem) in missing.into_iter().zip(query_index(&group, source, tolerance)) {
+            cache.insert(keys[i], res_elem.clone());
+            out[i] = Some(res_elem);
+        }
+    }
+    out.into_iter().map(|x| x.unwrap()).collect()
+}
+
+/// Queries the elution groups with the fragment tolerance of the stage, and
+/// again with its precursor tolerance for the MS1 traces if it has one.
+fn query_index(
+    queries: &[ElutionGroup<SafePosition>],
+    source: &QuerySource,
+    tolerance: &StageTolerance,
+) -> Vec<NaturalFinalizedMultiCMGStatsArrays<SafePosition>> {
+    let index = source.index;
+    let build = |x: &ElutionGroup<SafePosition>| source.factory.build_with_elution_group(x);
+    let mut res = query_multi_group(index, &tolerance.fragment, queries, &build);
+    if let Some(precursor) = &tolerance.precursor {
+        let ms1 = query_multi_group(index, precursor, queries, &build);
+        for (res_elem, ms1_elem) in res.iter_mut().zip(ms1) {
+            res_elem.ms1_stats = ms1_elem.ms1_stats;
+        }
+    }
+    res
+}
+
+fn process_chunk<'a>(
+    queries: NamedQueryChunk,
+    source: &'a QuerySource,
+    tolerance: &'a ChunkTolerance,
+    scorers: &'a [Box<dyn PsmScorer>],
+    output: &'a OutputConfig,
+    proteins: &'a ProteinAnnotations,
+) -> std::result::Result<(Vec<IonSearchResults>, ChunkMetrics), TimsSeekError> {
+    let xic_max_points = output.xic_max_points;
+    let score_traces = output.score_traces;
+    let diagnostic_sequences: HashSet<&str> = output
+        .diagnostic_sequences
+        .iter()
+        .map(|x| x.as_str())
+        .collect();
     let start = Instant::now();
     let num_queries = queries.len();
-    let res = query_multi_group(index, tolerance, &queries.queries, &|x| {
-        factory.build_with_elution_group(x)
-    });
-    let elap_time = start.elapsed();
-    info!("Querying + Aggregation took {:?}", elap_time);
+    let res = query_chunk(&queries, source, tolerance);
+    let raw_frames = diagnostic_peaks(&queries, &diagnostic_sequences, source, tolerance);
+    let rt_predicted = queries.rt_predicted().to_vec();
+    let query_time = start.elapsed();
+    info!("Querying + Aggregation took {:?}", query_time);
 
     let start = Instant::now();
 
     let tmp: Vec<(IonSearchResults, f64)> = res
         .into_par_iter()
+        .zip(rt_predicted.into_par_iter())
+        .zip(raw_frames.into_par_iter())
         .zip(queries.into_zip_par_iter())
-        .map(|(res_elem, (eg_elem, (digest, charge_elem)))| {
+        .map(|(((res_elem, rt_predicted), raw_frames), (eg_elem, (digest, charge_elem, channel)))| {
             let decoy = digest.decoy;
-            let res = IonSearchResults::new(digest.clone(), charge_elem, &eg_elem, res_elem, decoy);
+            let diagnostics = raw_frames.map(|frames| {
+                serde_json::json!({
+                    "elution_group": eg_elem,
+                    "tolerance": tolerance.of_query(rt_predicted),
+                    "frames": frames,
+                })
+            });
+            let trace_profiles = score_traces.then(|| score_trace_arrays(&res_elem));
+            let xic_profiles = xic_max_points.map(|x| xic_profiles(&res_elem, x));
+            let res = IonSearchResults::new(
+                digest.clone(),
+                charge_elem,
+                &eg_elem,
+                res_elem,
+                decoy,
+                channel,
+                scorers,
+            );
             if res.is_err() {
                 log::error!(
                     "Error creating Digest: {:#?} \nElutionGroup: {:#?}\n Error: {:?}",
@@ -64,7 +304,12 @@ fn process_chunk<'a>(
                 );
                 return None;
             }
-            let res = res.unwrap();
+            let mut res = res.unwrap();
+            res.xic_profiles = xic_profiles;
+            res.score_traces = trace_profiles;
+            res.diagnostics = diagnostics;
+            res.protein_names = proteins.names_of(digest.protein_ids());
+            res.is_contaminant = proteins.any_contaminant(digest.protein_ids());
             let main_score = res.score_data.main_score;
             Some((res, main_score))
         })
@@ -72,15 +317,22 @@ fn process_chunk<'a>(
         .collect();
 
     if tmp.is_empty() {
-        // TODO: Remove this and check the error elsewhere.
-        panic!("No results found");
+        return Err(TimsSeekError::SearchError {
+            msg: "No results found".to_string(),
+        });
     }
 
-    let (out, main_scores): (Vec<IonSearchResults>, Vec<f64>) = tmp.into_iter().unzip();
+    let (mut out, main_scores): (Vec<IonSearchResults>, Vec<f64>) = tmp.into_iter().unzip();
+    assign_channel_ratios(&mut out);
+    assign_localization_probabilities(&mut out);
 
     let avg_main_scores = main_scores.iter().sum::<f64>() / main_scores.len() as f64;
 
-    assert!(!avg_main_scores.is_nan());
+    if avg_main_scores.is_nan() {
+        return Err(TimsSeekError::SearchError {
+            msg: "Average main score is NaN".to_string(),
+        });
+    }
     let elapsed = start.elapsed();
     log::info!(
         "Bundling took {:?} for {} elution_groups",
@@ -89,7 +341,14 @@ fn process_chunk<'a>(
     );
     log::info!("Avg main score: {:?}", avg_main_scores);
 
-    out
+    let metrics = ChunkMetrics {
+        num_queries,
+        num_results: out.len(),
+        query: query_time,
+        scoring: elapsed,
+        ..Default::default()
+    };
+    Ok((out, metrics))
 }
 
 struct DigestedSequenceIterator {
@@ -98,7 +357,81 @@ struct DigestedSequenceIterator {
     max_iterations: usize,
     iteration_index: usize,
     converter: SequenceToElutionGroupConverter,
-    build_decoys: bool,
+    decoys_per_target: usize,
+    decoy_generator: Box<dyn DecoyGenerator>,
+    decoy_order: DecoyOrder,
+    /// Target sequences (I -> L if I/L are equivalent), decoys matching one
+    /// of them are dropped.
+    target_sequences: HashSet<String>,
+    il_equivalent: bool,
+    num_colliding_decoys: usize,
+    /// Counts of the steps that built `digest_sequences`.
+    database_stats: DatabaseStats,
+}
+
+/// Tolerances of the stages of a run.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct ToleranceProfiles {
+    /// Noise, lock mass and iRT anchor pre-scans.
+    prescan: Option<ToleranceProfile>,
+    /// The search, and the calibration pass of a two-pass search.
+    discovery: Option<ToleranceProfile>,
+    /// The calibrated search of a two-pass search, before its RT and 1/K0
+    /// windows are narrowed by the calibration. The discovery one if missing.
+    requantification: Option<ToleranceProfile>,
+}
+
+/// Precursor and fragment tolerances of a stage, each with the same fields
+/// as the `tolerance` of the analysis.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct ToleranceProfile {
+    /// MS1 precursor tracing, the fragment one if missing.
+    precursor: Option<DefaultTolerance>,
+    /// MS2 fragment extraction, the `tolerance` of the analysis if missing.
+    fragment: Option<DefaultTolerance>,
+}
+
+/// Tolerances a stage queries with, the MS2 traces use the fragment one and
+/// the MS1 traces the precursor one (the fragment one if None).
+#[derive(Debug, Clone, Serialize)]
+struct StageTolerance {
+    fragment: DefaultTolerance,
+    precursor: Option<DefaultTolerance>,
+}
+
+impl StageTolerance {
+    /// Same tolerances, each transformed by `f`.
+    fn map<F: Fn(&DefaultTolerance) -> DefaultTolerance>(&self, f: F) -> Self {
+        Self {
+            fragment: f(&self.fragment),
+            precursor: self.precursor.as_ref().map(&f),
+        }
+    }
+}
+
+/// What to do with the precursor isotope peaks outside the MS1 scan range.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Ms1RangeHandling {
+    /// Do not query them.
+    #[default]
+    Trim,
+    /// Query them and report how many there are.
+    Flag,
+}
+
+/// Order in which the decoy chunks are searched.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DecoyOrder {
+    /// Every target chunk is followed by its decoy chunks, so the
+    /// target/decoy balance is kept all through the run.
+    #[default]
+    Interleaved,
+    /// All the target chunks first, then all the decoy chunks.
+    AfterTargets,
 }
 
 impl DigestedSequenceIterator {
@@ -106,21 +439,78 @@ impl DigestedSequenceIterator {
         digest_sequences: Vec<DigestSlice>,
         chunk_size: usize,
         converter: SequenceToElutionGroupConverter,
-        build_decoys: bool,
+        decoys_per_target: usize,
+        decoy_generator: Box<dyn DecoyGenerator>,
     ) -> Self {
-        let max_iterations = digest_sequences.len() / chunk_size;
+        // The generated decoys copy the pair id of their target.
+        let digest_sequences: Vec<DigestSlice> = digest_sequences
+            .into_iter()
+            .enumerate()
+            .map(|(i, x)| match x.decoy {
+                DecoyMarking::Target => x.with_target_decoy_pair_id(i as u64),
+                _ => x,
+            })
+            .collect();
+        let max_iterations = digest_sequences.len().div_ceil(chunk_size);
         Self {
             digest_sequences,
             chunk_size,
             max_iterations,
             converter,
             iteration_index: 0,
-            build_decoys,
+            decoys_per_target,
+            decoy_generator,
+            decoy_order: DecoyOrder::Interleaved,
+            target_sequences: HashSet::new(),
+            il_equivalent: false,
+            num_colliding_decoys: 0,
+            database_stats: DatabaseStats::default(),
+        }
+    }
+
+    fn with_database_stats(mut self, database_stats: DatabaseStats) -> Self {
+        self.database_stats = database_stats;
+        self
+    }
+
+    /// Drops the decoys whose sequence is also a target sequence.
+    fn with_target_collision_filter(mut self, il_equivalent: bool) -> Self {
+        self.il_equivalent = il_equivalent;
+        self.target_sequences = self
+            .digest_sequences
+            .iter()
+            .filter(|x| x.decoy == DecoyMarking::Target)
+            .map(|x| self.collision_key(x.clone().into()))
+            .collect();
+        self
+    }
+
+    fn collision_key(&self, sequence: String) -> String {
+        if self.il_equivalent {
+            sequence.replace('I', "L")
+        } else {
+            sequence
+        }
+    }
+
+    fn with_decoy_order(mut self, decoy_order: DecoyOrder) -> Self {
+        self.decoy_order = decoy_order;
+        self
+    }
+
+    /// Shuffles the order of the sequences (and so of the queries in the
+    /// chunks) with a seeded Fisher-Yates shuffle.
+    fn with_shuffled_order(mut self, seed: u64) -> Self {
+        let mut rng = SplitMix64::new(seed);
+        for i in (1..self.digest_sequences.len()).rev() {
+            let j = rng.next_below(i + 1);
+            self.digest_sequences.swap(i, j);
         }
+        self
     }
 
     fn get_chunk_digests(&self, chunk_index: usize) -> &[DigestSlice] {
-        let start = chunk_index * self.chunk_size;
+        let start = (chunk_index * self.chunk_size).min(self.digest_sequences.len());
         let end = start + self.chunk_size;
         let end = if end > self.digest_sequences.len() {
             self.digest_sequences.len()
@@ -133,26 +523,76 @@ impl DigestedSequenceIterator {
     fn get_chunk(&self, chunk_index: usize) -> NamedQueryChunk {
         let seqs = self.get_chunk_digests(chunk_index);
         let (eg_seq, eg_chunk, charge_chunk) = self.converter.convert_sequences(seqs).unwrap();
-        let eg_seq = eg_seq.into_iter().cloned().collect();
-        NamedQueryChunk::new(eg_seq, charge_chunk, eg_chunk)
+        self.new_chunk(eg_seq, charge_chunk, eg_chunk)
     }
 
-    fn get_decoy_chunk(&self, chunk_index: usize) -> NamedQueryChunk {
+    /// Chunk of the converted queries, marking the ones with a predicted RT.
+    fn new_chunk(
+        &self,
+        digests: Vec<DigestSlice>,
+        charges: Vec<u8>,
+        queries: Vec<ElutionGroup<SafePosition>>,
+    ) -> NamedQueryChunk {
+        let rt_predicted = digests
+            .iter()
+            .map(|x| self.converter.has_predicted_rt(&x.peptidoform()))
+            .collect();
+        NamedQueryChunk::new(digests, charges, queries).with_predicted_rts(rt_predicted)
+    }
+
+    /// Decoys of the chunk for the given replicate, decoys that are the same
+    /// as any target are dropped.
+    fn get_decoy_chunk(&mut self, chunk_index: usize, replicate: usize) -> NamedQueryChunk {
         let seqs = self.get_chunk_digests(chunk_index);
         let decoys = seqs
             .iter()
-            .map(|x| x.as_decoy())
+            .filter_map(|x| self.decoy_generator.decoy(x, replicate))
             .enumerate()
             .collect::<Vec<(usize, DigestSlice)>>();
-        // NOTE: RN I am not checking if the decoy is also a target ... bc its hard ...
-        // .filter(|(_i, x)| !self.digest_sequences.contains(&x.as_str()))
+        let num_decoys = decoys.len();
+        let decoys: Vec<(usize, DigestSlice)> = decoys
+            .into_iter()
+            .filter(|(_i, x)| {
+                !self
+                    .target_sequences
+                    .contains(&self.collision_key(x.clone().into()))
+            })
+            .collect();
+        let num_dropped = num_decoys - decoys.len();
+        if num_dropped > 0 {
+            log::debug!(
+                "Dropped {} decoys matching a target in chunk {}",
+                num_dropped,
+                chunk_index
+            );
+            self.num_colliding_decoys += num_dropped;
+        }
 
         let (eg_seq, eg_chunk, charge_chunk) = self
             .converter
             .convert_enumerated_sequences(&decoys)
             .unwrap();
-        let eg_seq = eg_seq.into_iter().cloned().collect();
-        NamedQueryChunk::new(eg_seq, charge_chunk, eg_chunk)
+        self.new_chunk(eg_seq, charge_chunk, eg_chunk)
+    }
+
+    /// Logs the decoys and precursors dropped while building the chunks, at
+    /// the end of the iteration.
+    fn log_dropped(&mut self) {
+        if self.num_colliding_decoys > 0 {
+            log::info!(
+                "Dropped {} decoys matching a target sequence",
+                self.num_colliding_decoys
+            );
+            self.num_colliding_decoys = 0;
+        }
+        let num_skipped = self.converter.take_num_skipped_few_fragments();
+        if num_skipped > 0 {
+            log::info!(
+                "Skipped {} precursors with fewer than {} fragments",
+                num_skipped,
+                self.converter.min_fragments
+            );
+        }
     }
 }
 
@@ -160,41 +600,55 @@ impl Iterator for DigestedSequenceIterator {
     type Item = NamedQueryChunk;
 
     fn next(&mut self) -> Option<Self::Item> {
-        // If its an even iteration, we return the targets.
-        // And if its an odd iteration, we return the decoys.
-        // IF the struct is requested to build decoys.
-        let mut decoy_batch = false;
-        let index_use;
-        if self.build_decoys {
-            index_use = self.iteration_index / 2;
-            let decoy_index = self.iteration_index % 2;
-            if decoy_index == 1 {
-                decoy_batch = true;
+        if self.digest_sequences.is_empty() {
+            return None;
+        }
+        let (index_use, batch_offset) = match self.decoy_order {
+            // Every target batch is followed by `decoys_per_target` decoy
+            // batches built from the same digests.
+            DecoyOrder::Interleaved => {
+                let batches_per_chunk = 1 + self.decoys_per_target;
+                (
+                    self.iteration_index / batches_per_chunk,
+                    self.iteration_index % batches_per_chunk,
+                )
             }
-            self.iteration_index += 1;
-        } else {
-            index_use = self.iteration_index;
-            self.iteration_index += 1;
+            DecoyOrder::AfterTargets => {
+                let num_chunks = self.digest_sequences.len().div_ceil(self.chunk_size);
+                if self.iteration_index < num_chunks {
+                    (self.iteration_index, 0)
+                } else {
+                    let decoy_index = self.iteration_index - num_chunks;
+                    let replicate = decoy_index / num_chunks;
+                    if replicate >= self.decoys_per_target {
+                        self.log_dropped();
+                        return None;
+                    }
+                    (decoy_index % num_chunks, 1 + replicate)
+                }
+            }
+        };
+        // Chunks may come out empty (eg. all decoys dropped), the iteration
+        // only ends past the last digest.
+        if self.get_chunk_digests(index_use).is_empty() {
+            self.log_dropped();
+            return None;
         }
+        self.iteration_index += 1;
 
-        let out = if decoy_batch {
-            self.get_decoy_chunk(index_use)
+        let out = if batch_offset > 0 {
+            self.get_decoy_chunk(index_use, batch_offset - 1)
         } else {
             self.get_chunk(index_use)
         };
-
-        if out.is_empty() { None } else { Some(out) }
+        Some(out)
     }
 }
 
 impl ExactSizeIterator for DigestedSequenceIterator {
     fn len(&self) -> usize {
-        let num_chunks = self.digest_sequences.len() / self.chunk_size;
-        if self.build_decoys {
-            num_chunks * 2
-        } else {
-            num_chunks
-        }
+        let num_chunks = self.digest_sequences.len().div_ceil(self.chunk_size);
+        num_chunks * (1 + self.decoys_per_target)
     }
 }
 
@@ -203,64 +657,966 @@ fn main_loop<'a>(
     // def_converter: &SequenceToElutionGroupConverter,
     index: &'a QuadSplittedTransposedIndex,
     factory: &'a MultiCMGStatsFactory<SafePosition>,
-    tolerance: &'a DefaultTolerance,
-    out_path: &Path,
+    analysis: &AnalysisConfig,
+    output: &OutputConfig,
+    proteins: &ProteinAnnotations,
+    database: Option<DatabaseStats>,
 ) -> std::result::Result<(), TimsSeekError> {
+    let source = QuerySource::new(index, factory, analysis.query_cache_size);
+    // Only the raw peaks of the diagnosed precursors need the converters.
+    let source = match &analysis.dotd_file {
+        Some(dotd_file) if !output.diagnostic_sequences.is_empty() => {
+            let metadata = MetadataReader::new(dotd_file).map_err(|e| {
+                TimsSeekError::ParseError {
+                    msg: format!("Error reading the metadata of {}: {:?}", dotd_file.display(), e),
+                }
+            })?;
+            source.with_metadata(metadata)
+        }
+        _ => source,
+    };
+    let noise_floor = match &analysis.noise_prescan {
+        Some(prescan) => Some(estimate_noise_floor(
+            prescan,
+            index,
+            factory,
+            &analysis.prescan_tolerance().fragment,
+            &output.directory,
+        )?),
+        None => None,
+    };
+    // The trace-based scores remove at least the noise floor of the run.
+    let mut noise = analysis.noise.clone();
+    if let Some(segments) = &noise_floor {
+        noise.intensity_floor = noise.intensity_floor.max(overall_noise_floor(segments));
+        log::info!("Scoring with an intensity floor of {:.1}", noise.intensity_floor);
+    }
+    let mut scorers = scorers_from_names(&analysis.extra_scores, &noise)?;
+    let irt_calibration = match &analysis.irt_anchors {
+        Some(anchors) => estimate_irt_calibration(
+            anchors,
+            &source,
+            &analysis.converter()?,
+            &analysis.prescan_tolerance(),
+        ),
+        None => None,
+    };
+    let lock_mass_correction = match &analysis.lock_mass {
+        Some(lock_mass) => estimate_lock_mass_correction(
+            lock_mass,
+            index,
+            factory,
+            &analysis.prescan_tolerance().fragment,
+        ),
+        None => None,
+    };
+    if let (Some(scan_range), Ms1RangeHandling::Flag) =
+        (analysis.ms1_scan_range, analysis.ms1_range_handling)
+    {
+        scorers.push(Box::new(Ms1ScanRangeScorer { scan_range }));
+    }
+    if analysis.apex_strategy != ApexStrategy::MainScore {
+        scorers.push(Box::new(ApexScorer {
+            strategy: analysis.apex_strategy,
+            smoothing_window: analysis.apex_smoothing_window.unwrap_or(5),
+        }));
+    }
+    if let Some(segments) = &noise_floor {
+        scorers.push(Box::new(NoiseFloorScorer {
+            segments: segments.clone(),
+        }));
+    }
+    let checkpoint = match output.resume {
+        true => Checkpoint::read(&output.directory)?,
+        false => None,
+    };
     let mut chunk_num = 0;
+    let mut chunks_consumed = 0;
     let mut nqueries = 0;
+    let mut failed_chunks: Vec<(usize, String)> = Vec::new();
+    if let Some(checkpoint) = checkpoint {
+        log::info!(
+            "Resuming after {} chunks ({} results)",
+            checkpoint.chunks_consumed,
+            checkpoint.num_results
+        );
+        if output.peptide_rollup || output.charge_state_rollup || output.protein_map {
+            log::warn!(
+                "The rollups and protein map of a resumed run only cover the chunks \
+                 searched after resuming"
+            );
+        }
+        chunk_num = checkpoint.next_chunk;
+        chunks_consumed = checkpoint.chunks_consumed;
+        nqueries = checkpoint.num_results;
+        failed_chunks = checkpoint.failed_chunks;
+    }
+    let mut search_space = SearchSpaceStats::default();
+    let window_index = analysis.isolation_window_index();
+    let tolerance = ChunkTolerance::new(analysis, analysis.search_tolerance());
+    let chunked_query_iterator = PrefetchedChunks::new(
+        chunked_query_iterator,
+        analysis.calibration_pass.as_ref().map_or(0, |x| x.num_chunks),
+    );
+    let search_calibration = match &analysis.calibration_pass {
+        Some(pass) => {
+            let mut pass_chunks: Vec<NamedQueryChunk> = chunked_query_iterator
+                .prefetched()
+                .iter()
+                .map(|x| with_shifted_decoys(x.clone()))
+                .collect();
+            for chunk in pass_chunks.iter_mut() {
+                if let Some(calibration) = &irt_calibration {
+                    chunk.queries.iter_mut().for_each(|x| calibration.apply(x));
+                }
+                if let Some(correction) = &lock_mass_correction {
+                    chunk.queries.iter_mut().for_each(|x| correction.apply(x));
+                }
+            }
+            run_calibration_pass(pass, pass_chunks, &source, &tolerance, &scorers, output)
+        }
+        None => None,
+    };
+    let tolerance = match &search_calibration {
+        Some(calibration) => {
+            ChunkTolerance::new(analysis, analysis.requantification_tolerance())
+                .map(|x| calibration.narrowed_tolerance(x))
+        }
+        None => tolerance,
+    };
+    if let Some(window) = analysis.rt_predictor.search_window_seconds() {
+        let predictor = &analysis.rt_predictor;
+        if predictor.model == RtModel::Builtin && predictor.calibration.is_none() {
+            log::warn!(
+                "Searching {:.0} s around uncalibrated built-in RT predictions, they \
+                 may not match the run",
+                window
+            );
+        }
+    }
     let start = Instant::now();
 
+    // The ETA in the message uses the measured cost of the chunks instead
+    // of the uniform indicatif estimate.
     let style = ProgressStyle::with_template(
-        "{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {eta})",
+        "{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {msg})",
     )
     .unwrap();
-    chunked_query_iterator
-        .progress_with_style(style)
-        .for_each(|chunk| {
-            let out = process_chunk(chunk, &index, &factory, &tolerance);
-            nqueries += out.len();
-            let out_path = out_path.join(format!("chunk_{}.csv", chunk_num));
-            write_results_to_csv(&out, out_path).unwrap();
-            chunk_num += 1;
-        });
+    let num_chunks = chunked_query_iterator.len();
+    let progress = ProgressBar::new(num_chunks as u64).with_style(style);
+    let mut estimator = ChunkCostEstimator::new(num_chunks);
+    let mut record_chunk = |num_queries: usize, chunk_start: Instant| {
+        estimator.record(num_queries, chunk_start.elapsed());
+        if let Some(eta) = estimator.remaining(None) {
+            progress.set_message(format!("ETA {}", HumanDuration(eta)));
+            if estimator.chunks_done() == num_chunks / 2 {
+                log::info!(
+                    "Half way through, estimated total time: {}",
+                    HumanDuration(start.elapsed() + eta)
+                );
+            }
+        }
+    };
+    let mut rollup = PeptideRollup::default();
+    let mut charge_rollup = ChargeStateRollup::default();
+    let mut fdr_preview = FdrPreview::default();
+    // Protein maps are only written for FASTA databases.
+    let protein_map = output.protein_map && database.is_some();
+    let mut protein_evidence = ProteinEvidence::default();
+    let mut mobility_drift = analysis
+        .mobility_recalibration
+        .clone()
+        .map(MobilityDriftTracker::new);
+    // Errors left after the calibration, reported next to the ones before.
+    let mut mass_error_collector = search_calibration
+        .as_ref()
+        .map(|_| MassErrorCollector::default());
+    // Only appended to if the run resumes from a checkpoint.
+    let mut metrics_writer =
+        MetricsWriter::new(&output.directory.join("metrics.tsv"), chunks_consumed > 0)?;
+    let shutdown = shutdown_flag()?;
+    let mut interrupted = false;
+    let mut chunks = chunked_query_iterator.progress_with(progress.clone());
+    // The chunks are built deterministically, the ones already searched are
+    // skipped.
+    let resumed_chunks = chunks_consumed;
+    for _ in 0..chunks_consumed {
+        chunks.next();
+    }
+    loop {
+        // Chunks are built lazily, so pulling one includes the conversion.
+        let conversion_start = Instant::now();
+        let Some(chunk) = chunks.next() else {
+            break;
+        };
+        let conversion = conversion_start.elapsed();
+        // The in-flight chunk is always finished (and written), so no output
+        // file is left half-written.
+        if shutdown.load(Ordering::Relaxed) {
+            log::warn!("Interrupted, stopping after {} chunks", chunk_num);
+            interrupted = true;
+            break;
+        }
+        chunks_consumed += 1;
+        let chunk_start = Instant::now();
+        let chunk = match &window_index {
+            Some(window_index) => {
+                let num_total = chunk.len();
+                let chunk = chunk.retain_by_precursor_mz(|mz| window_index.contains(mz));
+                search_space.total += num_total;
+                search_space.queryable += chunk.len();
+                chunk
+            }
+            None => chunk,
+        };
+        if chunk.is_empty() {
+            record_chunk(0, chunk_start);
+            continue;
+        }
+        let mut chunk = match &analysis.labeling {
+            Some(label) => chunk.with_heavy_channels(label),
+            None => chunk,
+        };
+        if let Some(calibration) = &irt_calibration {
+            chunk.queries.iter_mut().for_each(|x| calibration.apply(x));
+        }
+        if let Some(correction) = &lock_mass_correction {
+            chunk.queries.iter_mut().for_each(|x| correction.apply(x));
+        }
+        if let Some(calibration) = &search_calibration {
+            chunk.queries.iter_mut().for_each(|x| calibration.apply(x));
+        }
+        let mobility_offset = mobility_drift
+            .as_ref()
+            .and_then(|x| x.drift())
+            .map(|x| x.offset);
+        if let Some(offset) = mobility_offset {
+            chunk
+                .queries
+                .iter_mut()
+                .for_each(|x| x.mobility += offset as f32);
+        }
+        if let (Some(scan_range), Ms1RangeHandling::Trim) =
+            (analysis.ms1_scan_range, analysis.ms1_range_handling)
+        {
+            for query in chunk.queries.iter_mut() {
+                let num_trimmed = trim_isotopes_to_range(
+                    &mut query.precursor_mzs,
+                    &mut query.expected_precursor_intensity,
+                    scan_range,
+                );
+                if num_trimmed > 0 {
+                    search_space.isotope_trimmed_elution_groups += 1;
+                }
+            }
+        }
+        search_space.add_queried(&chunk);
+        // Chunks failing on I/O (eg. writing their outputs) are retried as
+        // a whole, other errors would fail the same way again. Chunks that
+        // still fail are recorded and the run continues with the next one.
+        let mut attempt = 0;
+        let res = loop {
+            let res = process_chunk(
+                chunk.clone(),
+                &source,
+                &tolerance,
+                &scorers,
+                output,
+                proteins,
+            )
+            .and_then(|(out, mut metrics)| {
+                let write_start = Instant::now();
+                write_chunk_outputs(&out, chunk_num, output)?;
+                metrics.write = write_start.elapsed();
+                if output.peptide_rollup {
+                    rollup.add(&out);
+                }
+                if output.charge_state_rollup {
+                    charge_rollup.add(&out);
+                }
+                if analysis.fdr_preview_every.is_some() {
+                    fdr_preview.add(&out);
+                }
+                if protein_map {
+                    protein_evidence.add(&out);
+                }
+                if let Some(tracker) = mobility_drift.as_mut() {
+                    tracker.add(&out, mobility_offset.unwrap_or(0.0));
+                }
+                if let Some(collector) = mass_error_collector.as_mut() {
+                    collector.add(&out);
+                }
+                Ok(metrics)
+            });
+            match res {
+                Err(e @ TimsSeekError::Io(_)) if attempt < analysis.max_chunk_retries => {
+                    attempt += 1;
+                    log::warn!(
+                        "Chunk {} failed ({:?}), retrying ({}/{})",
+                        chunk_num,
+                        e,
+                        attempt,
+                        analysis.max_chunk_retries
+                    );
+                }
+                x => break x,
+            }
+        };
+        let mut metrics = match res {
+            Ok(metrics) => {
+                nqueries += metrics.num_results;
+                metrics
+            }
+            Err(e) => {
+                log::error!("Chunk {} failed after {} retries: {:?}", chunk_num, attempt, e);
+                failed_chunks.push((chunk_num, format!("{:?}", e)));
+                ChunkMetrics {
+                    num_queries: chunk.len(),
+                    ..Default::default()
+                }
+            }
+        };
+        metrics.chunk = chunk_num;
+        metrics.conversion = conversion;
+        metrics.mobility_offset = mobility_offset;
+        metrics.peak_memory_kb = peak_memory_kb();
+        metrics_writer.write(&metrics)?;
+        record_chunk(chunk.len(), chunk_start);
+        chunk_num += 1;
+        let checkpoint = Checkpoint {
+            chunks_consumed,
+            next_chunk: chunk_num,
+            num_results: nqueries,
+            failed_chunks: failed_chunks.clone(),
+        };
+        checkpoint.write(&output.directory)?;
+        if analysis
+            .fdr_preview_every
+            .is_some_and(|every| every > 0 && chunk_num % every == 0)
+        {
+            log_fdr_preview(&fdr_preview, chunk_num);
+        }
+    }
     let elap_time = start.elapsed();
-    println!("Querying took {:?} for {} queries", elap_time, nqueries);
+    eprintln!("Querying took {:?} for {} queries", elap_time, nqueries);
+    if window_index.is_some() {
+        eprintln!(
+            "{} of {} precursors ({:.2}%) fall within the acquisition windows",
+            search_space.queryable,
+            search_space.total,
+            100.0 * search_space.queryable_fraction(),
+        );
+    }
+    if search_space.isotope_trimmed_elution_groups > 0 {
+        log::info!(
+            "{} precursors queried without the isotope peaks outside the MS1 scan range",
+            search_space.isotope_trimmed_elution_groups
+        );
+    }
+    if let Some(drift) = mobility_drift.as_ref().and_then(|x| x.drift()) {
+        log::info!(
+            "1/K0 error of the run: {:.4} from {} confident targets",
+            drift.offset,
+            drift.num_points
+        );
+    }
+    let recalibrated_mass_errors = match (&analysis.calibration_pass, &mass_error_collector) {
+        (Some(pass), Some(collector)) => collector.mass_errors(pass.fdr),
+        _ => None,
+    };
+    if let (Some(calibration), Some(after)) = (&search_calibration, &recalibrated_mass_errors) {
+        log_mass_errors(&calibration.mass_errors, after);
+    }
+    if !failed_chunks.is_empty() {
+        log::error!(
+            "{} chunks failed, see failed_chunks.csv",
+            failed_chunks.len()
+        );
+        write_failed_chunks(&failed_chunks, &output.directory)
+            .map_err(|e| TimsSeekError::Io(std::io::Error::other(e.to_string())))?;
+    }
+    if output.peptide_rollup {
+        rollup
+            .write_to_csv(output.directory.join("peptide_rollup.csv"))
+            .map_err(|e| TimsSeekError::Io(std::io::Error::other(e.to_string())))?;
+    }
+    if output.charge_state_rollup {
+        charge_rollup
+            .write_to_csv(output.directory.join("modified_peptides.csv"))
+            .map_err(|e| TimsSeekError::Io(std::io::Error::other(e.to_string())))?;
+    }
+    if protein_map {
+        let assignments = protein_evidence.assignments(output.protein_map_fdr.unwrap_or(0.01));
+        if assignments.is_empty() {
+            log::warn!("No peptides pass the protein map FDR, the protein map is empty");
+        }
+        write_protein_map_to_csv(
+            &assignments,
+            proteins,
+            output.directory.join("protein_map.csv"),
+        )
+        .map_err(|e| TimsSeekError::Io(std::io::Error::other(e.to_string())))?;
+    }
+    let manifest = RunManifest {
+        complete: !interrupted,
+        total_chunks: num_chunks,
+        chunks_processed: resumed_chunks + estimator.chunks_done(),
+        num_results: nqueries,
+        failed_chunks: failed_chunks.iter().map(|(x, _)| *x).collect(),
+        database,
+        search_space,
+        noise_floor,
+        lock_mass_correction,
+        irt_calibration,
+        search_calibration,
+        mobility_drift: mobility_drift.as_ref().and_then(|x| x.drift()),
+        recalibrated_mass_errors,
+    };
+    serde_json::to_writer_pretty(
+        std::fs::File::create(output.directory.join("run_manifest.json"))?,
+        &manifest,
+    )
+    .map_err(|e| TimsSeekError::Io(std::io::Error::other(e.to_string())))?;
+    if interrupted {
+        eprintln!(
+            "Run interrupted, partial results for {} of {} chunks written to {} \
+             (continue it with --resume)",
+            manifest.chunks_processed,
+            num_chunks,
+            output.directory.display()
+        );
+    }
+    Ok(())
+}
+
+/// Progress of a run, rewritten after every searched chunk so an interrupted
+/// run can be resumed (see `OutputConfig::resume`).
+#[derive(Debug, Serialize, Deserialize)]
+struct Checkpoint {
+    /// Chunks taken from the query iterator, including the empty ones.
+    chunks_consumed: usize,
+    /// Number of the next `chunk_*.csv`.
+    next_chunk: usize,
+    num_results: usize,
+    failed_chunks: Vec<(usize, String)>,
+}
+
+impl Checkpoint {
+    const FILE_NAME: &'static str = "checkpoint.json";
+
+    /// None if the directory has no checkpoint.
+    fn read(directory: &Path) -> std::result::Result<Option<Self>, TimsSeekError> {
+        let path = directory.join(Self::FILE_NAME);
+        if !path.exists() {
+            log::warn!(
+                "No checkpoint in {}, starting from the beginning",
+                directory.display()
+            );
+            return Ok(None);
+        }
+        let checkpoint = serde_json::from_reader(std::fs::File::open(&path)?).map_err(|e| {
+            TimsSeekError::ParseError {
+                msg: format!("Error reading {}: {}", path.display(), e),
+            }
+        })?;
+        Ok(Some(checkpoint))
+    }
+
+    /// Written to a temporary file first, so an interruption never leaves a
+    /// truncated checkpoint.
+    fn write(&self, directory: &Path) -> std::result::Result<(), TimsSeekError> {
+        let path = directory.join(Self::FILE_NAME);
+        let tmp_path = directory.join(format!("{}.tmp", Self::FILE_NAME));
+        serde_json::to_writer(std::fs::File::create(&tmp_path)?, self)
+            .map_err(|e| TimsSeekError::Io(std::io::Error::other(e.to_string())))?;
+        std::fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Summary of what a run got through, written at the end of every run
+/// (including interrupted ones) so partial outputs can be told apart.
+#[derive(Debug, Serialize)]
+struct RunManifest {
+    complete: bool,
+    total_chunks: usize,
+    chunks_processed: usize,
+    num_results: usize,
+    failed_chunks: Vec<usize>,
+    /// Only set for searches of a FASTA database.
+    database: Option<DatabaseStats>,
+    search_space: SearchSpaceStats,
+    /// Only set if the noise pre-scan is on.
+    noise_floor: Option<Vec<NoiseFloorSegment>>,
+    /// Only set if lock mass ions are given and found.
+    lock_mass_correction: Option<MassCorrection>,
+    /// Only set if anchors are given and enough of them are found.
+    irt_calibration: Option<IrtCalibration>,
+    /// Only set if the calibration pass is on and found enough targets.
+    search_calibration: Option<SearchCalibration>,
+    /// Last 1/K0 error estimate of the mobility recalibration, the offsets
+    /// applied to each chunk are in metrics.tsv.
+    mobility_drift: Option<MobilityDrift>,
+    /// m/z errors of the confident targets of the calibrated search, the
+    /// ones before the calibration are in `search_calibration`.
+    recalibrated_mass_errors: Option<MassErrors>,
+}
+
+/// Logs the median MS1 and MS2 ppm errors before and after the calibration.
+fn log_mass_errors(before: &MassErrors, after: &MassErrors) {
+    let median = |x: Option<ErrorDistribution>| x.map(|x| format!("{:.2}", x.median));
+    log::info!(
+        "Median m/z errors (ppm) before/after calibration: MS1 {:?}/{:?}, MS2 {:?}/{:?}",
+        median(before.ms1_ppm),
+        median(after.ms1_ppm),
+        median(before.ms2_ppm),
+        median(after.ms2_ppm)
+    );
+}
+
+/// Searches the calibration pass chunks and fits the RT and 1/K0
+/// calibration on their confident targets, None if there are too few.
+fn run_calibration_pass(
+    pass: &CalibrationPassConfig,
+    chunks: Vec<NamedQueryChunk>,
+    source: &QuerySource,
+    tolerance: &ChunkTolerance,
+    scorers: &[Box<dyn PsmScorer>],
+    output: &OutputConfig,
+) -> Option<SearchCalibration> {
+    let start = Instant::now();
+    let proteins = ProteinAnnotations::default();
+    let mut points = Vec::new();
+    for chunk in chunks {
+        match process_chunk(chunk, source, tolerance, scorers, output, &proteins) {
+            Ok((results, _)) => points.extend(confident_points(&results, pass.fdr)),
+            Err(e) => log::warn!("Calibration pass chunk failed: {:?}", e),
+        }
+    }
+    let calibration = SearchCalibration::fit(&points, pass);
+    match &calibration {
+        Some(calibration) => log::info!(
+            "Calibration pass took {:?}: {} targets, RT window {:?} s, 1/K0 offset {:.4} \
+             and window {:.4}, MS1/MS2 m/z offsets {:?}/{:?} ppm",
+            start.elapsed(),
+            calibration.num_points,
+            calibration.rt_tolerance_seconds,
+            calibration.mobility_offset,
+            calibration.mobility_tolerance,
+            calibration.ms1_ppm_offset,
+            calibration.ms2_ppm_offset
+        ),
+        None => log::warn!(
+            "Only {} confident targets in the calibration pass, searching uncalibrated",
+            points.len()
+        ),
+    }
+    calibration
+}
+
+/// Searches the iRT anchors (with the charges, m/z ranges and modifications
+/// of the search) and fits the iRT to seconds calibration, None if too few
+/// of them are found.
+fn estimate_irt_calibration(
+    anchors: &IrtAnchors,
+    source: &QuerySource,
+    converter: &SequenceToElutionGroupConverter,
+    tolerance: &StageTolerance,
+) -> Option<IrtCalibration> {
+    let (queries, anchor_ids): (Vec<ElutionGroup<SafePosition>>, Vec<usize>) = anchors
+        .queries(converter)
+        .into_iter()
+        .unzip();
+    let res = query_stage(&queries, source, tolerance);
+    // The most intense charge state of every anchor.
+    let mut best: HashMap<usize, AnchorObservation> = HashMap::new();
+    for (arrays, anchor) in res.iter().zip(anchor_ids) {
+        let Some(observation) = anchor_observation(arrays, anchors.min_fragments) else {
+            continue;
+        };
+        let entry = best.entry(anchor).or_insert(observation);
+        if observation.summed_intensity > entry.summed_intensity {
+            *entry = observation;
+        }
+    }
+    let found: Vec<(f64, AnchorObservation)> = best
+        .into_iter()
+        .map(|(anchor, observation)| (anchors.peptides[anchor].irt, observation))
+        .collect();
+    let calibration = IrtCalibration::fit(&found, anchors.fit_mobility);
+    match &calibration {
+        Some(calibration) => log::info!(
+            "Calibrated iRT on {} of {} anchors: {:.1} s + {:.2} s per iRT unit",
+            calibration.num_anchors,
+            anchors.peptides.len(),
+            calibration.intercept,
+            calibration.slope
+        ),
+        None => log::warn!(
+            "Only {} of {} iRT anchors found, the query RTs are not calibrated",
+            found.len(),
+            anchors.peptides.len()
+        ),
+    }
+    calibration
+}
+
+/// Measures the lock mass ions and fits the fragment m/z correction, None
+/// if none of them is found.
+fn estimate_lock_mass_correction(
+    lock_mass: &LockMassConfig,
+    index: &QuadSplittedTransposedIndex,
+    factory: &MultiCMGStatsFactory<SafePosition>,
+    tolerance: &DefaultTolerance,
+) -> Option<MassCorrection> {
+    let probes = lock_mass.probe_elution_groups();
+    let res = query_multi_group(index, tolerance, &probes, &|x| {
+        factory.build_with_elution_group(x)
+    });
+    let errors: Vec<(f64, f64)> = res
+        .iter()
+        .zip(probes.iter())
+        .filter_map(|(arrays, probe)| lock_mass_error(arrays, probe))
+        .collect();
+    let correction = MassCorrection::fit(&errors, lock_mass.num_segments);
+    match &correction {
+        Some(correction) => log::info!(
+            "Lock mass ions found in {} of {} probes, median fragment error {:.2} ppm",
+            errors.len(),
+            probes.len(),
+            correction.run_ppm
+        ),
+        None => log::warn!("No lock mass ion found, fragment m/z are not corrected"),
+    }
+    correction
+}
+
+/// Queries the noise probes and writes the per-RT-segment noise floor to
+/// `noise_floor.tsv`.
+fn estimate_noise_floor(
+    prescan: &NoisePrescan,
+    index: &QuadSplittedTransposedIndex,
+    factory: &MultiCMGStatsFactory<SafePosition>,
+    tolerance: &DefaultTolerance,
+    directory: &Path,
+) -> std::result::Result<Vec<NoiseFloorSegment>, TimsSeekError> {
+    let start = Instant::now();
+    let probes = prescan.probe_elution_groups();
+    let res = query_multi_group(index, tolerance, &probes, &|x| {
+        factory.build_with_elution_group(x)
+    });
+    let points: Vec<(f64, f64)> = res.iter().flat_map(probe_points).collect();
+    let segments = noise_floor_by_segment(&points, prescan.num_segments);
+    log::info!(
+        "Noise pre-scan of {} probes took {:?}, run noise floor {:.1}",
+        probes.len(),
+        start.elapsed(),
+        overall_noise_floor(&segments)
+    );
+    write_noise_floor(&segments, directory)
+        .map_err(|e| TimsSeekError::Io(std::io::Error::other(e.to_string())))?;
+    Ok(segments)
+}
+
+fn write_noise_floor(
+    segments: &[NoiseFloorSegment],
+    directory: &Path,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_path(directory.join("noise_floor.tsv"))?;
+    for segment in segments {
+        writer.serialize(segment)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Flag set on the first SIGINT/SIGTERM, a second one exits right away.
+///
+/// The handlers are registered on the first call only, the later ones share
+/// the same flag.
+fn shutdown_flag() -> std::result::Result<Arc<AtomicBool>, TimsSeekError> {
+    static FLAG: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+    if let Some(flag) = FLAG.get() {
+        return Ok(flag.clone());
+    }
+    let flag = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, flag.clone())?;
+        signal_hook::flag::register(signal, flag.clone())?;
+    }
+    Ok(FLAG.get_or_init(|| flag).clone())
+}
+
+/// Logs the provisional FDR estimate, at 1% FDR.
+fn log_fdr_preview(preview: &FdrPreview, chunk_num: usize) {
+    match preview.summary(0.01) {
+        Some(summary) => log::info!(
+            "FDR preview after {} chunks: {} targets, {} decoys, separation {:.2}, ~{} IDs at 1% FDR",
+            chunk_num,
+            summary.num_targets,
+            summary.num_decoys,
+            summary.separation,
+            summary.ids_at_fdr,
+        ),
+        None => log::info!(
+            "FDR preview after {} chunks: no targets or no decoys searched yet",
+            chunk_num
+        ),
+    }
+}
+
+fn write_chunk_outputs(
+    out: &[IonSearchResults],
+    chunk_num: usize,
+    output: &OutputConfig,
+) -> std::result::Result<(), TimsSeekError> {
+    let as_io_error = |e: Box<dyn std::error::Error>| TimsSeekError::Io(std::io::Error::other(e.to_string()));
+    if output.xic_max_points.is_some() {
+        let xic_path = output
+            .directory
+            .join(format!("chunk_{}.xics.ndjson", chunk_num));
+        write_xics_to_ndjson(out, xic_path).map_err(as_io_error)?;
+    }
+    if output.score_traces {
+        let trace_path = output
+            .directory
+            .join(format!("chunk_{}.score_traces.ndjson", chunk_num));
+        write_score_traces_to_ndjson(out, trace_path).map_err(as_io_error)?;
+    }
+    if !output.diagnostic_sequences.is_empty() {
+        let diagnostics_path = output
+            .directory
+            .join(format!("chunk_{}.diagnostics.ndjson", chunk_num));
+        write_diagnostics_to_ndjson(out, diagnostics_path).map_err(as_io_error)?;
+    }
+    if output.stdout {
+        let stdout = std::io::stdout();
+        return write_results_to_ndjson(out, std::io::BufWriter::new(stdout.lock()))
+            .map_err(as_io_error);
+    }
+    let out_path = output.directory.join(format!("chunk_{}.csv", chunk_num));
+    if output.split_decoys {
+        let (targets, decoys): (Vec<_>, Vec<_>) = out
+            .iter()
+            .cloned()
+            .partition(|x| x.decoy == DecoyMarking::Target);
+        let headers = results_csv_headers(out);
+        write_results_to_csv_with_headers(&targets, &headers, out_path).map_err(as_io_error)?;
+        if !decoys.is_empty() {
+            let decoy_path = output
+                .directory
+                .join(format!("decoy_chunk_{}.csv", chunk_num));
+            write_results_to_csv_with_headers(&decoys, &headers, decoy_path)
+                .map_err(as_io_error)?;
+        }
+    } else {
+        write_results_to_csv(out, out_path).map_err(as_io_error)?;
+    }
+    Ok(())
+}
+
+fn write_failed_chunks(
+    failed_chunks: &[(usize, String)],
+    directory: &Path,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let mut writer = csv::Writer::from_path(directory.join("failed_chunks.csv"))?;
+    writer.write_record(["chunk", "error"])?;
+    for (chunk_num, err) in failed_chunks {
+        writer.write_record([chunk_num.to_string(), err.clone()])?;
+    }
+    writer.flush()?;
     Ok(())
 }
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// Path to the JSON configuration file
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Path to the JSON configuration file (required unless a subcommand is
+    /// used, where only the tolerance is read from it)
     #[arg(short, long)]
-    config: PathBuf,
+    config: Option<PathBuf>,
 
     /// Path to the .d file (will over-write the config file)
     #[arg(short, long)]
     dotd_file: Option<PathBuf>,
 
-    /// Path to the speclib file (will over-write the config file)
+    /// Path to the speclib file or Spectronaut library (will over-write the
+    /// config file)
     #[arg(short, long)]
     speclib_file: Option<PathBuf>,
 
     /// Path to the output directory
     #[arg(short, long)]
     output_dir: Option<PathBuf>,
-}
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Config {
-    /// Input configuration
-    input: InputConfig,
+    /// Stream the results to stdout as NDJSON (same as `output.stdout` in
+    /// the config file)
+    #[arg(long)]
+    stdout: bool,
 
-    /// Analysis parameters
-    analysis: AnalysisConfig,
+    /// Digest the FASTA file again even if a cached digestion exists
+    #[arg(long)]
+    ignore_cache: bool,
 
-    /// Output configuration
-    output: OutputConfig,
+    /// Continue an interrupted run from the checkpoint of its output
+    /// directory
+    #[arg(long)]
+    resume: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Extract the chromatograms of a peptide list, without scoring. The
+    /// queries and tolerance follow the analysis settings of `--config` if
+    /// one is given
+    Extract {
+        /// Tab separated file with a `sequence` and an optional `charge` column
+        #[arg(long)]
+        peptides: PathBuf,
+
+        /// Path to the .d file
+        #[arg(long)]
+        dotd: PathBuf,
+
+        /// Path of the output ndjson file
+        #[arg(long)]
+        out: PathBuf,
+
+        /// Maximum number of points kept per trace
+        #[arg(long)]
+        max_points: Option<usize>,
+    },
+    /// Write the predicted precursor m/z, RT and 1/K0 of a peptide list,
+    /// with the analysis settings of `--config` if one is given
+    Predict {
+        /// Tab separated file with a `sequence` and an optional `charge` column
+        #[arg(long)]
+        peptides: PathBuf,
+
+        /// Path of the output tsv file
+        #[arg(long)]
+        out: PathBuf,
+
+        /// Also write the predicted fragment m/z and intensities (as json)
+        #[arg(long)]
+        fragments: bool,
+    },
+    /// Merge results tables into one, keeping the rows passing a filter
+    Report {
+        /// Results tables (eg. the chunk_*.csv files of a run)
+        #[arg(long, num_args = 1.., required = true)]
+        results: Vec<PathBuf>,
+
+        /// Filter expression, eg. `main_score > 10 && npeaks >= 4 && !decoy`
+        #[arg(long)]
+        filter: Option<String>,
+
+        /// Path of the output csv file
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Compare the output directories of several runs (eg. a parameter
+    /// sweep), writing comparison.json and comparison.html
+    Compare {
+        /// Output directories of the runs
+        #[arg(long, num_args = 1.., required = true)]
+        runs: Vec<PathBuf>,
+
+        /// FDR the IDs are counted at
+        #[arg(long, default_value_t = 0.01)]
+        fdr: f64,
+
+        /// Directory the comparison is written to
+        #[arg(long)]
+        out: PathBuf,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+struct Config {
+    /// Input configuration
+    input: InputConfig,
+
+    /// Analysis parameters
+    analysis: AnalysisConfig,
+
+    /// Output configuration
+    output: OutputConfig,
+
+    /// Grid of tolerances searched one after the other, see [`SweepConfig`]
+    #[serde(default)]
+    sweep: SweepConfig,
+}
+
+/// Grid of tolerances searched one after the other, every setting in its own
+/// subdirectory of the output directory, followed by `comparison.json` and
+/// `comparison.html` of all of them (as the `compare` subcommand writes).
+///
+/// An empty list keeps the tolerance of the analysis config, so the grid is
+/// off unless one of them is set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct SweepConfig {
+    /// Symmetric precursor and fragment m/z tolerances (ppm) tried, eg.
+    /// `[10.0, 15.0, 20.0]`
+    ms_ppm: Vec<f64>,
+    /// Symmetric mobility tolerances (%) tried, eg. `[5.0, 10.0]`
+    mobility_pct: Vec<f64>,
+    /// FDR the IDs of the comparison are counted at
+    fdr: f64,
+}
+
+impl Default for SweepConfig {
+    fn default() -> Self {
+        Self {
+            ms_ppm: Vec::new(),
+            mobility_pct: Vec::new(),
+            fdr: 0.01,
+        }
+    }
+}
+
+impl SweepConfig {
+    /// Name (used as subdirectory) and m/z and mobility tolerances of every
+    /// setting of the grid, empty if there is no grid.
+    fn settings(&self) -> Vec<(String, Option<f64>, Option<f64>)> {
+        if self.ms_ppm.is_empty() && self.mobility_pct.is_empty() {
+            return Vec::new();
+        }
+        let ms_ppm: Vec<Option<f64>> = if self.ms_ppm.is_empty() {
+            vec![None]
+        } else {
+            self.ms_ppm.iter().copied().map(Some).collect()
+        };
+        let mobility_pct: Vec<Option<f64>> = if self.mobility_pct.is_empty() {
+            vec![None]
+        } else {
+            self.mobility_pct.iter().copied().map(Some).collect()
+        };
+        let mut out = Vec::new();
+        for ms in &ms_ppm {
+            for mobility in &mobility_pct {
+                let name = [
+                    ms.map(|x| format!("ms_{}ppm", x)),
+                    mobility.map(|x| format!("mobility_{}pct", x)),
+                ]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<String>>()
+                .join("_");
+                out.push((name, *ms, *mobility));
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 enum InputConfig {
     #[serde(rename = "fasta")]
@@ -269,7 +1625,35 @@ enum InputConfig {
         digestion: DigestionConfig,
     },
     #[serde(rename = "speclib")]
-    Speclib { path: PathBuf },
+    Speclib {
+        /// ndjson speclib, a library exported by Spectronaut (".tsv", ".xls"
+        /// or ".csv", whose RTs are iRT) or an EncyclopeDIA ".dlib"
+        path: PathBuf,
+        /// Other libraries searched together with `path` (eg. an in-silico
+        /// gap-fill), results report which library each precursor came from
+        #[serde(default)]
+        additional_paths: Vec<PathBuf>,
+        /// Decoys built for a target-only library, "none", "reverse" or
+        /// "mass_shift"
+        #[serde(default)]
+        decoys: SpeclibDecoys,
+        /// What to do with library decoys that have the sequence of a
+        /// target, "drop" or "reshuffle"
+        #[serde(default)]
+        decoy_collisions: DecoyCollisionHandling,
+    },
+    /// Spectral library plus the digests of a FASTA file, precursors in the
+    /// library are not searched again from the digests
+    #[serde(rename = "union")]
+    Union {
+        fasta: PathBuf,
+        digestion: DigestionConfig,
+        speclib: PathBuf,
+        #[serde(default)]
+        speclib_decoys: SpeclibDecoys,
+        #[serde(default)]
+        speclib_decoy_collisions: DecoyCollisionHandling,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -282,20 +1666,576 @@ struct AnalysisConfig {
 
     /// Tolerance settings
     tolerance: DefaultTolerance,
+
+    /// Precursor and fragment tolerances of single stages of the run,
+    /// `tolerance` is used for the ones not given, eg. `{"prescan":
+    /// {"fragment": {...}}, "discovery": {"precursor": {...}, "fragment":
+    /// {...}}}`
+    #[serde(default)]
+    tolerance_profiles: ToleranceProfiles,
+
+    /// Heavy-label (SILAC) channel settings, every light precursor gets a
+    /// linked heavy query (`{}` for the default Lys8/Arg10 labels). No heavy
+    /// channel is searched if missing
+    labeling: Option<HeavyLabel>,
+
+    /// Isobaric (TMT/iTRAQ) label of the sample, eg. `{"label": "tmt",
+    /// "exclude_reporter_region": true}`, added to the lysines and N-termini
+    /// of the digested peptides
+    #[serde(default)]
+    isobaric_labeling: Option<IsobaricLabeling>,
+
+    /// Precursor m/z range covered by the isolation windows of the run.
+    /// Set it for gas-phase fractionated (GPF) runs, so only precursors that
+    /// can be observed in the run are queried.
+    isolation_mz_range: Option<(f64, f64)>,
+
+    /// Isolation windows (low, high m/z) of the acquisition method, precursors
+    /// outside all of them are skipped
+    isolation_windows: Option<Vec<(f64, f64)>>,
+
+    /// Do not read the isolation windows of the runs to detect gas-phase
+    /// fractionated (GPF) ones. Without `isolation_mz_range` or
+    /// `isolation_windows`, a run whose windows span at most 300 m/z is only
+    /// searched for the precursors within them
+    #[serde(default)]
+    skip_gpf_detection: bool,
+
+    /// Other runs searched with the same settings, eg. the gas-phase
+    /// fractions of a chromatogram library. Every run gets a subdirectory of
+    /// the output directory and their results are combined in
+    /// `combined_results.csv`, with the main scores calibrated per run
+    #[serde(default)]
+    additional_dotd_files: Vec<PathBuf>,
+
+    /// MS1 scan range (low, high m/z) of the run, eg. `[100.0, 1700.0]`.
+    /// Precursor isotope peaks outside of it are handled as set by
+    /// `ms1_range_handling`
+    ms1_scan_range: Option<(f64, f64)>,
+
+    /// "trim" (default) does not query the isotope peaks outside the MS1
+    /// scan range (always keeping the monoisotopic one), "flag" queries them
+    /// and reports how many there are in `num_isotopes_outside_ms1_range`
+    #[serde(default)]
+    ms1_range_handling: Ms1RangeHandling,
+
+    /// Modifications applied to every occurrence of a residue of the digested
+    /// peptides, eg. `[{"residue": "C", "modification": "UNIMOD:4"}]` for
+    /// carbamidomethylated cysteines
+    #[serde(default)]
+    fixed_modifications: Vec<FixedModification>,
+
+    /// Modifications searched with and without, eg.
+    /// `{"modifications": [{"residue": "M", "modification": "Oxidation"}],
+    /// "max_per_peptide": 2}`, `^` as residue is the peptide N-terminus
+    #[serde(default)]
+    variable_modifications: VariableModifications,
+
+    /// Modifications declared by their mass, for chemistries without a
+    /// Unimod entry, eg. `[{"residues": "K", "mass_delta": 114.042927,
+    /// "position": "anywhere", "variable": true}]`. `position` is one of
+    /// "anywhere", "peptide_n_term" and "peptide_c_term"
+    #[serde(default)]
+    custom_modifications: Vec<CustomModification>,
+
+    /// Also search the protein N-terminal peptides with an acetylated
+    /// N-terminus
+    #[serde(default)]
+    protein_nterm_acetylation: bool,
+
+    /// Lowest and highest precursor charge queried, eg. `[2, 4]`, defaults
+    /// to `[2, 3]`
+    precursor_charge_range: Option<(u8, u8)>,
+
+    /// Precursor m/z range queried, eg. `[350.0, 1250.0]`, defaults to
+    /// `[400.0, 1000.0]`
+    precursor_mz_range: Option<(f64, f64)>,
+
+    /// Fragment m/z range queried, eg. `[150.0, 1700.0]`, defaults to
+    /// `[200.0, 2000.0]`
+    fragment_mz_range: Option<(f64, f64)>,
+
+    /// Highest fragment charge queried, defaults to 2
+    max_fragment_charge: Option<u8>,
+
+    /// Precursors with fewer fragments within the fragment m/z range are not
+    /// queried, eg. 4. All are queried by default
+    #[serde(default)]
+    min_fragments: usize,
+
+    /// Only query the most intense (predicted) fragments of every precursor,
+    /// eg. 12. All are queried by default
+    #[serde(default)]
+    max_fragments: usize,
+
+    /// Only query fragments up to the precursor charge minus one, so 2+
+    /// precursors do not get (unlikely) 2+ fragments
+    #[serde(default)]
+    cap_fragment_charge: bool,
+
+    /// Fragments of a precursor closer than this (ppm) are queried once,
+    /// with their summed expected intensity, eg. 10. Off (0) by default
+    #[serde(default)]
+    fragment_merge_ppm: f64,
+
+    /// Charge carrier of the precursors, "proton" by default. One of
+    /// "sodium", "potassium", "ammonium", "deprotonation" (negative mode) or
+    /// `{"custom": 22.989218}` (carrier mass, negative if removed)
+    #[serde(default)]
+    adduct: Adduct,
+
+    /// Precursor isotope peaks queried from the monoisotopic one up,
+    /// defaults to 3 (M, M+1 and M+2), at least 1
+    precursor_isotopes: Option<usize>,
+
+    /// Do not query the -1 isotope peak, only used to detect interferences
+    #[serde(default)]
+    skip_minus_one_isotope: bool,
+
+    /// Weights of the precursor isotope traces in the MS1 scores, from the
+    /// monoisotopic one up (1 for the ones not given), eg. `[2.0, 1.0, 0.0]`
+    /// emphasizes the monoisotopic trace and does not query the M+2 one
+    #[serde(default)]
+    precursor_isotope_weights: Vec<f32>,
+
+    /// Do not query the isotope traces expected below this fraction of the
+    /// most intense one, eg. 0.2
+    min_isotope_relative_intensity: Option<f32>,
+
+    /// Add internal fragments to the queries of long peptides, eg.
+    /// `{"min_peptide_length": 20, "min_length": 2, "max_length": 6}`
+    #[serde(default)]
+    internal_fragments: Option<InternalFragments>,
+
+    /// Add immonium and PTM-diagnostic ions to the queries, eg. `{"immonium":
+    /// false, "modified_immonium": true}`. Ions below the 200 m/z fragment
+    /// floor (most unmodified immonium ions) are not queried.
+    /// `"diagnostic_ions"` in extra_scores reports them
+    #[serde(default)]
+    diagnostic_ions: Option<DiagnosticIons>,
+
+    /// Retention time predictor of the digested peptides, eg.
+    /// `{"model": "builtin", "calibration": "observed_rts.tsv"}`, `{"model":
+    /// {"coefficients": "rt_model.json"}}` or `{"model": {"table":
+    /// "predicted_rts.tsv"}}`. With `"window_seconds": 120` the search
+    /// only looks 2 minutes around the predicted RT of the queries the
+    /// predictor has a prediction for
+    #[serde(default)]
+    rt_predictor: RtPredictorConfig,
+
+    /// 1/K0 predictor of the digested peptides, the built-in model by
+    /// default, eg. `{"model": {"table": "predicted_mobilities.tsv"},
+    /// "calibration": "observed_mobilities.tsv"}` or `{"model": {"per_charge":
+    /// "mobility_model.json"}}`. Spectral library 1/K0 are kept unless
+    /// `"apply_to_speclib": true`
+    #[serde(default)]
+    mobility_predictor: MobilityPredictorConfig,
+
+    /// Fragment intensities predicted offline by an external model (eg. a
+    /// Koina server or an ONNX model), replacing the fixed y/b intensities of
+    /// the precursors it covers, eg. `{"model": {"table":
+    /// "predicted_intensities.tsv"}}`
+    #[serde(default)]
+    intensity_predictor: IntensityPredictorConfig,
+
+    /// Names of the experimental scorers to append to the output
+    #[serde(default)]
+    extra_scores: Vec<String>,
+
+    /// Number of times a chunk failing on I/O is retried before it is
+    /// skipped
+    #[serde(default)]
+    max_chunk_retries: usize,
+
+    /// Extraction results (in elution groups) kept in memory, keyed by the
+    /// query content and its tolerance, so the same query (a retried chunk,
+    /// a precursor in several inputs) does not hit the index again. No
+    /// cache if missing
+    #[serde(default)]
+    query_cache_size: Option<usize>,
+
+    /// Log a provisional target/decoy separation and the projected IDs at
+    /// 1% FDR every this many chunks
+    #[serde(default)]
+    fdr_preview_every: Option<usize>,
+
+    /// Intensity floor / baseline removed from traces before the extra scores
+    #[serde(default)]
+    noise: NoiseModel,
+
+    /// Estimate the MS2 noise floor per RT segment before the search, eg.
+    /// `{"num_probes": 200, "num_segments": 20}`.
+    /// Written to noise_floor.tsv and the run manifest. The apex fragment
+    /// peaks below the floor of their RT segment do not count in npeaks and
+    /// the summed intensity, and the run-wide floor is the least intensity
+    /// floor of the noise model
+    #[serde(default)]
+    noise_prescan: Option<NoisePrescan>,
+
+    /// Known background ions used to correct the fragment m/z of the queries
+    /// for the mass drift of the run, eg. `{"fragment_mzs": [371.1012,
+    /// 445.1200], "gradient_seconds": [0, 3600]}`
+    #[serde(default)]
+    lock_mass: Option<LockMassConfig>,
+
+    /// Anchor peptides searched first to map the query RTs, taken as iRT, to
+    /// the seconds of the run, eg. `{}` for the Biognosys iRT kit or
+    /// `{"peptides": [{"sequence": "LGGNEQVTR", "irt": -24.92}], "fit_mobility": true}`
+    #[serde(default)]
+    irt_anchors: Option<IrtAnchors>,
+
+    /// Two-pass search: the first chunks are searched first to calibrate the
+    /// query RTs, 1/K0 and m/z and narrow the tolerances of the whole search,
+    /// eg. `{}` or `{"num_chunks": 4, "fdr": 0.01, "recalibrate_mz": false}`
+    #[serde(default)]
+    calibration_pass: Option<CalibrationPassConfig>,
+
+    /// Corrects the query 1/K0 of every chunk by the median 1/K0 error of the
+    /// confident targets of the chunks before it, eg. `{}` or `{"fdr": 0.01,
+    /// "min_points": 50}`
+    #[serde(default)]
+    mobility_recalibration: Option<MobilityRecalibrationConfig>,
+
+    /// Strategy used to pick the apex ("main_score", "smoothed_intensity" or
+    /// "composite"), anything but the main score adds `apex_*` columns with
+    /// the picked apex and the MS2 scores at it. The built-in columns stay at
+    /// the main score apex
+    #[serde(default)]
+    apex_strategy: ApexStrategy,
+
+    /// Moving average window (in cycles) used by the smoothed strategies,
+    /// defaults to 5
+    apex_smoothing_window: Option<usize>,
+}
+
+impl AnalysisConfig {
+    /// Tolerances of a stage, `tolerance` where the profile has none.
+    fn stage_tolerance(&self, profile: Option<&ToleranceProfile>) -> StageTolerance {
+        let profile = profile.cloned().unwrap_or_default();
+        StageTolerance {
+            fragment: profile.fragment.unwrap_or_else(|| self.tolerance.clone()),
+            precursor: profile.precursor,
+        }
+    }
+
+    /// Tolerances of the pre-scan probes.
+    fn prescan_tolerance(&self) -> StageTolerance {
+        self.stage_tolerance(self.tolerance_profiles.prescan.as_ref())
+    }
+
+    /// Tolerances of the search queries, see [`ChunkTolerance`] for the RT
+    /// window of the predictor.
+    fn search_tolerance(&self) -> StageTolerance {
+        self.stage_tolerance(self.tolerance_profiles.discovery.as_ref())
+    }
+
+    /// Tolerances of the calibrated search of a two-pass search, before the
+    /// calibration narrows them.
+    fn requantification_tolerance(&self) -> StageTolerance {
+        match &self.tolerance_profiles.requantification {
+            Some(profile) => self.stage_tolerance(Some(profile)),
+            None => self.search_tolerance(),
+        }
+    }
+
+    fn with_rt_window(&self, tolerance: &DefaultTolerance) -> DefaultTolerance {
+        let mut tolerance = tolerance.clone();
+        if let Some(window) = self.rt_predictor.search_window_seconds() {
+            tolerance.rt = RtTolerance::Absolute((window, window));
+        }
+        tolerance
+    }
+
+    /// Precursor isotope peaks queried from the monoisotopic one up.
+    fn num_precursor_isotopes(&self) -> usize {
+        match self.precursor_isotopes {
+            Some(num_isotopes) => num_isotopes.max(1),
+            None => SequenceToElutionGroupConverter::default().num_precursor_isotopes,
+        }
+    }
+
+    /// Converter of sequences to queries with the charges, m/z ranges,
+    /// isotopes, modifications and predictors of the config.
+    fn converter(&self) -> std::result::Result<SequenceToElutionGroupConverter, TimsSeekError> {
+        let mut converter = SequenceToElutionGroupConverter {
+            fixed_modifications: self.fixed_modifications.clone(),
+            variable_modifications: self.variable_modifications.clone(),
+            protein_nterm_acetylation: self.protein_nterm_acetylation,
+            mobility_predictor: self.mobility_predictor.build()?,
+            rt_predictor: self.rt_predictor.build()?,
+            intensity_predictor: self.intensity_predictor.build()?,
+            ..Default::default()
+        };
+        converter.fragment_buildder.internal_fragments = self.internal_fragments.clone();
+        converter.fragment_buildder.diagnostic_ions = self.diagnostic_ions.clone();
+        if let Some(max_charge) = self.max_fragment_charge {
+            converter.fragment_buildder.set_max_charge(max_charge);
+        }
+        if let Some((min_charge, max_charge)) = self.precursor_charge_range {
+            converter.precursor_charge_range = min_charge..=max_charge;
+        }
+        if let Some((min_mz, max_mz)) = self.precursor_mz_range {
+            converter.min_precursor_mz = min_mz;
+            converter.max_precursor_mz = max_mz;
+        }
+        if let Some((min_mz, max_mz)) = self.fragment_mz_range {
+            converter.min_fragment_mz = min_mz;
+            converter.max_fragment_mz = max_mz;
+        }
+        converter.cap_fragment_charge = self.cap_fragment_charge;
+        converter.fragment_merge_ppm = self.fragment_merge_ppm;
+        converter.min_fragments = self.min_fragments;
+        converter.max_fragments = self.max_fragments;
+        converter.adduct = self.adduct;
+        converter.num_precursor_isotopes = self.num_precursor_isotopes();
+        converter.minus_one_isotope = !self.skip_minus_one_isotope;
+        converter.precursor_isotope_weights = self.precursor_isotope_weights.clone();
+        if let Some(min_intensity) = self.min_isotope_relative_intensity {
+            converter.min_isotope_relative_intensity = min_intensity;
+        }
+        for custom in &self.custom_modifications {
+            if custom.variable {
+                converter
+                    .variable_modifications
+                    .modifications
+                    .extend(custom.variable_modifications()?);
+            } else {
+                converter
+                    .fixed_modifications
+                    .extend(custom.fixed_modifications()?);
+            }
+        }
+        if let Some(labeling) = &self.isobaric_labeling {
+            converter
+                .fixed_modifications
+                .extend(labeling.fixed_modifications());
+            if labeling.exclude_reporter_region {
+                converter
+                    .excluded_fragment_mz_ranges
+                    .push(labeling.label.reporter_mz_range());
+            }
+        }
+        if let Some((min_mz, max_mz)) = self.isolation_mz_range {
+            converter.min_precursor_mz = converter.min_precursor_mz.max(min_mz);
+            converter.max_precursor_mz = converter.max_precursor_mz.min(max_mz);
+        }
+        Ok(converter)
+    }
+
+    /// Converter of the library sequences: the settings of [`Self::converter`]
+    /// without the precursor m/z limits (the library decides what is
+    /// searched) or the modifications (the library sequences carry theirs).
+    fn library_converter(
+        &self,
+    ) -> std::result::Result<SequenceToElutionGroupConverter, TimsSeekError> {
+        let mut converter = self.converter()?;
+        converter.min_precursor_mz = 0.0;
+        converter.max_precursor_mz = f64::INFINITY;
+        converter.fixed_modifications.clear();
+        converter.variable_modifications = Default::default();
+        converter.protein_nterm_acetylation = false;
+        Ok(converter)
+    }
+
+    /// Index over the explicit isolation windows, or the GPF range if no
+    /// windows are given. None if neither is set.
+    fn isolation_window_index(&self) -> Option<IsolationWindowIndex> {
+        match (&self.isolation_windows, self.isolation_mz_range) {
+            (Some(windows), _) => Some(IsolationWindowIndex::new(windows)),
+            (None, Some(range)) => Some(IsolationWindowIndex::new(&[range])),
+            (None, None) => None,
+        }
+    }
+
+    /// Checks that the configured charge and m/z ranges are not empty, so
+    /// a swapped pair fails the run instead of searching nothing.
+    fn check_ranges(&self) -> std::result::Result<(), TimsSeekError> {
+        let empty = |name: &str, range: String| TimsSeekError::ParseError {
+            msg: format!("{} {} is not a valid [min, max] range", name, range),
+        };
+        if let Some((min, max)) = self.precursor_charge_range {
+            if min == 0 || min > max {
+                return Err(empty("precursor_charge_range", format!("[{}, {}]", min, max)));
+            }
+        }
+        let mz_ranges = [
+            ("precursor_mz_range", self.precursor_mz_range),
+            ("fragment_mz_range", self.fragment_mz_range),
+        ];
+        for (name, range) in mz_ranges {
+            if let Some((min, max)) = range {
+                if min >= max {
+                    return Err(empty(name, format!("[{}, {}]", min, max)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that every configured modification resolves to a formula, so
+    /// a typo fails the run before any data is loaded.
+    fn resolve_modifications(&self) -> std::result::Result<(), TimsSeekError> {
+        let isobaric = self
+            .isobaric_labeling
+            .iter()
+            .flat_map(|x| x.fixed_modifications())
+            .map(|x| (x.residue, x.modification));
+        let modifications = self
+            .fixed_modifications
+            .iter()
+            .map(|x| (x.residue, x.modification.clone()))
+            .chain(
+                self.variable_modifications
+                    .modifications
+                    .iter()
+                    .map(|x| (x.residue, x.modification.clone())),
+            )
+            .chain(isobaric);
+        for custom in &self.custom_modifications {
+            custom.validate()?;
+        }
+        for (residue, modification) in modifications {
+            let formula = resolve_modification(residue, &modification)?;
+            info!(
+                "Modification {} on {} resolved to {:+.6} Da",
+                modification,
+                residue,
+                resolved_modification_mass(&formula)
+            );
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct OutputConfig {
-    /// Directory for results
+    /// Directory for results, "-" streams them to stdout (see `stdout`) and
+    /// writes the other outputs to a temporary directory
     directory: PathBuf,
+
+    /// Stream the results to stdout as NDJSON (one object per result, with
+    /// the `chunk_*.csv` columns) instead of writing `chunk_*.csv`, the
+    /// other outputs still go to `directory`. Logs go to stderr
+    #[serde(default)]
+    stdout: bool,
+
+    /// Continue an interrupted run from the `checkpoint.json` of `directory`,
+    /// the chunks it already searched are skipped
+    #[serde(default)]
+    resume: bool,
+
+    /// Write decoys to their own `decoy_chunk_*.csv` files instead of
+    /// mixing them with the targets
+    #[serde(default)]
+    split_decoys: bool,
+
+    /// Also write the per-fragment chromatograms of every result, downsampled
+    /// to at most this many points, to `chunk_*.xics.ndjson`
+    xic_max_points: Option<usize>,
+
+    /// Also write the full per-RT score traces used for apex picking (eg.
+    /// lazyerscore vs baseline) to `chunk_*.score_traces.ndjson`
+    #[serde(default)]
+    score_traces: bool,
+
+    /// Sequences of a handful of precursors to debug, their query, tolerance
+    /// and the raw peaks matched in every frame (scan, m/z, 1/K0 and
+    /// intensity, before aggregation) are written to
+    /// `chunk_*.diagnostics.ndjson`
+    #[serde(default)]
+    diagnostic_sequences: Vec<String>,
+
+    /// Also write `peptide_rollup.csv`, with the peptidoforms of every
+    /// peptide collapsed (best main score and summed intensity)
+    #[serde(default)]
+    peptide_rollup: bool,
+
+    /// Also write `modified_peptides.csv`, one row per modified peptide (and
+    /// channel) with its charge states consolidated (observed charges, best
+    /// main score and summed intensities)
+    #[serde(default)]
+    charge_state_rollup: bool,
+
+    /// Write `protein_map.csv` with all the proteins each target peptide
+    /// accepted at `protein_map_fdr` maps to and its razor protein, assigned
+    /// from the accepted peptides once the run is done (FASTA inputs only)
+    #[serde(default)]
+    protein_map: bool,
+
+    /// FDR (by main score) of the peptides the protein map is built from,
+    /// 0.01 if not set
+    protein_map_fdr: Option<f64>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 struct DigestionConfig {
+    /// Protease used to digest the proteins, either a name (eg. "trypsin",
+    /// "lys-c", "asp-n") or a custom rule
+    /// (`{"regex": "[FWY]", "skip_suffix": "P", "terminus": "cterm"}`)
+    enzyme: EnzymeSpec,
     min_length: u32,
     max_length: u32,
     max_missed_cleavages: u32,
+    /// Also search peptides with a single enzymatic terminus
+    semi_enzymatic: bool,
+    /// Other proteases used together with `enzyme` (eg. trypsin + Lys-C)
+    additional_enzymes: Vec<AdditionalEnzymeConfig>,
+    /// Keep ("retain"), remove ("cleave") or keep both versions ("both") of
+    /// the initiator methionine of the proteins
+    initiator_methionine: InitiatorMethionine,
+    /// Also search the protein N-terminal (`nterm`) and C-terminal (`cterm`)
+    /// peptides with up to that many residues clipped, eg.
+    /// `{"nterm": 0, "cterm": 2}` for carboxypeptidase ragged ends
+    terminal_clipping: TerminalClipping,
+    /// Monoisotopic mass range (Da) of the peptides searched, checked on the
+    /// unmodified sequence (fixed modifications are not added)
+    mass_range: Option<(f64, f64)>,
+    /// Only search the most detectable peptides of every protein (by
+    /// uniqueness, missed cleavages, length and hydrophobicity), for fast
+    /// panel-style searches of very large databases
+    max_peptides_per_protein: Option<usize>,
+    /// Search the most detectable peptides first, so a run with a limited
+    /// time budget (or an interrupted one) covers the peptides most likely
+    /// to be observed. Ignored if `query_order_seed` is set
+    order_by_detectability: bool,
+    /// Treat isoleucine and leucine as the same residue when deduplicating
+    /// peptides, the collapsed variants are written to `il_variants.csv`
+    il_equivalent: bool,
+    /// Classify the peptides as proteotypic or shared against an n-mer index
+    /// of the whole database, which can be slow and memory hungry for very
+    /// large databases
+    classify_uniqueness: bool,
     build_decoys: bool,
+    /// Accession prefixes of the decoy proteins in a pre-built target+decoy
+    /// FASTA, their peptides are used as decoys instead of generating them
+    decoy_prefixes: Vec<String>,
+    /// Accession prefixes of the contaminant proteins, their peptides are
+    /// flagged with `is_contaminant` in the outputs
+    contaminant_prefixes: Vec<String>,
+    /// Number of decoys generated per target when `build_decoys` is set
+    decoy_ratio: usize,
+    /// Base seed for the shuffled decoys used past the first decoy
+    decoy_seed: u64,
+    /// How the first decoy of every target is built, "reverse", "shuffle",
+    /// "cleavage_shuffle", "mutate" or "none" (no decoys)
+    decoy_strategy: DecoyStrategy,
+    /// Other seeds tried when a shuffled decoy is the same as its target (or,
+    /// for "cleavage_shuffle", any target)
+    shuffle_max_retries: usize,
+    /// Search the decoy chunks right after their targets ("interleaved") or
+    /// all of them at the end ("after_targets")
+    decoy_order: DecoyOrder,
+    /// Shuffle the order of the peptides with this seed, instead of the
+    /// FASTA order
+    query_order_seed: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct AdditionalEnzymeConfig {
+    enzyme: EnzymeSpec,
+    #[serde(default)]
+    max_missed_cleavages: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -308,10 +2248,28 @@ struct ToleranceConfig {
 impl Default for DigestionConfig {
     fn default() -> Self {
         Self {
+            enzyme: EnzymeSpec::default(),
             min_length: 6,
             max_length: 20,
             max_missed_cleavages: 0,
+            semi_enzymatic: false,
+            additional_enzymes: Vec::new(),
+            initiator_methionine: InitiatorMethionine::Retain,
+            terminal_clipping: TerminalClipping::default(),
+            mass_range: None,
+            max_peptides_per_protein: None,
+            order_by_detectability: false,
+            il_equivalent: false,
+            classify_uniqueness: true,
             build_decoys: true,
+            decoy_prefixes: vec!["rev_".to_string(), "DECOY_".to_string()],
+            contaminant_prefixes: vec!["CON__".to_string(), "Cont_".to_string()],
+            decoy_ratio: 1,
+            decoy_seed: 42,
+            decoy_strategy: DecoyStrategy::Reverse,
+            shuffle_max_retries: 10,
+            decoy_order: DecoyOrder::Interleaved,
+            query_order_seed: None,
         }
     }
 }
@@ -326,6 +2284,20 @@ impl Default for ToleranceConfig {
     }
 }
 
+impl From<ToleranceConfig> for DefaultTolerance {
+    fn from(config: ToleranceConfig) -> Self {
+        let (ms_low, ms_high) = config.ms_ppm;
+        let (mobility_low, mobility_high) = config.mobility_pct;
+        let (quad_low, quad_high) = config.quad_absolute;
+        DefaultTolerance {
+            ms: MzToleramce::Ppm((ms_low as _, ms_high as _)),
+            rt: RtTolerance::None,
+            mobility: MobilityTolerance::Pct((mobility_low as _, mobility_high as _)),
+            quad: QuadTolerance::Absolute((quad_low as _, quad_high as _, 1)),
+        }
+    }
+}
+
 fn process_fasta(
     path: PathBuf,
     index: &QuadSplittedTransposedIndex,
@@ -333,70 +2305,617 @@ fn process_fasta(
     digestion: DigestionConfig,
     analysis: &AnalysisConfig,
     output: &OutputConfig,
+    ignore_cache: bool,
 ) -> std::result::Result<(), TimsSeekError> {
+    let (chunked_query_iterator, proteins) =
+        digest_fasta(path, digestion, analysis, output, ignore_cache)?;
+    let database = chunked_query_iterator.database_stats.clone();
+    main_loop(
+        chunked_query_iterator,
+        index,
+        factory,
+        analysis,
+        output,
+        &proteins,
+        Some(database),
+    )?;
+    Ok(())
+}
+
+/// Digests (or reads the cached digests of) a FASTA file, returns the query
+/// chunks and the protein annotations, indexed by protein id.
+fn digest_fasta(
+    path: PathBuf,
+    digestion: DigestionConfig,
+    analysis: &AnalysisConfig,
+    output: &OutputConfig,
+    ignore_cache: bool,
+) -> std::result::Result<(DigestedSequenceIterator, ProteinAnnotations), TimsSeekError> {
+    let as_parse_error = |e: regex::Error| TimsSeekError::ParseError {
+        msg: format!("Invalid enzyme regex: {}", e),
+    };
     let digestion_params = DigestionParameters {
         min_length: digestion.min_length as usize,
         max_length: digestion.max_length as usize,
-        pattern: DigestionPattern::trypsin(),
-        digestion_end: DigestionEnd::CTerm,
+        pattern: digestion.enzyme.pattern().map_err(as_parse_error)?,
+        digestion_end: digestion.enzyme.digestion_end(),
         max_missed_cleavages: digestion.max_missed_cleavages as usize,
+        semi_enzymatic: digestion.semi_enzymatic,
+        additional_enzymes: digestion
+            .additional_enzymes
+            .iter()
+            .map(|x| x.enzyme.cleavage_rule(x.max_missed_cleavages as usize))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(as_parse_error)?,
+        initiator_methionine: digestion.initiator_methionine,
+        terminal_clipping: digestion.terminal_clipping,
+        mass_range: digestion.mass_range,
     };
 
-    println!(
+    eprintln!(
         "Digesting {} with parameters: \n {:?}",
         path.display(),
         digestion_params
     );
 
-    let fasta_proteins = ProteinSequenceCollection::from_fasta_file(&path)?;
-    let sequences: Vec<Arc<str>> = fasta_proteins
+    let fasta_text = std::fs::read_to_string(&path)?;
+    let fasta_proteins = ProteinSequenceCollection::from_fasta(&fasta_text);
+    if fasta_proteins.sequences.is_empty() {
+        return Err(TimsSeekError::ParseError {
+            msg: format!("No protein sequences found in {}", path.display()),
+        });
+    }
+    let (decoy_proteins, target_proteins): (Vec<_>, Vec<_>) = fasta_proteins
         .sequences
         .iter()
-        .map(|x| x.sequence.clone())
+        .partition(|x| x.is_decoy(&digestion.decoy_prefixes));
+    let sequences: Vec<(u32, Arc<str>)> = target_proteins
+        .iter()
+        .map(|x| (x.id, x.sequence.clone()))
         .collect();
+    let decoy_sequences: Vec<(u32, Arc<str>)> = decoy_proteins
+        .iter()
+        .map(|x| (x.id, x.sequence.clone()))
+        .collect();
+    let proteins =
+        fasta_proteins.annotations(&digestion.decoy_prefixes, &digestion.contaminant_prefixes);
+    let validation = fasta_proteins.validation_summary(digestion_params.min_length);
+    validation.log_problems();
+    let mut database_stats = DatabaseStats {
+        fasta: validation,
+        target_proteins: sequences.len(),
+        decoy_proteins: decoy_sequences.len(),
+        ..Default::default()
+    };
 
-    let digest_sequences: Vec<DigestSlice> =
-        deduplicate_digests(digestion_params.digest_multiple(&sequences));
+    // Digests are cached next to the FASTA file, keyed by its contents and
+    // the digestion settings. The collapsed I/L variants are not part of the
+    // digests, so they are cached alongside them.
+    let cache_path = path.with_extension("digests.tsv");
+    let variants_cache_path = path.with_extension("il_variants.csv");
+    let variants_path = output.directory.join("il_variants.csv");
+    let cache_key = digest_cache_key(
+        &fasta_text,
+        &serde_json::to_string(&digestion).unwrap_or_default(),
+    );
+    let cached = if ignore_cache || (digestion.il_equivalent && !variants_cache_path.exists()) {
+        None
+    } else {
+        read_digest_cache(&cache_path, &cache_key).unwrap_or_else(|e| {
+            log::warn!("Ignoring unreadable digest cache {:?}: {:?}", cache_path, e);
+            None
+        })
+    };
+    let digest_sequences: Vec<DigestSlice> = match cached {
+        Some(x) => {
+            eprintln!("Using cached digests from {}", cache_path.display());
+            if digestion.il_equivalent {
+                std::fs::copy(&variants_cache_path, &variants_path).map_err(TimsSeekError::Io)?;
+            }
+            database_stats.record_step("cached", &x);
+            x
+        }
+        None => {
+            // Targets go first, so peptides shared with a decoy protein stay
+            // targets after deduplication.
+            let digestion_start = Instant::now();
+            let mut all_digests = digestion_params.digest_proteins(&sequences);
+            all_digests.extend(
+                digestion_params
+                    .digest_proteins(&decoy_sequences)
+                    .iter()
+                    .map(|x| x.with_decoy(DecoyMarking::ReversedDecoy)),
+            );
+            database_stats.record_step("digested", &all_digests);
+            let (digests, variants) =
+                deduplicate_digests_with_variants(all_digests, digestion.il_equivalent);
+            database_stats.record_step("deduplicated", &digests);
+            info!(
+                "Digestion took {:?} for {} proteins -> {} peptides",
+                digestion_start.elapsed(),
+                sequences.len() + decoy_sequences.len(),
+                digests.len()
+            );
+            if digestion.il_equivalent {
+                info!("Collapsed I/L variants of {} peptides", variants.len());
+                write_collapsed_variants_to_csv(&variants, &variants_path)
+                    .map_err(|e| TimsSeekError::Io(std::io::Error::other(e.to_string())))?;
+            }
+            // The variants are cached first, so cached digests always have
+            // matching variants next to them.
+            let variants_cached = !digestion.il_equivalent
+                || std::fs::copy(&variants_path, &variants_cache_path)
+                    .map_err(|e| {
+                        log::warn!(
+                            "Could not cache I/L variants {:?}: {:?}",
+                            variants_cache_path,
+                            e
+                        )
+                    })
+                    .is_ok();
+            if variants_cached {
+                if let Err(e) = write_digest_cache(&cache_path, &cache_key, &digests) {
+                    log::warn!("Could not write digest cache {:?}: {:?}", cache_path, e);
+                }
+            }
+            digests
+        }
+    };
+
+    // A database that already has decoys does not get internal ones.
+    let build_decoys = digestion.build_decoys
+        && digestion.decoy_strategy != DecoyStrategy::None
+        && decoy_sequences.is_empty();
+    if !decoy_sequences.is_empty() {
+        eprintln!(
+            "Found {} decoy proteins in the FASTA file, skipping decoy generation",
+            decoy_sequences.len()
+        );
+    }
+
+    // Peptides are classified against the whole database (not only the
+    // digests), so sequences also present in a non-enzymatic context count
+    // as shared.
+    let assignments = if digestion.classify_uniqueness {
+        let nmer_index = ProteinSequenceNmerIndex::from_collection(
+            fasta_proteins,
+            digestion_params.min_length.min(5),
+        );
+        let peptides: Vec<String> =
+            digest_sequences.iter().map(|x| x.clone().into()).collect();
+        assign_peptides(peptides, &nmer_index)
+    } else {
+        Vec::new()
+    };
+    let digest_sequences: Vec<DigestSlice> = if assignments.is_empty() {
+        digest_sequences
+    } else {
+        digest_sequences
+            .into_iter()
+            .zip(assignments.iter())
+            .map(|(mut digest, assignment)| {
+                digest.uniqueness = assignment.uniqueness();
+                digest
+            })
+            .collect()
+    };
+    let mut digest_sequences = match digestion.max_peptides_per_protein {
+        Some(max_per_protein) => {
+            let num_digests = digest_sequences.len();
+            let limited = limit_peptides_per_protein(digest_sequences, max_per_protein);
+            eprintln!(
+                "Kept {} of {} peptides (at most {} per protein)",
+                limited.len(),
+                num_digests,
+                max_per_protein
+            );
+            database_stats.record_step("per_protein_limit", &limited);
+            limited
+        }
+        None => digest_sequences,
+    };
+    if digestion.order_by_detectability {
+        sort_by_detectability(&mut digest_sequences);
+    }
 
     // ... rest of FASTA processing ...
-    let def_converter = SequenceToElutionGroupConverter::default();
+    let def_converter = analysis.converter()?;
+    // Only the cleavage preserving shuffles check their decoys against the
+    // targets while building them.
+    let decoy_targets: HashSet<String> = match digestion.decoy_strategy {
+        DecoyStrategy::CleavageShuffle => digest_sequences
+            .iter()
+            .filter(|x| x.decoy == DecoyMarking::Target)
+            .map(|x| x.clone().into())
+            .collect(),
+        _ => HashSet::new(),
+    };
     let chunked_query_iterator = DigestedSequenceIterator::new(
         digest_sequences,
         analysis.chunk_size,
         def_converter,
-        digestion.build_decoys,
-    );
+        if build_decoys {
+            digestion.decoy_ratio
+        } else {
+            0
+        },
+        digestion
+            .decoy_strategy
+            .generator(
+                digestion.decoy_seed,
+                digestion.shuffle_max_retries,
+                Arc::new(decoy_targets),
+                CleavageSites::new(&digestion_params.pattern, digestion_params.digestion_end),
+            ),
+    )
+    .with_decoy_order(digestion.decoy_order)
+    .with_target_collision_filter(digestion.il_equivalent)
+    .with_database_stats(database_stats);
+    let chunked_query_iterator = match digestion.query_order_seed {
+        Some(seed) => chunked_query_iterator.with_shuffled_order(seed),
+        None => chunked_query_iterator,
+    };
+    Ok((chunked_query_iterator, proteins))
+}
+
+/// Library source reported for the in-silico queries of a union search.
+const IN_SILICO_SOURCE: &str = "in_silico";
+
+/// Searches a spectral library together with the digests of a FASTA file.
+///
+/// Library entries have better intensities and retention times, so the
+/// in-silico queries (and their decoys) for a sequence and charge already in
+/// the library are dropped.
+fn process_union(
+    speclib: Speclib,
+    digests: DigestedSequenceIterator,
+    proteins: &ProteinAnnotations,
+    index: &QuadSplittedTransposedIndex,
+    factory: &MultiCMGStatsFactory<SafePosition>,
+    analysis: &AnalysisConfig,
+    output: &OutputConfig,
+) -> std::result::Result<(), TimsSeekError> {
+    let library_keys = speclib.precursor_keys(&analysis.fixed_modifications);
+    let fixed_modifications = analysis.fixed_modifications.clone();
+    let database = digests.database_stats.clone();
+    let source: Arc<str> = IN_SILICO_SOURCE.into();
+    let in_silico = digests.map(move |chunk| {
+        chunk
+            .retain(|digest, charge| {
+                !library_keys.contains(&precursor_key(digest, charge, &fixed_modifications))
+            })
+            .with_library_source(&source)
+    });
 
     main_loop(
-        chunked_query_iterator,
-        &index,
-        &factory,
-        &analysis.tolerance,
-        &output.directory,
+        ChainedChunks::new(speclib.as_iterator(analysis.chunk_size), in_silico),
+        index,
+        factory,
+        analysis,
+        output,
+        proteins,
+        Some(database),
     )?;
     Ok(())
 }
 
+/// Chunks of `rest` with the first ones taken ahead (for the calibration
+/// pass), keeps the length of `rest`.
+struct PrefetchedChunks<I> {
+    prefetched: std::vec::IntoIter<NamedQueryChunk>,
+    rest: I,
+    len: usize,
+}
+
+impl<I: ExactSizeIterator<Item = NamedQueryChunk>> PrefetchedChunks<I> {
+    fn new(mut rest: I, num_chunks: usize) -> Self {
+        let len = rest.len();
+        let prefetched: Vec<NamedQueryChunk> = rest.by_ref().take(num_chunks).collect();
+        Self {
+            prefetched: prefetched.into_iter(),
+            rest,
+            len,
+        }
+    }
+
+    fn prefetched(&self) -> &[NamedQueryChunk] {
+        self.prefetched.as_slice()
+    }
+}
+
+impl<I: Iterator<Item = NamedQueryChunk>> Iterator for PrefetchedChunks<I> {
+    type Item = NamedQueryChunk;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.prefetched.next().or_else(|| self.rest.next())
+    }
+}
+
+impl<I: ExactSizeIterator<Item = NamedQueryChunk>> ExactSizeIterator for PrefetchedChunks<I> {
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+/// Chunks of `first` followed by the ones of `second`.
+struct ChainedChunks<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A, B> ChainedChunks<A, B> {
+    fn new(first: A, second: B) -> Self {
+        Self { first, second }
+    }
+}
+
+impl<A, B> Iterator for ChainedChunks<A, B>
+where
+    A: Iterator<Item = NamedQueryChunk>,
+    B: Iterator<Item = NamedQueryChunk>,
+{
+    type Item = NamedQueryChunk;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.first.next().or_else(|| self.second.next())
+    }
+}
+
+impl<A, B> ExactSizeIterator for ChainedChunks<A, B>
+where
+    A: ExactSizeIterator<Item = NamedQueryChunk>,
+    B: ExactSizeIterator<Item = NamedQueryChunk>,
+{
+    fn len(&self) -> usize {
+        self.first.len() + self.second.len()
+    }
+}
+
 fn process_speclib(
     path: PathBuf,
+    additional_paths: &[PathBuf],
+    (decoys, decoy_collisions): (SpeclibDecoys, DecoyCollisionHandling),
     index: &QuadSplittedTransposedIndex,
     factory: &MultiCMGStatsFactory<SafePosition>,
     analysis: &AnalysisConfig,
     output: &OutputConfig,
 ) -> std::result::Result<(), TimsSeekError> {
-    let speclib = Speclib::from_ndjson_file(&path)?;
+    let library_converter = analysis.library_converter()?;
+    let mut speclib = Speclib::from_library_file(&path, &library_converter)?;
+    for path in additional_paths {
+        speclib = speclib.merge(Speclib::from_library_file(path, &library_converter)?);
+    }
+    resolve_speclib_decoy_collisions(&mut speclib, decoy_collisions, &output.directory)?;
+    let speclib = speclib.with_decoys(decoys, &speclib_decoy_converter());
     let speclib_iter = speclib.as_iterator(analysis.chunk_size);
+    let chunks: Box<dyn ExactSizeIterator<Item = NamedQueryChunk>> =
+        if analysis.mobility_predictor.apply_to_speclib {
+            let predictor = analysis.mobility_predictor.build()?;
+            Box::new(speclib_iter.map(move |x| with_predicted_mobility(x, predictor.as_ref())))
+        } else {
+            Box::new(speclib_iter)
+        };
 
     main_loop(
-        speclib_iter,
+        chunks,
         index,
         &factory,
-        &analysis.tolerance,
-        &output.directory,
+        analysis,
+        output,
+        &ProteinAnnotations::default(),
+        None,
     )?;
     Ok(())
 }
 
+/// Drops or reshuffles the library decoys matching a target, the ones found
+/// are written to `decoy_collisions.tsv`.
+fn resolve_speclib_decoy_collisions(
+    speclib: &mut Speclib,
+    handling: DecoyCollisionHandling,
+    directory: &Path,
+) -> std::result::Result<(), TimsSeekError> {
+    let collisions = speclib.resolve_decoy_collisions(handling, &speclib_decoy_converter());
+    if collisions.is_empty() {
+        return Ok(());
+    }
+    write_decoy_collisions(&collisions, directory)
+        .map_err(|e| TimsSeekError::Io(std::io::Error::other(e.to_string())))
+}
+
+fn write_decoy_collisions(
+    collisions: &[DecoyCollision],
+    directory: &Path,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_path(directory.join("decoy_collisions.tsv"))?;
+    for collision in collisions {
+        writer.serialize(collision)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Replaces the 1/K0 of the queries with the ones of the predictor.
+fn with_predicted_mobility(
+    mut chunk: NamedQueryChunk,
+    predictor: &dyn MobilityPredictor,
+) -> NamedQueryChunk {
+    let mobilities: Vec<f32> = chunk
+        .digests()
+        .iter()
+        .zip(chunk.charges())
+        .zip(chunk.queries.iter())
+        .map(|((digest, charge), query)| {
+            let mz = monoisotopic_precursor_mz(
+                &query.precursor_mzs,
+                query.expected_precursor_intensity.as_deref(),
+            );
+            predictor.predict(&digest.peptidoform(), mz, *charge) as f32
+        })
+        .collect();
+    for (query, mobility) in chunk.queries.iter_mut().zip(mobilities) {
+        query.mobility = mobility;
+    }
+    chunk
+}
+
+/// Converter for the fragments of reversed library decoys, the precursor
+/// comes from the library so its m/z is not limited.
+fn speclib_decoy_converter() -> SequenceToElutionGroupConverter {
+    SequenceToElutionGroupConverter {
+        min_precursor_mz: 0.0,
+        max_precursor_mz: f64::INFINITY,
+        ..Default::default()
+    }
+}
+
+/// Tolerance and sequence converter of the peptide list subcommands, from
+/// the analysis section of the config if one is given.
+fn peptide_list_settings(
+    config: Option<&Path>,
+) -> std::result::Result<(DefaultTolerance, SequenceToElutionGroupConverter), TimsSeekError> {
+    let Some(config) = config else {
+        return Ok((
+            ToleranceConfig::default().into(),
+            SequenceToElutionGroupConverter::default(),
+        ));
+    };
+    let config: Config = serde_json::from_reader(std::fs::File::open(config)?)
+        .map_err(|e| TimsSeekError::ParseError { msg: e.to_string() })?;
+    config.analysis.resolve_modifications()?;
+    config.analysis.check_ranges()?;
+    let converter = config.analysis.converter()?;
+    Ok((config.analysis.tolerance, converter))
+}
+
+/// Elution groups of every peptide in a peptide list, for all the converter
+/// charges unless the list gives one, with the sequence and charge of each.
+fn peptide_list_queries(
+    peptides: &Path,
+    converter: &SequenceToElutionGroupConverter,
+) -> std::result::Result<(Vec<ElutionGroup<SafePosition>>, Vec<(String, u8)>), TimsSeekError> {
+    let entries = read_peptide_list(peptides)?;
+    let mut queries: Vec<ElutionGroup<SafePosition>> = Vec::new();
+    let mut labels: Vec<(String, u8)> = Vec::new();
+    for entry in entries {
+        let charges = match entry.charge {
+            Some(charge) => charge..=charge,
+            None => converter.precursor_charge_range.clone(),
+        };
+        let converted = match queries_for_sequence(&entry.sequence, charges, converter) {
+            Ok(x) => x,
+            Err(e) => {
+                log::warn!("Skipping {}: {:?}", entry.sequence, e);
+                continue;
+            }
+        };
+        let id = labels.len() as u64;
+        for (mut eg, charge) in converted {
+            eg.id = id;
+            labels.push((entry.sequence.clone(), charge));
+            queries.push(eg);
+        }
+    }
+    Ok((queries, labels))
+}
+
+/// Writes the predicted precursor m/z, retention time and 1/K0 (and
+/// optionally fragments) of every peptide in `peptides` as a tab separated
+/// file. The retention time is left empty without an RT predictor.
+fn predict_peptides(
+    peptides: &Path,
+    out_path: &Path,
+    converter: &SequenceToElutionGroupConverter,
+    fragments: bool,
+) -> std::result::Result<(), TimsSeekError> {
+    let (queries, labels) = peptide_list_queries(peptides, converter)?;
+    let as_io_error = |e: csv::Error| TimsSeekError::Io(std::io::Error::other(e.to_string()));
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_path(out_path)
+        .map_err(as_io_error)?;
+    let mut header = vec!["sequence", "charge", "precursor_mz", "rt_seconds", "mobility"];
+    if fragments {
+        header.extend(["fragment_mzs", "fragment_intensities"]);
+    }
+    writer.write_record(&header).map_err(as_io_error)?;
+    for (query, (sequence, charge)) in queries.iter().zip(labels.iter()) {
+        let mono_mz = monoisotopic_precursor_mz(
+            &query.precursor_mzs,
+            query.expected_precursor_intensity.as_deref(),
+        );
+        // Not the query RT, which falls back to 0 without a prediction.
+        let rt_seconds = converter
+            .rt_predictor
+            .as_ref()
+            .and_then(|x| x.predict(sequence))
+            .map(|x| x.to_string())
+            .unwrap_or_default();
+        let mut record = vec![
+            sequence.clone(),
+            charge.to_string(),
+            mono_mz.to_string(),
+            rt_seconds,
+            query.mobility.to_string(),
+        ];
+        if fragments {
+            let to_json = |x: serde_json::Result<String>| {
+                x.map_err(|e| TimsSeekError::ParseError { msg: e.to_string() })
+            };
+            record.push(to_json(serde_json::to_string(&query.fragment_mzs))?);
+            record.push(to_json(serde_json::to_string(&query.expected_fragment_intensity))?);
+        }
+        writer.write_record(&record).map_err(as_io_error)?;
+    }
+    writer.flush()?;
+    println!("Wrote predictions for {} precursors to {}", queries.len(), out_path.display());
+    Ok(())
+}
+
+/// Queries every peptide in `peptides` (all the converter charges unless the
+/// list gives one) and writes the traces as one json object per precursor.
+fn extract_xics(
+    peptides: &Path,
+    dotd_file: &Path,
+    out_path: &Path,
+    tolerance: &DefaultTolerance,
+    converter: &SequenceToElutionGroupConverter,
+    max_points: Option<usize>,
+) -> std::result::Result<(), TimsSeekError> {
+    check_raw_data_path(dotd_file)?;
+    let (queries, labels) = peptide_list_queries(peptides, converter)?;
+    println!("Extracting {} precursors from {}", queries.len(), dotd_file.display());
+
+    let index = QuadSplittedTransposedIndex::from_path_centroided(
+        dotd_file.to_str().expect("Path is not convertable to string"),
+    )?;
+    let factory = MultiCMGStatsFactory {
+        converters: (index.mz_converter, index.im_converter),
+        _phantom: std::marker::PhantomData::<SafePosition>,
+    };
+    let res = query_multi_group(&index, tolerance, &queries, &|x| {
+        factory.build_with_elution_group(x)
+    });
+
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(out_path)?);
+    for ((sequence, charge), (eg, arrays)) in labels.iter().zip(queries.iter().zip(res)) {
+        let xics = xic_profiles(&arrays, max_points.unwrap_or(0));
+        let line = serde_json::json!({
+            "sequence": sequence,
+            "precursor_charge": charge,
+            "precursor_mzs": eg.precursor_mzs,
+            "xics": xics,
+        });
+        serde_json::to_writer(&mut writer, &line)
+            .map_err(|e| TimsSeekError::Io(std::io::Error::other(e.to_string())))?;
+        std::io::Write::write_all(&mut writer, b"\n")?;
+    }
+    std::io::Write::flush(&mut writer)?;
+    Ok(())
+}
+
 fn main() -> std::result::Result<(), TimsSeekError> {
     // Initialize logging
     env_logger::init();
@@ -404,8 +2923,57 @@ fn main() -> std::result::Result<(), TimsSeekError> {
     // Parse command line arguments
     let args = Cli::parse();
 
+    if let Some(Command::Extract {
+        peptides,
+        dotd,
+        out,
+        max_points,
+    }) = args.command
+    {
+        let (tolerance, converter) = peptide_list_settings(args.config.as_deref())?;
+        return extract_xics(&peptides, &dotd, &out, &tolerance, &converter, max_points);
+    }
+    if let Some(Command::Predict {
+        peptides,
+        out,
+        fragments,
+    }) = args.command
+    {
+        let (_, converter) = peptide_list_settings(args.config.as_deref())?;
+        return predict_peptides(&peptides, &out, &converter, fragments);
+    }
+    if let Some(Command::Report {
+        results,
+        filter,
+        out,
+    }) = args.command
+    {
+        let filter = filter.map(|x| FilterExpression::parse(&x)).transpose()?;
+        let num_rows = filter_results_csv(&results, &out, filter.as_ref())?;
+        info!("Wrote {} rows to {}", num_rows, out.display());
+        return Ok(());
+    }
+
+    if let Some(Command::Compare { runs, fdr, out }) = args.command {
+        let summaries = runs
+            .iter()
+            .map(|x| summarize_run(x, fdr))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        write_comparison(&summaries, fdr, &out)?;
+        info!("Wrote the comparison of {} runs to {}", summaries.len(), out.display());
+        return Ok(());
+    }
+
     // Load and parse configuration
-    let config: Result<Config, _> = serde_json::from_reader(std::fs::File::open(args.config)?);
+    let config_path = match args.config {
+        Some(x) => x,
+        None => {
+            return Err(TimsSeekError::ParseError {
+                msg: "A configuration file is required (--config)".to_string(),
+            });
+        }
+    };
+    let config: Result<Config, _> = serde_json::from_reader(std::fs::File::open(config_path)?);
     let mut config = match config {
         Ok(x) => x,
         Err(e) => {
@@ -416,24 +2984,201 @@ fn main() -> std::result::Result<(), TimsSeekError> {
         config.analysis.dotd_file = Some(dotd_file);
     }
     if let Some(speclib_file) = args.speclib_file {
-        config.input = InputConfig::Speclib { path: speclib_file };
+        config.input = InputConfig::Speclib {
+            path: speclib_file,
+            additional_paths: Vec::new(),
+            decoys: SpeclibDecoys::None,
+            decoy_collisions: DecoyCollisionHandling::default(),
+        };
     }
     if let Some(output_dir) = args.output_dir {
         config.output.directory = output_dir;
     }
+    config.output.stdout |= args.stdout;
+    config.output.resume |= args.resume;
+    if config.output.directory == Path::new("-") {
+        config.output.stdout = true;
+        config.output.directory =
+            std::env::temp_dir().join(format!("timsseek_{}", std::process::id()));
+        log::info!(
+            "Streaming results to stdout, other outputs go to {}",
+            config.output.directory.display()
+        );
+    }
 
-    println!("{:?}", config);
+    eprintln!("{:?}", config);
+    config.analysis.resolve_modifications()?;
+    config.analysis.check_ranges()?;
 
     // Create output directory
     std::fs::create_dir_all(&config.output.directory)?;
 
-    let dotd_file_location = &config.analysis.dotd_file;
+    let settings = config.sweep.settings();
+    if settings.is_empty() {
+        search_runs(&mut config, args.ignore_cache)?;
+        return Ok(());
+    }
+    if config.output.stdout {
+        return Err(TimsSeekError::ParseError {
+            msg: "A sweep cannot stream its results to stdout".to_string(),
+        });
+    }
+    let directory = config.output.directory.clone();
+    let mut summaries = Vec::new();
+    for (name, ms_ppm, mobility_pct) in settings {
+        info!("Searching sweep setting {}", name);
+        if let Some(ppm) = ms_ppm {
+            config.analysis.tolerance.ms = MzToleramce::Ppm((ppm as _, ppm as _));
+        }
+        if let Some(pct) = mobility_pct {
+            config.analysis.tolerance.mobility = MobilityTolerance::Pct((pct as _, pct as _));
+        }
+        config.output.directory = directory.join(&name);
+        std::fs::create_dir_all(&config.output.directory)?;
+        let Some(run_directories) = search_runs(&mut config, args.ignore_cache)? else {
+            log::warn!("Interrupted, the sweep comparison is not written");
+            return Ok(());
+        };
+        let multiple_runs = run_directories.len() > 1;
+        for run_directory in run_directories {
+            let mut summary = summarize_run(&run_directory, config.sweep.fdr)?;
+            if multiple_runs {
+                summary.name = format!("{}/{}", name, summary.name);
+            }
+            summaries.push(summary);
+        }
+    }
+    write_comparison(&summaries, config.sweep.fdr, &directory)?;
+    info!(
+        "Wrote the comparison of {} sweep runs to {}",
+        summaries.len(),
+        directory.display()
+    );
+
+    Ok(())
+}
+
+/// Searches the input in every run of the config, each in its own
+/// subdirectory of the output directory if there are several, and returns
+/// the directories the runs were written to. None if the search was
+/// interrupted, then the remaining runs are not searched and the results
+/// are not combined.
+///
+/// The run specific settings of the config are restored afterwards.
+fn search_runs(
+    config: &mut Config,
+    ignore_cache: bool,
+) -> std::result::Result<Option<Vec<PathBuf>>, TimsSeekError> {
+    let shutdown = shutdown_flag()?;
+    let runs: Vec<PathBuf> = config
+        .analysis
+        .dotd_file
+        .iter()
+        .chain(config.analysis.additional_dotd_files.iter())
+        .cloned()
+        .collect();
+    if runs.is_empty() {
+        return Err(TimsSeekError::ParseError {
+            msg: "No .d file given (analysis.dotd_file or --dotd-file)".to_string(),
+        });
+    }
+    let multiple_runs = runs.len() > 1;
+    let num_runs = runs.len();
+    let first_run = config.analysis.dotd_file.clone();
+    let directory = config.output.directory.clone();
+    let configured_mz_range = config.analysis.isolation_mz_range;
+    let detect_gpf =
+        !config.analysis.skip_gpf_detection && config.analysis.isolation_windows.is_none();
+    let mut run_directories: Vec<(String, PathBuf)> = Vec::new();
+    let mut interrupted = false;
+    for run in runs {
+        config.analysis.isolation_mz_range = match configured_mz_range {
+            Some(range) => Some(range),
+            None if detect_gpf => detect_gas_phase_fraction(&run),
+            None => None,
+        };
+        if multiple_runs {
+            let mut name = run
+                .file_stem()
+                .map(|x| x.to_string_lossy().to_string())
+                .unwrap_or_default();
+            if run_directories.iter().any(|x| x.0 == name) {
+                name = format!("{}_{}", name, run_directories.len());
+            }
+            info!("Searching run {} ({})", name, run.display());
+            config.output.directory = directory.join(&name);
+            std::fs::create_dir_all(&config.output.directory)?;
+            run_directories.push((name, config.output.directory.clone()));
+        }
+        config.analysis.dotd_file = Some(run);
+        search_run(config, ignore_cache)?;
+        if shutdown.load(Ordering::Relaxed) {
+            interrupted = true;
+            break;
+        }
+    }
+    config.analysis.dotd_file = first_run;
+    config.analysis.isolation_mz_range = configured_mz_range;
+    config.output.directory = directory.clone();
+    if interrupted {
+        if multiple_runs {
+            log::warn!(
+                "Interrupted in run {} of {}, the other runs are not searched and the \
+                 results are not combined",
+                run_directories.len(),
+                num_runs
+            );
+        }
+        return Ok(None);
+    }
+    if !multiple_runs {
+        return Ok(Some(vec![directory]));
+    }
+    if !config.output.stdout {
+        let out = directory.join("combined_results.csv");
+        let num_rows = combine_runs(&run_directories, &out)?;
+        info!(
+            "Wrote {} results of {} runs to {}",
+            num_rows,
+            run_directories.len(),
+            out.display()
+        );
+    }
+    Ok(Some(run_directories.into_iter().map(|x| x.1).collect()))
+}
+
+/// Precursor m/z range of a gas-phase fractionated run, from its isolation
+/// windows. None for the other runs, or if the windows cannot be read.
+fn detect_gas_phase_fraction(dotd_file: &Path) -> Option<(f64, f64)> {
+    let windows = match IsolationWindowIndex::from_dotd(dotd_file) {
+        Ok(x) => x,
+        Err(e) => {
+            log::warn!("Not checking {} for gas-phase fractionation: {}", dotd_file.display(), e);
+            return None;
+        }
+    };
+    let (low, high) = windows.gas_phase_fraction()?;
+    info!(
+        "{} is a gas-phase fraction, only precursors within {:.1}-{:.1} m/z are queried",
+        dotd_file.display(),
+        low,
+        high
+    );
+    Some((low, high))
+}
+
+/// Searches the input in the run of `config.analysis.dotd_file`.
+fn search_run(config: &Config, ignore_cache: bool) -> std::result::Result<(), TimsSeekError> {
+    let dotd_file = config
+        .analysis
+        .dotd_file
+        .as_ref()
+        .ok_or_else(|| TimsSeekError::ParseError {
+            msg: "No .d file given (analysis.dotd_file or --dotd-file)".to_string(),
+        })?;
+    check_raw_data_path(dotd_file)?;
     let index = QuadSplittedTransposedIndex::from_path_centroided(
-        dotd_file_location
-            .clone()
-            .unwrap() // TODO: Error handling
-            .to_str()
-            .expect("Path is not convertable to string"),
+        dotd_file.to_str().expect("Path is not convertable to string"),
     )?;
 
     let factory = MultiCMGStatsFactory {
@@ -442,7 +3187,7 @@ fn main() -> std::result::Result<(), TimsSeekError> {
     };
 
     // Process based on input type
-    match config.input {
+    match config.input.clone() {
         InputConfig::Fasta { path, digestion } => {
             process_fasta(
                 path,
@@ -451,10 +3196,56 @@ fn main() -> std::result::Result<(), TimsSeekError> {
                 digestion,
                 &config.analysis,
                 &config.output,
+                ignore_cache,
+            )?;
+        }
+        InputConfig::Speclib {
+            path,
+            additional_paths,
+            decoys,
+            decoy_collisions,
+        } => {
+            process_speclib(
+                path,
+                &additional_paths,
+                (decoys, decoy_collisions),
+                &index,
+                &factory,
+                &config.analysis,
+                &config.output,
             )?;
         }
-        InputConfig::Speclib { path } => {
-            process_speclib(path, &index, &factory, &config.analysis, &config.output)?;
+        InputConfig::Union {
+            fasta,
+            digestion,
+            speclib,
+            speclib_decoys,
+            speclib_decoy_collisions,
+        } => {
+            let library_converter = config.analysis.library_converter()?;
+            let mut speclib = Speclib::from_library_file(&speclib, &library_converter)?;
+            resolve_speclib_decoy_collisions(
+                &mut speclib,
+                speclib_decoy_collisions,
+                &config.output.directory,
+            )?;
+            let speclib = speclib.with_decoys(speclib_decoys, &speclib_decoy_converter());
+            let (digests, proteins) = digest_fasta(
+                fasta,
+                digestion,
+                &config.analysis,
+                &config.output,
+                ignore_cache,
+            )?;
+            process_union(
+                speclib,
+                digests,
+                &proteins,
+                &index,
+                &factory,
+                &config.analysis,
+                &config.output,
+            )?;
         }
     }
 
@@ -0,0 +1,152 @@
+use crate::errors::TimsSeekError;
+use std::fs::{
+    File,
+    OpenOptions,
+};
+use std::io::{
+    BufWriter,
+    Write,
+};
+use std::path::Path;
+use std::time::Duration;
+
+/// Timings and counts of a single chunk of a run.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChunkMetrics {
+    pub chunk: usize,
+    pub num_queries: usize,
+    pub num_results: usize,
+    /// Building the queries of the chunk (digest to elution group conversion).
+    pub conversion: Duration,
+    /// Querying the index and aggregating the chromatograms.
+    pub query: Duration,
+    pub scoring: Duration,
+    pub write: Duration,
+    /// Peak resident memory of the process so far, if known.
+    pub peak_memory_kb: Option<u64>,
+    /// 1/K0 correction applied to the queries of the chunk, if the mobility
+    /// recalibration is on and has one yet.
+    pub mobility_offset: Option<f64>,
+}
+
+impl ChunkMetrics {
+    const COLUMNS: [&'static str; 10] = [
+        "chunk",
+        "num_queries",
+        "num_results",
+        "conversion_seconds",
+        "query_seconds",
+        "scoring_seconds",
+        "write_seconds",
+        "total_seconds",
+        "peak_memory_kb",
+        "mobility_offset",
+    ];
+
+    pub fn total(&self) -> Duration {
+        self.conversion + self.query + self.scoring + self.write
+    }
+
+    fn as_row(&self) -> [String; 10] {
+        [
+            self.chunk.to_string(),
+            self.num_queries.to_string(),
+            self.num_results.to_string(),
+            self.conversion.as_secs_f64().to_string(),
+            self.query.as_secs_f64().to_string(),
+            self.scoring.as_secs_f64().to_string(),
+            self.write.as_secs_f64().to_string(),
+            self.total().as_secs_f64().to_string(),
+            self.peak_memory_kb
+                .map(|x| x.to_string())
+                .unwrap_or_default(),
+            self.mobility_offset
+                .map(|x| x.to_string())
+                .unwrap_or_default(),
+        ]
+    }
+}
+
+/// Peak resident memory (VmHWM) of the process, only available on Linux.
+pub fn peak_memory_kb() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))
+        .and_then(|x| x.trim().trim_end_matches("kB").trim().parse().ok())
+}
+
+/// Writes one tab separated row per chunk, flushed as it goes so the file is
+/// usable while the run is going (or after it crashed).
+pub struct MetricsWriter {
+    writer: BufWriter<File>,
+}
+
+impl MetricsWriter {
+    /// With `append` (resumed runs) the rows go after the ones already in the
+    /// file, the header is only written to a new file.
+    pub fn new(path: &Path, append: bool) -> Result<Self, TimsSeekError> {
+        let file = match append {
+            true => OpenOptions::new().create(true).append(true).open(path)?,
+            false => File::create(path)?,
+        };
+        let is_new = file.metadata()?.len() == 0;
+        let mut writer = BufWriter::new(file);
+        if is_new {
+            writeln!(writer, "{}", ChunkMetrics::COLUMNS.join("\t"))?;
+            writer.flush()?;
+        }
+        Ok(Self { writer })
+    }
+
+    pub fn write(&mut self, metrics: &ChunkMetrics) -> Result<(), TimsSeekError> {
+        writeln!(self.writer, "{}", metrics.as_row().join("\t"))?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_row() {
+        let metrics = ChunkMetrics {
+            chunk: 3,
+            num_queries: 10,
+            num_results: 8,
+            conversion: Duration::from_millis(500),
+            query: Duration::from_secs(2),
+            scoring: Duration::from_secs(1),
+            write: Duration::from_millis(250),
+            peak_memory_kb: None,
+            mobility_offset: Some(0.02),
+        };
+        let row = metrics.as_row();
+        assert_eq!(row.len(), ChunkMetrics::COLUMNS.len());
+        assert_eq!(row[0], "3");
+        assert_eq!(row[7], "3.75");
+        assert_eq!(row[8], "");
+        assert_eq!(row[9], "0.02");
+    }
+
+    #[test]
+    fn test_metrics_append() {
+        let path = std::env::temp_dir().join("timsseek_test_metrics.tsv");
+        let _ = std::fs::remove_file(&path);
+        let metrics = ChunkMetrics::default();
+        MetricsWriter::new(&path, true).unwrap().write(&metrics).unwrap();
+        MetricsWriter::new(&path, true).unwrap().write(&metrics).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("chunk\t"));
+        assert!(lines[1].starts_with("0\t"));
+
+        MetricsWriter::new(&path, false).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
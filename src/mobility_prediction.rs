@@ -0,0 +1,303 @@
+use crate::errors::TimsSeekError;
+use crate::fragment_mass::elution_group_converter::supersimpleprediction;
+use crate::numeric::deserialize_decimal;
+use crate::rt_prediction::{
+    fit_line,
+    read_tsv,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::collections::{
+    BTreeMap,
+    HashMap,
+};
+use std::fmt::Debug;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::sync::Arc;
+
+/// Observed 1/K0 of a precursor, used to calibrate a predictor.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MobilityObservation {
+    pub sequence: String,
+    pub charge: u8,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    pub precursor_mz: f64,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    pub mobility: f64,
+}
+
+/// Ion mobility (1/K0) predictor used to set the mobility of the queries.
+pub trait MobilityPredictor: Debug + Send + Sync {
+    /// Predicted 1/K0 of a precursor of a ProForma sequence.
+    fn predict(&self, sequence: &str, precursor_mz: f64, charge: u8) -> f64;
+
+    /// Fits the predictor to the mobilities observed in the run.
+    fn calibrate(&mut self, observations: &[MobilityObservation]) -> Result<(), TimsSeekError>;
+}
+
+/// [`supersimpleprediction`] with a linear correction, identity by default.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SimpleMobilityModel {
+    pub slope: f64,
+    pub intercept: f64,
+}
+
+impl Default for SimpleMobilityModel {
+    fn default() -> Self {
+        Self {
+            slope: 1.0,
+            intercept: 0.0,
+        }
+    }
+}
+
+impl MobilityPredictor for SimpleMobilityModel {
+    fn predict(&self, _sequence: &str, precursor_mz: f64, charge: u8) -> f64 {
+        self.intercept + self.slope * supersimpleprediction(precursor_mz, charge as i32)
+    }
+
+    fn calibrate(&mut self, observations: &[MobilityObservation]) -> Result<(), TimsSeekError> {
+        let points: Vec<(f64, f64)> = observations
+            .iter()
+            .map(|x| {
+                (
+                    supersimpleprediction(x.precursor_mz, x.charge as i32),
+                    x.mobility,
+                )
+            })
+            .collect();
+        (self.slope, self.intercept) = fit_line(&points)?;
+        Ok(())
+    }
+}
+
+/// Linear regression of 1/K0 on the precursor m/z, one line per charge, eg.
+/// fitted on a previous run. Charges without a line use the
+/// [`SimpleMobilityModel`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PerChargeMobilityModel {
+    /// `(slope, intercept)` of the line of every charge.
+    pub lines: BTreeMap<u8, (f64, f64)>,
+    #[serde(skip)]
+    fallback: SimpleMobilityModel,
+}
+
+impl PerChargeMobilityModel {
+    pub fn new(lines: BTreeMap<u8, (f64, f64)>) -> Self {
+        Self {
+            lines,
+            fallback: SimpleMobilityModel::default(),
+        }
+    }
+
+    /// Reads the lines from a JSON file, eg. `{"lines": {"2": [0.0007,
+    /// 0.45], "3": [0.0009, 0.3]}}`.
+    pub fn from_json_file(path: &Path) -> Result<Self, TimsSeekError> {
+        let contents = std::fs::read_to_string(path)?;
+        serde_json::from_str(&contents).map_err(|e| TimsSeekError::ParseError {
+            msg: format!("Invalid mobility model file {}: {}", path.display(), e),
+        })
+    }
+}
+
+impl MobilityPredictor for PerChargeMobilityModel {
+    fn predict(&self, sequence: &str, precursor_mz: f64, charge: u8) -> f64 {
+        match self.lines.get(&charge) {
+            Some((slope, intercept)) => intercept + slope * precursor_mz,
+            None => self.fallback.predict(sequence, precursor_mz, charge),
+        }
+    }
+
+    /// Refits the line of every charge with enough observations.
+    fn calibrate(&mut self, observations: &[MobilityObservation]) -> Result<(), TimsSeekError> {
+        let mut by_charge: BTreeMap<u8, Vec<(f64, f64)>> = BTreeMap::new();
+        for x in observations {
+            by_charge
+                .entry(x.charge)
+                .or_default()
+                .push((x.precursor_mz, x.mobility));
+        }
+        let mut num_fitted = 0;
+        for (charge, points) in by_charge {
+            if let Ok(line) = fit_line(&points) {
+                self.lines.insert(charge, line);
+                num_fitted += 1;
+            }
+        }
+        if num_fitted == 0 {
+            return Err(TimsSeekError::ParseError {
+                msg: format!(
+                    "Cannot fit any charge of the mobility model on {} observations",
+                    observations.len()
+                ),
+            });
+        }
+        let _ = self.fallback.calibrate(observations);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MobilityTableEntry {
+    sequence: String,
+    charge: u8,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    mobility: f64,
+}
+
+/// 1/K0 predicted ahead of time by an external (eg. CCS) model, looked up by
+/// sequence and charge. Precursors missing from the table use the
+/// [`SimpleMobilityModel`].
+///
+/// Calibration maps the table values to the run with a linear fit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableMobilityPredictor {
+    mobilities: HashMap<(String, u8), f64>,
+    slope: f64,
+    intercept: f64,
+    fallback: SimpleMobilityModel,
+}
+
+impl TableMobilityPredictor {
+    pub fn new(mobilities: HashMap<(String, u8), f64>) -> Self {
+        Self {
+            mobilities,
+            slope: 1.0,
+            intercept: 0.0,
+            fallback: SimpleMobilityModel::default(),
+        }
+    }
+
+    /// Reads a tab separated file with `sequence`, `charge` and `mobility`
+    /// columns.
+    pub fn from_tsv(path: &Path) -> Result<Self, TimsSeekError> {
+        let entries: Vec<MobilityTableEntry> = read_tsv(path)?;
+        Ok(Self::new(
+            entries
+                .into_iter()
+                .map(|x| ((x.sequence, x.charge), x.mobility))
+                .collect(),
+        ))
+    }
+}
+
+impl MobilityPredictor for TableMobilityPredictor {
+    fn predict(&self, sequence: &str, precursor_mz: f64, charge: u8) -> f64 {
+        match self.mobilities.get(&(sequence.to_string(), charge)) {
+            Some(x) => self.intercept + self.slope * x,
+            None => self.fallback.predict(sequence, precursor_mz, charge),
+        }
+    }
+
+    fn calibrate(&mut self, observations: &[MobilityObservation]) -> Result<(), TimsSeekError> {
+        let points: Vec<(f64, f64)> = observations
+            .iter()
+            .filter_map(|x| {
+                self.mobilities
+                    .get(&(x.sequence.clone(), x.charge))
+                    .map(|predicted| (*predicted, x.mobility))
+            })
+            .collect();
+        (self.slope, self.intercept) = fit_line(&points)?;
+        // The fallback is only calibrated if there is enough to go on.
+        let _ = self.fallback.calibrate(observations);
+        Ok(())
+    }
+}
+
+/// Which mobility predictor is used for the queries.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MobilityModel {
+    /// The built-in [`SimpleMobilityModel`].
+    #[default]
+    Builtin,
+    /// [`PerChargeMobilityModel`] read from a JSON file.
+    PerCharge(PathBuf),
+    /// [`TableMobilityPredictor`] read from a tab separated file.
+    Table(PathBuf),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MobilityPredictorConfig {
+    pub model: MobilityModel,
+    /// Tab separated `sequence`, `charge`, `precursor_mz` and `mobility` of
+    /// precursors observed in the run, the predictor is calibrated on them.
+    pub calibration: Option<PathBuf>,
+    /// Also predict the 1/K0 of spectral library queries, by default the
+    /// values of the library are passed through.
+    pub apply_to_speclib: bool,
+}
+
+impl MobilityPredictorConfig {
+    /// Builds (and calibrates) the configured predictor.
+    pub fn build(&self) -> Result<Arc<dyn MobilityPredictor>, TimsSeekError> {
+        let mut predictor: Box<dyn MobilityPredictor> = match &self.model {
+            MobilityModel::Builtin => Box::new(SimpleMobilityModel::default()),
+            MobilityModel::PerCharge(path) => {
+                Box::new(PerChargeMobilityModel::from_json_file(path)?)
+            }
+            MobilityModel::Table(path) => Box::new(TableMobilityPredictor::from_tsv(path)?),
+        };
+        if let Some(path) = &self.calibration {
+            let observations: Vec<MobilityObservation> = read_tsv(path)?;
+            predictor.calibrate(&observations)?;
+        }
+        Ok(Arc::from(predictor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mobility_calibration() {
+        let mut model = SimpleMobilityModel::default();
+        assert_eq!(
+            model.predict("PEPTIDEPINK", 905.0, 2),
+            supersimpleprediction(905.0, 2)
+        );
+
+        // The run reads 0.05 higher than the model.
+        let observations: Vec<MobilityObservation> = [(500.0, 2), (700.0, 2), (600.0, 3)]
+            .iter()
+            .map(|(mz, charge)| MobilityObservation {
+                sequence: "PEPTIDEK".to_string(),
+                charge: *charge,
+                precursor_mz: *mz,
+                mobility: supersimpleprediction(*mz, *charge as i32) + 0.05,
+            })
+            .collect();
+        model.calibrate(&observations).unwrap();
+        assert!((model.slope - 1.0).abs() < 1e-6);
+        assert!((model.intercept - 0.05).abs() < 1e-6);
+
+        let mut per_charge = PerChargeMobilityModel::new(BTreeMap::from([(2, (0.001, 0.3))]));
+        assert!((per_charge.predict("PEPTIDEK", 600.0, 2) - 0.9).abs() < 1e-9);
+        assert_eq!(
+            per_charge.predict("PEPTIDEK", 600.0, 3),
+            supersimpleprediction(600.0, 3)
+        );
+        // Only the charges with enough observations are refitted.
+        per_charge.calibrate(&observations[..2]).unwrap();
+        let expected = supersimpleprediction(600.0, 2) + 0.05;
+        assert!((per_charge.predict("PEPTIDEK", 600.0, 2) - expected).abs() < 0.01);
+        let parsed: PerChargeMobilityModel =
+            serde_json::from_str(r#"{"lines": {"2": [0.001, 0.3]}}"#).unwrap();
+        assert_eq!(parsed.lines[&2], (0.001, 0.3));
+
+        let table = TableMobilityPredictor::new(HashMap::from([(("PEPTIDEK".to_string(), 2), 0.9)]));
+        assert_eq!(table.predict("PEPTIDEK", 500.0, 2), 0.9);
+        assert_eq!(
+            table.predict("PEPTIDEK", 500.0, 3),
+            supersimpleprediction(500.0, 3)
+        );
+    }
+}
@@ -1,12 +1,22 @@
+use crate::digest::decoys::{
+    as_cleavage_shuffled_decoy_string,
+    as_mutated_decoy_string,
+    as_shuffled_decoy_string,
+    CleavageSites,
+};
 use crate::fragment_mass::fragment_mass_builder::SafePosition;
-use rayon::iter::Zip as RayonZip;
+use crate::fragment_mass::labeling::HeavyLabel;
+use crate::isotopes::monoisotopic_precursor_mz;
 use rayon::prelude::*;
-use rayon::vec::IntoIter as RayonVecIntoIter;
 use serde::{
     Deserialize,
     Serialize,
 };
-use std::collections::HashSet;
+use std::collections::HashMap;
+use std::hash::{
+    Hash,
+    Hasher,
+};
 use std::ops::Range;
 use std::sync::Arc;
 use timsquery::models::elution_group::ElutionGroup;
@@ -15,11 +25,19 @@ use timsquery::models::elution_group::ElutionGroup;
 ///
 /// NOTE: The main difference between the decoy and reversed decoy is that the reversed decoy
 /// has already been reversed, thus converting it to a string can be done as-is.
+/// Shuffled decoys (both kinds) keep the seed used to shuffle them, and the
+/// cleavage preserving ones the cleavage sites, so the sequence can be
+/// re-generated on demand. Mass-shifted decoys keep the
+/// sequence of their target, only their fragment m/z differ.
 #[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, std::hash::Hash, PartialOrd, Ord)]
 pub enum DecoyMarking {
     Target,
     Decoy,
     ReversedDecoy,
+    ShuffledDecoy(u64),
+    CleavageShuffledDecoy(u64, CleavageSites),
+    MutatedDecoy,
+    MassShiftedDecoy,
 }
 impl DecoyMarking {
     pub fn as_str(&self) -> &'static str {
@@ -27,6 +45,44 @@ impl DecoyMarking {
             DecoyMarking::Target => "Target",
             DecoyMarking::Decoy => "Decoy",
             DecoyMarking::ReversedDecoy => "Decoy",
+            DecoyMarking::ShuffledDecoy(_) => "Decoy",
+            DecoyMarking::CleavageShuffledDecoy(..) => "Decoy",
+            DecoyMarking::MutatedDecoy => "Decoy",
+            DecoyMarking::MassShiftedDecoy => "Decoy",
+        }
+    }
+}
+
+/// Builds the decoys of target digests.
+///
+/// `replicate` counts the decoys built for the same target, so strategies
+/// can give several different decoys per target.
+pub trait DecoyGenerator: std::fmt::Debug + Send + Sync {
+    /// None if no decoy is built for this target and replicate.
+    fn decoy(&self, target: &DigestSlice, replicate: usize) -> Option<DigestSlice>;
+}
+
+/// Whether a peptide maps to a single protein or to several of them.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, std::hash::Hash)]
+pub enum PeptideUniqueness {
+    Proteotypic,
+    Shared,
+}
+
+impl PeptideUniqueness {
+    /// None if the peptide maps to no protein.
+    pub fn from_num_proteins(num_proteins: usize) -> Option<Self> {
+        match num_proteins {
+            0 => None,
+            1 => Some(PeptideUniqueness::Proteotypic),
+            _ => Some(PeptideUniqueness::Shared),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PeptideUniqueness::Proteotypic => "proteotypic",
+            PeptideUniqueness::Shared => "shared",
         }
     }
 }
@@ -36,6 +92,25 @@ pub struct DigestSlice {
     ref_seq: Arc<str>,
     range: Range<usize>,
     pub decoy: DecoyMarking,
+    /// Ids of the proteins the peptide comes from, empty if unknown (eg.
+    /// spectral libraries).
+    protein_ids: Arc<[u32]>,
+    /// Cleavage sites inside the peptide, None if it was not digested here.
+    pub missed_cleavages: Option<usize>,
+    /// Proteotypic/shared classification against the whole database, None if
+    /// it was not classified.
+    pub uniqueness: Option<PeptideUniqueness>,
+    /// Library the precursor comes from, for merged spectral libraries.
+    pub library_source: Option<Arc<str>>,
+    /// Id shared by a target and the decoys generated from it, for paired
+    /// target-decoy competition. None if there is no generated counterpart.
+    pub target_decoy_pair_id: Option<u64>,
+    /// ProForma sequence with the variable modifications searched, None for
+    /// the unmodified peptide.
+    pub peptidoform: Option<Arc<str>>,
+    /// Whether the peptide starts at the N-terminus of one of its proteins
+    /// (after the initiator methionine, if it was removed).
+    pub protein_nterm: bool,
 }
 
 impl Serialize for DigestSlice {
@@ -54,17 +129,78 @@ impl DigestSlice {
             ref_seq,
             range,
             decoy,
+            protein_ids: Arc::from([]),
+            missed_cleavages: None,
+            uniqueness: None,
+            library_source: None,
+            target_decoy_pair_id: None,
+            peptidoform: None,
+            protein_nterm: false,
         }
     }
 
-    pub fn as_decoy(&self) -> DigestSlice {
+    pub fn with_missed_cleavages(mut self, missed_cleavages: usize) -> Self {
+        self.missed_cleavages = Some(missed_cleavages);
+        self
+    }
+
+    pub fn with_protein_ids(mut self, protein_ids: &[u32]) -> Self {
+        self.protein_ids = protein_ids.into();
+        self
+    }
+
+    pub fn with_protein_nterm(mut self, protein_nterm: bool) -> Self {
+        self.protein_nterm = protein_nterm;
+        self
+    }
+
+    pub fn with_target_decoy_pair_id(mut self, pair_id: u64) -> Self {
+        self.target_decoy_pair_id = Some(pair_id);
+        self
+    }
+
+    pub fn protein_ids(&self) -> &[u32] {
+        &self.protein_ids
+    }
+
+    fn merge_protein_ids(&mut self, other: &[u32]) {
+        let mut ids: Vec<u32> = self.protein_ids.iter().chain(other).copied().collect();
+        ids.sort_unstable();
+        ids.dedup();
+        self.protein_ids = ids.into();
+    }
+
+    /// Copy of the digest marked as `marking`. The decoy sequence is
+    /// re-generated from the marking, so the modifications of the target are
+    /// dropped and placed again on the final (decoy) sequence.
+    pub fn with_decoy(&self, marking: DecoyMarking) -> DigestSlice {
         DigestSlice {
             ref_seq: self.ref_seq.clone(),
             range: self.range.clone(),
-            decoy: DecoyMarking::Decoy,
+            decoy: marking,
+            protein_ids: self.protein_ids.clone(),
+            missed_cleavages: self.missed_cleavages,
+            uniqueness: self.uniqueness,
+            library_source: self.library_source.clone(),
+            target_decoy_pair_id: self.target_decoy_pair_id,
+            peptidoform: None,
+            protein_nterm: self.protein_nterm,
+        }
+    }
+
+    /// Modified sequence if any, otherwise the sequence.
+    pub fn peptidoform(&self) -> String {
+        match &self.peptidoform {
+            Some(x) => x.to_string(),
+            None => self.clone().into(),
         }
     }
 
+    /// Sequence the digest was cut from, before any decoy transformation.
+    pub fn unmarked_sequence(&self) -> &str {
+        &self.ref_seq.as_ref()[self.range.clone()]
+    }
+
     pub fn as_decoy_string(&self) -> String {
         as_decoy_string(&self.ref_seq.as_ref()[self.range.clone()])
     }
@@ -78,15 +214,75 @@ impl DigestSlice {
     }
 }
 
-pub fn deduplicate_digests(mut digest_slices: Vec<DigestSlice>) -> Vec<DigestSlice> {
-    let mut seen = HashSet::new();
-    digest_slices.retain(|x| {
+/// Keeps the first occurrence of every sequence, merging the proteins of the
+/// duplicates (with the same decoy marking) into it.
+///
+/// Semi-enzymatic digestion emits the same sub-sequence from several
+/// enzymatic spans (and missed cleavage levels), so duplicates are common.
+pub fn deduplicate_digests(digest_slices: Vec<DigestSlice>) -> Vec<DigestSlice> {
+    deduplicate_digests_with_variants(digest_slices, false).0
+}
+
+/// Other sequences collapsed into each kept sequence during deduplication.
+pub type CollapsedVariants = HashMap<String, Vec<String>>;
+
+/// Same as [`deduplicate_digests`], optionally treating isoleucine and leucine
+/// as the same residue (they have the same mass).
+///
+/// The first sequence of each group is kept, the others are returned keyed by
+/// it.
+pub fn deduplicate_digests_with_variants(
+    digest_slices: Vec<DigestSlice>,
+    il_equivalent: bool,
+) -> (Vec<DigestSlice>, CollapsedVariants) {
+    let mut seen: HashMap<String, usize> = HashMap::with_capacity(digest_slices.len());
+    let mut out: Vec<DigestSlice> = Vec::with_capacity(digest_slices.len());
+    let mut variants = CollapsedVariants::new();
+    for x in digest_slices {
         let local_str: String = x.clone().into();
-        let is_first = !seen.contains(&local_str);
-        seen.insert(local_str);
-        is_first
-    });
-    digest_slices
+        let key = if il_equivalent {
+            local_str.replace('I', "L")
+        } else {
+            local_str.clone()
+        };
+        match seen.get(&key) {
+            Some(&i) => {
+                if out[i].decoy == x.decoy {
+                    out[i].merge_protein_ids(&x.protein_ids);
+                    out[i].protein_nterm |= x.protein_nterm;
+                }
+                let kept: String = out[i].clone().into();
+                if kept != local_str {
+                    let entry = variants.entry(kept).or_default();
+                    if !entry.contains(&local_str) {
+                        entry.push(local_str);
+                    }
+                }
+            }
+            None => {
+                seen.insert(key, out.len());
+                out.push(x);
+            }
+        }
+    }
+    (out, variants)
+}
+
+/// Writes the collapsed sequence variants as `kept_sequence,variants` rows,
+/// with the variants `;` separated.
+pub fn write_collapsed_variants_to_csv<P: AsRef<std::path::Path>>(
+    variants: &CollapsedVariants,
+    out_path: P,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let mut writer = csv::Writer::from_path(out_path.as_ref())?;
+    writer.write_record(["kept_sequence", "variants"])?;
+    let mut keys: Vec<&String> = variants.keys().collect();
+    keys.sort();
+    for key in keys {
+        writer.write_record([key.as_str(), variants[key].join(";").as_str()])?;
+    }
+    writer.flush()?;
+    Ok(())
 }
 
 impl From<DigestSlice> for String {
@@ -97,6 +293,12 @@ impl From<DigestSlice> for String {
             DecoyMarking::Target => tmp.to_string(),
             DecoyMarking::ReversedDecoy => tmp.to_string(),
             DecoyMarking::Decoy => as_decoy_string(tmp),
+            DecoyMarking::ShuffledDecoy(seed) => as_shuffled_decoy_string(tmp, seed),
+            DecoyMarking::CleavageShuffledDecoy(seed, sites) => {
+                as_cleavage_shuffled_decoy_string(tmp, seed, &sites)
+            }
+            DecoyMarking::MutatedDecoy => as_mutated_decoy_string(tmp),
+            DecoyMarking::MassShiftedDecoy => tmp.to_string(),
         }
     }
 }
@@ -112,10 +314,72 @@ fn as_decoy_string(sequence: &str) -> String {
     sequence
 }
 
+/// Isotope label channel a query was built for.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, std::hash::Hash)]
+pub enum LabelChannel {
+    Light,
+    Heavy,
+}
+
+impl LabelChannel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LabelChannel::Light => "Light",
+            LabelChannel::Heavy => "Heavy",
+        }
+    }
+}
+
+/// FNV-1a hasher, unlike the std ones its output does not change between
+/// Rust versions, so it can be used for ids that end up in the outputs.
+struct StableHasher(u64);
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+/// Hash of `value` that is the same in every run, build and platform (as
+/// long as it hashes no `usize`).
+pub fn stable_hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = StableHasher(0xcbf29ce484222325);
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Channel of a query plus the id linking it to the other channels of the
+/// same precursor.
+///
+/// The pair id comes from the light precursor (see [`Self::pair_id_of`]), so
+/// it is the same in every chunk and run.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+pub struct ChannelLabel {
+    pub channel: LabelChannel,
+    pub pair_id: u64,
+}
+
+impl ChannelLabel {
+    /// Hash of the searched sequence, decoy marking and charge of a
+    /// precursor.
+    pub fn pair_id_of(digest: &DigestSlice, charge: u8) -> u64 {
+        stable_hash(&(String::from(digest.clone()), digest.decoy.as_str(), charge))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct NamedQueryChunk {
     digests: Vec<DigestSlice>,
     charges: Vec<u8>,
+    channels: Vec<ChannelLabel>,
+    rt_predicted: Vec<bool>,
     pub queries: Vec<ElutionGroup<SafePosition>>,
 }
 
@@ -127,18 +391,128 @@ impl NamedQueryChunk {
     ) -> Self {
         assert_eq!(digests.len(), charges.len());
         assert_eq!(digests.len(), queries.len());
+        let channels = digests
+            .iter()
+            .zip(&charges)
+            .map(|(digest, charge)| ChannelLabel {
+                channel: LabelChannel::Light,
+                pair_id: ChannelLabel::pair_id_of(digest, *charge),
+            })
+            .collect();
+        let rt_predicted = vec![false; digests.len()];
+        Self {
+            digests,
+            charges,
+            channels,
+            rt_predicted,
+            queries,
+        }
+    }
+
+    /// Marks which queries have a predicted RT (none by default).
+    pub fn with_predicted_rts(mut self, rt_predicted: Vec<bool>) -> Self {
+        assert_eq!(rt_predicted.len(), self.queries.len());
+        self.rt_predicted = rt_predicted;
+        self
+    }
+
+    /// Replaces the default channels (all light, unpaired).
+    pub fn with_channels(mut self, channels: Vec<ChannelLabel>) -> Self {
+        assert_eq!(channels.len(), self.queries.len());
+        self.channels = channels;
+        self
+    }
+
+    /// Appends a heavy-labeled copy of every light query in the chunk.
+    ///
+    /// Heavy queries share the pair id of the light query they were built from.
+    pub fn with_heavy_channels(mut self, label: &HeavyLabel) -> Self {
+        let num_light = self.len();
+        for i in 0..num_light {
+            if self.channels[i].channel != LabelChannel::Light {
+                continue;
+            }
+            let peptidoform = self.digests[i].peptidoform();
+            let heavy = label.heavy_elution_group(&peptidoform, self.charges[i], &self.queries[i]);
+            self.queries.push(heavy);
+            self.digests.push(self.digests[i].clone());
+            self.charges.push(self.charges[i]);
+            self.rt_predicted.push(self.rt_predicted[i]);
+            self.channels.push(ChannelLabel {
+                channel: LabelChannel::Heavy,
+                pair_id: self.channels[i].pair_id,
+            });
+        }
+        self
+    }
+
+    /// Keeps only the queries whose monoisotopic precursor m/z passes `keep_mz`.
+    pub fn retain_by_precursor_mz<F: Fn(f64) -> bool>(self, keep_mz: F) -> Self {
+        let keep: Vec<bool> = self
+            .queries
+            .iter()
+            .map(|x| {
+                keep_mz(monoisotopic_precursor_mz(
+                    &x.precursor_mzs,
+                    x.expected_precursor_intensity.as_deref(),
+                ))
+            })
+            .collect();
+        self.retain_mask(&keep)
+    }
+
+    /// Keeps only the queries whose digest and charge pass `keep`.
+    pub fn retain<F: Fn(&DigestSlice, u8) -> bool>(self, keep: F) -> Self {
+        let keep: Vec<bool> = self
+            .digests
+            .iter()
+            .zip(self.charges.iter())
+            .map(|(digest, charge)| keep(digest, *charge))
+            .collect();
+        self.retain_mask(&keep)
+    }
+
+    fn retain_mask(self, keep: &[bool]) -> Self {
+        let mut keep_iter = keep.iter();
+        let mut queries = self.queries;
+        queries.retain(|_| *keep_iter.next().unwrap());
+        let mut keep_iter = keep.iter();
+        let mut digests = self.digests;
+        digests.retain(|_| *keep_iter.next().unwrap());
+        let mut keep_iter = keep.iter();
+        let mut charges = self.charges;
+        charges.retain(|_| *keep_iter.next().unwrap());
+        let mut keep_iter = keep.iter();
+        let mut channels = self.channels;
+        channels.retain(|_| *keep_iter.next().unwrap());
+        let mut keep_iter = keep.iter();
+        let mut rt_predicted = self.rt_predicted;
+        rt_predicted.retain(|_| *keep_iter.next().unwrap());
+
         Self {
             digests,
             charges,
+            channels,
+            rt_predicted,
             queries,
         }
     }
 
+    /// Sets the library source of every query in the chunk.
+    pub fn with_library_source(mut self, source: &Arc<str>) -> Self {
+        for digest in self.digests.iter_mut() {
+            digest.library_source = Some(source.clone());
+        }
+        self
+    }
+
     pub fn into_zip_par_iter(
         self,
-    ) -> RayonZip<
-        RayonVecIntoIter<ElutionGroup<SafePosition>>,
-        RayonZip<RayonVecIntoIter<DigestSlice>, RayonVecIntoIter<u8>>,
+    ) -> impl IndexedParallelIterator<
+        Item = (
+            ElutionGroup<SafePosition>,
+            (DigestSlice, u8, ChannelLabel),
+        ),
     > {
         // IN THEORY I should implement IntoIter for this struct
         // but I failed at it (skill issues?) so this will do for now.
@@ -146,10 +520,24 @@ impl NamedQueryChunk {
         self.queries.into_par_iter().zip(
             self.digests
                 .into_par_iter()
-                .zip(self.charges.into_par_iter()),
+                .zip(self.charges.into_par_iter())
+                .zip(self.channels.into_par_iter())
+                .map(|((digest, charge), channel)| (digest, charge, channel)),
         )
     }
 
+    pub fn digests(&self) -> &[DigestSlice] {
+        &self.digests
+    }
+
+    pub fn charges(&self) -> &[u8] {
+        &self.charges
+    }
+
+    pub fn rt_predicted(&self) -> &[bool] {
+        &self.rt_predicted
+    }
+
     pub fn len(&self) -> usize {
         self.queries.len()
     }
@@ -169,12 +557,34 @@ mod tests {
             ref_seq: seq.clone(),
             range: 0..seq.as_ref().len(),
             decoy: DecoyMarking::Target,
+            protein_ids: Arc::from([]),
+            missed_cleavages: None,
+            uniqueness: None,
+            library_source: None,
+            target_decoy_pair_id: None,
+            peptidoform: None,
+            protein_nterm: false,
         };
         let decoy = my_digest.as_decoy_string();
         assert_eq!(Into::<String>::into(my_digest.clone()), "PEPTIDEPINK");
         assert_eq!(Into::<String>::into(decoy.clone()), "PNIPEDITPEK");
     }
 
+    #[test]
+    fn test_channel_pair_id() {
+        let digest = |protein: &str, start: usize, decoy: DecoyMarking| {
+            let seq: Arc<str> = protein.into();
+            DigestSlice::new(seq, start..start + 8, decoy)
+        };
+        let target = ChannelLabel::pair_id_of(&digest("PEPTIDEK", 0, DecoyMarking::Target), 2);
+        // Same peptide cut from another protein.
+        let other = digest("AAKPEPTIDEK", 3, DecoyMarking::Target);
+        assert_eq!(ChannelLabel::pair_id_of(&other, 2), target);
+        assert_ne!(ChannelLabel::pair_id_of(&other, 3), target);
+        let decoy = digest("PEPTIDEK", 0, DecoyMarking::MassShiftedDecoy);
+        assert_ne!(ChannelLabel::pair_id_of(&decoy, 2), target);
+    }
+
     #[test]
     fn test_deduplicate_digests() {
         let seq: Arc<str> = "PEPTIDEPINKTOMATOTOMATO".into();
@@ -184,21 +594,68 @@ mod tests {
                 ref_seq: seq.clone(),
                 range: 0..seq.as_ref().len(),
                 decoy: DecoyMarking::Target,
+                protein_ids: Arc::from([0]),
+                missed_cleavages: None,
+                uniqueness: None,
+                library_source: None,
+                target_decoy_pair_id: None,
+                peptidoform: None,
+                protein_nterm: false,
             },
             DigestSlice {
                 ref_seq: seq.clone(),
                 range: 0..seq2.as_ref().len(), // Note the short length
                 decoy: DecoyMarking::Target,
+                protein_ids: Arc::from([0]),
+                missed_cleavages: None,
+                uniqueness: None,
+                library_source: None,
+                target_decoy_pair_id: None,
+                peptidoform: None,
+                protein_nterm: false,
             },
             DigestSlice {
                 ref_seq: seq2.clone(),
                 range: 0..seq2.as_ref().len(),
                 decoy: DecoyMarking::Target,
+                protein_ids: Arc::from([1]),
+                missed_cleavages: None,
+                uniqueness: None,
+                library_source: None,
+                target_decoy_pair_id: None,
+                peptidoform: None,
+                protein_nterm: false,
             },
         ];
         let deduped = deduplicate_digests(digests);
         assert_eq!(deduped.len(), 2);
         assert_eq!(deduped[0].len(), seq.as_ref().len());
         assert_eq!(deduped[1].len(), seq2.as_ref().len());
+        assert_eq!(deduped[0].protein_ids(), &[0]);
+        assert_eq!(deduped[1].protein_ids(), &[0, 1]);
+    }
+
+    #[test]
+    fn test_deduplicate_digests_il_equivalent() {
+        let digests: Vec<DigestSlice> = ["PEPTIDEK", "PEPTLDEK", "PEPTLDEK", "PINK"]
+            .iter()
+            .enumerate()
+            .map(|(i, x)| {
+                let seq: Arc<str> = (*x).into();
+                DigestSlice::new(seq.clone(), 0..seq.len(), DecoyMarking::Target)
+                    .with_protein_ids(&[i as u32])
+            })
+            .collect();
+
+        let (deduped, variants) = deduplicate_digests_with_variants(digests.clone(), false);
+        assert_eq!(deduped.len(), 3);
+        assert!(variants.is_empty());
+
+        let (deduped, variants) = deduplicate_digests_with_variants(digests, true);
+        assert_eq!(deduped.len(), 2);
+        assert_eq!(String::from(deduped[0].clone()), "PEPTIDEK");
+        assert_eq!(deduped[0].protein_ids(), &[0, 1, 2]);
+        assert_eq!(variants.len(), 1);
+        assert_eq!(variants["PEPTIDEK"], vec!["PEPTLDEK".to_string()]);
     }
 }
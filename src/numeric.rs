@@ -0,0 +1,138 @@
+use serde::{
+    Deserialize,
+    Deserializer,
+};
+
+/// Parses a decimal number written in any locale, eg. `1234.5`, `1234,5`
+/// (comma decimal separator, as exported by spreadsheets in most of Europe),
+/// `1,234.5`, `1.234,5` or `1 234,5`.
+///
+/// The last of `.` and `,` is the decimal separator, a single comma is one
+/// too. A single comma followed by exactly three digits (eg. `1,234`) could
+/// be either, so it is rejected.
+pub fn parse_decimal(value: &str) -> Result<f64, String> {
+    let trimmed: String = value
+        .trim()
+        .chars()
+        .filter(|x| !matches!(x, ' ' | '\u{a0}' | '\u{202f}' | '\''))
+        .collect();
+    if is_ambiguous_comma(&trimmed) {
+        return Err(format!(
+            "ambiguous number {:?}, write it as eg. 1.234 or 1234 (or 1234.0)",
+            value
+        ));
+    }
+    let normalized = match (trimmed.rfind('.'), trimmed.rfind(',')) {
+        (Some(dot), Some(comma)) if comma > dot => trimmed.replace('.', "").replace(',', "."),
+        (Some(_), Some(_)) => trimmed.replace(',', ""),
+        (None, Some(_)) if trimmed.matches(',').count() == 1 => trimmed.replace(',', "."),
+        (None, Some(_)) => trimmed.replace(',', ""),
+        _ => trimmed,
+    };
+    normalized
+        .parse::<f64>()
+        .map_err(|_| format!("invalid number {:?}, expected eg. 1234.5 or 1234,5", value))
+}
+
+/// Whether the only separator is a comma that could be a thousands separator
+/// as well as a decimal one, ie. 1 to 3 digits (not a lone 0) before it and
+/// exactly 3 after it.
+fn is_ambiguous_comma(value: &str) -> bool {
+    let Some((integer, fraction)) = value.split_once(',') else {
+        return false;
+    };
+    let integer = integer.strip_prefix(['-', '+']).unwrap_or(integer);
+    let all_digits = |x: &str| x.chars().all(|c| c.is_ascii_digit());
+    (1..=3).contains(&integer.len())
+        && integer != "0"
+        && all_digits(integer)
+        && fraction.len() == 3
+        && all_digits(fraction)
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NumberOrText {
+    Number(f64),
+    Text(String),
+}
+
+/// Deserializes a number that may be written with [`parse_decimal`]
+/// conventions (`#[serde(deserialize_with = "deserialize_decimal")]`).
+pub fn deserialize_decimal<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match NumberOrText::deserialize(deserializer)? {
+        NumberOrText::Number(x) => Ok(x),
+        NumberOrText::Text(x) => parse_decimal(&x).map_err(serde::de::Error::custom),
+    }
+}
+
+/// Same as [`deserialize_decimal`], for `f32` fields.
+pub fn deserialize_decimal_f32<'de, D>(deserializer: D) -> Result<f32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_decimal(deserializer).map(|x| x as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_decimal() {
+        assert_eq!(parse_decimal("1234.5"), Ok(1234.5));
+        assert_eq!(parse_decimal("1234,5"), Ok(1234.5));
+        assert_eq!(parse_decimal(" -0,85 "), Ok(-0.85));
+        assert_eq!(parse_decimal("1,234.5"), Ok(1234.5));
+        assert_eq!(parse_decimal("1.234,5"), Ok(1234.5));
+        assert_eq!(parse_decimal("1 234,5"), Ok(1234.5));
+        assert_eq!(parse_decimal("1,234,567"), Ok(1234567.0));
+        assert_eq!(parse_decimal("1,5E-03"), Ok(1.5e-3));
+        let err = parse_decimal("1.2.3").unwrap_err();
+        assert!(err.contains("\"1.2.3\""), "{}", err);
+        assert!(parse_decimal("").is_err());
+    }
+
+    #[test]
+    fn test_parse_decimal_ambiguous_comma() {
+        let err = parse_decimal("1,234").unwrap_err();
+        assert!(err.contains("ambiguous"), "{}", err);
+        assert!(parse_decimal("-12,500").is_err());
+        // A leading 0, more than 3 integer digits, another separator or a
+        // fraction of another length settle it.
+        assert_eq!(parse_decimal("0,125"), Ok(0.125));
+        assert_eq!(parse_decimal("1234,567"), Ok(1234.567));
+        assert_eq!(parse_decimal("1,234,567"), Ok(1234567.0));
+        assert_eq!(parse_decimal("1,2345"), Ok(1.2345));
+        assert_eq!(parse_decimal("1,234.5"), Ok(1234.5));
+    }
+
+    #[test]
+    fn test_deserialize_decimal_tsv() {
+        #[derive(Debug, Deserialize)]
+        struct Row {
+            sequence: String,
+            #[serde(deserialize_with = "deserialize_decimal_f32")]
+            rt_seconds: f32,
+        }
+
+        let tsv = "sequence\trt_seconds\nPEPTIDEK\t123,5\nLESLIEK\t80.25\n";
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .from_reader(tsv.as_bytes());
+        let rows: Vec<Row> = reader.deserialize().collect::<Result<_, _>>().unwrap();
+        assert_eq!(rows[0].sequence, "PEPTIDEK");
+        assert_eq!(rows[0].rt_seconds, 123.5);
+        assert_eq!(rows[1].rt_seconds, 80.25);
+
+        let tsv = "sequence\trt_seconds\nPEPTIDEK\tabc\n";
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .from_reader(tsv.as_bytes());
+        let err = reader.deserialize::<Row>().next().unwrap().unwrap_err();
+        assert!(err.to_string().contains("invalid number"), "{}", err);
+    }
+}
@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+/// Estimates the remaining run time from the measured cost of the chunks
+/// processed so far.
+///
+/// Chunks can differ a lot in cost (eg. decoy chunks, or RT-sorted chunks in
+/// a crowded part of the gradient), so instead of assuming every chunk takes
+/// the same time the estimate uses the recent cost per query (exponential
+/// moving average) and the number of queries expected in the remaining chunks.
+#[derive(Debug, Clone)]
+pub struct ChunkCostEstimator {
+    total_chunks: usize,
+    chunks_done: usize,
+    queries_done: usize,
+    secs_per_query: Option<f64>,
+    /// Weight of the latest chunk in the moving average.
+    pub smoothing: f64,
+}
+
+impl ChunkCostEstimator {
+    pub fn new(total_chunks: usize) -> Self {
+        Self {
+            total_chunks,
+            chunks_done: 0,
+            queries_done: 0,
+            secs_per_query: None,
+            smoothing: 0.3,
+        }
+    }
+
+    pub fn record(&mut self, num_queries: usize, elapsed: Duration) {
+        self.chunks_done += 1;
+        self.queries_done += num_queries;
+        if num_queries == 0 {
+            return;
+        }
+        let cost = elapsed.as_secs_f64() / num_queries as f64;
+        self.secs_per_query = Some(match self.secs_per_query {
+            Some(prev) => prev + self.smoothing * (cost - prev),
+            None => cost,
+        });
+    }
+
+    pub fn chunks_done(&self) -> usize {
+        self.chunks_done
+    }
+
+    /// Estimated time left, None until a chunk with queries was recorded.
+    ///
+    /// `remaining_queries` can be given when the size of the remaining chunks
+    /// is known, otherwise the average size of the chunks seen is used.
+    pub fn remaining(&self, remaining_queries: Option<usize>) -> Option<Duration> {
+        let secs_per_query = self.secs_per_query?;
+        let remaining_queries = match remaining_queries {
+            Some(x) => x as f64,
+            None => {
+                let remaining_chunks = self.total_chunks.saturating_sub(self.chunks_done);
+                remaining_chunks as f64 * self.queries_done as f64 / self.chunks_done as f64
+            }
+        };
+        Some(Duration::from_secs_f64(remaining_queries * secs_per_query))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_cost_estimator() {
+        let mut estimator = ChunkCostEstimator::new(4);
+        assert_eq!(estimator.remaining(None), None);
+        estimator.record(100, Duration::from_secs(1));
+        estimator.smoothing = 0.5;
+        // Second chunk is 3x as expensive per query.
+        estimator.record(100, Duration::from_secs(3));
+        // 0.02 s/query * 2 chunks * 100 queries
+        let remaining = estimator.remaining(None).unwrap();
+        assert!((remaining.as_secs_f64() - 4.0).abs() < 1e-9);
+        let remaining = estimator.remaining(Some(50)).unwrap();
+        assert!((remaining.as_secs_f64() - 1.0).abs() < 1e-9);
+    }
+}
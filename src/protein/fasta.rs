@@ -3,6 +3,8 @@ use super::models::{
     ProteinSequenceBuilder,
 };
 use log::*;
+use rayon::prelude::*;
+use serde::Serialize;
 use std::collections::HashMap;
 use std::path::Path;
 use std::sync::Arc;
@@ -11,6 +13,47 @@ use std::time::Instant;
 #[derive(Debug)]
 pub struct ProteinSequenceCollection {
     pub sequences: Vec<ProteinSequence>,
+    /// Problems found while parsing the FASTA file.
+    pub validation: FastaValidation,
+}
+
+/// Records of a FASTA file that were skipped, or that give no peptides.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct FastaValidation {
+    /// Headers in the file, including the skipped records.
+    pub num_records: usize,
+    /// Descriptions of the records without a sequence, they are skipped.
+    pub empty_records: Vec<String>,
+    /// Sequence lines before the first header, they are skipped.
+    pub lines_without_header: usize,
+    /// Descriptions of the proteins shorter than the shortest peptide.
+    pub short_records: Vec<String>,
+}
+
+impl FastaValidation {
+    /// Warns about the skipped records and lines.
+    pub fn log_problems(&self) {
+        if let Some(first) = self.empty_records.first() {
+            warn!(
+                "Skipped {} FASTA records without a sequence (eg. {:?})",
+                self.empty_records.len(),
+                first
+            );
+        }
+        if self.lines_without_header > 0 {
+            warn!(
+                "Skipped {} FASTA lines before the first header",
+                self.lines_without_header
+            );
+        }
+        if let Some(first) = self.short_records.first() {
+            info!(
+                "{} proteins are shorter than the shortest peptide (eg. {:?})",
+                self.short_records.len(),
+                first
+            );
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -23,24 +66,39 @@ pub struct ProteinSequenceNmerIndex {
 
 impl ProteinSequenceNmerIndex {
     pub fn new(nmer_size: usize, sequences: Vec<ProteinSequence>) -> Self {
+        // Windows of size 0 are not a thing.
+        let nmer_size = nmer_size.max(1);
         let st = Instant::now();
-        let mut index = HashMap::new();
-        for (curr_id, sequence) in sequences.iter().enumerate() {
-            // let curr_id = sequence.id;
-            let sequence = sequence.sequence.as_bytes();
-
-            sequence.windows(nmer_size).for_each(|window| {
-                // I am pretty sure this clones the content of each window.
-                // RN this is not a problem but COULD be better.
-                let key = Arc::from(window);
+        // Every rayon job indexes a share of the proteins, the partial indices
+        // are then merged.
+        let mut index: HashMap<Arc<[u8]>, Vec<usize>> = sequences
+            .par_iter()
+            .enumerate()
+            .fold(HashMap::new, |mut index, (curr_id, sequence)| {
+                // let curr_id = sequence.id;
+                let sequence = sequence.sequence.as_bytes();
+
+                sequence.windows(nmer_size).for_each(|window| {
+                    // I am pretty sure this clones the content of each window.
+                    // RN this is not a problem but COULD be better.
+                    let key = Arc::from(window);
+                    index
+                        .entry(key)
+                        .and_modify(|e: &mut Vec<usize>| {
+                            e.push(curr_id);
+                        })
+                        .or_insert(vec![curr_id]);
+                });
                 index
-                    .entry(key)
-                    .and_modify(|e: &mut Vec<usize>| {
-                        e.push(curr_id);
-                    })
-                    .or_insert(vec![curr_id]);
+            })
+            .reduce(HashMap::new, |mut left, right| {
+                for (key, ids) in right {
+                    left.entry(key).or_default().extend(ids);
+                }
+                left
             });
-        }
+        // Merging order depends on the scheduling, keep the ids sorted.
+        index.values_mut().for_each(|ids| ids.sort_unstable());
         let elapsed = st.elapsed();
         info!("Indexing took {:#?}", elapsed);
 
@@ -94,7 +152,7 @@ impl ProteinSequenceNmerIndex {
         }
     }
 
-    fn get_sequence(&self, id: usize) -> Option<&ProteinSequence> {
+    pub fn get_sequence(&self, id: usize) -> Option<&ProteinSequence> {
         self.sequences.get(id)
     }
 
@@ -103,26 +161,129 @@ impl ProteinSequenceNmerIndex {
     }
 }
 
+/// Per-protein annotations of a database, indexed by protein id.
+#[derive(Debug, Clone, Default)]
+pub struct ProteinAnnotations {
+    pub names: Vec<String>,
+    pub decoys: Vec<bool>,
+    pub contaminants: Vec<bool>,
+}
+
+impl ProteinAnnotations {
+    /// `;` separated names of the proteins.
+    pub fn names_of(&self, protein_ids: &[u32]) -> String {
+        protein_ids
+            .iter()
+            .filter_map(|id| self.names.get(*id as usize))
+            .cloned()
+            .collect::<Vec<String>>()
+            .join(";")
+    }
+
+    /// A peptide is a contaminant if any of its proteins is.
+    pub fn any_contaminant(&self, protein_ids: &[u32]) -> bool {
+        protein_ids
+            .iter()
+            .any(|id| self.contaminants.get(*id as usize).copied().unwrap_or(false))
+    }
+
+    /// A peptide is a decoy if it has proteins and all of them are decoys.
+    pub fn all_decoys(&self, protein_ids: &[u32]) -> bool {
+        !protein_ids.is_empty()
+            && protein_ids
+                .iter()
+                .all(|id| self.decoys.get(*id as usize).copied().unwrap_or(false))
+    }
+}
+
 impl ProteinSequenceCollection {
+    pub fn annotations<S: AsRef<str>>(
+        &self,
+        decoy_prefixes: &[S],
+        contaminant_prefixes: &[S],
+    ) -> ProteinAnnotations {
+        ProteinAnnotations {
+            names: self
+                .sequences
+                .iter()
+                .map(|x| x.description.clone())
+                .collect(),
+            decoys: self
+                .sequences
+                .iter()
+                .map(|x| x.is_decoy(decoy_prefixes))
+                .collect(),
+            contaminants: self
+                .sequences
+                .iter()
+                .map(|x| x.is_contaminant(contaminant_prefixes))
+                .collect(),
+        }
+    }
+
+    /// Parses the records of a FASTA file.
+    ///
+    /// Records without a sequence and lines before the first header are
+    /// skipped (and recorded in [`Self::validation`]), the ids of the kept
+    /// proteins are their positions in [`Self::sequences`].
     pub fn from_fasta(fasta: &str) -> ProteinSequenceCollection {
-        let mut sequences = vec![];
-        let mut num = 0;
-        let mut current_sequence = ProteinSequenceBuilder::new(num);
+        let mut sequences: Vec<ProteinSequence> = vec![];
+        let mut validation = FastaValidation::default();
+        let mut current_sequence: Option<ProteinSequenceBuilder> = None;
+        let mut push_record = |mut builder: ProteinSequenceBuilder,
+                               sequences: &mut Vec<ProteinSequence>| {
+            if builder.is_empty() {
+                validation
+                    .empty_records
+                    .push(builder.description.unwrap_or_default());
+                return;
+            }
+            builder.id = sequences.len() as u32;
+            sequences.push(builder.build());
+        };
+        let mut num_records = 0;
+        let mut lines_without_header = 0;
         for line in fasta.lines() {
-            if line.starts_with(">") {
-                if !current_sequence.is_empty() {
-                    sequences.push(current_sequence.build());
+            let line = line.trim();
+            if let Some(description) = line.strip_prefix('>') {
+                if let Some(builder) = current_sequence.take() {
+                    push_record(builder, &mut sequences);
                 }
-                current_sequence = ProteinSequenceBuilder::new(num);
-                num += 1;
-                let description = line.trim_start_matches('>').trim();
-                current_sequence = current_sequence.with_description(description);
-            } else {
-                current_sequence = current_sequence.append_sequence(line.trim());
+                num_records += 1;
+                current_sequence =
+                    Some(ProteinSequenceBuilder::new(0).with_description(description.trim()));
+            } else if !line.is_empty() {
+                current_sequence = match current_sequence {
+                    Some(builder) => Some(builder.append_sequence(line)),
+                    None => {
+                        lines_without_header += 1;
+                        None
+                    }
+                };
             }
         }
-        sequences.push(current_sequence.build());
-        ProteinSequenceCollection { sequences }
+        if let Some(builder) = current_sequence {
+            push_record(builder, &mut sequences);
+        }
+        validation.num_records = num_records;
+        validation.lines_without_header = lines_without_header;
+        ProteinSequenceCollection {
+            sequences,
+            validation,
+        }
+    }
+
+    /// Validation of the file, also flagging the proteins too short to give
+    /// a peptide of `min_length` residues.
+    pub fn validation_summary(&self, min_length: usize) -> FastaValidation {
+        let mut out = self.validation.clone();
+        out.short_records = self
+            .sequences
+            .iter()
+            .filter(|x| x.sequence.len() < min_length)
+            .map(|x| x.description.clone())
+            .collect();
+        out
     }
 
     pub fn from_fasta_file<P: AsRef<Path> + std::fmt::Debug>(
@@ -148,6 +309,21 @@ pub struct ProteinPeptideGraph {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_protein_annotations() {
+        let fasta = ">sp|P1|A\nPEPTIDEK\n>CON__P2 B\nPEPTIDEK\n>rev_sp|P1|A\nKEDITPEP\n";
+        let collection = ProteinSequenceCollection::from_fasta(fasta);
+        let annotations = collection.annotations(&["rev_"], &["CON__"]);
+        assert_eq!(annotations.decoys, vec![false, false, true]);
+        assert_eq!(annotations.contaminants, vec![false, true, false]);
+        assert!(annotations.any_contaminant(&[0, 1]));
+        assert!(!annotations.any_contaminant(&[0, 2]));
+        assert!(annotations.all_decoys(&[2]));
+        assert!(!annotations.all_decoys(&[0, 2]));
+        assert!(!annotations.all_decoys(&[]));
+        assert_eq!(annotations.names_of(&[0, 1]), "sp|P1|A;CON__P2 B");
+    }
+
     #[test]
     fn test_fasta_parsing() {
         let dummy_fasta_string = r#">mysupercoolprotein
@@ -174,4 +350,39 @@ PEPTIDEPLNK
         assert_eq!(fasta.sequences[0].description, "mysupercoolprotein");
         assert_eq!(fasta.sequences[1].description, "mysupercoolprotein2");
     }
+
+    #[test]
+    fn test_malformed_fasta() {
+        let fasta = "PEPTIDEK\n>empty\n\n>sp|P1|A\nPEPTIDEK\n>sp|P2|B\nPK\n>\n\n";
+        let collection = ProteinSequenceCollection::from_fasta(fasta);
+        let ids: Vec<u32> = collection.sequences.iter().map(|x| x.id).collect();
+        assert_eq!(ids, vec![0, 1]);
+        let validation = collection.validation_summary(3);
+        assert_eq!(validation.num_records, 4);
+        assert_eq!(validation.empty_records, vec!["empty", ""]);
+        assert_eq!(validation.lines_without_header, 1);
+        assert_eq!(validation.short_records, vec!["sp|P2|B"]);
+
+        assert!(ProteinSequenceCollection::from_fasta("").sequences.is_empty());
+
+        // Proteins shorter than the n-mers are not indexed, but do not break
+        // the index.
+        let index = ProteinSequenceNmerIndex::from_collection(collection, 5);
+        assert_eq!(index.query_sequences(b"PEPTIDEK"), Some(vec![0]));
+        assert_eq!(index.query_sequences(b"PK"), None);
+    }
+
+    #[test]
+    fn test_decoy_proteins() {
+        let fasta = ProteinSequenceCollection::from_fasta(
+            ">sp|P12345|PROT_HUMAN Some protein\nPEPTIDEK\n>rev_sp|P12345|PROT_HUMAN\nKEDITPEP\n>sp|DECOY_P54321|OTHER_HUMAN\nPEPTIDER\n",
+        );
+        let prefixes = ["rev_", "DECOY_"];
+        let decoys: Vec<bool> = fasta
+            .sequences
+            .iter()
+            .map(|x| x.is_decoy(&prefixes))
+            .collect();
+        assert_eq!(decoys, vec![false, true, true]);
+    }
 }
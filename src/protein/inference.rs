@@ -0,0 +1,239 @@
+use super::fasta::{
+    ProteinAnnotations,
+    ProteinSequenceNmerIndex,
+};
+use crate::models::{
+    DecoyMarking,
+    PeptideUniqueness,
+};
+use crate::scoring::fdr_preview::FdrPreview;
+use crate::scoring::search_results::IonSearchResults;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Proteins a peptide maps to and the one it is assigned to (razor protein).
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeptideProteinAssignment {
+    pub peptide: String,
+    pub protein_ids: Vec<usize>,
+    pub razor_protein: Option<usize>,
+}
+
+impl PeptideProteinAssignment {
+    pub fn uniqueness(&self) -> Option<PeptideUniqueness> {
+        PeptideUniqueness::from_num_proteins(self.protein_ids.len())
+    }
+}
+
+/// Razor assignment, MaxQuant style.
+///
+/// Each peptide is counted toward the protein (among the ones it maps to)
+/// with the most total evidence, where the evidence of a protein is the sum of
+/// the weights of all peptides mapping to it. Ties go to the lowest protein id.
+pub fn razor_proteins(protein_ids: &[Vec<usize>], weights: &[f64]) -> Vec<Option<usize>> {
+    assert_eq!(protein_ids.len(), weights.len());
+    let mut evidence: HashMap<usize, f64> = HashMap::new();
+    for (ids, weight) in protein_ids.iter().zip(weights.iter()) {
+        for id in ids {
+            *evidence.entry(*id).or_default() += weight;
+        }
+    }
+
+    protein_ids
+        .iter()
+        .map(|ids| {
+            ids.iter().copied().reduce(|best, id| {
+                let best_ev = evidence[&best];
+                let ev = evidence[&id];
+                if ev > best_ev || (ev == best_ev && id < best) {
+                    id
+                } else {
+                    best
+                }
+            })
+        })
+        .collect()
+}
+
+/// Maps peptides to proteins with the n-mer index and assigns razor proteins,
+/// every peptide weighted equally.
+pub fn assign_peptides(
+    peptides: Vec<String>,
+    index: &ProteinSequenceNmerIndex,
+) -> Vec<PeptideProteinAssignment> {
+    let protein_ids: Vec<Vec<usize>> = peptides
+        .par_iter()
+        .map(|x| index.query_sequences(x.as_bytes()).unwrap_or_default())
+        .collect();
+    let weights = vec![1.0; peptides.len()];
+    let razors = razor_proteins(&protein_ids, &weights);
+
+    peptides
+        .into_iter()
+        .zip(protein_ids)
+        .zip(razors)
+        .map(|((peptide, protein_ids), razor_protein)| PeptideProteinAssignment {
+            peptide,
+            protein_ids,
+            razor_protein,
+        })
+        .collect()
+}
+
+/// Proteins and best main score of the target peptides of a run, collected
+/// as it goes so the razor proteins are assigned from the peptides accepted
+/// at the end instead of from every theoretical digest.
+#[derive(Debug, Default)]
+pub struct ProteinEvidence {
+    peptides: HashMap<String, (Vec<usize>, f64)>,
+    scores: FdrPreview,
+}
+
+impl ProteinEvidence {
+    pub fn add(&mut self, results: &[IonSearchResults]) {
+        for result in results {
+            let protein_ids = result.sequence.protein_ids();
+            self.add_peptide(
+                result.sequence.clone().into(),
+                protein_ids.iter().map(|x| *x as usize).collect(),
+                result.score_data.main_score,
+                result.decoy != DecoyMarking::Target,
+            );
+        }
+    }
+
+    /// Adds a single scored peptide, non-finite scores are ignored.
+    pub fn add_peptide(
+        &mut self,
+        peptide: String,
+        protein_ids: Vec<usize>,
+        main_score: f64,
+        is_decoy: bool,
+    ) {
+        if !main_score.is_finite() {
+            return;
+        }
+        self.scores.add_score(main_score, is_decoy);
+        if is_decoy {
+            return;
+        }
+        let entry = self
+            .peptides
+            .entry(peptide)
+            .or_insert((protein_ids, f64::NEG_INFINITY));
+        entry.1 = entry.1.max(main_score);
+    }
+
+    /// Razor assignment of the target peptides whose best main score passes
+    /// `fdr`, every accepted peptide counting once toward the evidence of
+    /// its proteins. Empty if none pass.
+    pub fn assignments(&self, fdr: f64) -> Vec<PeptideProteinAssignment> {
+        let Some(threshold) = self.scores.score_threshold(fdr) else {
+            return Vec::new();
+        };
+        let mut accepted: Vec<(&String, &Vec<usize>)> = self
+            .peptides
+            .iter()
+            .filter(|(_, (_, score))| *score >= threshold)
+            .map(|(peptide, (protein_ids, _))| (peptide, protein_ids))
+            .collect();
+        accepted.sort_unstable();
+        let protein_ids: Vec<Vec<usize>> = accepted.iter().map(|x| x.1.clone()).collect();
+        let weights = vec![1.0; protein_ids.len()];
+        let razors = razor_proteins(&protein_ids, &weights);
+
+        accepted
+            .into_iter()
+            .zip(protein_ids)
+            .zip(razors)
+            .map(|(((peptide, _), protein_ids), razor_protein)| PeptideProteinAssignment {
+                peptide: peptide.clone(),
+                protein_ids,
+                razor_protein,
+            })
+            .collect()
+    }
+}
+
+/// Writes a peptide table with all the mapped proteins and the razor one,
+/// flagging the decoy and contaminant peptides.
+pub fn write_protein_map_to_csv<P: AsRef<Path>>(
+    assignments: &[PeptideProteinAssignment],
+    annotations: &ProteinAnnotations,
+    out_path: P,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let mut writer = csv::Writer::from_path(out_path.as_ref())?;
+    writer.write_record([
+        "sequence",
+        "proteins",
+        "razor_protein",
+        "is_decoy",
+        "is_contaminant",
+    ])?;
+    for assignment in assignments {
+        let protein_ids: Vec<u32> = assignment.protein_ids.iter().map(|x| *x as u32).collect();
+        let razor = assignment
+            .razor_protein
+            .map(|x| annotations.names_of(&[x as u32]))
+            .unwrap_or_default();
+        writer.write_record([
+            assignment.peptide.as_str(),
+            &annotations.names_of(&protein_ids),
+            &razor,
+            &annotations.all_decoys(&protein_ids).to_string(),
+            &annotations.any_contaminant(&protein_ids).to_string(),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_razor_proteins() {
+        // Protein 1 has 4 peptides, proteins 0 and 2 have 2 each.
+        let protein_ids = vec![vec![0, 1], vec![1], vec![1, 2], vec![1], vec![0, 2], vec![]];
+        let weights = vec![1.0; 6];
+        let razors = razor_proteins(&protein_ids, &weights);
+        assert_eq!(
+            razors,
+            vec![Some(1), Some(1), Some(1), Some(1), Some(0), None]
+        );
+    }
+
+    #[test]
+    fn test_protein_evidence() {
+        let mut evidence = ProteinEvidence::default();
+        // Protein 1 only has theoretical support from the rejected peptides.
+        evidence.add_peptide("AAAK".to_string(), vec![0, 1], 10.0, false);
+        evidence.add_peptide("CCCK".to_string(), vec![0], 9.0, false);
+        evidence.add_peptide("DDDK".to_string(), vec![1], 1.0, false);
+        evidence.add_peptide("EEEK".to_string(), vec![1], 0.5, false);
+        evidence.add_peptide("KAAA".to_string(), vec![], 2.0, true);
+        evidence.add_peptide("KCCC".to_string(), vec![], 0.1, true);
+
+        let assignments = evidence.assignments(0.01);
+        let peptides: Vec<&str> = assignments.iter().map(|x| x.peptide.as_str()).collect();
+        assert_eq!(peptides, vec!["AAAK", "CCCK"]);
+        assert_eq!(assignments[0].razor_protein, Some(0));
+        assert!(ProteinEvidence::default().assignments(0.01).is_empty());
+    }
+
+    #[test]
+    fn test_peptide_uniqueness() {
+        let fasta = ">sp|P1|A\nPEPTIDEKPINK\n>sp|P2|B\nTOMATOPEPTIDEK\n";
+        let collection = crate::protein::fasta::ProteinSequenceCollection::from_fasta(fasta);
+        let index = ProteinSequenceNmerIndex::from_collection(collection, 3);
+        let assignments = assign_peptides(
+            vec!["PEPTIDEK".to_string(), "PINK".to_string(), "NOTHERE".to_string()],
+            &index,
+        );
+        assert_eq!(assignments[0].uniqueness(), Some(PeptideUniqueness::Shared));
+        assert_eq!(assignments[1].uniqueness(), Some(PeptideUniqueness::Proteotypic));
+        assert_eq!(assignments[2].uniqueness(), None);
+    }
+}
@@ -1,2 +1,3 @@
 pub mod fasta;
+pub mod inference;
 mod models;
@@ -8,6 +8,29 @@ pub struct ProteinSequence {
     pub sequence: Arc<str>,
 }
 
+impl ProteinSequence {
+    fn accession_has_prefix<S: AsRef<str>>(&self, prefixes: &[S]) -> bool {
+        let accession = self.description.split_whitespace().next().unwrap_or("");
+        accession.split('|').any(|field| {
+            prefixes
+                .iter()
+                .any(|prefix| field.starts_with(prefix.as_ref()))
+        })
+    }
+
+    /// Whether the accession marks a decoy of a pre-built target+decoy
+    /// database (eg. `rev_sp|P12345|...` or `sp|DECOY_P12345|...`).
+    pub fn is_decoy<S: AsRef<str>>(&self, prefixes: &[S]) -> bool {
+        self.accession_has_prefix(prefixes)
+    }
+
+    /// Whether the accession marks a contaminant (eg. `CON__P02768` or
+    /// `sp|Cont_P02768|...`), same matching as [`Self::is_decoy`].
+    pub fn is_contaminant<S: AsRef<str>>(&self, prefixes: &[S]) -> bool {
+        self.accession_has_prefix(prefixes)
+    }
+}
+
 #[derive(Debug)]
 pub struct ProteinSequenceBuilder {
     pub id: u32,
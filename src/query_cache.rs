@@ -0,0 +1,117 @@
+use crate::fragment_mass::fragment_mass_builder::SafePosition;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{
+    HashMap,
+    VecDeque,
+};
+use std::hash::{
+    Hash,
+    Hasher,
+};
+use serde::Serialize;
+use timsquery::models::elution_group::ElutionGroup;
+
+/// Key of a cached extraction, a hash of the elution group content and the
+/// tolerance used to extract it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct QueryCacheKey(u64);
+
+impl QueryCacheKey {
+    /// Hashes the serialized form of both inputs, so two elution groups with
+    /// the same content (regardless of where they came from) share a key.
+    pub fn new<T: Serialize>(elution_group: &ElutionGroup<SafePosition>, tolerance: &T) -> Self {
+        let mut hasher = DefaultHasher::new();
+        serde_json::to_string(elution_group)
+            .expect("Elution groups should always be serializable")
+            .hash(&mut hasher);
+        serde_json::to_string(tolerance)
+            .expect("Tolerances should always be serializable")
+            .hash(&mut hasher);
+        Self(hasher.finish())
+    }
+}
+
+/// Bounded least-recently-used cache of extraction results.
+///
+/// Sizes are small (a few chunks of queries at most), so recency is tracked
+/// with a plain queue.
+#[derive(Debug)]
+pub struct QueryCache<V> {
+    capacity: usize,
+    entries: HashMap<QueryCacheKey, V>,
+    recency: VecDeque<QueryCacheKey>,
+}
+
+impl<V: Clone> QueryCache<V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::with_capacity(capacity),
+            recency: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    fn touch(&mut self, key: QueryCacheKey) {
+        if let Some(pos) = self.recency.iter().position(|x| *x == key) {
+            self.recency.remove(pos);
+        }
+        self.recency.push_back(key);
+    }
+
+    pub fn get(&mut self, key: &QueryCacheKey) -> Option<V> {
+        let out = self.entries.get(key).cloned();
+        if out.is_some() {
+            self.touch(*key);
+        }
+        out
+    }
+
+    pub fn insert(&mut self, key: QueryCacheKey, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            if let Some(oldest) = self.recency.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(key, value);
+        self.touch(key);
+    }
+
+    /// Returns the cached value for the query or computes (and caches) it.
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&mut self, key: QueryCacheKey, f: F) -> V {
+        if let Some(x) = self.get(&key) {
+            return x;
+        }
+        let value = f();
+        self.insert(key, value.clone());
+        value
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut cache: QueryCache<u32> = QueryCache::new(2);
+        cache.insert(QueryCacheKey(1), 1);
+        cache.insert(QueryCacheKey(2), 2);
+        assert_eq!(cache.get(&QueryCacheKey(1)), Some(1));
+        cache.insert(QueryCacheKey(3), 3);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&QueryCacheKey(2)), None);
+        assert_eq!(cache.get(&QueryCacheKey(1)), Some(1));
+        assert_eq!(cache.get(&QueryCacheKey(3)), Some(3));
+    }
+}
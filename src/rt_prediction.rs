@@ -0,0 +1,322 @@
+use crate::errors::TimsSeekError;
+use crate::fragment_mass::modifications::strip_modifications;
+use crate::numeric::deserialize_decimal_f32;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::sync::Arc;
+
+/// Retention time predictor used to set the RT of the queries.
+///
+/// There is no native ONNX or Koina backend, predictions from those are used
+/// through a [`TableRtPredictor`]. A native one would implement this trait
+/// and get its own [`RtModel`] variant.
+pub trait RtPredictor: Debug + Send + Sync {
+    /// Predicted retention time (seconds) of a ProForma sequence, None if the
+    /// predictor has nothing for it.
+    fn predict(&self, sequence: &str) -> Option<f32>;
+
+    /// Fits the predictor to the retention times of the run, from observed
+    /// `(sequence, rt_seconds)` pairs.
+    fn calibrate(&mut self, observations: &[(String, f32)]) -> Result<(), TimsSeekError>;
+}
+
+/// Least squares `(slope, intercept)` of `y = slope * x + intercept`.
+pub(crate) fn fit_line(points: &[(f64, f64)]) -> Result<(f64, f64), TimsSeekError> {
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let sxx: f64 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+    let sxy: f64 = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+    if points.len() < 2 || sxx <= 0.0 || !sxy.is_finite() {
+        return Err(TimsSeekError::ParseError {
+            msg: format!(
+                "Cannot calibrate the predictor on {} usable observations",
+                points.len()
+            ),
+        });
+    }
+    let slope = sxy / sxx;
+    Ok((slope, mean_y - slope * mean_x))
+}
+
+/// Sum of per-residue retention coefficients, mapped linearly to seconds.
+///
+/// The default coefficients are the reversed-phase (TFA, pH 2) ones of Guo et
+/// al. (1986), with a slope and intercept roughly matching a one hour
+/// gradient; calibrate it for anything else.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AdditiveRtModel {
+    pub coefficients: HashMap<char, f64>,
+    /// Seconds per unit of summed coefficients.
+    pub slope: f64,
+    pub intercept: f64,
+}
+
+impl Default for AdditiveRtModel {
+    fn default() -> Self {
+        let coefficients = [
+            ('W', 8.8),
+            ('F', 8.1),
+            ('L', 8.1),
+            ('I', 7.4),
+            ('M', 5.5),
+            ('V', 5.0),
+            ('Y', 4.5),
+            ('C', 2.6),
+            ('P', 2.0),
+            ('A', 2.0),
+            ('E', 1.1),
+            ('T', 0.6),
+            ('D', 0.2),
+            ('Q', 0.0),
+            ('S', -0.2),
+            ('G', -0.2),
+            ('R', -0.6),
+            ('N', -0.6),
+            ('H', -2.1),
+            ('K', -2.1),
+        ];
+        Self {
+            coefficients: HashMap::from(coefficients),
+            slope: 40.0,
+            intercept: 300.0,
+        }
+    }
+}
+
+impl AdditiveRtModel {
+    /// Reads the coefficients, slope and intercept from a JSON file.
+    pub fn from_json_file(path: &Path) -> Result<Self, TimsSeekError> {
+        let contents = std::fs::read_to_string(path)?;
+        serde_json::from_str(&contents).map_err(|e| TimsSeekError::ParseError {
+            msg: format!("Invalid RT coefficients file {}: {}", path.display(), e),
+        })
+    }
+
+    /// Summed retention coefficients of the residues of a sequence.
+    pub fn hydrophobicity(&self, sequence: &str) -> f64 {
+        strip_modifications(sequence)
+            .chars()
+            .map(|c| self.coefficients.get(&c).copied().unwrap_or(0.0))
+            .sum()
+    }
+}
+
+impl RtPredictor for AdditiveRtModel {
+    fn predict(&self, sequence: &str) -> Option<f32> {
+        Some((self.intercept + self.slope * self.hydrophobicity(sequence)) as f32)
+    }
+
+    fn calibrate(&mut self, observations: &[(String, f32)]) -> Result<(), TimsSeekError> {
+        let points: Vec<(f64, f64)> = observations
+            .iter()
+            .map(|(seq, rt)| (self.hydrophobicity(seq), *rt as f64))
+            .collect();
+        (self.slope, self.intercept) = fit_line(&points)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RtTableEntry {
+    sequence: String,
+    #[serde(deserialize_with = "deserialize_decimal_f32")]
+    rt_seconds: f32,
+}
+
+/// Reads the rows of a tab separated file with a header.
+///
+/// Number columns should be read with [`crate::numeric::deserialize_decimal`]
+/// so files exported with comma decimal separators are read right.
+pub(crate) fn read_tsv<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<Vec<T>, TimsSeekError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_path(path)
+        .map_err(|e| TimsSeekError::ParseError { msg: e.to_string() })?;
+    let mut out = Vec::new();
+    for (i, record) in reader.deserialize().enumerate() {
+        let entry: T = record.map_err(|e| TimsSeekError::ParseError {
+            msg: format!("Error reading line {} of {}: {}", i + 2, path.display(), e),
+        })?;
+        out.push(entry);
+    }
+    Ok(out)
+}
+
+/// Reads a tab separated file with `sequence` and `rt_seconds` columns.
+fn read_rt_table(path: &Path) -> Result<Vec<(String, f32)>, TimsSeekError> {
+    let entries: Vec<RtTableEntry> = read_tsv(path)?;
+    Ok(entries
+        .into_iter()
+        .map(|x| (x.sequence, x.rt_seconds))
+        .collect())
+}
+
+/// Retention times predicted ahead of time by an external model (eg. an ONNX
+/// model or a Koina server), looked up by sequence.
+///
+/// The predictions have to be exported to a table first, the models are not
+/// run from here. Sequences missing from the table get no prediction.
+///
+/// Calibration maps the table values to the run with a linear fit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableRtPredictor {
+    retention_times: HashMap<String, f32>,
+    slope: f64,
+    intercept: f64,
+}
+
+impl TableRtPredictor {
+    pub fn new(retention_times: HashMap<String, f32>) -> Self {
+        Self {
+            retention_times,
+            slope: 1.0,
+            intercept: 0.0,
+        }
+    }
+
+    pub fn from_tsv(path: &Path) -> Result<Self, TimsSeekError> {
+        Ok(Self::new(read_rt_table(path)?.into_iter().collect()))
+    }
+}
+
+impl RtPredictor for TableRtPredictor {
+    fn predict(&self, sequence: &str) -> Option<f32> {
+        self.retention_times
+            .get(sequence)
+            .map(|rt| (self.intercept + self.slope * *rt as f64) as f32)
+    }
+
+    fn calibrate(&mut self, observations: &[(String, f32)]) -> Result<(), TimsSeekError> {
+        let points: Vec<(f64, f64)> = observations
+            .iter()
+            .filter_map(|(seq, rt)| {
+                self.retention_times
+                    .get(seq)
+                    .map(|x| (*x as f64, *rt as f64))
+            })
+            .collect();
+        (self.slope, self.intercept) = fit_line(&points)?;
+        Ok(())
+    }
+}
+
+/// Which RT predictor is used for the queries.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RtModel {
+    /// No prediction, queries span the whole run.
+    #[default]
+    None,
+    /// The built-in [`AdditiveRtModel`].
+    Builtin,
+    /// [`AdditiveRtModel`] read from a JSON file.
+    Coefficients(PathBuf),
+    /// [`TableRtPredictor`] read from a tab separated file, for predictions
+    /// exported from ONNX models or Koina.
+    Table(PathBuf),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RtPredictorConfig {
+    pub model: RtModel,
+    /// Tab separated `sequence` and `rt_seconds` of peptides observed in the
+    /// run, the predictor is calibrated on them.
+    pub calibration: Option<PathBuf>,
+    /// The search only looks this many seconds around the predicted RT of
+    /// the queries with a prediction (instead of the RT tolerance of the
+    /// config). Queries the predictor has nothing for keep the RT tolerance
+    /// of the config.
+    pub window_seconds: Option<f32>,
+}
+
+impl RtPredictorConfig {
+    /// Builds (and calibrates) the configured predictor, None if RT
+    /// prediction is off.
+    pub fn build(&self) -> Result<Option<Arc<dyn RtPredictor>>, TimsSeekError> {
+        let mut predictor: Box<dyn RtPredictor> = match &self.model {
+            RtModel::None => return Ok(None),
+            RtModel::Builtin => Box::new(AdditiveRtModel::default()),
+            RtModel::Coefficients(path) => Box::new(AdditiveRtModel::from_json_file(path)?),
+            RtModel::Table(path) => Box::new(TableRtPredictor::from_tsv(path)?),
+        };
+        if let Some(path) = &self.calibration {
+            predictor.calibrate(&read_rt_table(path)?)?;
+        }
+        Ok(Some(Arc::from(predictor)))
+    }
+
+    /// RT window (seconds) of the search, only if a predictor is configured.
+    pub fn search_window_seconds(&self) -> Option<f32> {
+        match self.model {
+            RtModel::None => None,
+            _ => self.window_seconds,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_window() {
+        let mut config = RtPredictorConfig {
+            window_seconds: Some(120.0),
+            ..Default::default()
+        };
+        assert_eq!(config.search_window_seconds(), None);
+        config.model = RtModel::Builtin;
+        assert_eq!(config.search_window_seconds(), Some(120.0));
+    }
+
+    #[test]
+    fn test_additive_rt_calibration() {
+        let mut model = AdditiveRtModel::default();
+        let hydrophobic = model.predict("LLLWFK").unwrap();
+        assert!(hydrophobic > model.predict("GSSDEK").unwrap());
+        // Modifications are ignored.
+        assert_eq!(model.predict("LLLM[Oxidation]K"), model.predict("LLLMK"));
+
+        // 10 s per unit, starting at 100 s.
+        let observations: Vec<(String, f32)> = ["AK", "LLK", "WWWK", "GGK"]
+            .iter()
+            .map(|seq| {
+                let rt = 100.0 + 10.0 * model.hydrophobicity(seq);
+                (seq.to_string(), rt as f32)
+            })
+            .collect();
+        model.calibrate(&observations).unwrap();
+        assert!((model.slope - 10.0).abs() < 1e-3);
+        assert!((model.intercept - 100.0).abs() < 1e-2);
+
+        assert!(model.calibrate(&observations[..1]).is_err());
+    }
+
+    #[test]
+    fn test_table_rt_predictor() {
+        let mut table = TableRtPredictor::new(HashMap::from([
+            ("PEPTIDEK".to_string(), 10.0),
+            ("AAAAAK".to_string(), 20.0),
+        ]));
+        assert_eq!(table.predict("PEPTIDEK"), Some(10.0));
+        assert_eq!(table.predict("CCCCK"), None);
+
+        let observations = vec![
+            ("PEPTIDEK".to_string(), 600.0),
+            ("AAAAAK".to_string(), 1200.0),
+            ("CCCCK".to_string(), 50.0),
+        ];
+        table.calibrate(&observations).unwrap();
+        assert!((table.predict("AAAAAK").unwrap() - 1200.0).abs() < 1e-3);
+    }
+}
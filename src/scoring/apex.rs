@@ -0,0 +1,155 @@
+use crate::fragment_mass::fragment_mass_builder::SafePosition;
+use crate::scoring::coelution::ms2_fragment_traces;
+use crate::scoring::scorers::PsmScorer;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use timsquery::models::aggregators::raw_peak_agg::multi_chromatogram_agg::multi_chromatogram_agg::NaturalFinalizedMultiCMGStatsArrays;
+use timsquery::ElutionGroup;
+
+/// How the apex of a peptide's elution profile is picked.
+///
+/// Short high-flow gradients have narrow, well defined peaks where the main
+/// score works well, long nanoflow runs benefit from smoothing first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApexStrategy {
+    /// Apex of the main score, as picked by timsquery.
+    #[default]
+    MainScore,
+    /// Apex of the moving average of the summed fragment intensity.
+    SmoothedIntensity,
+    /// Smoothed summed intensity weighted by the fraction of fragments
+    /// observed at each point.
+    Composite,
+}
+
+/// Centered moving average, the window is shrunk at the edges.
+fn moving_average(values: &[f64], window: usize) -> Vec<f64> {
+    let half = window / 2;
+    (0..values.len())
+        .map(|i| {
+            let lo = i.saturating_sub(half);
+            let hi = (i + half + 1).min(values.len());
+            values[lo..hi].iter().sum::<f64>() / (hi - lo) as f64
+        })
+        .collect()
+}
+
+impl ApexStrategy {
+    /// Index of the apex in the (equally long) fragment traces.
+    ///
+    /// Returns None for `MainScore`, whose apex comes from timsquery, or when
+    /// there are no traces.
+    pub fn pick_apex(&self, traces: &[Vec<f64>], smoothing_window: usize) -> Option<usize> {
+        let len = traces.iter().map(|x| x.len()).min()?;
+        if len == 0 {
+            return None;
+        }
+        let summed: Vec<f64> = (0..len)
+            .map(|i| traces.iter().map(|x| x[i]).sum())
+            .collect();
+        let smoothed = moving_average(&summed, smoothing_window.max(1));
+        let profile: Vec<f64> = match self {
+            ApexStrategy::MainScore => return None,
+            ApexStrategy::SmoothedIntensity => smoothed,
+            ApexStrategy::Composite => smoothed
+                .iter()
+                .enumerate()
+                .map(|(i, x)| {
+                    let observed = traces.iter().filter(|t| t[i] > 0.0).count();
+                    x * observed as f64 / traces.len() as f64
+                })
+                .collect(),
+        };
+        profile
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(i, _)| i)
+    }
+}
+
+/// Reports the apex picked with a non-default strategy and the MS2 scores at
+/// it in `apex_*` columns.
+///
+/// The built-in columns stay at the apex of timsquery, which is the only one
+/// it computes the main score, cosine similarity, m/z and mobility errors
+/// and MS1 scores at, so they all describe the same point.
+#[derive(Debug)]
+pub struct ApexScorer {
+    pub strategy: ApexStrategy,
+    pub smoothing_window: usize,
+}
+
+impl ApexScorer {
+    fn pick_apex(
+        &self,
+        arrays: &NaturalFinalizedMultiCMGStatsArrays<SafePosition>,
+    ) -> Option<usize> {
+        let traces: Vec<Vec<f64>> = ms2_fragment_traces(arrays)
+            .into_iter()
+            .map(|(_, x)| x)
+            .collect();
+        self.strategy.pick_apex(&traces, self.smoothing_window)
+    }
+}
+
+impl PsmScorer for ApexScorer {
+    fn column_names(&self) -> &'static [&'static str] {
+        &[
+            "apex_index",
+            "apex_rt_miliseconds",
+            "apex_summed_intensity",
+            "apex_npeaks",
+            "apex_lazyerscore",
+            "apex_lazyerscore_vs_baseline",
+        ]
+    }
+
+    fn score(
+        &self,
+        arrays: &NaturalFinalizedMultiCMGStatsArrays<SafePosition>,
+        _elution_group: &ElutionGroup<SafePosition>,
+    ) -> Vec<f64> {
+        let ms2 = &arrays.ms2_stats;
+        let Some(apex) = self
+            .pick_apex(arrays)
+            .filter(|x| *x < ms2.retention_time_miliseconds.len())
+        else {
+            return vec![f64::NAN; 6];
+        };
+        let intensity = ms2_fragment_traces(arrays)
+            .iter()
+            .map(|(_, x)| x[apex])
+            .sum();
+        vec![
+            apex as f64,
+            ms2.retention_time_miliseconds[apex] as f64,
+            intensity,
+            ms2.npeaks[apex] as f64,
+            ms2.lazyerscore[apex] as f64,
+            ms2.lazyerscore_vs_baseline[apex] as f64,
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick_apex() {
+        // A single-point spike in one fragment vs a broad peak in all of them.
+        let traces = vec![
+            vec![0.0, 2.0, 3.0, 2.0, 0.0, 0.0, 12.0, 0.0, 0.0],
+            vec![0.0, 2.0, 3.0, 2.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+            vec![0.0, 2.0, 3.0, 2.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+        ];
+        assert_eq!(ApexStrategy::MainScore.pick_apex(&traces, 3), None);
+        assert_eq!(ApexStrategy::SmoothedIntensity.pick_apex(&traces, 1), Some(6));
+        assert_eq!(ApexStrategy::SmoothedIntensity.pick_apex(&traces, 3), Some(2));
+        assert_eq!(ApexStrategy::Composite.pick_apex(&traces, 1), Some(2));
+    }
+}
@@ -0,0 +1,116 @@
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+/// Value at quantile `q` of `sorted` (linear interpolation).
+pub(crate) fn quantile(sorted: &[f64], q: f64) -> f64 {
+    let pos = (sorted.len() - 1) as f64 * q.clamp(0.0, 1.0);
+    let lo = pos.floor() as usize;
+    let hi = pos.ceil() as usize;
+    let frac = pos - lo as f64;
+    sorted[lo] * (1.0 - frac) + sorted[hi] * frac
+}
+
+/// Median of `values` (the mean of the two middle ones for an even count),
+/// None if empty. Sorts `values` in place.
+pub(crate) fn median(values: &mut [f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    let mid = values.len() / 2;
+    if values.len() % 2 == 0 {
+        Some((values[mid - 1] + values[mid]) / 2.0)
+    } else {
+        Some(values[mid])
+    }
+}
+
+/// Maps the main scores of a run to a common scale using the distribution of
+/// its decoy scores.
+///
+/// After calibration the decoy `low_quantile` sits at 0 and the decoy
+/// `high_quantile` at 1 in every run, so a run whose scores drift up (or down)
+/// does not dominate a combined cross-run FDR estimate.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct QuantileCalibration {
+    pub low: f64,
+    pub high: f64,
+}
+
+impl QuantileCalibration {
+    /// Fits the calibration from the decoy scores of a single run.
+    ///
+    /// Returns None if there are no (finite) decoy scores or they are all the
+    /// same.
+    pub fn fit(decoy_scores: &[f64], low_quantile: f64, high_quantile: f64) -> Option<Self> {
+        let mut sorted: Vec<f64> = decoy_scores
+            .iter()
+            .copied()
+            .filter(|x| x.is_finite())
+            .collect();
+        if sorted.is_empty() {
+            return None;
+        }
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let low = quantile(&sorted, low_quantile);
+        let high = quantile(&sorted, high_quantile);
+        if high <= low {
+            return None;
+        }
+        Some(Self { low, high })
+    }
+
+    pub fn apply(&self, score: f64) -> f64 {
+        (score - self.low) / (self.high - self.low)
+    }
+}
+
+/// Calibrates the scores of several runs in place.
+///
+/// Each run is a list of `(main_score, is_decoy)`, runs without enough decoys
+/// to fit a calibration are left as-is (and reported as None).
+pub fn calibrate_runs(
+    runs: &mut [Vec<(f64, bool)>],
+    low_quantile: f64,
+    high_quantile: f64,
+) -> Vec<Option<QuantileCalibration>> {
+    runs.iter_mut()
+        .map(|run| {
+            let decoys: Vec<f64> = run.iter().filter(|x| x.1).map(|x| x.0).collect();
+            let calibration = QuantileCalibration::fit(&decoys, low_quantile, high_quantile);
+            if let Some(cal) = calibration {
+                run.iter_mut().for_each(|x| x.0 = cal.apply(x.0));
+            }
+            calibration
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_median() {
+        assert_eq!(median(&mut []), None);
+        assert_eq!(median(&mut [3.0, 1.0, 2.0]), Some(2.0));
+        assert_eq!(median(&mut [4.0, 1.0, 3.0, 2.0]), Some(2.5));
+    }
+
+    #[test]
+    fn test_calibrate_runs() {
+        let run_a: Vec<(f64, bool)> = (0..=10).map(|x| (x as f64, true)).collect();
+        let mut run_b: Vec<(f64, bool)> = (0..=10).map(|x| (2.0 * x as f64 + 5.0, true)).collect();
+        run_b.push((45.0, false));
+        let mut runs = vec![run_a, run_b];
+        let cals = calibrate_runs(&mut runs, 0.5, 1.0);
+        assert!(cals.iter().all(|x| x.is_some()));
+        assert!((runs[0][5].0 - 0.0).abs() < 1e-9);
+        assert!((runs[1][5].0 - 0.0).abs() < 1e-9);
+        assert!((runs[0][10].0 - 1.0).abs() < 1e-9);
+        assert!((runs[1][10].0 - 1.0).abs() < 1e-9);
+        assert!((runs[1][11].0 - 3.0).abs() < 1e-9);
+    }
+}
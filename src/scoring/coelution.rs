@@ -0,0 +1,153 @@
+use crate::fragment_mass::fragment_mass_builder::SafePosition;
+use crate::scoring::noise::NoiseModel;
+use crate::scoring::scorers::PsmScorer;
+use timsquery::models::aggregators::raw_peak_agg::multi_chromatogram_agg::multi_chromatogram_agg::NaturalFinalizedMultiCMGStatsArrays;
+use timsquery::ElutionGroup;
+
+fn pearson_correlation(a: &[f64], b: &[f64]) -> f64 {
+    let n = a.len().min(b.len());
+    if n < 2 {
+        return 0.0;
+    }
+    let mean_a = a[..n].iter().sum::<f64>() / n as f64;
+    let mean_b = b[..n].iter().sum::<f64>() / n as f64;
+    let mut cov = 0.0;
+    let mut var_a = 0.0;
+    let mut var_b = 0.0;
+    for i in 0..n {
+        let da = a[i] - mean_a;
+        let db = b[i] - mean_b;
+        cov += da * db;
+        var_a += da * da;
+        var_b += db * db;
+    }
+    if var_a == 0.0 || var_b == 0.0 {
+        return 0.0;
+    }
+    cov / (var_a.sqrt() * var_b.sqrt())
+}
+
+/// Result of clustering the fragment traces of a precursor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoelutionClusters {
+    /// Fragments in the largest group of mutually co-eluting traces.
+    pub dominant_size: usize,
+    /// Fragments with signal that do not co-elute with the dominant group.
+    pub num_outliers: usize,
+}
+
+/// Groups traces whose correlation is above `min_correlation` (single
+/// linkage) and reports the size of the largest group.
+///
+/// Traces without any variation (eg. no signal) are not counted at all.
+pub fn cluster_traces(traces: &[Vec<f64>], min_correlation: f64) -> CoelutionClusters {
+    let with_signal: Vec<&Vec<f64>> = traces
+        .iter()
+        .filter(|x| x.iter().any(|v| *v != x[0]))
+        .collect();
+    let n = with_signal.len();
+
+    // Union-find over the correlation graph.
+    let mut parent: Vec<usize> = (0..n).collect();
+    fn find(parent: &mut [usize], i: usize) -> usize {
+        let mut root = i;
+        while parent[root] != root {
+            root = parent[root];
+        }
+        parent[i] = root;
+        root
+    }
+    for i in 0..n {
+        for j in (i + 1)..n {
+            if pearson_correlation(with_signal[i], with_signal[j]) >= min_correlation {
+                let ri = find(&mut parent, i);
+                let rj = find(&mut parent, j);
+                parent[ri] = rj;
+            }
+        }
+    }
+
+    let mut sizes = vec![0usize; n];
+    for i in 0..n {
+        let root = find(&mut parent, i);
+        sizes[root] += 1;
+    }
+    let dominant_size = sizes.into_iter().max().unwrap_or(0);
+    CoelutionClusters {
+        dominant_size,
+        num_outliers: n - dominant_size,
+    }
+}
+
+/// Per-fragment MS2 intensity traces of the finalized arrays, sorted by
+/// fragment.
+pub fn ms2_fragment_traces(
+    arrays: &NaturalFinalizedMultiCMGStatsArrays<SafePosition>,
+) -> Vec<(SafePosition, Vec<f64>)> {
+    let mut traces: Vec<(SafePosition, Vec<f64>)> = arrays
+        .ms2_stats
+        .transition_intensities
+        .iter()
+        .map(|(k, v)| (*k, v.iter().map(|x| *x as f64).collect()))
+        .collect();
+    traces.sort_by_key(|x| x.0);
+    traces
+}
+
+/// Reports how many fragments co-elute in the dominant peak group and how
+/// many are outliers.
+#[derive(Debug)]
+pub struct CoelutionScorer {
+    pub min_correlation: f64,
+    pub noise: NoiseModel,
+}
+
+impl Default for CoelutionScorer {
+    fn default() -> Self {
+        Self {
+            min_correlation: 0.75,
+            noise: NoiseModel::default(),
+        }
+    }
+}
+
+impl PsmScorer for CoelutionScorer {
+    fn column_names(&self) -> &'static [&'static str] {
+        &["coelution_dominant_fragments", "coelution_outlier_fragments"]
+    }
+
+    fn score(
+        &self,
+        arrays: &NaturalFinalizedMultiCMGStatsArrays<SafePosition>,
+        _elution_group: &ElutionGroup<SafePosition>,
+    ) -> Vec<f64> {
+        let traces: Vec<Vec<f64>> = ms2_fragment_traces(arrays)
+            .into_iter()
+            .map(|(_, mut x)| {
+                self.noise.apply(&mut x);
+                x
+            })
+            .collect();
+        let clusters = cluster_traces(&traces, self.min_correlation);
+        vec![clusters.dominant_size as f64, clusters.num_outliers as f64]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cluster_traces() {
+        let traces = vec![
+            vec![0.0, 1.0, 5.0, 1.0, 0.0],
+            vec![0.0, 2.0, 9.0, 2.0, 0.0],
+            vec![0.0, 1.0, 4.0, 2.0, 0.0],
+            vec![5.0, 1.0, 0.0, 0.0, 0.0],
+            vec![0.0, 0.0, 0.0, 0.0, 0.0],
+        ];
+        let clusters = cluster_traces(&traces, 0.75);
+        assert_eq!(clusters.dominant_size, 3);
+        assert_eq!(clusters.num_outliers, 1);
+    }
+}
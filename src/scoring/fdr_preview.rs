@@ -0,0 +1,133 @@
+use crate::models::DecoyMarking;
+use crate::scoring::search_results::IonSearchResults;
+
+/// Provisional target/decoy statistics of the results seen so far in a run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FdrPreviewSummary {
+    pub num_targets: usize,
+    pub num_decoys: usize,
+    /// Difference of the mean target and decoy main scores over their pooled
+    /// standard deviation, close to 0 when targets look like decoys.
+    pub separation: f64,
+    /// Targets passing the FDR threshold, projected from the results so far.
+    pub ids_at_fdr: usize,
+}
+
+/// Collects the main scores of a run as it goes, so a hopeless run (wrong
+/// tolerances, wrong FASTA) can be spotted without waiting for it to finish.
+#[derive(Debug, Default)]
+pub struct FdrPreview {
+    target_scores: Vec<f64>,
+    decoy_scores: Vec<f64>,
+}
+
+fn mean_and_variance(values: &[f64]) -> (f64, f64) {
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let variance = values.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / values.len() as f64;
+    (mean, variance)
+}
+
+impl FdrPreview {
+    pub fn add(&mut self, results: &[IonSearchResults]) {
+        for result in results {
+            let is_decoy = !matches!(result.decoy, DecoyMarking::Target);
+            self.add_score(result.score_data.main_score, is_decoy);
+        }
+    }
+
+    /// Adds a single main score, non-finite ones are ignored.
+    pub fn add_score(&mut self, score: f64, is_decoy: bool) {
+        if !score.is_finite() {
+            return;
+        }
+        if is_decoy {
+            self.decoy_scores.push(score);
+        } else {
+            self.target_scores.push(score);
+        }
+    }
+
+    /// Returns None until there are both targets and decoys.
+    ///
+    /// Decoy counts are scaled by the decoy to target ratio seen so far, so
+    /// the estimate holds with several decoys per target.
+    pub fn summary(&self, fdr: f64) -> Option<FdrPreviewSummary> {
+        if self.target_scores.is_empty() || self.decoy_scores.is_empty() {
+            return None;
+        }
+        let (target_mean, target_var) = mean_and_variance(&self.target_scores);
+        let (decoy_mean, decoy_var) = mean_and_variance(&self.decoy_scores);
+        let pooled_sd = ((target_var + decoy_var) / 2.0).sqrt();
+        let separation = if pooled_sd > 0.0 {
+            (target_mean - decoy_mean) / pooled_sd
+        } else {
+            0.0
+        };
+
+        Some(FdrPreviewSummary {
+            num_targets: self.target_scores.len(),
+            num_decoys: self.decoy_scores.len(),
+            separation,
+            ids_at_fdr: self.fdr_cutoff(fdr).map(|x| x.0).unwrap_or(0),
+        })
+    }
+
+    /// Lowest main score of the targets passing the FDR threshold, None
+    /// until there are both targets and decoys or if none pass.
+    pub fn score_threshold(&self, fdr: f64) -> Option<f64> {
+        self.fdr_cutoff(fdr).map(|x| x.1)
+    }
+
+    /// Number of targets passing the FDR threshold and the score of the last
+    /// one, walking down the scores.
+    fn fdr_cutoff(&self, fdr: f64) -> Option<(usize, f64)> {
+        if self.target_scores.is_empty() || self.decoy_scores.is_empty() {
+            return None;
+        }
+        let mut scores: Vec<(f64, bool)> = self
+            .target_scores
+            .iter()
+            .map(|x| (*x, false))
+            .chain(self.decoy_scores.iter().map(|x| (*x, true)))
+            .collect();
+        scores.sort_by(|a, b| b.0.total_cmp(&a.0));
+        let decoy_weight = self.target_scores.len() as f64 / self.decoy_scores.len() as f64;
+        let mut targets = 0usize;
+        let mut decoys = 0usize;
+        let mut cutoff = None;
+        for (score, is_decoy) in scores {
+            if is_decoy {
+                decoys += 1;
+            } else {
+                targets += 1;
+            }
+            if !is_decoy && decoys as f64 * decoy_weight / targets as f64 <= fdr {
+                cutoff = Some((targets, score));
+            }
+        }
+        cutoff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fdr_preview_summary() {
+        let mut preview = FdrPreview::default();
+        assert!(preview.summary(0.01).is_none());
+
+        // 100 targets above every decoy, then 100 mixed in with them.
+        preview.target_scores = (0..100).map(|x| 100.0 + x as f64).collect();
+        preview.target_scores.extend((0..100).map(|x| x as f64 + 0.5));
+        preview.decoy_scores = (0..100).map(|x| x as f64).collect();
+        let summary = preview.summary(0.01).unwrap();
+        assert_eq!(summary.num_targets, 200);
+        assert_eq!(summary.num_decoys, 100);
+        assert!(summary.separation > 1.0);
+        // The first decoy (weighted x2) already goes over 1%, after 101 targets.
+        assert_eq!(summary.ids_at_fdr, 101);
+        assert_eq!(preview.score_threshold(0.01), Some(99.5));
+    }
+}
@@ -0,0 +1,406 @@
+use crate::errors::TimsSeekError;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Filter over the columns of a results table, eg.
+/// `main_score > 10 && npeaks >= 4 && !decoy`.
+///
+/// Supports `&&`, `||`, `!`, parentheses and the comparisons `<`, `<=`, `>`,
+/// `>=`, `==` and `!=` between columns, numbers and double quoted strings.
+/// Values that parse as numbers are compared as numbers, the others as
+/// strings. A bare column is true unless it is empty, zero, "false" or
+/// "Target", so `decoy` and `!decoy` select decoys and targets.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterExpression {
+    expr: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Operand, CompareOp, Operand),
+    Truthy(Operand),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Column(String),
+    Number(f64),
+    Text(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CompareOp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(f64),
+    Text(String),
+    Op(CompareOp),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+fn parse_error(expression: &str, reason: &str) -> TimsSeekError {
+    TimsSeekError::ParseError {
+        msg: format!("Invalid filter {:?}: {}", expression, reason),
+    }
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, TimsSeekError> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let (token, width) = match (c, next) {
+            (c, _) if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('<', Some('=')) => (Token::Op(CompareOp::Le), 2),
+            ('>', Some('=')) => (Token::Op(CompareOp::Ge), 2),
+            ('=', Some('=')) => (Token::Op(CompareOp::Eq), 2),
+            ('!', Some('=')) => (Token::Op(CompareOp::Ne), 2),
+            ('<', _) => (Token::Op(CompareOp::Lt), 1),
+            ('>', _) => (Token::Op(CompareOp::Gt), 1),
+            ('!', _) => (Token::Not, 1),
+            ('(', _) => (Token::Open, 1),
+            (')', _) => (Token::Close, 1),
+            ('"', _) => {
+                let end = chars[i + 1..]
+                    .iter()
+                    .position(|x| *x == '"')
+                    .ok_or_else(|| parse_error(expression, "unterminated string"))?;
+                let text: String = chars[i + 1..i + 1 + end].iter().collect();
+                (Token::Text(text), end + 2)
+            }
+            (c, _) if c.is_ascii_digit() || c == '-' || c == '.' => {
+                let mut len = 1;
+                while let Some(x) = chars.get(i + len) {
+                    let exponent_sign =
+                        (*x == '-' || *x == '+') && matches!(chars[i + len - 1], 'e' | 'E');
+                    if !(x.is_ascii_alphanumeric() || *x == '.' || exponent_sign) {
+                        break;
+                    }
+                    len += 1;
+                }
+                let text: String = chars[i..i + len].iter().collect();
+                let number = text
+                    .parse()
+                    .map_err(|_| parse_error(expression, &format!("invalid number {}", text)))?;
+                (Token::Number(number), len)
+            }
+            (c, _) if c.is_alphabetic() || c == '_' => {
+                let len = chars[i..]
+                    .iter()
+                    .take_while(|x| x.is_alphanumeric() || **x == '_')
+                    .count();
+                (Token::Ident(chars[i..i + len].iter().collect()), len)
+            }
+            (c, _) => return Err(parse_error(expression, &format!("unexpected {:?}", c))),
+        };
+        tokens.push(token);
+        i += width;
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser, `||` binds looser than `&&`, which binds looser
+/// than `!`.
+struct Parser<'a> {
+    expression: &'a str,
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let out = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        out
+    }
+
+    fn or(&mut self) -> Result<Expr, TimsSeekError> {
+        let mut left = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.position += 1;
+            left = Expr::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, TimsSeekError> {
+        let mut left = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.position += 1;
+            left = Expr::And(Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, TimsSeekError> {
+        if self.peek() == Some(&Token::Not) {
+            self.position += 1;
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.peek() == Some(&Token::Open) {
+            self.position += 1;
+            let inner = self.or()?;
+            return match self.next() {
+                Some(Token::Close) => Ok(inner),
+                _ => Err(parse_error(self.expression, "missing closing parenthesis")),
+            };
+        }
+        let left = self.operand()?;
+        match self.peek() {
+            Some(Token::Op(op)) => {
+                let op = *op;
+                self.position += 1;
+                Ok(Expr::Compare(left, op, self.operand()?))
+            }
+            _ => Ok(Expr::Truthy(left)),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, TimsSeekError> {
+        match self.next() {
+            Some(Token::Ident(x)) => Ok(Operand::Column(x)),
+            Some(Token::Number(x)) => Ok(Operand::Number(x)),
+            Some(Token::Text(x)) => Ok(Operand::Text(x)),
+            Some(x) => Err(parse_error(
+                self.expression,
+                &format!("expected a column or a value, got {:?}", x),
+            )),
+            None => Err(parse_error(self.expression, "unexpected end")),
+        }
+    }
+}
+
+fn is_truthy(value: &str) -> bool {
+    match value.parse::<f64>() {
+        Ok(x) => x != 0.0 && !x.is_nan(),
+        Err(_) => !(value.is_empty()
+            || value.eq_ignore_ascii_case("false")
+            || value.eq_ignore_ascii_case("target")),
+    }
+}
+
+fn operand_value<'c, 'v: 'c, F>(operand: &'c Operand, lookup: &F) -> Option<Cow<'c, str>>
+where
+    F: Fn(&str) -> Option<&'v str>,
+{
+    match operand {
+        Operand::Column(name) => lookup(name).map(Cow::Borrowed),
+        Operand::Number(x) => Some(Cow::Owned(x.to_string())),
+        Operand::Text(x) => Some(Cow::Borrowed(x.as_str())),
+    }
+}
+
+impl Operand {
+    fn column(&self) -> Option<&str> {
+        match self {
+            Operand::Column(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+impl Expr {
+    fn columns<'a>(&'a self, out: &mut Vec<&'a str>) {
+        match self {
+            Expr::Or(a, b) | Expr::And(a, b) => {
+                a.columns(out);
+                b.columns(out);
+            }
+            Expr::Not(a) => a.columns(out),
+            Expr::Compare(a, _, b) => out.extend(a.column().into_iter().chain(b.column())),
+            Expr::Truthy(a) => out.extend(a.column()),
+        }
+    }
+
+    fn evaluate<'v, F>(&self, lookup: &F) -> bool
+    where
+        F: Fn(&str) -> Option<&'v str>,
+    {
+        match self {
+            Expr::Or(a, b) => a.evaluate(lookup) || b.evaluate(lookup),
+            Expr::And(a, b) => a.evaluate(lookup) && b.evaluate(lookup),
+            Expr::Not(a) => !a.evaluate(lookup),
+            Expr::Truthy(a) => operand_value(a, lookup)
+                .map(|x| is_truthy(&x))
+                .unwrap_or(false),
+            Expr::Compare(a, op, b) => {
+                let (Some(a), Some(b)) = (operand_value(a, lookup), operand_value(b, lookup))
+                else {
+                    return false;
+                };
+                let ordering = match (a.parse::<f64>(), b.parse::<f64>()) {
+                    (Ok(a), Ok(b)) => a.partial_cmp(&b),
+                    _ => Some(a.cmp(&b)),
+                };
+                let Some(ordering) = ordering else {
+                    return false;
+                };
+                match op {
+                    CompareOp::Lt => ordering.is_lt(),
+                    CompareOp::Le => ordering.is_le(),
+                    CompareOp::Gt => ordering.is_gt(),
+                    CompareOp::Ge => ordering.is_ge(),
+                    CompareOp::Eq => ordering.is_eq(),
+                    CompareOp::Ne => ordering.is_ne(),
+                }
+            }
+        }
+    }
+}
+
+impl FilterExpression {
+    pub fn parse(expression: &str) -> Result<Self, TimsSeekError> {
+        let mut parser = Parser {
+            expression,
+            tokens: tokenize(expression)?,
+            position: 0,
+        };
+        let expr = parser.or()?;
+        if parser.position != parser.tokens.len() {
+            return Err(parse_error(expression, "unexpected trailing tokens"));
+        }
+        Ok(Self { expr })
+    }
+
+    /// Columns used by the expression.
+    pub fn columns(&self) -> Vec<&str> {
+        let mut out = Vec::new();
+        self.expr.columns(&mut out);
+        out
+    }
+
+    /// Whether a row passes the filter, `lookup` gives the value of a column.
+    ///
+    /// Comparisons with a missing column are false.
+    pub fn matches<'v, F>(&self, lookup: F) -> bool
+    where
+        F: Fn(&str) -> Option<&'v str>,
+    {
+        self.expr.evaluate(&lookup)
+    }
+}
+
+/// Writes the rows of the results tables passing the filter (all of them if
+/// there is none) to a single table, returns the number of rows written.
+///
+/// All the inputs must have the same columns.
+pub fn filter_results_csv(
+    inputs: &[impl AsRef<Path>],
+    output: &Path,
+    filter: Option<&FilterExpression>,
+) -> Result<usize, TimsSeekError> {
+    let as_error = |e: csv::Error| TimsSeekError::ParseError { msg: e.to_string() };
+    let mut writer = csv::Writer::from_path(output).map_err(as_error)?;
+    let mut first_headers: Option<csv::StringRecord> = None;
+    let mut num_rows = 0;
+    for input in inputs {
+        let input = input.as_ref();
+        let mut reader = csv::Reader::from_path(input).map_err(as_error)?;
+        let headers = reader.headers().map_err(as_error)?.clone();
+        match &first_headers {
+            Some(x) if *x != headers => {
+                return Err(TimsSeekError::ParseError {
+                    msg: format!("{} has different columns", input.display()),
+                });
+            }
+            Some(_) => {}
+            None => {
+                let unknown = filter
+                    .into_iter()
+                    .flat_map(|x| x.columns())
+                    .find(|x| !headers.iter().any(|h| h == *x));
+                if let Some(x) = unknown {
+                    return Err(TimsSeekError::ParseError {
+                        msg: format!("Unknown column in filter: {}", x),
+                    });
+                }
+                writer.write_record(&headers).map_err(as_error)?;
+                first_headers = Some(headers.clone());
+            }
+        }
+
+        let column_index: HashMap<&str, usize> =
+            headers.iter().enumerate().map(|(i, x)| (x, i)).collect();
+        for record in reader.records() {
+            let record = record.map_err(as_error)?;
+            let keep = match filter {
+                Some(filter) => filter.matches(|name: &str| {
+                    column_index.get(name).and_then(|i| record.get(*i))
+                }),
+                None => true,
+            };
+            if keep {
+                writer.write_record(&record).map_err(as_error)?;
+                num_rows += 1;
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(num_rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row<'a>(values: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<&'a str> {
+        move |name: &str| values.iter().find(|(k, _)| *k == name).map(|(_, v)| *v)
+    }
+
+    #[test]
+    fn test_filter_expression() {
+        let filter = FilterExpression::parse("main_score > 10 && npeaks >= 4 && !decoy").unwrap();
+        assert_eq!(filter.columns(), vec!["main_score", "npeaks", "decoy"]);
+
+        let target = [("main_score", "12.5"), ("npeaks", "4"), ("decoy", "Target")];
+        let decoy = [("main_score", "12.5"), ("npeaks", "4"), ("decoy", "Decoy")];
+        let low = [("main_score", "9"), ("npeaks", "6"), ("decoy", "Target")];
+        assert!(filter.matches(row(&target)));
+        assert!(!filter.matches(row(&decoy)));
+        assert!(!filter.matches(row(&low)));
+
+        let filter = FilterExpression::parse(
+            r#"(precursor_charge == 2 || sequence != "PEPTIDEK") && x < -1e-3"#,
+        )
+        .unwrap();
+        let charge_2 = [("precursor_charge", "2"), ("sequence", "PEPTIDEK"), ("x", "-1")];
+        let charge_3 = [("precursor_charge", "3"), ("sequence", "PEPTIDEK"), ("x", "-1")];
+        assert!(filter.matches(row(&charge_2)));
+        assert!(!filter.matches(row(&charge_3)));
+        // Missing columns never pass a comparison.
+        assert!(!filter.matches(row(&[("precursor_charge", "2")])));
+
+        assert!(FilterExpression::parse("main_score >").is_err());
+        assert!(FilterExpression::parse("(main_score > 1").is_err());
+        assert!(FilterExpression::parse("main_score > 1 2").is_err());
+        assert!(FilterExpression::parse("main_score = 1").is_err());
+    }
+}
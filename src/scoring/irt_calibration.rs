@@ -0,0 +1,247 @@
+use crate::fragment_mass::elution_group_converter::{
+    queries_for_sequence,
+    SequenceToElutionGroupConverter,
+};
+use crate::fragment_mass::fragment_mass_builder::SafePosition;
+use crate::rt_prediction::fit_line;
+use crate::scoring::calibration::median;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use timsquery::models::aggregators::raw_peak_agg::multi_chromatogram_agg::multi_chromatogram_agg::NaturalFinalizedMultiCMGStatsArrays;
+use timsquery::ElutionGroup;
+
+/// Peptides of the Biognosys iRT kit with their iRT.
+pub const BIOGNOSYS_IRT_PEPTIDES: [(&str, f64); 11] = [
+    ("LGGNEQVTR", -24.92),
+    ("GAGSSEPVTGLDAK", 0.0),
+    ("VEATFGVDESNK", 12.39),
+    ("YILAGVENSK", 19.79),
+    ("TPVISGGPYEYR", 28.71),
+    ("TPVITGAPYEYR", 33.38),
+    ("DGLDAASYYAPVR", 42.26),
+    ("ADVTPADFSEWSK", 54.62),
+    ("GTFIIDPGGVIR", 70.52),
+    ("GTFIIDPAAVIR", 87.23),
+    ("LFLQFGAQGSPFLK", 100.0),
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnchorPeptide {
+    /// ProForma sequence.
+    pub sequence: String,
+    pub irt: f64,
+}
+
+/// Anchor peptides (eg. spiked-in iRT or PRTC peptides) searched before
+/// everything else, to map the iRT of the queries to the seconds of the run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IrtAnchors {
+    /// The Biognosys iRT kit by default.
+    pub peptides: Vec<AnchorPeptide>,
+    /// Anchors matching fewer fragments at their apex are not used.
+    pub min_fragments: usize,
+    /// Also shift the 1/K0 of the queries by the median 1/K0 error of the
+    /// anchors.
+    pub fit_mobility: bool,
+}
+
+impl Default for IrtAnchors {
+    fn default() -> Self {
+        Self {
+            peptides: BIOGNOSYS_IRT_PEPTIDES
+                .iter()
+                .map(|(sequence, irt)| AnchorPeptide {
+                    sequence: sequence.to_string(),
+                    irt: *irt,
+                })
+                .collect(),
+            min_fragments: 3,
+            fit_mobility: false,
+        }
+    }
+}
+
+impl IrtAnchors {
+    /// Queries of every charge of the anchors (over the whole run), with the
+    /// index of their anchor.
+    pub fn queries(
+        &self,
+        converter: &SequenceToElutionGroupConverter,
+    ) -> Vec<(ElutionGroup<SafePosition>, usize)> {
+        let mut out = Vec::new();
+        for (i, anchor) in self.peptides.iter().enumerate() {
+            let charges = converter.precursor_charge_range.clone();
+            let queries = match queries_for_sequence(&anchor.sequence, charges, converter) {
+                Ok(x) => x,
+                Err(e) => {
+                    log::warn!("Skipping anchor {}: {:?}", anchor.sequence, e);
+                    continue;
+                }
+            };
+            for (mut eg, _) in queries {
+                eg.id = out.len() as u64;
+                eg.rt_seconds = 0.0;
+                out.push((eg, i));
+            }
+        }
+        out
+    }
+}
+
+/// Apex of an anchor query in the run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AnchorObservation {
+    pub rt_seconds: f64,
+    pub summed_intensity: f64,
+    /// Median 1/K0 error of the fragments, observed minus expected.
+    pub mobility_error: Option<f64>,
+}
+
+/// Apex of an anchor query, None if it matched fewer than `min_fragments`.
+pub fn anchor_observation(
+    arrays: &NaturalFinalizedMultiCMGStatsArrays<SafePosition>,
+    min_fragments: usize,
+) -> Option<AnchorObservation> {
+    let scores = arrays.finalized_score().ok()?;
+    if (scores.ms2_scores.npeaks as usize) < min_fragments
+        || scores.ms2_scores.summed_intensity as f64 <= 0.0
+    {
+        return None;
+    }
+    let mut mobility_errors: Vec<f64> = scores
+        .ms2_scores
+        .mobility_errors
+        .iter()
+        .map(|x| *x as f64)
+        .filter(|x| x.is_finite())
+        .collect();
+    Some(AnchorObservation {
+        rt_seconds: scores.ms2_scores.retention_time_miliseconds as f64 / 1000.0,
+        summed_intensity: scores.ms2_scores.summed_intensity as f64,
+        mobility_error: median(&mut mobility_errors),
+    })
+}
+
+/// Linear map from iRT to the seconds of the run, and optionally the 1/K0
+/// offset of the run.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IrtCalibration {
+    pub slope: f64,
+    pub intercept: f64,
+    pub mobility_offset: Option<f64>,
+    /// Anchors the calibration is fitted on, after dropping outliers.
+    pub num_anchors: usize,
+}
+
+impl IrtCalibration {
+    /// Fits the calibration on the `(irt, observation)` of the anchors found,
+    /// dropping the ones more than 3 times the median residual (at least a
+    /// second) off the first fit. None with fewer than 3 anchors left.
+    pub fn fit(anchors: &[(f64, AnchorObservation)], fit_mobility: bool) -> Option<Self> {
+        if anchors.len() < 3 {
+            return None;
+        }
+        let points: Vec<(f64, f64)> = anchors
+            .iter()
+            .map(|(irt, x)| (*irt, x.rt_seconds))
+            .collect();
+        let (slope, intercept) = fit_line(&points).ok()?;
+        let residuals: Vec<f64> = points
+            .iter()
+            .map(|(irt, rt)| (rt - (intercept + slope * irt)).abs())
+            .collect();
+        let max_residual = 3.0 * median(&mut residuals.clone())?.max(1.0);
+        let kept: Vec<(f64, AnchorObservation)> = anchors
+            .iter()
+            .zip(residuals)
+            .filter(|(_, residual)| *residual <= max_residual)
+            .map(|(x, _)| *x)
+            .collect();
+        if kept.len() < 3 {
+            return None;
+        }
+        let points: Vec<(f64, f64)> = kept
+            .iter()
+            .map(|(irt, x)| (*irt, x.rt_seconds))
+            .collect();
+        let (slope, intercept) = fit_line(&points).ok()?;
+        let mobility_offset = if fit_mobility {
+            let mut errors: Vec<f64> = kept.iter().filter_map(|x| x.1.mobility_error).collect();
+            median(&mut errors)
+        } else {
+            None
+        };
+        Some(Self {
+            slope,
+            intercept,
+            mobility_offset,
+            num_anchors: kept.len(),
+        })
+    }
+
+    pub fn rt_seconds(&self, irt: f64) -> f64 {
+        self.intercept + self.slope * irt
+    }
+
+    /// Maps the RT of a query (taken as iRT) to the run, and shifts its 1/K0.
+    pub fn apply(&self, elution_group: &mut ElutionGroup<SafePosition>) {
+        elution_group.rt_seconds = self.rt_seconds(elution_group.rt_seconds as f64) as f32;
+        if let Some(offset) = self.mobility_offset {
+            elution_group.mobility += offset as f32;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_irt_calibration() {
+        let observation = |rt_seconds: f64| AnchorObservation {
+            rt_seconds,
+            summed_intensity: 1.0,
+            mobility_error: Some(0.02),
+        };
+        // 20 s per iRT unit, starting at 600 s, plus a misidentified anchor.
+        let mut anchors: Vec<(f64, AnchorObservation)> = BIOGNOSYS_IRT_PEPTIDES
+            .iter()
+            .map(|(_, irt)| (*irt, observation(600.0 + 20.0 * irt)))
+            .collect();
+        anchors[3].1.rt_seconds = 3000.0;
+
+        let calibration = IrtCalibration::fit(&anchors, true).unwrap();
+        assert_eq!(calibration.num_anchors, 10);
+        assert!((calibration.slope - 20.0).abs() < 1e-6);
+        assert!((calibration.intercept - 600.0).abs() < 1e-6);
+        assert_eq!(calibration.mobility_offset, Some(0.02));
+
+        let mut eg = ElutionGroup {
+            id: 0,
+            precursor_mzs: vec![500.0],
+            mobility: 0.9,
+            rt_seconds: 50.0,
+            fragment_mzs: Default::default(),
+            expected_fragment_intensity: None,
+            expected_precursor_intensity: None,
+        };
+        calibration.apply(&mut eg);
+        assert!((eg.rt_seconds - 1600.0).abs() < 1e-3);
+        assert!((eg.mobility - 0.92).abs() < 1e-6);
+
+        assert!(IrtCalibration::fit(&anchors[..2], false).is_none());
+    }
+
+    #[test]
+    fn test_anchor_queries() {
+        let anchors = IrtAnchors::default();
+        let queries = anchors.queries(&SequenceToElutionGroupConverter::default());
+        assert!(!queries.is_empty());
+        assert!(queries.iter().all(|(eg, i)| *i < 11 && eg.rt_seconds == 0.0));
+        let ids: Vec<u64> = queries.iter().map(|x| x.0.id).collect();
+        assert_eq!(ids, (0..queries.len() as u64).collect::<Vec<_>>());
+    }
+}
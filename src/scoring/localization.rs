@@ -0,0 +1,148 @@
+use crate::fragment_mass::fragment_mass_builder::SafePosition;
+use crate::fragment_mass::modifications::{
+    modification_names,
+    strip_modifications,
+};
+use crate::scoring::coelution::ms2_fragment_traces;
+use crate::scoring::search_results::IonSearchResults;
+use serde::Serialize;
+use std::collections::HashMap;
+use timsquery::models::aggregators::raw_peak_agg::multi_chromatogram_agg::multi_chromatogram_agg::NaturalFinalizedMultiCMGStatsArrays;
+use timsquery::ElutionGroup;
+
+/// Fragments of two isomers closer than this (in m/z) do not tell them apart.
+const SITE_DETERMINING_MIN_MZ_DIFF: f64 = 0.01;
+
+/// Queried m/z and observed intensity (summed over the extraction window) of
+/// a fragment.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FragmentEvidence {
+    pub position: SafePosition,
+    pub mz: f64,
+    pub intensity: f64,
+}
+
+/// Evidence of every queried fragment of a PSM.
+pub fn fragment_evidence(
+    arrays: &NaturalFinalizedMultiCMGStatsArrays<SafePosition>,
+    elution_group: &ElutionGroup<SafePosition>,
+) -> Vec<FragmentEvidence> {
+    let intensities: HashMap<SafePosition, f64> = ms2_fragment_traces(arrays)
+        .into_iter()
+        .map(|(pos, trace)| (pos, trace.into_iter().sum()))
+        .collect();
+    elution_group
+        .fragment_mzs
+        .iter()
+        .map(|(pos, mz)| FragmentEvidence {
+            position: *pos,
+            mz: *mz,
+            intensity: intensities.get(pos).copied().unwrap_or(0.0),
+        })
+        .collect()
+}
+
+/// Intensity of the fragments of `evidence` that are missing from (or at
+/// another m/z in) any of the `others` isomers.
+fn site_determining_intensity(evidence: &[FragmentEvidence], others: &[&[FragmentEvidence]]) -> f64 {
+    evidence
+        .iter()
+        .filter(|frag| {
+            others.iter().any(|other| {
+                !other.iter().any(|x| {
+                    x.position == frag.position
+                        && (x.mz - frag.mz).abs() < SITE_DETERMINING_MIN_MZ_DIFF
+                })
+            })
+        })
+        .map(|frag| frag.intensity)
+        .sum()
+}
+
+/// Sequence, modifications, charge, decoy marking and channel of a PSM.
+type IsomerKey = (String, Vec<String>, u8, &'static str, &'static str);
+
+/// Fills in the localization probability of every modified PSM.
+///
+/// Isomers are the peptidoforms of the same sequence, charge and set of
+/// modifications (on different residues) searched in the same chunk. The
+/// probability of each is its share of the intensity of its site-determining
+/// fragments, the ones that tell it apart from the other isomers. PSMs
+/// without competing isomers get 1, with no evidence at all the isomers
+/// share it evenly.
+pub fn assign_localization_probabilities(results: &mut [IonSearchResults]) {
+    let mut groups: HashMap<IsomerKey, Vec<usize>> = HashMap::new();
+    for (i, res) in results.iter().enumerate() {
+        if res.fragment_evidence.is_none() {
+            continue;
+        }
+        let peptidoform = res.sequence.peptidoform();
+        // Sorted so isomers get the same list.
+        let mut modifications = modification_names(&peptidoform);
+        modifications.sort();
+        let key = (
+            strip_modifications(&peptidoform),
+            modifications,
+            res.precursor_data.charge,
+            res.decoy.as_str(),
+            res.channel.channel.as_str(),
+        );
+        groups.entry(key).or_default().push(i);
+    }
+
+    for members in groups.values() {
+        let evidence: Vec<&[FragmentEvidence]> = members
+            .iter()
+            .map(|&i| results[i].fragment_evidence.as_deref().unwrap_or_default())
+            .collect();
+        let scores: Vec<f64> = (0..members.len())
+            .map(|i| {
+                let others: Vec<&[FragmentEvidence]> = evidence
+                    .iter()
+                    .enumerate()
+                    .filter(|(j, _)| *j != i)
+                    .map(|(_, x)| *x)
+                    .collect();
+                site_determining_intensity(evidence[i], &others)
+            })
+            .collect();
+        let total: f64 = scores.iter().sum();
+        for (&i, score) in members.iter().zip(scores) {
+            results[i].localization_probability = Some(if members.len() == 1 {
+                1.0
+            } else if total > 0.0 {
+                score / total
+            } else {
+                1.0 / members.len() as f64
+            });
+        }
+    }
+    results
+        .iter_mut()
+        .for_each(|res| res.fragment_evidence = None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evidence(frags: &[(&str, f64, f64)]) -> Vec<FragmentEvidence> {
+        frags
+            .iter()
+            .map(|(pos, mz, intensity)| FragmentEvidence {
+                position: SafePosition::from_str(pos).unwrap(),
+                mz: *mz,
+                intensity: *intensity,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_site_determining_intensity() {
+        // Phospho on S (b4 heavier) vs on T (b4 unmodified), y3 is shared.
+        let on_s = evidence(&[("b4", 500.0, 100.0), ("y3", 400.0, 1000.0)]);
+        let on_t = evidence(&[("b4", 420.0, 10.0), ("y3", 400.0, 1000.0)]);
+        assert_eq!(site_determining_intensity(&on_s, &[&on_t]), 100.0);
+        assert_eq!(site_determining_intensity(&on_t, &[&on_s]), 10.0);
+    }
+}
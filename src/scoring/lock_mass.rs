@@ -0,0 +1,224 @@
+use crate::fragment_mass::elution_group_converter::supersimpleprediction;
+use crate::fragment_mass::fragment_mass_builder::SafePosition;
+use crate::scoring::calibration::median;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::collections::HashMap;
+use timsquery::models::aggregators::raw_peak_agg::multi_chromatogram_agg::multi_chromatogram_agg::NaturalFinalizedMultiCMGStatsArrays;
+use timsquery::ElutionGroup;
+
+/// Known background (lock mass) fragment ions, measured before the search to
+/// correct the fragment m/z of the queries for the drift of the run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LockMassConfig {
+    /// Theoretical m/z of the lock mass ions.
+    pub fragment_mzs: Vec<f64>,
+    /// Precursor m/z of the probes, selects the isolation window the ions are
+    /// measured in.
+    pub precursor_mz: f64,
+    /// The correction is the median error of each of these RT segments,
+    /// interpolated between them.
+    pub num_segments: usize,
+    /// RT range (seconds) of the run, the probes are spread over it. Without
+    /// it every ion is probed once over the whole run, so the correction
+    /// only follows the apexes the ions happen to have.
+    pub gradient_seconds: Option<(f32, f32)>,
+}
+
+impl Default for LockMassConfig {
+    fn default() -> Self {
+        Self {
+            fragment_mzs: Vec::new(),
+            precursor_mz: 700.0,
+            num_segments: 10,
+            gradient_seconds: None,
+        }
+    }
+}
+
+impl LockMassConfig {
+    /// One probe per lock mass ion (with it as its only fragment) and RT
+    /// segment, centered on the segment. With a whole-run RT tolerance the
+    /// segments of an ion all find the same apex.
+    pub fn probe_elution_groups(&self) -> Vec<ElutionGroup<SafePosition>> {
+        let rt_centers: Vec<f32> = match self.gradient_seconds {
+            Some((start, end)) => {
+                let num_segments = self.num_segments.max(1);
+                let width = (end - start) / num_segments as f32;
+                (0..num_segments)
+                    .map(|i| start + width * (i as f32 + 0.5))
+                    .collect()
+            }
+            None => vec![0.0],
+        };
+        let mobility = supersimpleprediction(self.precursor_mz, 2) as f32;
+        let mut out = Vec::new();
+        for (i, mz) in self.fragment_mzs.iter().enumerate() {
+            let pos = SafePosition {
+                series_id: b'l',
+                series_number: i as u16 + 1,
+                series_end: 0,
+                neutral_loss: None,
+                charge: 1,
+            };
+            for rt_seconds in &rt_centers {
+                out.push(ElutionGroup {
+                    id: out.len() as u64,
+                    precursor_mzs: vec![self.precursor_mz],
+                    mobility,
+                    rt_seconds: *rt_seconds,
+                    fragment_mzs: HashMap::from([(pos, *mz)]),
+                    expected_fragment_intensity: None,
+                    expected_precursor_intensity: None,
+                });
+            }
+        }
+        out
+    }
+}
+
+/// `(rt_seconds, ppm_error)` at the apex of a lock mass probe, None if the
+/// ion was not found.
+///
+/// The m/z errors of the apex scores are observed minus theoretical m/z.
+pub fn lock_mass_error(
+    arrays: &NaturalFinalizedMultiCMGStatsArrays<SafePosition>,
+    elution_group: &ElutionGroup<SafePosition>,
+) -> Option<(f64, f64)> {
+    let theoretical = *elution_group.fragment_mzs.values().next()?;
+    let scores = arrays.finalized_score().ok()?;
+    if scores.ms2_scores.summed_intensity as f64 <= 0.0 {
+        return None;
+    }
+    let error = *scores.ms2_scores.mz_errors.first()? as f64;
+    if !error.is_finite() {
+        return None;
+    }
+    let rt_seconds = scores.ms2_scores.retention_time_miliseconds as f64 / 1000.0;
+    Some((rt_seconds, 1e6 * error / theoretical))
+}
+
+/// Time dependent fragment m/z error, in ppm, linearly interpolated between
+/// the `(rt_seconds, ppm)` knots and constant outside of them.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MassCorrection {
+    pub knots: Vec<(f64, f64)>,
+    /// Median error of the run, used for queries without an RT.
+    pub run_ppm: f64,
+}
+
+impl MassCorrection {
+    /// Fits the correction from the `(rt_seconds, ppm_error)` lock mass
+    /// measurements, one knot per RT segment with any. None without
+    /// measurements.
+    pub fn fit(errors: &[(f64, f64)], num_segments: usize) -> Option<Self> {
+        let mut all: Vec<f64> = errors.iter().map(|x| x.1).collect();
+        let run_ppm = median(&mut all)?;
+        let num_segments = num_segments.max(1);
+        let min_rt = errors.iter().map(|x| x.0).fold(f64::INFINITY, f64::min);
+        let max_rt = errors.iter().map(|x| x.0).fold(f64::NEG_INFINITY, f64::max);
+        let width = ((max_rt - min_rt) / num_segments as f64).max(f64::MIN_POSITIVE);
+        let mut segments: Vec<Vec<(f64, f64)>> = vec![Vec::new(); num_segments];
+        for (rt, ppm) in errors {
+            let i = (((rt - min_rt) / width) as usize).min(num_segments - 1);
+            segments[i].push((*rt, *ppm));
+        }
+        let knots = segments
+            .into_iter()
+            .filter_map(|points| {
+                let mut rts: Vec<f64> = points.iter().map(|x| x.0).collect();
+                let mut ppms: Vec<f64> = points.iter().map(|x| x.1).collect();
+                Some((median(&mut rts)?, median(&mut ppms)?))
+            })
+            .collect();
+        Some(Self { knots, run_ppm })
+    }
+
+    /// Error (ppm) at a retention time.
+    pub fn ppm_at(&self, rt_seconds: f64) -> f64 {
+        let (Some(first), Some(last)) = (self.knots.first(), self.knots.last()) else {
+            return self.run_ppm;
+        };
+        if rt_seconds <= first.0 {
+            return first.1;
+        }
+        if rt_seconds >= last.0 {
+            return last.1;
+        }
+        self.knots
+            .windows(2)
+            .find(|x| rt_seconds <= x[1].0)
+            .map(|x| {
+                let frac = (rt_seconds - x[0].0) / (x[1].0 - x[0].0);
+                x[0].1 + frac * (x[1].1 - x[0].1)
+            })
+            .unwrap_or(self.run_ppm)
+    }
+
+    /// Moves the fragment m/z of a query to where the run measures them, at
+    /// its RT (the run median for queries at RT 0, which span the run).
+    pub fn apply(&self, elution_group: &mut ElutionGroup<SafePosition>) {
+        let ppm = if elution_group.rt_seconds > 0.0 {
+            self.ppm_at(elution_group.rt_seconds as f64)
+        } else {
+            self.run_ppm
+        };
+        elution_group
+            .fragment_mzs
+            .values_mut()
+            .for_each(|mz| *mz *= 1.0 + ppm * 1e-6);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_elution_groups() {
+        let mut config = LockMassConfig {
+            fragment_mzs: vec![300.0, 600.0],
+            num_segments: 4,
+            ..Default::default()
+        };
+        assert_eq!(config.probe_elution_groups().len(), 2);
+        config.gradient_seconds = Some((0.0, 400.0));
+        let probes = config.probe_elution_groups();
+        assert_eq!(probes.len(), 8);
+        assert_eq!(probes[0].rt_seconds, 50.0);
+        assert_eq!(probes[7].rt_seconds, 350.0);
+        assert_eq!(probes[7].fragment_mzs.values().next(), Some(&600.0));
+    }
+
+    #[test]
+    fn test_mass_correction() {
+        // Drifting from 0 to +10 ppm over the run.
+        let errors: Vec<(f64, f64)> = (0..=100)
+            .map(|i| (i as f64 * 10.0, i as f64 / 10.0))
+            .collect();
+        let correction = MassCorrection::fit(&errors, 10).unwrap();
+        assert_eq!(correction.knots.len(), 10);
+        assert!((correction.run_ppm - 5.0).abs() < 1e-9);
+        assert!((correction.ppm_at(500.0) - 5.0).abs() < 0.2);
+        assert!(correction.ppm_at(-10.0) < 1.0);
+        assert!(correction.ppm_at(2000.0) > 9.0);
+
+        let mut eg = ElutionGroup {
+            id: 0,
+            precursor_mzs: vec![500.0],
+            mobility: 0.9,
+            rt_seconds: 0.0,
+            fragment_mzs: HashMap::from([(SafePosition::from_str("y3").unwrap(), 1000.0)]),
+            expected_fragment_intensity: None,
+            expected_precursor_intensity: None,
+        };
+        correction.apply(&mut eg);
+        let mz = eg.fragment_mzs.values().next().unwrap();
+        assert!((mz - 1000.005).abs() < 1e-9, "{}", mz);
+
+        assert!(MassCorrection::fit(&[], 10).is_none());
+    }
+}
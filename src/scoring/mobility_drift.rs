@@ -0,0 +1,135 @@
+use crate::models::DecoyMarking;
+use crate::scoring::calibration::median;
+use crate::scoring::fdr_preview::FdrPreview;
+use crate::scoring::search_calibration::precursor_mobility_error;
+use crate::scoring::search_results::IonSearchResults;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+/// Correction of the query 1/K0 for the systematic error of the run,
+/// re-estimated after every chunk from the confident targets so far.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MobilityRecalibrationConfig {
+    /// FDR of the targets the error is estimated from.
+    pub fdr: f64,
+    /// The queries are not corrected until there are this many of them.
+    pub min_points: usize,
+}
+
+impl Default for MobilityRecalibrationConfig {
+    fn default() -> Self {
+        Self {
+            fdr: 0.01,
+            min_points: 50,
+        }
+    }
+}
+
+/// Last estimate of the 1/K0 error of the run.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct MobilityDrift {
+    /// Median observed minus library 1/K0 of the confident targets.
+    pub offset: f64,
+    pub num_points: usize,
+}
+
+/// Running estimate of the 1/K0 error of a run, see
+/// [`MobilityRecalibrationConfig`].
+#[derive(Debug, Default)]
+pub struct MobilityDriftTracker {
+    config: MobilityRecalibrationConfig,
+    preview: FdrPreview,
+    /// Main score and 1/K0 error (before any correction) of every target.
+    targets: Vec<(f64, f64)>,
+}
+
+impl MobilityDriftTracker {
+    pub fn new(config: MobilityRecalibrationConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Adds the results of a chunk whose queries had `applied_offset` added
+    /// to their 1/K0.
+    pub fn add(&mut self, results: &[IonSearchResults], applied_offset: f64) {
+        let points: Vec<(f64, bool, Option<f64>)> = results
+            .iter()
+            .map(|x| {
+                (
+                    x.score_data.main_score,
+                    x.decoy != DecoyMarking::Target,
+                    precursor_mobility_error(x),
+                )
+            })
+            .collect();
+        self.add_points(&points, applied_offset);
+    }
+
+    /// `(main_score, is_decoy, mobility_error)` of the results of a chunk.
+    fn add_points(&mut self, points: &[(f64, bool, Option<f64>)], applied_offset: f64) {
+        for &(score, is_decoy, error) in points {
+            self.preview.add_score(score, is_decoy);
+            if let (false, Some(error)) = (is_decoy, error) {
+                if score.is_finite() {
+                    self.targets.push((score, error + applied_offset));
+                }
+            }
+        }
+    }
+
+    /// Current estimate, None until there are enough confident targets.
+    pub fn drift(&self) -> Option<MobilityDrift> {
+        let threshold = self.preview.score_threshold(self.config.fdr)?;
+        let mut errors: Vec<f64> = self
+            .targets
+            .iter()
+            .filter(|x| x.0 >= threshold)
+            .map(|x| x.1)
+            .collect();
+        if errors.len() < self.config.min_points {
+            return None;
+        }
+        Some(MobilityDrift {
+            offset: median(&mut errors)?,
+            num_points: errors.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mobility_drift() {
+        let config = MobilityRecalibrationConfig {
+            min_points: 10,
+            ..Default::default()
+        };
+        let mut tracker = MobilityDriftTracker::new(config);
+        // Confident targets measured 0.03 above their queries, low scoring
+        // ones (and decoys) all over the place.
+        let chunk = |error: f64| -> Vec<(f64, bool, Option<f64>)> {
+            let mut points: Vec<(f64, bool, Option<f64>)> =
+                (0..10).map(|i| (100.0 + i as f64, false, Some(error))).collect();
+            points.extend((0..10).map(|i| (i as f64, i % 2 == 0, Some(-0.1 * i as f64))));
+            points
+        };
+        tracker.add_points(&chunk(0.03)[..5], 0.0);
+        assert!(tracker.drift().is_none());
+        tracker.add_points(&chunk(0.03), 0.0);
+        let drift = tracker.drift().unwrap();
+        assert!((drift.offset - 0.03).abs() < 1e-9);
+
+        // Once corrected, the chunks only show what is left of the error.
+        tracker.add_points(&chunk(0.0), drift.offset);
+        let drift = tracker.drift().unwrap();
+        assert!((drift.offset - 0.03).abs() < 1e-9);
+        assert!(drift.num_points >= 20);
+    }
+}
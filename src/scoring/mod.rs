@@ -1 +1,18 @@
+pub mod apex;
+pub mod calibration;
+pub mod coelution;
+pub mod fdr_preview;
+pub mod filter;
+pub mod irt_calibration;
+pub mod localization;
+pub mod lock_mass;
+pub mod mobility_drift;
+pub mod noise;
+pub mod noise_floor;
+pub mod peptide_features;
+pub mod rollup;
+pub mod run_comparison;
+pub mod scorers;
+pub mod search_calibration;
 pub mod search_results;
+pub mod xics;
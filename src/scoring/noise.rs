@@ -0,0 +1,75 @@
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+/// Intensity floor and baseline estimation applied to traces before they are
+/// scored, so runs with different digitizer settings are comparable.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NoiseModel {
+    /// Constant intensity subtracted from every point.
+    pub intensity_floor: f64,
+    /// If set, the baseline of each trace is estimated as this quantile of
+    /// its intensities and used instead of the floor when it is larger.
+    pub baseline_quantile: Option<f64>,
+}
+
+impl Default for NoiseModel {
+    fn default() -> Self {
+        Self {
+            intensity_floor: 0.0,
+            baseline_quantile: None,
+        }
+    }
+}
+
+impl NoiseModel {
+    pub fn baseline(&self, trace: &[f64]) -> f64 {
+        let quantile_baseline = match self.baseline_quantile {
+            Some(q) if !trace.is_empty() => {
+                let mut sorted = trace.to_vec();
+                sorted.sort_by(|a, b| a.total_cmp(b));
+                let idx = ((sorted.len() - 1) as f64 * q.clamp(0.0, 1.0)).round() as usize;
+                sorted[idx]
+            }
+            _ => 0.0,
+        };
+        self.intensity_floor.max(quantile_baseline)
+    }
+
+    /// Subtracts the baseline from the trace, clamping at zero.
+    pub fn apply(&self, trace: &mut [f64]) {
+        let baseline = self.baseline(trace);
+        if baseline <= 0.0 {
+            return;
+        }
+        trace
+            .iter_mut()
+            .for_each(|x| *x = (*x - baseline).max(0.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_noise_model() {
+        let model = NoiseModel {
+            intensity_floor: 10.0,
+            baseline_quantile: Some(0.5),
+        };
+        let mut trace = vec![20.0, 20.0, 100.0, 20.0, 5.0];
+        model.apply(&mut trace);
+        assert_eq!(trace, vec![0.0, 0.0, 80.0, 0.0, 0.0]);
+
+        let model = NoiseModel {
+            intensity_floor: 10.0,
+            baseline_quantile: None,
+        };
+        let mut trace = vec![20.0, 5.0];
+        model.apply(&mut trace);
+        assert_eq!(trace, vec![10.0, 0.0]);
+    }
+}
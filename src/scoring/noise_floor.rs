@@ -0,0 +1,284 @@
+use crate::digest::decoys::SplitMix64;
+use crate::fragment_mass::elution_group_converter::supersimpleprediction;
+use crate::fragment_mass::fragment_mass_builder::SafePosition;
+use crate::scoring::calibration::median;
+use crate::scoring::coelution::ms2_fragment_traces;
+use crate::scoring::scorers::PsmScorer;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::collections::HashMap;
+use timsquery::models::aggregators::raw_peak_agg::multi_chromatogram_agg::multi_chromatogram_agg::{NaturalFinalizedMultiCMGStatsArrays, ApexScores};
+use timsquery::ElutionGroup;
+
+/// Fragments queried per probe.
+const FRAGMENTS_PER_PROBE: usize = 10;
+
+/// Quick pre-scan of the run that estimates the MS2 noise floor, by querying
+/// probes at pseudo-random m/z (which mostly land on noise) before the
+/// search.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NoisePrescan {
+    pub num_probes: usize,
+    pub num_segments: usize,
+    pub seed: u64,
+    /// Precursor m/z range the probes are drawn from.
+    pub precursor_mz_range: (f64, f64),
+    /// Fragment m/z range the probes are drawn from.
+    pub fragment_mz_range: (f64, f64),
+}
+
+impl Default for NoisePrescan {
+    fn default() -> Self {
+        Self {
+            num_probes: 200,
+            num_segments: 20,
+            seed: 42,
+            precursor_mz_range: (400.0, 1000.0),
+            fragment_mz_range: (200.0, 2000.0),
+        }
+    }
+}
+
+/// Uniform value in `low..high`.
+fn uniform(rng: &mut SplitMix64, (low, high): (f64, f64)) -> f64 {
+    let unit = (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+    low + (high - low) * unit
+}
+
+impl NoisePrescan {
+    /// Probe elution groups, over the whole run (RT 0) at the predicted
+    /// mobility of a 2+ precursor.
+    pub fn probe_elution_groups(&self) -> Vec<ElutionGroup<SafePosition>> {
+        let mut rng = SplitMix64::new(self.seed);
+        (0..self.num_probes)
+            .map(|i| {
+                let precursor_mz = uniform(&mut rng, self.precursor_mz_range);
+                let fragment_mzs: HashMap<SafePosition, f64> = (1..=FRAGMENTS_PER_PROBE)
+                    .map(|j| {
+                        let pos = SafePosition::from_str(&format!("y{}", j))
+                            .expect("Valid fragment label");
+                        (pos, uniform(&mut rng, self.fragment_mz_range))
+                    })
+                    .collect();
+                ElutionGroup {
+                    id: i as u64,
+                    precursor_mzs: vec![precursor_mz],
+                    mobility: supersimpleprediction(precursor_mz, 2) as f32,
+                    rt_seconds: 0.0,
+                    fragment_mzs,
+                    expected_fragment_intensity: None,
+                    expected_precursor_intensity: None,
+                }
+            })
+            .collect()
+    }
+}
+
+/// MS2 noise floor of a retention time segment of the run.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NoiseFloorSegment {
+    pub rt_start_seconds: f64,
+    pub rt_end_seconds: f64,
+    /// Median of the non-zero probe intensities, 0 if there are none.
+    pub noise_floor: f64,
+    /// Fraction of the probe points with any intensity.
+    pub occupancy: f64,
+    pub num_points: usize,
+}
+
+/// `(rt_seconds, intensity)` points of the fragment traces of a probe.
+pub fn probe_points(
+    arrays: &NaturalFinalizedMultiCMGStatsArrays<SafePosition>,
+) -> Vec<(f64, f64)> {
+    let rts = &arrays.ms2_stats.retention_time_miliseconds;
+    ms2_fragment_traces(arrays)
+        .into_iter()
+        .flat_map(|(_, trace)| {
+            rts.iter()
+                .zip(trace)
+                .map(|(rt_ms, intensity)| (*rt_ms as f64 / 1000.0, intensity))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Noise floor of `num_segments` equally wide RT segments spanning the
+/// points.
+pub fn noise_floor_by_segment(
+    points: &[(f64, f64)],
+    num_segments: usize,
+) -> Vec<NoiseFloorSegment> {
+    if points.is_empty() || num_segments == 0 {
+        return Vec::new();
+    }
+    let min_rt = points.iter().map(|x| x.0).fold(f64::INFINITY, f64::min);
+    let max_rt = points.iter().map(|x| x.0).fold(f64::NEG_INFINITY, f64::max);
+    let width = ((max_rt - min_rt) / num_segments as f64).max(f64::MIN_POSITIVE);
+
+    let mut segments: Vec<Vec<f64>> = vec![Vec::new(); num_segments];
+    for (rt, intensity) in points {
+        let i = (((rt - min_rt) / width) as usize).min(num_segments - 1);
+        segments[i].push(*intensity);
+    }
+    segments
+        .into_iter()
+        .enumerate()
+        .map(|(i, intensities)| {
+            let num_points = intensities.len();
+            let mut non_zero: Vec<f64> =
+                intensities.into_iter().filter(|x| *x > 0.0).collect();
+            let occupancy = if num_points == 0 {
+                0.0
+            } else {
+                non_zero.len() as f64 / num_points as f64
+            };
+            NoiseFloorSegment {
+                rt_start_seconds: min_rt + width * i as f64,
+                rt_end_seconds: min_rt + width * (i + 1) as f64,
+                noise_floor: median(&mut non_zero).unwrap_or(0.0),
+                occupancy,
+                num_points,
+            }
+        })
+        .collect()
+}
+
+/// Noise floor of the segment holding `rt_seconds`, the first or last one
+/// outside of the segments, 0 without segments.
+pub fn noise_floor_at(segments: &[NoiseFloorSegment], rt_seconds: f64) -> f64 {
+    let segment = segments
+        .iter()
+        .find(|x| rt_seconds < x.rt_end_seconds)
+        .or(segments.last());
+    segment.map_or(0.0, |x| x.noise_floor)
+}
+
+/// Number and summed intensity of the peaks above `floor`.
+fn peaks_above_floor(intensities: &[f64], floor: f64) -> (usize, f64) {
+    intensities
+        .iter()
+        .filter(|x| **x > floor && **x > 0.0)
+        .fold((0, 0.0), |(n, total), x| (n + 1, total + x))
+}
+
+/// Applies the pre-scan noise floor to the built-in scores: the fragment
+/// peaks at the apex below the floor of its RT segment do not count in its
+/// npeaks and summed intensity.
+///
+/// Goes after the scorers that move the apex, it reads the final one.
+#[derive(Debug)]
+pub struct NoiseFloorScorer {
+    pub segments: Vec<NoiseFloorSegment>,
+}
+
+impl PsmScorer for NoiseFloorScorer {
+    fn column_names(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    fn score(
+        &self,
+        _arrays: &NaturalFinalizedMultiCMGStatsArrays<SafePosition>,
+        _elution_group: &ElutionGroup<SafePosition>,
+    ) -> Vec<f64> {
+        Vec::new()
+    }
+
+    fn adjust_apex_scores(
+        &self,
+        scores: &mut ApexScores,
+        arrays: &NaturalFinalizedMultiCMGStatsArrays<SafePosition>,
+    ) {
+        let apex_rt = scores.ms2_scores.retention_time_miliseconds as f64;
+        let Some(apex) = arrays
+            .ms2_stats
+            .retention_time_miliseconds
+            .iter()
+            .position(|x| *x as f64 == apex_rt)
+        else {
+            return;
+        };
+        let floor = noise_floor_at(&self.segments, apex_rt / 1000.0);
+        let intensities: Vec<f64> = ms2_fragment_traces(arrays)
+            .iter()
+            .map(|(_, x)| x[apex])
+            .collect();
+        let (npeaks, summed_intensity) = peaks_above_floor(&intensities, floor);
+        scores.ms2_scores.npeaks = npeaks as _;
+        scores.ms2_scores.summed_intensity = summed_intensity as _;
+    }
+}
+
+/// Run-wide noise floor, the median over the segments with points.
+pub fn overall_noise_floor(segments: &[NoiseFloorSegment]) -> f64 {
+    let mut floors: Vec<f64> = segments
+        .iter()
+        .filter(|x| x.num_points > 0)
+        .map(|x| x.noise_floor)
+        .collect();
+    median(&mut floors).unwrap_or(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_noise_floor_by_segment() {
+        // Quiet first half, noisier second half.
+        let mut points = Vec::new();
+        for i in 0..100 {
+            let rt = i as f64;
+            let intensity = if i < 50 { 10.0 } else { 100.0 };
+            points.push((rt, intensity));
+            points.push((rt, 0.0));
+        }
+        let segments = noise_floor_by_segment(&points, 2);
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].noise_floor, 10.0);
+        assert_eq!(segments[1].noise_floor, 100.0);
+        assert_eq!(segments[0].occupancy, 0.5);
+        assert_eq!(segments[0].num_points + segments[1].num_points, 200);
+        assert_eq!(segments[0].rt_start_seconds, 0.0);
+        assert_eq!(segments[1].rt_end_seconds, 99.0);
+
+        assert!(noise_floor_by_segment(&[], 2).is_empty());
+
+        assert_eq!(noise_floor_at(&segments, 10.0), 10.0);
+        assert_eq!(noise_floor_at(&segments, 60.0), 100.0);
+        assert_eq!(noise_floor_at(&segments, 500.0), 100.0);
+        assert_eq!(noise_floor_at(&[], 10.0), 0.0);
+    }
+
+    #[test]
+    fn test_peaks_above_floor() {
+        // The peaks under the floor are discarded.
+        let intensities = [5.0, 50.0, 0.0, 200.0, 10.0];
+        assert_eq!(peaks_above_floor(&intensities, 10.0), (2, 250.0));
+        assert_eq!(peaks_above_floor(&intensities, 0.0), (4, 265.0));
+    }
+
+    #[test]
+    fn test_probe_elution_groups() {
+        let prescan = NoisePrescan {
+            num_probes: 5,
+            ..Default::default()
+        };
+        let probes = prescan.probe_elution_groups();
+        assert_eq!(probes.len(), 5);
+        // Reproducible from the seed.
+        let again = prescan.probe_elution_groups();
+        assert_eq!(probes[0].precursor_mzs, again[0].precursor_mzs);
+        assert_eq!(probes[4].fragment_mzs, again[4].fragment_mzs);
+        for probe in probes {
+            assert_eq!(probe.fragment_mzs.len(), FRAGMENTS_PER_PROBE);
+            assert!(probe
+                .fragment_mzs
+                .values()
+                .all(|mz| (200.0..2000.0).contains(mz)));
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use serde::Serialize;
+
+/// Sequence-only features that are cheap to compute and help rescoring.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct PeptideFeatures {
+    pub length: usize,
+    pub missed_cleavages: usize,
+    pub num_prolines: usize,
+    /// Assumed precursor charge minus the number of likely protonation
+    /// sites (N-terminus + K/R/H). Large positive values are implausible.
+    pub charge_plausibility: i32,
+}
+
+impl PeptideFeatures {
+    /// Computes the features for a bare peptide sequence.
+    ///
+    /// Missed cleavages are counted with tryptic rules (internal K/R not
+    /// followed by P).
+    pub fn new(sequence: &str, charge: u8) -> Self {
+        let residues = sequence.as_bytes();
+        let length = residues.len();
+        let num_prolines = residues.iter().filter(|&&x| x == b'P').count();
+        let missed_cleavages = residues
+            .windows(2)
+            .filter(|w| matches!(w[0], b'K' | b'R') && w[1] != b'P')
+            .count();
+        let basic_sites = 1 + residues
+            .iter()
+            .filter(|&&x| matches!(x, b'K' | b'R' | b'H'))
+            .count();
+
+        Self {
+            length,
+            missed_cleavages,
+            num_prolines,
+            charge_plausibility: charge as i32 - basic_sites as i32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peptide_features() {
+        let feats = PeptideFeatures::new("PEPKTIDEKPINK", 2);
+        assert_eq!(feats.length, 13);
+        assert_eq!(feats.num_prolines, 3);
+        assert_eq!(feats.missed_cleavages, 1);
+        assert_eq!(feats.charge_plausibility, -2);
+    }
+}
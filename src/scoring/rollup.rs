@@ -0,0 +1,293 @@
+pub use crate::fragment_mass::modifications::strip_modifications;
+use crate::scoring::search_results::IonSearchResults;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Modification-agnostic summary of all the peptidoforms of a peptide.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PeptideRollupEntry {
+    pub peptidoforms: Vec<String>,
+    pub num_precursors: usize,
+    pub best_peptidoform: String,
+    pub best_main_score: f64,
+    pub summed_ms2_intensity: f64,
+}
+
+/// Stripped sequence, decoy marking and channel of a PSM.
+type PeptideKey = (String, &'static str, &'static str);
+
+/// Collects the peptide-level rollup of the results of a run (one row per
+/// channel), the peptidoform-level rows are the regular result tables.
+///
+/// Targets and decoys are rolled up separately.
+#[derive(Debug, Default)]
+pub struct PeptideRollup {
+    entries: HashMap<PeptideKey, PeptideRollupEntry>,
+}
+
+impl PeptideRollup {
+    pub fn add(&mut self, results: &[IonSearchResults]) {
+        for result in results {
+            let peptidoform = result.sequence.peptidoform();
+            let key = (
+                strip_modifications(&peptidoform),
+                result.decoy.as_str(),
+                result.channel.channel.as_str(),
+            );
+            self.record(
+                key,
+                peptidoform,
+                result.score_data.main_score,
+                result.score_data.ms2_scores.summed_intensity as f64,
+            );
+        }
+    }
+
+    fn record(
+        &mut self,
+        key: PeptideKey,
+        peptidoform: String,
+        main_score: f64,
+        ms2_intensity: f64,
+    ) {
+        let entry = self.entries.entry(key).or_insert_with(|| PeptideRollupEntry {
+            best_main_score: f64::NEG_INFINITY,
+            ..Default::default()
+        });
+        if !entry.peptidoforms.contains(&peptidoform) {
+            entry.peptidoforms.push(peptidoform.clone());
+        }
+        entry.num_precursors += 1;
+        entry.summed_ms2_intensity += ms2_intensity;
+        if main_score > entry.best_main_score {
+            entry.best_main_score = main_score;
+            entry.best_peptidoform = peptidoform;
+        }
+    }
+
+    pub fn get(
+        &self,
+        peptide: &str,
+        decoy: &'static str,
+        channel: &'static str,
+    ) -> Option<&PeptideRollupEntry> {
+        self.entries.get(&(peptide.to_string(), decoy, channel))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn write_to_csv<P: AsRef<Path>>(
+        &self,
+        out_path: P,
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut writer = csv::Writer::from_path(out_path.as_ref())?;
+        writer.write_record([
+            "peptide",
+            "decoy",
+            "channel",
+            "num_peptidoforms",
+            "num_precursors",
+            "peptidoforms",
+            "best_peptidoform",
+            "best_main_score",
+            "summed_ms2_intensity",
+        ])?;
+        let mut keys: Vec<&PeptideKey> = self.entries.keys().collect();
+        keys.sort();
+        for key in keys {
+            let entry = &self.entries[key];
+            writer.write_record([
+                key.0.clone(),
+                key.1.to_string(),
+                key.2.to_string(),
+                entry.peptidoforms.len().to_string(),
+                entry.num_precursors.to_string(),
+                entry.peptidoforms.join(";"),
+                entry.best_peptidoform.clone(),
+                entry.best_main_score.to_string(),
+                entry.summed_ms2_intensity.to_string(),
+            ])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Summary of all the charge states of a peptidoform.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChargeStateRollupEntry {
+    /// Sorted, without duplicates.
+    pub charges: Vec<u8>,
+    pub num_precursors: usize,
+    pub best_charge: u8,
+    pub best_main_score: f64,
+    pub summed_ms1_intensity: f64,
+    pub summed_ms2_intensity: f64,
+}
+
+/// Peptidoform, decoy marking and channel of a PSM.
+type ChargeStateKey = (String, &'static str, &'static str);
+
+/// Collects one row per peptidoform (and channel) of a run, with the
+/// evidence of its charge states consolidated.
+///
+/// Targets and decoys are rolled up separately.
+#[derive(Debug, Default)]
+pub struct ChargeStateRollup {
+    entries: HashMap<ChargeStateKey, ChargeStateRollupEntry>,
+}
+
+impl ChargeStateRollup {
+    pub fn add(&mut self, results: &[IonSearchResults]) {
+        for result in results {
+            let key = (
+                result.sequence.peptidoform(),
+                result.decoy.as_str(),
+                result.channel.channel.as_str(),
+            );
+            self.record(
+                key,
+                result.precursor_data.charge,
+                result.score_data.main_score,
+                (
+                    result.score_data.ms1_scores.summed_intensity as f64,
+                    result.score_data.ms2_scores.summed_intensity as f64,
+                ),
+            );
+        }
+    }
+
+    fn record(
+        &mut self,
+        key: ChargeStateKey,
+        charge: u8,
+        main_score: f64,
+        (ms1_intensity, ms2_intensity): (f64, f64),
+    ) {
+        let entry = self.entries.entry(key).or_insert_with(|| ChargeStateRollupEntry {
+            best_main_score: f64::NEG_INFINITY,
+            ..Default::default()
+        });
+        if let Err(i) = entry.charges.binary_search(&charge) {
+            entry.charges.insert(i, charge);
+        }
+        entry.num_precursors += 1;
+        entry.summed_ms1_intensity += ms1_intensity;
+        entry.summed_ms2_intensity += ms2_intensity;
+        if main_score > entry.best_main_score {
+            entry.best_main_score = main_score;
+            entry.best_charge = charge;
+        }
+    }
+
+    pub fn get(
+        &self,
+        peptidoform: &str,
+        decoy: &'static str,
+        channel: &'static str,
+    ) -> Option<&ChargeStateRollupEntry> {
+        self.entries.get(&(peptidoform.to_string(), decoy, channel))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn write_to_csv<P: AsRef<Path>>(
+        &self,
+        out_path: P,
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut writer = csv::Writer::from_path(out_path.as_ref())?;
+        writer.write_record([
+            "modified_sequence",
+            "sequence",
+            "decoy",
+            "channel",
+            "charges",
+            "num_precursors",
+            "best_charge",
+            "best_main_score",
+            "summed_ms1_intensity",
+            "summed_ms2_intensity",
+        ])?;
+        let mut keys: Vec<&ChargeStateKey> = self.entries.keys().collect();
+        keys.sort();
+        for key in keys {
+            let entry = &self.entries[key];
+            let charges: Vec<String> = entry.charges.iter().map(|x| x.to_string()).collect();
+            writer.write_record([
+                key.0.clone(),
+                strip_modifications(&key.0),
+                key.1.to_string(),
+                key.2.to_string(),
+                charges.join(";"),
+                entry.num_precursors.to_string(),
+                entry.best_charge.to_string(),
+                entry.best_main_score.to_string(),
+                entry.summed_ms1_intensity.to_string(),
+                entry.summed_ms2_intensity.to_string(),
+            ])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peptide_rollup_channels() {
+        let key = |channel: &'static str| ("PEPMTIDEK".to_string(), "target", channel);
+        let mut rollup = PeptideRollup::default();
+        rollup.record(key("Light"), "PEPM[Oxidation]TIDEK".to_string(), 1.0, 100.0);
+        rollup.record(key("Light"), "PEPMTIDEK".to_string(), 4.0, 50.0);
+        rollup.record(key("Heavy"), "PEPMTIDEK".to_string(), 2.0, 10.0);
+        assert_eq!(rollup.len(), 2);
+
+        let light = rollup.get("PEPMTIDEK", "target", "Light").unwrap();
+        assert_eq!(light.peptidoforms.len(), 2);
+        assert_eq!(light.best_peptidoform, "PEPMTIDEK");
+        assert_eq!(light.summed_ms2_intensity, 150.0);
+        let heavy = rollup.get("PEPMTIDEK", "target", "Heavy").unwrap();
+        assert_eq!(heavy.num_precursors, 1);
+        assert_eq!(heavy.summed_ms2_intensity, 10.0);
+    }
+
+    #[test]
+    fn test_charge_state_rollup() {
+        let key = |x: &str| (x.to_string(), "target", "light");
+        let mut rollup = ChargeStateRollup::default();
+        rollup.record(key("PEPM[Oxidation]TIDEK"), 3, 1.0, (10.0, 100.0));
+        rollup.record(key("PEPM[Oxidation]TIDEK"), 2, 5.0, (20.0, 200.0));
+        rollup.record(key("PEPM[Oxidation]TIDEK"), 2, 2.0, (0.0, 50.0));
+        rollup.record(key("PEPMTIDEK"), 2, 3.0, (1.0, 1.0));
+        assert_eq!(rollup.len(), 2);
+
+        let entry = rollup.get("PEPM[Oxidation]TIDEK", "target", "light").unwrap();
+        assert_eq!(entry.charges, vec![2, 3]);
+        assert_eq!(entry.num_precursors, 3);
+        assert_eq!(entry.best_charge, 2);
+        assert_eq!(entry.best_main_score, 5.0);
+        assert_eq!(entry.summed_ms1_intensity, 30.0);
+        assert_eq!(entry.summed_ms2_intensity, 350.0);
+    }
+
+    #[test]
+    fn test_strip_modifications() {
+        assert_eq!(strip_modifications("PEPTIDE"), "PEPTIDE");
+        assert_eq!(strip_modifications("PEPM[+15.9949]TC[UNIMOD:4]IDE"), "PEPMTCIDE");
+        assert_eq!(strip_modifications("[Acetyl]-PEPS(Phospho)IDE/2"), "PEPSIDE");
+    }
+}
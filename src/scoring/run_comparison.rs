@@ -0,0 +1,425 @@
+use crate::errors::TimsSeekError;
+use crate::scoring::calibration::calibrate_runs;
+use crate::scoring::fdr_preview::FdrPreview;
+use serde::Serialize;
+use std::fmt::Write as _;
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+/// Headline numbers of a finished (or interrupted) run, to compare the runs
+/// of a parameter sweep.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RunSummary {
+    pub name: String,
+    pub directory: PathBuf,
+    pub num_results: usize,
+    pub num_targets: usize,
+    pub num_decoys: usize,
+    /// See [`crate::scoring::fdr_preview::FdrPreviewSummary`].
+    pub separation: f64,
+    pub ids_at_fdr: usize,
+    /// Summed chunk times of `metrics.tsv`.
+    pub runtime_seconds: f64,
+    /// From `run_manifest.json`, None if there is none.
+    pub complete: Option<bool>,
+}
+
+fn csv_error(path: &Path) -> impl Fn(csv::Error) -> TimsSeekError + '_ {
+    move |e| TimsSeekError::ParseError {
+        msg: format!("Error reading {}: {}", path.display(), e),
+    }
+}
+
+/// Result tables (`<prefix><n>.csv`) of a run directory, in chunk order.
+fn tables_with_prefix(directory: &Path, prefix: &str) -> Result<Vec<PathBuf>, TimsSeekError> {
+    let mut tables: Vec<(usize, PathBuf)> = Vec::new();
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        let chunk = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .and_then(|x| x.strip_prefix(prefix))
+            .and_then(|x| x.strip_suffix(".csv"))
+            .and_then(|x| x.parse::<usize>().ok());
+        if let Some(chunk) = chunk {
+            tables.push((chunk, path));
+        }
+    }
+    tables.sort();
+    Ok(tables.into_iter().map(|x| x.1).collect())
+}
+
+/// Result tables (`chunk_<n>.csv`) of a run directory, in chunk order.
+fn chunk_tables(directory: &Path) -> Result<Vec<PathBuf>, TimsSeekError> {
+    tables_with_prefix(directory, "chunk_")
+}
+
+/// Result tables of a run directory, targets and decoys (`decoy_chunk_<n>.csv`
+/// when they were written apart).
+fn result_tables(directory: &Path) -> Result<Vec<PathBuf>, TimsSeekError> {
+    let mut tables = chunk_tables(directory)?;
+    tables.extend(tables_with_prefix(directory, "decoy_chunk_")?);
+    Ok(tables)
+}
+
+/// Decoy score quantiles mapped to 0 and 1 by the calibration of the
+/// combined results, see [`crate::scoring::calibration::QuantileCalibration`].
+const CALIBRATION_QUANTILES: (f64, f64) = (0.5, 0.99);
+
+/// Position of the `name` column of `table`.
+fn column_index(
+    headers: &csv::StringRecord,
+    name: &str,
+    table: &Path,
+) -> Result<usize, TimsSeekError> {
+    headers
+        .iter()
+        .position(|x| x == name)
+        .ok_or_else(|| TimsSeekError::ParseError {
+            msg: format!("{} has no {} column", table.display(), name),
+        })
+}
+
+/// `(main_score, is_decoy)` of every result of a run directory, in the order
+/// of [`result_tables`].
+fn run_scores(directory: &Path) -> Result<Vec<(f64, bool)>, TimsSeekError> {
+    let mut scores = Vec::new();
+    for table in result_tables(directory)? {
+        let mut reader = csv::Reader::from_path(&table).map_err(csv_error(&table))?;
+        let headers = reader.headers().map_err(csv_error(&table))?.clone();
+        let score_column = column_index(&headers, "main_score", &table)?;
+        let decoy_column = column_index(&headers, "decoy", &table)?;
+        for record in reader.records() {
+            let record = record.map_err(csv_error(&table))?;
+            let score = record
+                .get(score_column)
+                .and_then(|x| x.parse::<f64>().ok())
+                .unwrap_or(f64::NAN);
+            scores.push((score, record.get(decoy_column) != Some("Target")));
+        }
+    }
+    Ok(scores)
+}
+
+/// Concatenates the result tables of several runs (eg. the gas-phase
+/// fractions of a chromatogram library) into `out`, with a leading `run`
+/// column. Returns the number of rows written.
+///
+/// The main scores are also calibrated on the decoys of their run, in a
+/// trailing `calibrated_main_score` column (empty for runs with too few
+/// decoys), so the runs can share a single FDR estimate.
+pub fn combine_runs(runs: &[(String, PathBuf)], out: &Path) -> Result<usize, TimsSeekError> {
+    let mut calibrated = runs
+        .iter()
+        .map(|(_, directory)| run_scores(directory))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (low_quantile, high_quantile) = CALIBRATION_QUANTILES;
+    let calibrations = calibrate_runs(&mut calibrated, low_quantile, high_quantile);
+
+    let mut writer = csv::Writer::from_path(out).map_err(csv_error(out))?;
+    let mut header: Option<csv::StringRecord> = None;
+    let mut num_rows = 0;
+    for (((name, directory), scores), calibration) in
+        runs.iter().zip(&calibrated).zip(&calibrations)
+    {
+        let mut scores = scores.iter();
+        for table in result_tables(directory)? {
+            let mut reader = csv::Reader::from_path(&table).map_err(csv_error(&table))?;
+            let headers = reader.headers().map_err(csv_error(&table))?.clone();
+            match &header {
+                Some(x) if *x != headers => {
+                    return Err(TimsSeekError::ParseError {
+                        msg: format!(
+                            "{} has different columns than the other runs",
+                            table.display()
+                        ),
+                    });
+                }
+                Some(_) => {}
+                None => {
+                    let mut record = csv::StringRecord::from(vec!["run"]);
+                    record.extend(headers.iter());
+                    record.push_field("calibrated_main_score");
+                    writer.write_record(&record).map_err(csv_error(out))?;
+                    header = Some(headers);
+                }
+            }
+            for record in reader.records() {
+                let record = record.map_err(csv_error(&table))?;
+                let mut row = csv::StringRecord::from(vec![name.as_str()]);
+                row.extend(record.iter());
+                let score = scores.next().map(|x| x.0);
+                match (calibration, score) {
+                    (Some(_), Some(x)) => row.push_field(&x.to_string()),
+                    _ => row.push_field(""),
+                }
+                writer.write_record(&row).map_err(csv_error(out))?;
+                num_rows += 1;
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(num_rows)
+}
+
+/// Summed `total_seconds` column of a `metrics.tsv`.
+fn total_runtime(path: &Path) -> Result<f64, TimsSeekError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_path(path)
+        .map_err(csv_error(path))?;
+    let headers = reader.headers().map_err(csv_error(path))?.clone();
+    let Some(column) = headers.iter().position(|x| x == "total_seconds") else {
+        return Ok(0.0);
+    };
+    let mut total = 0.0;
+    for record in reader.records() {
+        let record = record.map_err(csv_error(path))?;
+        total += record
+            .get(column)
+            .and_then(|x| x.parse::<f64>().ok())
+            .unwrap_or(0.0);
+    }
+    Ok(total)
+}
+
+/// Summarizes the outputs of a run directory, at the given FDR.
+pub fn summarize_run(directory: &Path, fdr: f64) -> Result<RunSummary, TimsSeekError> {
+    let mut preview = FdrPreview::default();
+    let mut num_results = 0;
+    for table in chunk_tables(directory)? {
+        let mut reader = csv::Reader::from_path(&table).map_err(csv_error(&table))?;
+        let headers = reader.headers().map_err(csv_error(&table))?.clone();
+        let score_column = column_index(&headers, "main_score", &table)?;
+        let decoy_column = column_index(&headers, "decoy", &table)?;
+        for record in reader.records() {
+            let record = record.map_err(csv_error(&table))?;
+            num_results += 1;
+            let score = record
+                .get(score_column)
+                .and_then(|x| x.parse::<f64>().ok())
+                .unwrap_or(f64::NAN);
+            preview.add_score(score, record.get(decoy_column) != Some("Target"));
+        }
+    }
+    let summary = preview.summary(fdr);
+
+    let metrics = directory.join("metrics.tsv");
+    let runtime_seconds = if metrics.exists() {
+        total_runtime(&metrics)?
+    } else {
+        0.0
+    };
+    let complete = std::fs::read_to_string(directory.join("run_manifest.json"))
+        .ok()
+        .and_then(|x| serde_json::from_str::<serde_json::Value>(&x).ok())
+        .and_then(|x| x.get("complete").and_then(|x| x.as_bool()));
+
+    Ok(RunSummary {
+        name: directory
+            .file_name()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_else(|| directory.display().to_string()),
+        directory: directory.to_path_buf(),
+        num_results,
+        num_targets: summary.map(|x| x.num_targets).unwrap_or_default(),
+        num_decoys: summary.map(|x| x.num_decoys).unwrap_or_default(),
+        separation: summary.map(|x| x.separation).unwrap_or_default(),
+        ids_at_fdr: summary.map(|x| x.ids_at_fdr).unwrap_or_default(),
+        runtime_seconds,
+        complete,
+    })
+}
+
+/// Horizontal SVG bar chart of one value per run.
+fn svg_bar_chart(title: &str, bars: &[(&str, f64)]) -> String {
+    const ROW_HEIGHT: usize = 22;
+    const LABEL_WIDTH: f64 = 220.0;
+    const BAR_WIDTH: f64 = 400.0;
+    let max = bars.iter().map(|x| x.1).fold(0.0f64, f64::max);
+    let height = ROW_HEIGHT * (bars.len() + 1);
+    let mut svg = format!(
+        "<svg width=\"{}\" height=\"{}\" xmlns=\"http://www.w3.org/2000/svg\">\n<text x=\"0\" y=\"15\" font-weight=\"bold\">{}</text>\n",
+        LABEL_WIDTH + BAR_WIDTH + 100.0,
+        height,
+        html_escape(title)
+    );
+    for (i, (label, value)) in bars.iter().enumerate() {
+        let y = ROW_HEIGHT * (i + 1);
+        let width = if max > 0.0 {
+            BAR_WIDTH * value.max(0.0) / max
+        } else {
+            0.0
+        };
+        let _ = writeln!(
+            svg,
+            "<text x=\"0\" y=\"{}\">{}</text><rect x=\"{}\" y=\"{}\" width=\"{:.1}\" height=\"16\" fill=\"steelblue\"/><text x=\"{:.1}\" y=\"{}\">{:.2}</text>",
+            y + 15,
+            html_escape(label),
+            LABEL_WIDTH,
+            y + 3,
+            width,
+            LABEL_WIDTH + width + 5.0,
+            y + 15,
+            value
+        );
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+fn html_escape(x: &str) -> String {
+    x.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Self-contained HTML page with a table and bar charts of the runs.
+pub fn comparison_html(runs: &[RunSummary], fdr: f64) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>timsseek run comparison</title>\n<style>table { border-collapse: collapse; } td, th { border: 1px solid #ccc; padding: 4px 8px; }</style>\n</head>\n<body>\n",
+    );
+    let _ = writeln!(html, "<h1>Run comparison ({} runs)</h1>", runs.len());
+    let _ = writeln!(
+        html,
+        "<table>\n<tr><th>run</th><th>results</th><th>targets</th><th>decoys</th><th>separation</th><th>IDs at {}% FDR</th><th>runtime (s)</th><th>complete</th></tr>",
+        fdr * 100.0
+    );
+    for run in runs {
+        let _ = writeln!(
+            html,
+            "<tr><td title=\"{}\">{}</td><td>{}</td><td>{}</td><td>{}</td><td>{:.3}</td><td>{}</td><td>{:.1}</td><td>{}</td></tr>",
+            html_escape(&run.directory.display().to_string()),
+            html_escape(&run.name),
+            run.num_results,
+            run.num_targets,
+            run.num_decoys,
+            run.separation,
+            run.ids_at_fdr,
+            run.runtime_seconds,
+            run.complete.map(|x| x.to_string()).unwrap_or_default(),
+        );
+    }
+    html.push_str("</table>\n");
+    let charts: [(&str, fn(&RunSummary) -> f64); 3] = [
+        ("IDs at FDR", |x| x.ids_at_fdr as f64),
+        ("Target/decoy separation", |x| x.separation),
+        ("Runtime (s)", |x| x.runtime_seconds),
+    ];
+    for (title, value) in charts {
+        let bars: Vec<(&str, f64)> =
+            runs.iter().map(|x| (x.name.as_str(), value(x))).collect();
+        html.push_str(&svg_bar_chart(title, &bars));
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// Writes `comparison.json` and `comparison.html` of the runs to `directory`.
+pub fn write_comparison(
+    runs: &[RunSummary],
+    fdr: f64,
+    directory: &Path,
+) -> Result<(), TimsSeekError> {
+    std::fs::create_dir_all(directory)?;
+    serde_json::to_writer_pretty(
+        std::fs::File::create(directory.join("comparison.json"))?,
+        runs,
+    )
+    .map_err(|e| TimsSeekError::Io(std::io::Error::other(e.to_string())))?;
+    std::fs::write(directory.join("comparison.html"), comparison_html(runs, fdr))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scoring::calibration::QuantileCalibration;
+
+    #[test]
+    fn test_summarize_run() {
+        let directory = std::env::temp_dir().join("timsseek_test_run_comparison");
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(
+            directory.join("chunk_0.csv"),
+            "sequence,decoy,main_score\nPEPTIDEK,Target,10.0\nKEDITPEP,Decoy,1.0\n",
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("chunk_1.csv"),
+            "sequence,decoy,main_score\nLESLIEK,Target,8.0\n",
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("metrics.tsv"),
+            "chunk\ttotal_seconds\n0\t1.5\n1\t2.5\n",
+        )
+        .unwrap();
+        std::fs::write(directory.join("run_manifest.json"), "{\"complete\": true}").unwrap();
+
+        let summary = summarize_run(&directory, 0.01).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(summary.name, "timsseek_test_run_comparison");
+        assert_eq!(summary.num_results, 3);
+        assert_eq!(summary.num_targets, 2);
+        assert_eq!(summary.num_decoys, 1);
+        assert_eq!(summary.ids_at_fdr, 2);
+        assert_eq!(summary.runtime_seconds, 4.0);
+        assert_eq!(summary.complete, Some(true));
+
+        let html = comparison_html(&[summary], 0.01);
+        assert!(html.contains(">timsseek_test_run_comparison</td>"));
+        assert_eq!(html.matches("<svg").count(), 3);
+    }
+
+    #[test]
+    fn test_combine_runs() {
+        let directory = std::env::temp_dir().join("timsseek_test_combine_runs");
+        let gpf_1 = directory.join("gpf_1");
+        let gpf_2 = directory.join("gpf_2");
+        std::fs::create_dir_all(&gpf_1).unwrap();
+        std::fs::create_dir_all(&gpf_2).unwrap();
+        std::fs::write(
+            gpf_1.join("chunk_0.csv"),
+            "sequence,decoy,main_score\nPEPTIDEK,Target,10.0\n",
+        )
+        .unwrap();
+        std::fs::write(
+            gpf_1.join("decoy_chunk_0.csv"),
+            "sequence,decoy,main_score\nKEDITPEP,Decoy,1.0\nKELSEL,Decoy,3.0\n",
+        )
+        .unwrap();
+        std::fs::write(
+            gpf_2.join("chunk_0.csv"),
+            "sequence,decoy,main_score\nLESLIEK,Target,8.0\n",
+        )
+        .unwrap();
+
+        let out = directory.join("combined_results.csv");
+        let runs = vec![("gpf_1".to_string(), gpf_1), ("gpf_2".to_string(), gpf_2)];
+        let num_rows = combine_runs(&runs, &out).unwrap();
+        let mut reader = csv::Reader::from_path(&out).unwrap();
+        let headers = reader.headers().unwrap().clone();
+        let rows: Vec<csv::StringRecord> = reader.records().map(|x| x.unwrap()).collect();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(num_rows, 4);
+        assert_eq!(
+            headers,
+            vec!["run", "sequence", "decoy", "main_score", "calibrated_main_score"]
+        );
+        let runs: Vec<&str> = rows.iter().map(|x| &x[0]).collect();
+        assert_eq!(runs, vec!["gpf_1", "gpf_1", "gpf_1", "gpf_2"]);
+        assert_eq!(&rows[0][1], "PEPTIDEK");
+
+        // Only the first run has enough decoys to be calibrated.
+        let (low_quantile, high_quantile) = CALIBRATION_QUANTILES;
+        let calibration = QuantileCalibration::fit(&[1.0, 3.0], low_quantile, high_quantile);
+        let expected = calibration.unwrap().apply(10.0);
+        let calibrated: f64 = rows[0][4].parse().unwrap();
+        assert!((calibrated - expected).abs() < 1e-9);
+        assert_eq!(&rows[3][4], "");
+    }
+}
@@ -0,0 +1,189 @@
+use crate::errors::TimsSeekError;
+use crate::fragment_mass::fragment_mass_builder::SafePosition;
+use crate::isotopes::num_isotopes_outside_range;
+use crate::scoring::coelution::{
+    ms2_fragment_traces,
+    CoelutionScorer,
+};
+use crate::scoring::noise::NoiseModel;
+use timsquery::models::aggregators::raw_peak_agg::multi_chromatogram_agg::multi_chromatogram_agg::{NaturalFinalizedMultiCMGStatsArrays, ApexScores};
+use timsquery::ElutionGroup;
+
+/// Experimental score computed for every PSM.
+///
+/// Each scorer declares the columns it produces and returns one value per
+/// column, those are appended to the output after the built-in scores. This
+/// way new scores can be prototyped without touching `IonSearchResults`.
+pub trait PsmScorer: Send + Sync + std::fmt::Debug {
+    fn column_names(&self) -> &'static [&'static str];
+
+    fn score(
+        &self,
+        arrays: &NaturalFinalizedMultiCMGStatsArrays<SafePosition>,
+        elution_group: &ElutionGroup<SafePosition>,
+    ) -> Vec<f64>;
+
+    /// Adjusts the built-in scores before they are reported, eg. to take them
+    /// at a different apex. Most scorers leave them as they are.
+    fn adjust_apex_scores(
+        &self,
+        _scores: &mut ApexScores,
+        _arrays: &NaturalFinalizedMultiCMGStatsArrays<SafePosition>,
+    ) {
+    }
+}
+
+/// Number of fragments queried and how many of them carry most of the
+/// expected intensity.
+#[derive(Debug, Default)]
+pub struct FragmentCoverageScorer;
+
+impl PsmScorer for FragmentCoverageScorer {
+    fn column_names(&self) -> &'static [&'static str] {
+        &["num_query_fragments", "num_major_query_fragments"]
+    }
+
+    fn score(
+        &self,
+        _arrays: &NaturalFinalizedMultiCMGStatsArrays<SafePosition>,
+        elution_group: &ElutionGroup<SafePosition>,
+    ) -> Vec<f64> {
+        let num_fragments = elution_group.fragment_mzs.len();
+        let num_major = match &elution_group.expected_fragment_intensity {
+            Some(intensities) => {
+                let max_inten = intensities.values().cloned().fold(0.0f32, f32::max);
+                intensities
+                    .values()
+                    .filter(|x| **x >= 0.5 * max_inten)
+                    .count()
+            }
+            None => num_fragments,
+        };
+        vec![num_fragments as f64, num_major as f64]
+    }
+}
+
+/// Summed MS2 fragment intensity after removing the noise baseline of every
+/// trace, comparable between instruments with different noise levels.
+#[derive(Debug, Default)]
+pub struct DenoisedIntensityScorer {
+    pub noise: NoiseModel,
+}
+
+impl PsmScorer for DenoisedIntensityScorer {
+    fn column_names(&self) -> &'static [&'static str] {
+        &["denoised_summed_intensity"]
+    }
+
+    fn score(
+        &self,
+        arrays: &NaturalFinalizedMultiCMGStatsArrays<SafePosition>,
+        _elution_group: &ElutionGroup<SafePosition>,
+    ) -> Vec<f64> {
+        let total = ms2_fragment_traces(arrays)
+            .into_iter()
+            .map(|(_, mut trace)| {
+                self.noise.apply(&mut trace);
+                trace.into_iter().sum::<f64>()
+            })
+            .sum();
+        vec![total]
+    }
+}
+
+/// Immonium and PTM-diagnostic ions (see
+/// [`crate::fragment_mass::fragment_mass_builder::DiagnosticIons`]) queried
+/// and observed, and their summed intensity after removing the noise
+/// baseline.
+#[derive(Debug, Default)]
+pub struct DiagnosticIonScorer {
+    pub noise: NoiseModel,
+}
+
+impl PsmScorer for DiagnosticIonScorer {
+    fn column_names(&self) -> &'static [&'static str] {
+        &[
+            "num_diagnostic_ions",
+            "num_observed_diagnostic_ions",
+            "diagnostic_ion_intensity",
+        ]
+    }
+
+    fn score(
+        &self,
+        arrays: &NaturalFinalizedMultiCMGStatsArrays<SafePosition>,
+        elution_group: &ElutionGroup<SafePosition>,
+    ) -> Vec<f64> {
+        let num_queried = elution_group
+            .fragment_mzs
+            .keys()
+            .filter(|x| x.is_diagnostic())
+            .count();
+        let intensities: Vec<f64> = ms2_fragment_traces(arrays)
+            .into_iter()
+            .filter(|(pos, _)| pos.is_diagnostic())
+            .map(|(_, mut trace)| {
+                self.noise.apply(&mut trace);
+                trace.into_iter().sum::<f64>()
+            })
+            .collect();
+        let num_observed = intensities.iter().filter(|x| **x > 0.0).count();
+        vec![
+            num_queried as f64,
+            num_observed as f64,
+            intensities.into_iter().sum(),
+        ]
+    }
+}
+
+/// Number of precursor isotope peaks of the query outside of the MS1 scan
+/// range, their MS1 features are skewed.
+#[derive(Debug)]
+pub struct Ms1ScanRangeScorer {
+    pub scan_range: (f64, f64),
+}
+
+impl PsmScorer for Ms1ScanRangeScorer {
+    fn column_names(&self) -> &'static [&'static str] {
+        &["num_isotopes_outside_ms1_range"]
+    }
+
+    fn score(
+        &self,
+        _arrays: &NaturalFinalizedMultiCMGStatsArrays<SafePosition>,
+        elution_group: &ElutionGroup<SafePosition>,
+    ) -> Vec<f64> {
+        vec![num_isotopes_outside_range(&elution_group.precursor_mzs, self.scan_range) as f64]
+    }
+}
+
+/// Builds the scorers requested by name (eg. in the config file).
+///
+/// Scorers that look at raw traces remove the `noise` baseline first.
+pub fn scorers_from_names(
+    names: &[String],
+    noise: &NoiseModel,
+) -> Result<Vec<Box<dyn PsmScorer>>, TimsSeekError> {
+    names
+        .iter()
+        .map(|name| match name.as_str() {
+            "fragment_coverage" => Ok(Box::new(FragmentCoverageScorer) as Box<dyn PsmScorer>),
+            "coelution" => Ok(Box::new(CoelutionScorer {
+                noise: noise.clone(),
+                ..Default::default()
+            }) as Box<dyn PsmScorer>),
+            "denoised_intensity" => Ok(Box::new(DenoisedIntensityScorer {
+                noise: noise.clone(),
+            }) as Box<dyn PsmScorer>),
+            "diagnostic_ions" => Ok(Box::new(DiagnosticIonScorer {
+                noise: noise.clone(),
+            }) as Box<dyn PsmScorer>),
+            _ => Err(TimsSeekError::ParseError {
+                msg: format!(
+                    "Unknown scorer: {}, known scorers: [fragment_coverage, coelution, denoised_intensity, diagnostic_ions]",
+                    name
+                ),
+            }),
+        })
+        .collect()
+}
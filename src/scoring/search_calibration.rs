@@ -0,0 +1,477 @@
+use crate::data_sources::speclib::DECOY_FRAGMENT_MZ_SHIFT;
+use crate::fragment_mass::fragment_mass_builder::SafePosition;
+use crate::models::{
+    DecoyMarking,
+    NamedQueryChunk,
+};
+use crate::rt_prediction::fit_line;
+use crate::scoring::calibration::{
+    median,
+    quantile,
+};
+use crate::scoring::fdr_preview::FdrPreview;
+use crate::scoring::search_results::IonSearchResults;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use timsquery::traits::tolerance::{
+    DefaultTolerance,
+    MobilityTolerance,
+    RtTolerance,
+};
+use timsquery::ElutionGroup;
+
+/// Narrowest half width of the calibrated 1/K0 window, so a pass with
+/// (almost) no 1/K0 scatter does not shut the window.
+pub const MIN_MOBILITY_TOLERANCE: f64 = 0.01;
+
+/// First pass of a two-pass search: the first chunks are searched with the
+/// configured tolerance, the confident targets give the RT and 1/K0
+/// calibration, then the whole search runs calibrated with narrowed
+/// tolerances.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CalibrationPassConfig {
+    /// Chunks searched in the first pass, only their targets are used
+    /// (against fragment m/z shifted decoys).
+    pub num_chunks: usize,
+    /// FDR of the targets the calibration is fitted on.
+    pub fdr: f64,
+    /// The search is not calibrated with fewer confident targets.
+    pub min_points: usize,
+    /// Narrowed tolerances, in robust standard deviations of the calibrated
+    /// errors.
+    pub num_sds: f64,
+    /// Also correct the precursor and fragment m/z of the queries by the
+    /// median MS1 and MS2 ppm errors, which centers the m/z tolerance on the
+    /// errors of the run.
+    pub recalibrate_mz: bool,
+}
+
+impl Default for CalibrationPassConfig {
+    fn default() -> Self {
+        Self {
+            num_chunks: 2,
+            fdr: 0.01,
+            min_points: 50,
+            num_sds: 4.0,
+            recalibrate_mz: true,
+        }
+    }
+}
+
+/// Targets of a chunk followed by a fragment m/z shifted decoy of each, so
+/// the first pass has decoys to set its FDR threshold whatever the order of
+/// the decoys of the input.
+pub fn with_shifted_decoys(chunk: NamedQueryChunk) -> NamedQueryChunk {
+    let chunk = chunk.retain(|digest, _| digest.decoy == DecoyMarking::Target);
+    let mut digests = chunk.digests().to_vec();
+    let mut charges = chunk.charges().to_vec();
+    let mut rt_predicted = chunk.rt_predicted().to_vec();
+    let mut queries = chunk.queries;
+    for i in 0..queries.len() {
+        let mut decoy = queries[i].clone();
+        decoy
+            .fragment_mzs
+            .values_mut()
+            .for_each(|mz| *mz += DECOY_FRAGMENT_MZ_SHIFT);
+        queries.push(decoy);
+        digests.push(digests[i].with_decoy(DecoyMarking::ReversedDecoy));
+        charges.push(charges[i]);
+        rt_predicted.push(rt_predicted[i]);
+    }
+    NamedQueryChunk::new(digests, charges, queries).with_predicted_rts(rt_predicted)
+}
+
+/// Query and observed RT and 1/K0 of a first pass identification.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CalibrationPoint {
+    pub query_rt_seconds: f64,
+    pub observed_rt_seconds: f64,
+    pub query_mobility: f64,
+    /// Query 1/K0 plus the median 1/K0 error of the fragments, None if no
+    /// fragment has one.
+    pub observed_mobility: Option<f64>,
+    pub ms1_ppm_error: Option<f64>,
+    pub ms2_ppm_error: Option<f64>,
+}
+
+/// Standard deviation estimated from the median absolute deviation, so the
+/// false identifications left at the FDR do not widen it.
+fn robust_sd(residuals: &[f64]) -> Option<f64> {
+    let mut deviations: Vec<f64> = residuals.iter().map(|x| x.abs()).collect();
+    median(&mut deviations).map(|x| 1.4826 * x)
+}
+
+/// Median 1/K0 error (observed minus query) of the fragments of a result,
+/// None if no fragment has one.
+pub fn precursor_mobility_error(result: &IonSearchResults) -> Option<f64> {
+    let mut mobility_errors: Vec<f64> = result
+        .score_data
+        .ms2_scores
+        .mobility_errors
+        .iter()
+        .map(|x| *x as f64)
+        .filter(|x| x.is_finite())
+        .collect();
+    median(&mut mobility_errors)
+}
+
+/// Median m/z error (observed minus query, in ppm) of the precursor isotopes
+/// of a result.
+pub fn precursor_ppm_error(result: &IonSearchResults) -> Option<f64> {
+    let mut mz_errors: Vec<f64> = result
+        .score_data
+        .ms1_scores
+        .mz_errors
+        .iter()
+        .map(|x| *x as f64)
+        .filter(|x| x.is_finite())
+        .collect();
+    let error = median(&mut mz_errors)? * 1e6 / result.precursor_data.monoisotopic_mz;
+    error.is_finite().then_some(error)
+}
+
+/// Median m/z error (observed minus query, in ppm) of the fragments of a
+/// result.
+///
+/// The errors are not matched to their fragments, the median error is taken
+/// relative to the median fragment m/z, which is exact for a constant ppm
+/// error.
+pub fn fragment_ppm_error(result: &IonSearchResults) -> Option<f64> {
+    let mut mz_errors: Vec<f64> = result
+        .score_data
+        .ms2_scores
+        .mz_errors
+        .iter()
+        .map(|x| *x as f64)
+        .filter(|x| x.is_finite())
+        .collect();
+    let error = median(&mut mz_errors)? * 1e6 / result.median_fragment_mz;
+    error.is_finite().then_some(error)
+}
+
+/// Quantiles of the m/z errors (ppm) of confident targets.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ErrorDistribution {
+    pub num_points: usize,
+    pub q05: f64,
+    pub q25: f64,
+    pub median: f64,
+    pub q75: f64,
+    pub q95: f64,
+}
+
+impl ErrorDistribution {
+    /// None without values.
+    pub fn new(values: impl IntoIterator<Item = f64>) -> Option<Self> {
+        let mut sorted: Vec<f64> = values.into_iter().filter(|x| x.is_finite()).collect();
+        if sorted.is_empty() {
+            return None;
+        }
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        Some(Self {
+            num_points: sorted.len(),
+            q05: quantile(&sorted, 0.05),
+            q25: quantile(&sorted, 0.25),
+            median: quantile(&sorted, 0.5),
+            q75: quantile(&sorted, 0.75),
+            q95: quantile(&sorted, 0.95),
+        })
+    }
+}
+
+/// MS1 and MS2 m/z errors of confident targets.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct MassErrors {
+    pub ms1_ppm: Option<ErrorDistribution>,
+    pub ms2_ppm: Option<ErrorDistribution>,
+}
+
+impl MassErrors {
+    pub fn new(ms1_ppm: &[f64], ms2_ppm: &[f64]) -> Self {
+        Self {
+            ms1_ppm: ErrorDistribution::new(ms1_ppm.iter().copied()),
+            ms2_ppm: ErrorDistribution::new(ms2_ppm.iter().copied()),
+        }
+    }
+}
+
+/// m/z errors of the targets of a search, to report the ones of the
+/// confident targets once the search is done.
+#[derive(Debug, Default)]
+pub struct MassErrorCollector {
+    preview: FdrPreview,
+    /// Main score and MS1 and MS2 ppm errors of every target.
+    targets: Vec<(f64, Option<f64>, Option<f64>)>,
+}
+
+impl MassErrorCollector {
+    pub fn add(&mut self, results: &[IonSearchResults]) {
+        self.preview.add(results);
+        self.targets.extend(
+            results
+                .iter()
+                .filter(|x| x.decoy == DecoyMarking::Target)
+                .map(|x| {
+                    (
+                        x.score_data.main_score,
+                        precursor_ppm_error(x),
+                        fragment_ppm_error(x),
+                    )
+                }),
+        );
+    }
+
+    /// Errors of the targets passing `fdr`, None until there are both
+    /// targets and decoys.
+    pub fn mass_errors(&self, fdr: f64) -> Option<MassErrors> {
+        let threshold = self.preview.score_threshold(fdr)?;
+        let confident = self.targets.iter().filter(|x| x.0 >= threshold);
+        let ms1: Vec<f64> = confident.clone().filter_map(|x| x.1).collect();
+        let ms2: Vec<f64> = confident.filter_map(|x| x.2).collect();
+        Some(MassErrors::new(&ms1, &ms2))
+    }
+}
+
+/// Points of the targets passing `fdr` (target-decoy competition on the main
+/// score) among the first pass results.
+pub fn confident_points(results: &[IonSearchResults], fdr: f64) -> Vec<CalibrationPoint> {
+    let mut preview = FdrPreview::default();
+    preview.add(results);
+    let Some(threshold) = preview.score_threshold(fdr) else {
+        return Vec::new();
+    };
+    results
+        .iter()
+        .filter(|x| x.decoy == DecoyMarking::Target && x.score_data.main_score >= threshold)
+        .map(|x| {
+            let query_mobility = x.precursor_data.mobility as f64;
+            CalibrationPoint {
+                query_rt_seconds: x.precursor_data.rt as f64,
+                observed_rt_seconds: x.score_data.ms2_scores.retention_time_miliseconds as f64
+                    / 1000.0,
+                query_mobility,
+                observed_mobility: precursor_mobility_error(x).map(|e| query_mobility + e),
+                ms1_ppm_error: precursor_ppm_error(x),
+                ms2_ppm_error: fragment_ppm_error(x),
+            }
+        })
+        .collect()
+}
+
+/// RT and 1/K0 calibration fitted on the first pass, with the tolerances the
+/// calibrated search uses.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SearchCalibration {
+    /// `(slope, intercept)` from query to observed RT, None if the queries
+    /// have no RT (eg. no RT prediction), then the RT tolerance is kept.
+    pub rt_line: Option<(f64, f64)>,
+    /// Half width of the calibrated RT window.
+    pub rt_tolerance_seconds: Option<f64>,
+    /// Median observed minus query 1/K0.
+    pub mobility_offset: f64,
+    /// Half width of the calibrated 1/K0 window.
+    pub mobility_tolerance: f64,
+    /// Median MS1 and MS2 ppm errors the query m/z are corrected by, None
+    /// if the m/z are not recalibrated.
+    pub ms1_ppm_offset: Option<f64>,
+    pub ms2_ppm_offset: Option<f64>,
+    /// Errors of the calibration targets, before the correction.
+    pub mass_errors: MassErrors,
+    pub num_points: usize,
+}
+
+impl SearchCalibration {
+    /// None with fewer than `min_points` points (with an observed 1/K0).
+    pub fn fit(points: &[CalibrationPoint], config: &CalibrationPassConfig) -> Option<Self> {
+        let mobility_points: Vec<(f64, f64)> = points
+            .iter()
+            .filter_map(|x| x.observed_mobility.map(|m| (x.query_mobility, m)))
+            .collect();
+        if mobility_points.len() < config.min_points.max(2) {
+            return None;
+        }
+        let mut offsets: Vec<f64> = mobility_points.iter().map(|(q, m)| m - q).collect();
+        let mobility_offset = median(&mut offsets)?;
+        let residuals: Vec<f64> = offsets.iter().map(|x| x - mobility_offset).collect();
+        let mobility_tolerance = config.num_sds * robust_sd(&residuals)?;
+
+        let rt_points: Vec<(f64, f64)> = points
+            .iter()
+            .map(|x| (x.query_rt_seconds, x.observed_rt_seconds))
+            .collect();
+        let rt_line = fit_line(&rt_points).ok();
+        let rt_tolerance_seconds = rt_line.and_then(|(slope, intercept)| {
+            let residuals: Vec<f64> = rt_points
+                .iter()
+                .map(|(query, observed)| observed - (intercept + slope * query))
+                .collect();
+            robust_sd(&residuals).map(|x| config.num_sds * x)
+        });
+
+        let mut ms1_errors: Vec<f64> = points.iter().filter_map(|x| x.ms1_ppm_error).collect();
+        let mut ms2_errors: Vec<f64> = points.iter().filter_map(|x| x.ms2_ppm_error).collect();
+        let mass_errors = MassErrors::new(&ms1_errors, &ms2_errors);
+        let (ms1_ppm_offset, ms2_ppm_offset) = if config.recalibrate_mz {
+            (median(&mut ms1_errors), median(&mut ms2_errors))
+        } else {
+            (None, None)
+        };
+        Some(Self {
+            rt_line,
+            rt_tolerance_seconds,
+            mobility_offset,
+            mobility_tolerance,
+            ms1_ppm_offset,
+            ms2_ppm_offset,
+            mass_errors,
+            num_points: points.len(),
+        })
+    }
+
+    /// Moves the RT, 1/K0 and m/z of a query to where the run has them.
+    pub fn apply(&self, elution_group: &mut ElutionGroup<SafePosition>) {
+        if let Some((slope, intercept)) = self.rt_line {
+            let rt = intercept + slope * elution_group.rt_seconds as f64;
+            elution_group.rt_seconds = rt as f32;
+        }
+        elution_group.mobility += self.mobility_offset as f32;
+        if let Some(ppm) = self.ms1_ppm_offset {
+            elution_group
+                .precursor_mzs
+                .iter_mut()
+                .for_each(|mz| *mz *= 1.0 + ppm * 1e-6);
+        }
+        if let Some(ppm) = self.ms2_ppm_offset {
+            elution_group
+                .fragment_mzs
+                .values_mut()
+                .for_each(|mz| *mz *= 1.0 + ppm * 1e-6);
+        }
+    }
+
+    /// `tolerance` with the calibrated RT (if any) and 1/K0 windows, the 1/K0
+    /// one at least [`MIN_MOBILITY_TOLERANCE`] wide.
+    pub fn narrowed_tolerance(&self, tolerance: &DefaultTolerance) -> DefaultTolerance {
+        let mut tolerance = tolerance.clone();
+        if let Some(window) = self.rt_tolerance_seconds {
+            let window = window as f32;
+            tolerance.rt = RtTolerance::Absolute((window, window));
+        }
+        let window = self.mobility_tolerance.max(MIN_MOBILITY_TOLERANCE) as f32;
+        tolerance.mobility = MobilityTolerance::Absolute((window, window));
+        tolerance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use timsquery::traits::tolerance::{
+        MzToleramce,
+        QuadTolerance,
+    };
+
+    #[test]
+    fn test_search_calibration() {
+        // Observed RT = 30 s + 1.1 x query, with +-2 s of scatter, and 1/K0
+        // 0.02 above the query, with +-0.005 of scatter.
+        let points: Vec<CalibrationPoint> = (0..100)
+            .map(|i| {
+                let query_rt_seconds = 60.0 * i as f64;
+                let scatter = if i % 2 == 0 { 1.0 } else { -1.0 };
+                CalibrationPoint {
+                    query_rt_seconds,
+                    observed_rt_seconds: 30.0 + 1.1 * query_rt_seconds + 2.0 * scatter,
+                    query_mobility: 0.9,
+                    observed_mobility: Some(0.92 + 0.005 * scatter),
+                    ms1_ppm_error: Some(3.0 + scatter),
+                    ms2_ppm_error: Some(-2.0),
+                }
+            })
+            .collect();
+        let config = CalibrationPassConfig::default();
+        let calibration = SearchCalibration::fit(&points, &config).unwrap();
+        let (slope, intercept) = calibration.rt_line.unwrap();
+        assert!((slope - 1.1).abs() < 1e-3, "{}", slope);
+        assert!((intercept - 30.0).abs() < 1.0, "{}", intercept);
+        let rt_tolerance = calibration.rt_tolerance_seconds.unwrap();
+        assert!(rt_tolerance > 8.0 && rt_tolerance < 16.0, "{}", rt_tolerance);
+        assert!((calibration.mobility_offset - 0.02).abs() < 1e-9);
+        assert!(calibration.mobility_tolerance < 0.1);
+        assert_eq!(calibration.ms1_ppm_offset, Some(3.0));
+        assert_eq!(calibration.ms2_ppm_offset, Some(-2.0));
+        let ms1 = calibration.mass_errors.ms1_ppm.unwrap();
+        assert_eq!((ms1.num_points, ms1.q05, ms1.q95), (100, 2.0, 4.0));
+
+        let mut eg = ElutionGroup {
+            id: 0,
+            precursor_mzs: vec![500.0],
+            mobility: 0.9,
+            rt_seconds: 100.0,
+            fragment_mzs: HashMap::from([(SafePosition::from_str("y3").unwrap(), 1000.0)]),
+            expected_fragment_intensity: None,
+            expected_precursor_intensity: None,
+        };
+        calibration.apply(&mut eg);
+        assert!((eg.precursor_mzs[0] - 500.0015).abs() < 1e-9);
+        assert!((eg.fragment_mzs.values().next().unwrap() - 999.998).abs() < 1e-9);
+        let uncorrected = SearchCalibration::fit(
+            &points,
+            &CalibrationPassConfig {
+                recalibrate_mz: false,
+                ..config.clone()
+            },
+        )
+        .unwrap();
+        assert!(uncorrected.ms1_ppm_offset.is_none());
+        assert!(uncorrected.mass_errors.ms2_ppm.is_some());
+
+        // Queries without RT only get the 1/K0 calibration.
+        let no_rt: Vec<CalibrationPoint> = points
+            .iter()
+            .map(|x| CalibrationPoint {
+                query_rt_seconds: 0.0,
+                ..*x
+            })
+            .collect();
+        let calibration = SearchCalibration::fit(&no_rt, &config).unwrap();
+        assert!(calibration.rt_line.is_none());
+        assert!(calibration.rt_tolerance_seconds.is_none());
+
+        assert!(SearchCalibration::fit(&points[..10], &config).is_none());
+    }
+
+    #[test]
+    fn test_narrowed_mobility_tolerance() {
+        // Without 1/K0 scatter the window is kept open.
+        let points: Vec<CalibrationPoint> = (0..100)
+            .map(|i| CalibrationPoint {
+                query_rt_seconds: 60.0 * i as f64,
+                observed_rt_seconds: 60.0 * i as f64,
+                query_mobility: 0.9,
+                observed_mobility: Some(0.92),
+                ms1_ppm_error: None,
+                ms2_ppm_error: None,
+            })
+            .collect();
+        let calibration =
+            SearchCalibration::fit(&points, &CalibrationPassConfig::default()).unwrap();
+        assert_eq!(calibration.mobility_tolerance, 0.0);
+        let tolerance = DefaultTolerance {
+            ms: MzToleramce::Ppm((10.0, 10.0)),
+            rt: RtTolerance::None,
+            mobility: MobilityTolerance::Pct((3.0, 3.0)),
+            quad: QuadTolerance::Absolute((0.1, 0.1, 1)),
+        };
+        let narrowed = calibration.narrowed_tolerance(&tolerance);
+        let min = MIN_MOBILITY_TOLERANCE as f32;
+        assert!(matches!(
+            narrowed.mobility,
+            MobilityTolerance::Absolute((low, high)) if low == min && high == min
+        ));
+    }
+}
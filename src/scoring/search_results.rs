@@ -1,18 +1,33 @@
 use serde::Serialize;
+use crate::digest::prioritization::default_detectability_score;
 use crate::errors::TimsSeekError;
 use crate::models::DigestSlice;
 use crate::fragment_mass::fragment_mass_builder::SafePosition;
+use crate::isotopes::monoisotopic_precursor_mz;
 use timsquery::models::aggregators::raw_peak_agg::multi_chromatogram_agg::multi_chromatogram_agg::{NaturalFinalizedMultiCMGStatsArrays, ApexScores};
 use timsquery::ElutionGroup;
 use std::path::Path;
 use csv::Writer;
 use std::time::Instant;
-use crate::models::DecoyMarking;
+use crate::scoring::localization::{
+    fragment_evidence,
+    FragmentEvidence,
+};
+use crate::scoring::peptide_features::PeptideFeatures;
+use crate::scoring::scorers::PsmScorer;
+use crate::models::{
+    ChannelLabel,
+    DecoyMarking,
+    LabelChannel,
+};
+use std::collections::HashMap;
 
 #[derive(Debug, Serialize, Clone)]
 pub struct PrecursorData {
     pub charge: u8,
+    /// First precursor m/z queried, the -1 isotope peak unless it is skipped.
     pub mz: f64,
+    pub monoisotopic_mz: f64,
     pub mobility: f32,
     pub rt: f32,
 }
@@ -22,7 +37,37 @@ pub struct IonSearchResults {
     pub sequence: DigestSlice,
     pub score_data: ApexScores,
     pub precursor_data: PrecursorData,
+    pub peptide_features: PeptideFeatures,
+    /// Prior of the peptide being observed, see
+    /// [`crate::digest::prioritization::detectability_score`].
+    pub detectability: f64,
     pub decoy: DecoyMarking,
+    pub channel: ChannelLabel,
+    pub heavy_light_ratio: Option<f64>,
+    /// Apex intensities of the light and heavy channels of the pair.
+    pub light_intensity: Option<f64>,
+    pub heavy_intensity: Option<f64>,
+    pub ratio_quality: Option<RatioQuality>,
+    pub extra_scores: Vec<(&'static str, f64)>,
+    pub xic_profiles: Option<serde_json::Value>,
+    pub score_traces: Option<serde_json::Value>,
+    /// Query, tolerance and per-frame matched peaks, only for the precursors
+    /// selected for diagnostics.
+    pub diagnostics: Option<serde_json::Value>,
+    /// `;` separated descriptions of the proteins the peptide comes from.
+    pub protein_names: String,
+    /// Whether any of the proteins is a contaminant of the database.
+    pub is_contaminant: bool,
+    /// Queried fragments and their intensities, kept for modified
+    /// peptidoforms until their localization is assigned.
+    #[serde(skip)]
+    pub fragment_evidence: Option<Vec<FragmentEvidence>>,
+    /// Probability that the modifications are on the residues of this
+    /// peptidoform rather than of one of its isomers.
+    pub localization_probability: Option<f64>,
+    /// Median m/z of the queried fragments, to express their m/z errors in
+    /// ppm.
+    pub median_fragment_mz: f64,
 }
 
 impl IonSearchResults {
@@ -32,28 +77,79 @@ impl IonSearchResults {
         elution_group: &ElutionGroup<SafePosition>,
         finalized_scores: NaturalFinalizedMultiCMGStatsArrays<SafePosition>,
         decoy: DecoyMarking,
+        channel: ChannelLabel,
+        scorers: &[Box<dyn PsmScorer>],
     ) -> Result<Self, TimsSeekError> {
+        let extra_scores = scorers
+            .iter()
+            .flat_map(|scorer| {
+                let values = scorer.score(&finalized_scores, elution_group);
+                debug_assert_eq!(values.len(), scorer.column_names().len());
+                scorer.column_names().iter().copied().zip(values)
+            })
+            .collect();
+        let fragment_evidence = digest_sequence
+            .peptidoform
+            .is_some()
+            .then(|| fragment_evidence(&finalized_scores, elution_group));
         // let score_data = ScoreData::new(finalized_scores, elution_group);
-        let score_data = finalized_scores.finalized_score()?;
+        let mut score_data = finalized_scores.finalized_score()?;
+        for scorer in scorers {
+            scorer.adjust_apex_scores(&mut score_data, &finalized_scores);
+        }
         let precursor_data = PrecursorData {
             charge,
-            mz: elution_group.precursor_mzs[0],
+            mz: elution_group.precursor_mzs.first().copied().unwrap_or_default(),
+            monoisotopic_mz: monoisotopic_precursor_mz(
+                &elution_group.precursor_mzs,
+                elution_group.expected_precursor_intensity.as_deref(),
+            ),
             mobility: elution_group.mobility,
             rt: elution_group.rt_seconds,
         };
+        let sequence: String = digest_sequence.clone().into();
+        let mut peptide_features = PeptideFeatures::new(&sequence, charge);
+        // The count from digestion follows the actual enzyme(s), the one from
+        // the sequence assumes trypsin.
+        if let Some(missed_cleavages) = digest_sequence.missed_cleavages {
+            peptide_features.missed_cleavages = missed_cleavages;
+        }
+        let detectability = default_detectability_score(&digest_sequence);
+        let mut fragment_mzs: Vec<f64> = elution_group.fragment_mzs.values().copied().collect();
+        fragment_mzs.sort_by(|a, b| a.total_cmp(b));
+        let median_fragment_mz = fragment_mzs
+            .get(fragment_mzs.len() / 2)
+            .copied()
+            .unwrap_or(f64::NAN);
 
         Ok(Self {
             sequence: digest_sequence,
             score_data,
             precursor_data,
+            peptide_features,
+            detectability,
             decoy,
+            channel,
+            heavy_light_ratio: None,
+            light_intensity: None,
+            heavy_intensity: None,
+            ratio_quality: None,
+            extra_scores,
+            xic_profiles: None,
+            score_traces: None,
+            diagnostics: None,
+            protein_names: String::new(),
+            is_contaminant: false,
+            fragment_evidence,
+            localization_probability: None,
+            median_fragment_mz,
         })
     }
 
-    pub fn get_csv_labels() -> [&'static str; 22] {
+    pub fn get_csv_labels() -> [&'static str; 44] {
         let out = {
-            let mut whole: [&'static str; 22] = [""; 22];
-            let (id_sec, score_sec) = whole.split_at_mut(6);
+            let mut whole: [&'static str; 44] = [""; 44];
+            let (id_sec, score_sec) = whole.split_at_mut(28);
             id_sec.copy_from_slice(&Self::get_info_labels());
             score_sec.copy_from_slice(&Self::get_scoring_labels());
             whole
@@ -61,8 +157,8 @@ impl IonSearchResults {
         out
     }
 
-    pub fn as_csv_record(&self) -> [String; 22] {
-        let mut out: [String; 22] = core::array::from_fn(|_| "".to_string());
+    pub fn as_csv_record(&self) -> [String; 44] {
+        let mut out: [String; 44] = core::array::from_fn(|_| "".to_string());
         let lab_sec = self.get_csv_record_lab_sec();
         let mut offset = 0;
         for x in lab_sec.into_iter() {
@@ -82,29 +178,96 @@ impl IonSearchResults {
             offset += 1;
         }
 
-        assert!(offset == 22);
+        assert!(offset == 44);
         out
     }
 
-    fn get_info_labels() -> [&'static str; 6] {
+    fn get_info_labels() -> [&'static str; 27] {
         [
             "sequence",
+            "modified_sequence",
             "precursor_mz",
+            "precursor_monoisotopic_mz",
             "precursor_charge",
             "precursor_mobility_query",
             "precursor_rt_query",
             "decoy",
+            "channel",
+            "pair_id",
+            "heavy_light_ratio",
+            "light_intensity",
+            "heavy_intensity",
+            "ratio_quality",
+            "peptide_length",
+            "missed_cleavages",
+            "num_prolines",
+            "charge_plausibility",
+            "detectability",
+            "peptide_uniqueness",
+            "protein_ids",
+            "protein_names",
+            "library_source",
+            "target_decoy_pair_id",
+            "is_decoy",
+            "is_contaminant",
+            "localization_probability",
         ]
     }
 
-    fn get_csv_record_lab_sec(&self) -> [String; 6] {
+    fn get_csv_record_lab_sec(&self) -> [String; 27] {
         [
             self.sequence.clone().into(),
+            self.sequence.peptidoform(),
             self.precursor_data.mz.to_string(),
+            self.precursor_data.monoisotopic_mz.to_string(),
             self.precursor_data.charge.to_string(),
             self.precursor_data.mobility.to_string(),
             self.precursor_data.rt.to_string(),
             self.decoy.as_str().to_string(),
+            self.channel.channel.as_str().to_string(),
+            self.channel.pair_id.to_string(),
+            self.heavy_light_ratio
+                .map(|x| x.to_string())
+                .unwrap_or_default(),
+            self.light_intensity
+                .map(|x| x.to_string())
+                .unwrap_or_default(),
+            self.heavy_intensity
+                .map(|x| x.to_string())
+                .unwrap_or_default(),
+            self.ratio_quality
+                .map(|x| x.as_str().to_string())
+                .unwrap_or_default(),
+            self.peptide_features.length.to_string(),
+            self.peptide_features.missed_cleavages.to_string(),
+            self.peptide_features.num_prolines.to_string(),
+            self.peptide_features.charge_plausibility.to_string(),
+            self.detectability.to_string(),
+            self.sequence
+                .uniqueness
+                .map(|x| x.as_str().to_string())
+                .unwrap_or_default(),
+            self.sequence
+                .protein_ids()
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<String>>()
+                .join(";"),
+            self.protein_names.clone(),
+            self.sequence
+                .library_source
+                .as_deref()
+                .unwrap_or_default()
+                .to_string(),
+            self.sequence
+                .target_decoy_pair_id
+                .map(|x| x.to_string())
+                .unwrap_or_default(),
+            (self.decoy != DecoyMarking::Target).to_string(),
+            self.is_contaminant.to_string(),
+            self.localization_probability
+                .map(|x| x.to_string())
+                .unwrap_or_default(),
         ]
     }
 
@@ -190,18 +353,145 @@ impl IonSearchResults {
     }
 }
 
+/// Quality flag of a heavy/light ratio.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+pub enum RatioQuality {
+    Ok,
+    /// One of the channels was not found or has no intensity.
+    MissingChannel,
+    /// The apexes of the channels are too far apart to be the same peak.
+    ApexMismatch,
+}
+
+impl RatioQuality {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RatioQuality::Ok => "ok",
+            RatioQuality::MissingChannel => "missing_channel",
+            RatioQuality::ApexMismatch => "apex_mismatch",
+        }
+    }
+}
+
+/// Labeled and unlabeled versions of a peptide co-elute, apexes further
+/// apart than this are flagged.
+const MAX_PAIR_APEX_RT_DIFF_SECONDS: f64 = 6.0;
+
+/// Fills in the heavy/light ratio (and its quality flag) of every result
+/// that has both channels present in `results`, and the intensities of both
+/// channels side by side.
+///
+/// The ratio uses the summed MS2 transition intensity at the apex of each
+/// channel. Results without a heavy counterpart are not flagged.
+pub fn assign_channel_ratios(results: &mut [IonSearchResults]) {
+    type ChannelApex = Option<(f64, f64)>;
+    let mut pairs: HashMap<u64, (ChannelApex, ChannelApex)> = HashMap::new();
+    for res in results.iter() {
+        let entry = pairs.entry(res.channel.pair_id).or_default();
+        let intensity = res.score_data.ms2_scores.summed_intensity as f64;
+        let rt_seconds = res.score_data.ms2_scores.retention_time_miliseconds as f64 / 1000.0;
+        match res.channel.channel {
+            LabelChannel::Light => entry.0 = Some((intensity, rt_seconds)),
+            LabelChannel::Heavy => entry.1 = Some((intensity, rt_seconds)),
+        }
+    }
+
+    for res in results.iter_mut() {
+        let (light, heavy) = match pairs.get(&res.channel.pair_id) {
+            Some(x) => *x,
+            None => (None, None),
+        };
+        res.light_intensity = light.map(|x| x.0);
+        res.heavy_intensity = heavy.map(|x| x.0);
+        res.heavy_light_ratio = match (light, heavy) {
+            (Some((light, _)), Some((heavy, _))) if light > 0.0 => Some(heavy / light),
+            _ => None,
+        };
+        res.ratio_quality = match (light, heavy) {
+            (_, None) => None,
+            (Some((light, light_rt)), Some((heavy, heavy_rt))) if light > 0.0 && heavy > 0.0 => {
+                if (light_rt - heavy_rt).abs() > MAX_PAIR_APEX_RT_DIFF_SECONDS {
+                    Some(RatioQuality::ApexMismatch)
+                } else {
+                    Some(RatioQuality::Ok)
+                }
+            }
+            _ => Some(RatioQuality::MissingChannel),
+        };
+    }
+}
+
+/// JSON value of a CSV field: numbers as numbers (null if not finite),
+/// `true` and `false` as booleans, the rest as strings.
+fn csv_field_to_json(field: &str) -> serde_json::Value {
+    if matches!(field, "NaN" | "inf" | "-inf") {
+        return serde_json::Value::Null;
+    }
+    if let Ok(x) = field.parse::<bool>() {
+        return serde_json::Value::Bool(x);
+    }
+    let is_number = field.chars().any(|x| x.is_ascii_digit()) && !field.starts_with('[');
+    match field.parse::<f64>() {
+        Ok(x) if is_number => serde_json::Number::from_f64(x)
+            .map(serde_json::Value::Number)
+            .unwrap_or(serde_json::Value::Null),
+        _ => serde_json::Value::String(field.to_string()),
+    }
+}
+
+/// Writes the results as NDJSON, one object per result keyed by the CSV
+/// column names (see [`write_results_to_csv`]).
+pub fn write_results_to_ndjson<W: std::io::Write>(
+    results: &[IonSearchResults],
+    mut writer: W,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let labels = IonSearchResults::get_csv_labels();
+    for result in results {
+        let mut object = serde_json::Map::new();
+        for (label, field) in labels.iter().zip(result.as_csv_record().iter()) {
+            object.insert(label.to_string(), csv_field_to_json(field));
+        }
+        for (name, value) in result.extra_scores.iter() {
+            object.insert(name.to_string(), csv_field_to_json(&value.to_string()));
+        }
+        serde_json::to_writer(&mut writer, &object)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// CSV header of the results, all results share the same extra scorers so
+/// the first one defines the extra columns.
+pub fn results_csv_headers(results: &[IonSearchResults]) -> Vec<&'static str> {
+    let mut headers = IonSearchResults::get_csv_labels().to_vec();
+    if let Some(first) = results.first() {
+        headers.extend(first.extra_scores.iter().map(|(name, _)| *name));
+    }
+    headers
+}
+
 pub fn write_results_to_csv<P: AsRef<Path>>(
     results: &[IonSearchResults],
     out_path: P,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    write_results_to_csv_with_headers(results, &results_csv_headers(results), out_path)
+}
+
+/// Writes the results under the given header, so that files holding parts
+/// of the same results (eg. targets and decoys) share their columns.
+pub fn write_results_to_csv_with_headers<P: AsRef<Path>>(
+    results: &[IonSearchResults],
+    headers: &[&str],
+    out_path: P,
 ) -> std::result::Result<(), Box<dyn std::error::Error>> {
     let start = Instant::now();
     let mut writer = Writer::from_path(out_path.as_ref())?;
-
-    // Write the headers
-    writer.write_record(IonSearchResults::get_csv_labels())?;
+    writer.write_record(headers)?;
 
     for result in results {
-        let record = result.as_csv_record();
+        let mut record = result.as_csv_record().to_vec();
+        record.extend(result.extra_scores.iter().map(|(_, x)| x.to_string()));
         writer.write_record(&record)?;
     }
     writer.flush()?;
@@ -212,3 +502,20 @@ pub fn write_results_to_csv<P: AsRef<Path>>(
     );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csv_field_to_json() {
+        assert_eq!(csv_field_to_json("12.5"), serde_json::json!(12.5));
+        assert_eq!(csv_field_to_json("-3"), serde_json::json!(-3.0));
+        assert_eq!(csv_field_to_json("NaN"), serde_json::Value::Null);
+        assert_eq!(csv_field_to_json("1e400"), serde_json::Value::Null);
+        assert_eq!(csv_field_to_json("Target"), serde_json::json!("Target"));
+        assert_eq!(csv_field_to_json("true"), serde_json::json!(true));
+        assert_eq!(csv_field_to_json("false"), serde_json::json!(false));
+        assert_eq!(csv_field_to_json("[1.0, 2.0]"), serde_json::json!("[1.0, 2.0]"));
+    }
+}
@@ -0,0 +1,247 @@
+use crate::fragment_mass::fragment_mass_builder::SafePosition;
+use crate::scoring::search_results::IonSearchResults;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{
+    BufWriter,
+    Write,
+};
+use std::path::Path;
+use std::time::Instant;
+use timsquery::models::aggregators::raw_peak_agg::multi_chromatogram_agg::multi_chromatogram_agg::NaturalFinalizedMultiCMGStatsArrays;
+use timsquery::models::aggregators::raw_peak_agg::point_agg::RawPeakVectorArrays;
+use timsrust::converters::ConvertableDomain;
+use timsrust::Metadata;
+
+/// Keeps every n-th value so that at most `max_points` are left (0 keeps
+/// all of them).
+///
+/// Arrays of the same length are strided the same way, so retention times
+/// and intensities stay aligned after downsampling.
+fn downsample<T: Copy>(values: &[T], max_points: usize) -> Vec<T> {
+    if max_points == 0 || values.len() <= max_points {
+        return values.to_vec();
+    }
+    let stride = values.len().div_ceil(max_points);
+    values.iter().step_by(stride).copied().collect()
+}
+
+/// Retention times and per-transition intensity traces of one MS level.
+fn level_profile<R, K, T>(
+    retention_times: &[R],
+    intensities: &HashMap<K, Vec<T>>,
+    max_points: usize,
+) -> Value
+where
+    R: Copy + Serialize,
+    K: ToString,
+    T: Copy + Serialize,
+{
+    let traces: serde_json::Map<String, Value> = intensities
+        .iter()
+        .map(|(k, v)| (k.to_string(), serde_json::json!(downsample(v, max_points))))
+        .collect();
+    serde_json::json!({
+        "retention_time_miliseconds": downsample(retention_times, max_points),
+        "transition_intensities": traces,
+    })
+}
+
+/// Chromatograms of a precursor, the MS1 traces are keyed by isotope index
+/// and the MS2 ones by fragment label. Downsampled to at most `max_points`
+/// points (0 keeps all of them).
+pub fn xic_profiles(
+    arrays: &NaturalFinalizedMultiCMGStatsArrays<SafePosition>,
+    max_points: usize,
+) -> Value {
+    serde_json::json!({
+        "ms1": level_profile(
+            &arrays.ms1_stats.retention_time_miliseconds,
+            &arrays.ms1_stats.transition_intensities,
+            max_points,
+        ),
+        "ms2": level_profile(
+            &arrays.ms2_stats.retention_time_miliseconds,
+            &arrays.ms2_stats.transition_intensities,
+            max_points,
+        ),
+    })
+}
+
+/// Raw peak matched by the extraction of a precursor, before timsquery
+/// aggregates it into the chromatograms.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct RawFramePeak {
+    pub frame: usize,
+    pub scan: usize,
+    pub retention_time_seconds: f64,
+    pub mz: f64,
+    pub mobility: f64,
+    pub intensity: u32,
+}
+
+/// Raw peaks of a query with their frame and scan numbers, m/z and 1/K0
+/// converted with the calibration of the run.
+pub fn raw_peaks(arrays: &RawPeakVectorArrays, metadata: &Metadata) -> Vec<RawFramePeak> {
+    (0..arrays.intensities.len())
+        .map(|i| {
+            let retention_time_seconds = arrays.retention_times[i] as f64;
+            RawFramePeak {
+                frame: metadata.rt_converter.invert(retention_time_seconds).round() as usize,
+                scan: arrays.scans[i],
+                retention_time_seconds,
+                mz: metadata.mz_converter.convert(arrays.tofs[i]),
+                mobility: metadata.im_converter.convert(arrays.scans[i] as f64),
+                intensity: arrays.intensities[i],
+            }
+        })
+        .collect()
+}
+
+/// Raw peaks grouped by frame (in frame and scan order), so they can be
+/// compared with the peak lists of the vendor software.
+pub fn diagnostic_frames(mut peaks: Vec<RawFramePeak>) -> Value {
+    peaks.sort_by(|a, b| (a.frame, a.scan).cmp(&(b.frame, b.scan)));
+    let mut frames: Vec<Value> = Vec::new();
+    for chunk in peaks.chunk_by(|a, b| a.frame == b.frame) {
+        let peaks: Vec<Value> = chunk
+            .iter()
+            .map(|x| {
+                serde_json::json!({
+                    "scan": x.scan,
+                    "mz": x.mz,
+                    "mobility": x.mobility,
+                    "intensity": x.intensity,
+                })
+            })
+            .collect();
+        frames.push(serde_json::json!({
+            "frame": chunk[0].frame,
+            "retention_time_seconds": chunk[0].retention_time_seconds,
+            "peaks": peaks,
+        }));
+    }
+    Value::Array(frames)
+}
+
+/// Time-resolved MS2 score arrays of a precursor and the retention times
+/// they are aligned to.
+///
+/// These are the traces the apex is picked from, so they are what is needed
+/// to debug a mis-picked apex.
+pub fn score_trace_arrays(arrays: &NaturalFinalizedMultiCMGStatsArrays<SafePosition>) -> Value {
+    let ms2 = &arrays.ms2_stats;
+    serde_json::json!({
+        "retention_time_miliseconds": ms2.retention_time_miliseconds,
+        "lazyerscore": ms2.lazyerscore,
+        "lazyerscore_vs_baseline": ms2.lazyerscore_vs_baseline,
+        "norm_lazyerscore_vs_baseline": ms2.norm_lazyerscore_vs_baseline,
+    })
+}
+
+/// Writes the chromatogram profiles of the results that have them, one JSON
+/// object per line.
+pub fn write_xics_to_ndjson<P: AsRef<Path>>(
+    results: &[IonSearchResults],
+    out_path: P,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    write_profiles_to_ndjson(results, out_path, |x| x.xic_profiles.as_ref())
+}
+
+/// Writes the per-RT score traces of the results that have them, one JSON
+/// object per line.
+pub fn write_score_traces_to_ndjson<P: AsRef<Path>>(
+    results: &[IonSearchResults],
+    out_path: P,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    write_profiles_to_ndjson(results, out_path, |x| x.score_traces.as_ref())
+}
+
+/// Writes the diagnostics dump of the results that have one, one JSON object
+/// per line.
+pub fn write_diagnostics_to_ndjson<P: AsRef<Path>>(
+    results: &[IonSearchResults],
+    out_path: P,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    write_profiles_to_ndjson(results, out_path, |x| x.diagnostics.as_ref())
+}
+
+fn write_profiles_to_ndjson<P: AsRef<Path>>(
+    results: &[IonSearchResults],
+    out_path: P,
+    profiles: impl Fn(&IonSearchResults) -> Option<&Value>,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let start = Instant::now();
+    let mut writer = BufWriter::new(std::fs::File::create(out_path.as_ref())?);
+    for result in results {
+        let xics = match profiles(result) {
+            Some(x) => x,
+            None => continue,
+        };
+        let sequence: String = result.sequence.clone().into();
+        let line = serde_json::json!({
+            "sequence": sequence,
+            "modified_sequence": result.sequence.peptidoform(),
+            "precursor_charge": result.precursor_data.charge,
+            "decoy": result.decoy.as_str(),
+            "pair_id": result.channel.pair_id,
+            "xics": xics,
+        });
+        serde_json::to_writer(&mut writer, &line)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    log::info!(
+        "Writing profiles took {:?} -> {:?}",
+        start.elapsed(),
+        out_path.as_ref()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diagnostic_frames() {
+        let peak = |frame: usize, scan: usize, intensity: u32| RawFramePeak {
+            frame,
+            scan,
+            retention_time_seconds: frame as f64 / 10.0,
+            mz: 147.1,
+            mobility: 0.9,
+            intensity,
+        };
+        let frames = diagnostic_frames(vec![peak(12, 300, 5), peak(10, 301, 7), peak(10, 299, 9)]);
+        assert_eq!(
+            frames,
+            serde_json::json!([
+                {
+                    "frame": 10,
+                    "retention_time_seconds": 1.0,
+                    "peaks": [
+                        {"scan": 299, "mz": 147.1, "mobility": 0.9, "intensity": 9},
+                        {"scan": 301, "mz": 147.1, "mobility": 0.9, "intensity": 7},
+                    ],
+                },
+                {
+                    "frame": 12,
+                    "retention_time_seconds": 1.2,
+                    "peaks": [{"scan": 300, "mz": 147.1, "mobility": 0.9, "intensity": 5}],
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_level_profile() {
+        assert_eq!(downsample(&[1, 2, 3, 4, 5, 6], 3), vec![1, 3, 5]);
+        assert_eq!(downsample(&[1, 2, 3], 0), vec![1, 2, 3]);
+        let intensities = HashMap::from([(0usize, vec![10u64, 20, 30, 40, 50, 60])]);
+        let out = level_profile(&[1u32, 2, 3, 4, 5, 6], &intensities, 3);
+        assert_eq!(out["retention_time_miliseconds"], serde_json::json!([1, 3, 5]));
+        assert_eq!(out["transition_intensities"]["0"], serde_json::json!([10, 30, 50]));
+    }
+}
@@ -0,0 +1,248 @@
+use crate::errors::TimsSeekError;
+use crate::models::{
+    DecoyMarking,
+    DigestSlice,
+    NamedQueryChunk,
+};
+use crate::protein::fasta::FastaValidation;
+use rusqlite::{
+    Connection,
+    OpenFlags,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Runs whose isolation windows span at most this many m/z are taken as one
+/// gas-phase fraction (GPF) of a sample.
+pub const GPF_MAX_SPAN_MZ: f64 = 300.0;
+
+/// Sorted, non-overlapping precursor m/z intervals covered by the acquisition
+/// (isolation) windows of a run.
+///
+/// Used to skip precursors that cannot be observed in the run before they are
+/// queried.
+#[derive(Debug, Clone)]
+pub struct IsolationWindowIndex {
+    windows: Vec<(f64, f64)>,
+}
+
+impl IsolationWindowIndex {
+    /// Builds the index from (possibly overlapping, unsorted) `(low, high)`
+    /// m/z windows.
+    pub fn new(windows: &[(f64, f64)]) -> Self {
+        let mut sorted: Vec<(f64, f64)> = windows
+            .iter()
+            .map(|(a, b)| (a.min(*b), a.max(*b)))
+            .collect();
+        sorted.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut merged: Vec<(f64, f64)> = Vec::with_capacity(sorted.len());
+        for (low, high) in sorted {
+            match merged.last_mut() {
+                Some(last) if low <= last.1 => last.1 = last.1.max(high),
+                _ => merged.push((low, high)),
+            }
+        }
+        Self { windows: merged }
+    }
+
+    pub fn contains(&self, mz: f64) -> bool {
+        // Index of the first window starting after the m/z, the candidate is
+        // the one right before it.
+        let idx = self.windows.partition_point(|(low, _)| *low <= mz);
+        idx > 0 && mz <= self.windows[idx - 1].1
+    }
+
+    pub fn windows(&self) -> &[(f64, f64)] {
+        &self.windows
+    }
+
+    /// Isolation windows of the DIA method of a Bruker .d directory, read
+    /// from the `DiaFrameMsMsWindows` table of its `analysis.tdf`.
+    pub fn from_dotd(dotd: &Path) -> Result<Self, TimsSeekError> {
+        let tdf = dotd.join("analysis.tdf");
+        let sqlite_error = |e: rusqlite::Error| TimsSeekError::ParseError {
+            msg: format!("Error reading the DIA windows of {}: {}", tdf.display(), e),
+        };
+        let connection = Connection::open_with_flags(&tdf, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(sqlite_error)?;
+        let mut statement = connection
+            .prepare("SELECT IsolationMz, IsolationWidth FROM DiaFrameMsMsWindows")
+            .map_err(sqlite_error)?;
+        let windows = statement
+            .query_map([], |row| {
+                let (mz, width): (f64, f64) = (row.get(0)?, row.get(1)?);
+                Ok((mz - width / 2.0, mz + width / 2.0))
+            })
+            .map_err(sqlite_error)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(sqlite_error)?;
+        Ok(Self::new(&windows))
+    }
+
+    /// Lowest and highest m/z covered by the windows.
+    pub fn span(&self) -> Option<(f64, f64)> {
+        Some((self.windows.first()?.0, self.windows.last()?.1))
+    }
+
+    /// Span of the windows if it is at most [`GPF_MAX_SPAN_MZ`] wide, ie. the
+    /// run is one gas-phase fraction.
+    pub fn gas_phase_fraction(&self) -> Option<(f64, f64)> {
+        self.span().filter(|(low, high)| high - low <= GPF_MAX_SPAN_MZ)
+    }
+}
+
+/// Counts of the precursors that could be queried in a run, and of the ones
+/// that were.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SearchSpaceStats {
+    /// Precursors checked against the isolation windows, 0 without windows.
+    pub total: usize,
+    /// Precursors within the isolation windows.
+    pub queryable: usize,
+    /// Queried elution groups per precursor charge.
+    pub elution_groups_per_charge: BTreeMap<u8, usize>,
+    pub target_elution_groups: usize,
+    pub decoy_elution_groups: usize,
+    /// Elution groups queried without some of their isotope peaks, which
+    /// were outside the MS1 scan range.
+    pub isotope_trimmed_elution_groups: usize,
+}
+
+impl SearchSpaceStats {
+    /// Counts the elution groups of a chunk that is about to be queried.
+    pub fn add_queried(&mut self, chunk: &NamedQueryChunk) {
+        for (digest, charge) in chunk.digests().iter().zip(chunk.charges()) {
+            *self.elution_groups_per_charge.entry(*charge).or_default() += 1;
+            if digest.decoy == DecoyMarking::Target {
+                self.target_elution_groups += 1;
+            } else {
+                self.decoy_elution_groups += 1;
+            }
+        }
+    }
+
+    pub fn queryable_fraction(&self) -> f64 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        self.queryable as f64 / self.total as f64
+    }
+}
+
+/// Target and decoy peptides left after a step of building the database.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DatabaseStep {
+    pub step: String,
+    pub targets: usize,
+    pub decoys: usize,
+}
+
+/// Proteins of the database and peptides left after every step of the
+/// digestion, so differences in IDs between runs can be traced back to the
+/// database instead of the scoring.
+///
+/// Generated decoys are built chunk by chunk, they are only counted as
+/// elution groups in [`SearchSpaceStats`].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DatabaseStats {
+    pub fasta: FastaValidation,
+    pub target_proteins: usize,
+    pub decoy_proteins: usize,
+    pub steps: Vec<DatabaseStep>,
+}
+
+impl DatabaseStats {
+    pub fn record_step(&mut self, step: &str, digests: &[DigestSlice]) {
+        let targets = digests
+            .iter()
+            .filter(|x| x.decoy == DecoyMarking::Target)
+            .count();
+        self.steps.push(DatabaseStep {
+            step: step.to_string(),
+            targets,
+            decoys: digests.len() - targets,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_index() {
+        let index = IsolationWindowIndex::new(&[(500.0, 525.0), (400.0, 425.0), (520.0, 550.0)]);
+        assert_eq!(index.windows(), &[(400.0, 425.0), (500.0, 550.0)]);
+        assert!(index.contains(400.0));
+        assert!(index.contains(530.0));
+        assert!(!index.contains(450.0));
+        assert!(!index.contains(399.0));
+        assert!(!index.contains(551.0));
+        assert_eq!(index.span(), Some((400.0, 550.0)));
+        assert_eq!(index.gas_phase_fraction(), Some((400.0, 550.0)));
+
+        let wide = IsolationWindowIndex::new(&[(400.0, 425.0), (1175.0, 1200.0)]);
+        assert_eq!(wide.gas_phase_fraction(), None);
+        assert_eq!(IsolationWindowIndex::new(&[]).span(), None);
+    }
+
+    #[test]
+    fn test_window_index_from_dotd() {
+        let dotd = std::env::temp_dir().join("timsseek_test_gpf.d");
+        std::fs::create_dir_all(&dotd).unwrap();
+        let tdf = dotd.join("analysis.tdf");
+        let _ = std::fs::remove_file(&tdf);
+        let connection = Connection::open(&tdf).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE DiaFrameMsMsWindows (WindowGroup integer, ScanNumBegin \
+                 integer, ScanNumEnd integer, IsolationMz double, IsolationWidth double, \
+                 CollisionEnergy double); \
+                 INSERT INTO DiaFrameMsMsWindows VALUES (1, 0, 400, 512.5, 25.0, 30.0); \
+                 INSERT INTO DiaFrameMsMsWindows VALUES (2, 0, 400, 537.5, 25.0, 30.0);",
+            )
+            .unwrap();
+        drop(connection);
+
+        let index = IsolationWindowIndex::from_dotd(&dotd).unwrap();
+        std::fs::remove_dir_all(&dotd).unwrap();
+        assert_eq!(index.windows(), &[(500.0, 550.0)]);
+        assert_eq!(index.gas_phase_fraction(), Some((500.0, 550.0)));
+    }
+
+    #[test]
+    fn test_search_space_counts() {
+        use crate::fragment_mass::elution_group_converter::SequenceToElutionGroupConverter;
+        use std::sync::Arc;
+
+        let seq: Arc<str> = "PEPTIDEKAAAAK".into();
+        let target = DigestSlice::new(seq.clone(), 0..8, DecoyMarking::Target);
+        let decoy = target.with_decoy(DecoyMarking::Decoy);
+        let digests = vec![target.clone(), target, decoy];
+
+        let mut database = DatabaseStats::default();
+        database.record_step("deduplicated", &digests);
+        assert_eq!(
+            database.steps,
+            vec![DatabaseStep {
+                step: "deduplicated".to_string(),
+                targets: 2,
+                decoys: 1,
+            }]
+        );
+
+        let (queries, _) = SequenceToElutionGroupConverter::default()
+            .convert_sequence("PEPTIDEK", 0)
+            .unwrap();
+        let queries = vec![queries[0].clone(); 3];
+        let chunk = NamedQueryChunk::new(digests, vec![2, 3, 2], queries);
+        let mut search_space = SearchSpaceStats::default();
+        search_space.add_queried(&chunk);
+        assert_eq!(search_space.elution_groups_per_charge[&2], 2);
+        assert_eq!(search_space.elution_groups_per_charge[&3], 1);
+        assert_eq!(search_space.target_elution_groups, 2);
+        assert_eq!(search_space.decoy_elution_groups, 1);
+    }
+}